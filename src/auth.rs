@@ -0,0 +1,564 @@
+use crate::config::config_dir;
+use crate::keyring;
+use crate::resolve_input;
+use reqwest::Client;
+use serde::Deserialize;
+use serde_json::Value;
+use sha1::{Digest, Sha1};
+use std::io::{self, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::{env, fmt, fs};
+
+pub const LOGIN_URL: &str = "https://api.shelly.cloud/auth/login";
+pub const DEFAULT_BASE_URL: &str = "https://shelly-001-eu.shelly.cloud";
+const SYSTEMD_CREDENTIAL: &str = "shelly-auth-key";
+/// OAuth client of the Shelly cloud for personal integrations.
+pub const OAUTH_CLIENT_ID: &str = "shelly-diy";
+
+/// A credential that never shows up in logs, errors or panics; the raw value
+/// is only reachable through [`Secret::expose`].
+#[derive(Clone, PartialEq, Deserialize)]
+pub struct Secret(String);
+
+impl Secret {
+    pub fn new(value: impl Into<String>) -> Self {
+        Secret(value.into())
+    }
+
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+
+    /// Masks any occurrence of the secret in `text`, e.g. an error echoed by a server.
+    pub fn redact(&self, text: &str) -> String {
+        if self.0.is_empty() {
+            return text.to_string();
+        }
+        text.replace(&self.0, "[redacted]")
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Secret([redacted])")
+    }
+}
+
+impl fmt::Display for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("[redacted]")
+    }
+}
+
+impl FromStr for Secret {
+    type Err = std::convert::Infallible;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        Ok(Secret::new(value))
+    }
+}
+
+#[derive(Deserialize, Debug)]
+struct CloudResponse {
+    isok: bool,
+    errors: Option<Value>,
+    data: Option<Value>,
+}
+
+impl CloudResponse {
+    fn into_data(self) -> Result<Value, Box<dyn std::error::Error>> {
+        match (self.isok, self.data) {
+            (true, Some(data)) => Ok(data),
+            _ => Err(format!(
+                "Shelly cloud returned an error - {}",
+                self.errors.unwrap_or(Value::Null)
+            )
+            .into()),
+        }
+    }
+}
+
+/// Logs in with the Shelly account credentials and stores the cloud auth key
+/// and the account's server URL for later runs.
+pub async fn login(
+    client: &Client,
+    login_url: &str,
+    email: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let password = Secret::new(rpassword::prompt_password(format!(
+        "Shelly password for {}: ",
+        email
+    ))?);
+    let (auth_key, base_url) = fetch_credentials(client, login_url, email, &password).await?;
+
+    store("auth_key", auth_key.expose())?;
+    store("base_url", &base_url)?;
+    println!("Logged in, devices are served by {}", base_url);
+    Ok(())
+}
+
+// Exchange email and password for a session token, then read the auth key of the account
+async fn fetch_credentials(
+    client: &Client,
+    login_url: &str,
+    email: &str,
+    password: &Secret,
+) -> Result<(Secret, String), Box<dyn std::error::Error>> {
+    let password_hash = format!("{:x}", Sha1::digest(password.expose().as_bytes()));
+    let session = client
+        .post(login_url)
+        .form(&[("email", email), ("password", &password_hash), ("var", "2")])
+        .send()
+        .await?
+        .json::<CloudResponse>()
+        .await?
+        .into_data()?;
+
+    let token = session["token"]
+        .as_str()
+        .ok_or("Login response has no token")?;
+    let base_url = session["user_api_url"]
+        .as_str()
+        .ok_or("Login response has no server URL")?
+        .trim_end_matches('/')
+        .to_string();
+
+    let auth_key = fetch_auth_key(client, &base_url, token).await?;
+    Ok((auth_key, base_url))
+}
+
+/// Tokens of the cloud OAuth flow: the access token reads the auth key of the account, and the
+/// refresh token, when the cloud gives one, gets new tokens once it has expired.
+#[derive(Debug)]
+struct OAuthTokens {
+    access: Secret,
+    refresh: Option<Secret>,
+}
+
+impl OAuthTokens {
+    // The single-use code itself is never kept
+    async fn store(&self) -> io::Result<()> {
+        store_secret("oauth_access_token", &self.access).await?;
+        if let Some(refresh) = &self.refresh {
+            store_secret("oauth_refresh_token", refresh).await?;
+        }
+        Ok(())
+    }
+
+    async fn stored() -> Option<Self> {
+        Some(OAuthTokens {
+            access: stored_secret("oauth_access_token").await?,
+            refresh: stored_secret("oauth_refresh_token").await,
+        })
+    }
+
+    fn redact(&self, text: &str) -> String {
+        let text = self.access.redact(text);
+        match &self.refresh {
+            Some(refresh) => refresh.redact(&text),
+            None => text,
+        }
+    }
+}
+
+/// Logs in with the code of the cloud OAuth flow, exchanged for tokens that are stored to
+/// renew the auth key once revoked.
+pub async fn login_oauth(
+    client: &Client,
+    base_url: &str,
+    code: &Secret,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let tokens = fetch_oauth_tokens(client, base_url, ("code", code)).await?;
+    let auth_key = fetch_auth_key(client, base_url, tokens.access.expose()).await?;
+    tokens.store().await?;
+    store("auth_key", auth_key.expose())?;
+    store("base_url", base_url)?;
+    println!("Logged in, devices are served by {}", base_url);
+    Ok(())
+}
+
+/// Fetches a new auth key with the OAuth tokens stored by `login --code`, and stores it.
+pub async fn renew_auth_key(client: &Client, base_url: &str) -> Option<Secret> {
+    let tokens = OAuthTokens::stored().await?;
+    match fetch_renewed_auth_key(client, base_url, &tokens).await {
+        Ok((auth_key, refreshed)) => {
+            if let Some(refreshed) = refreshed {
+                if let Err(err) = refreshed.store().await {
+                    eprintln!(
+                        "Error: Unable to store the refreshed OAuth tokens - {}",
+                        err
+                    );
+                }
+            }
+            if let Err(err) = store("auth_key", auth_key.expose()) {
+                eprintln!("Error: Unable to store the renewed auth key - {}", err);
+            }
+            Some(auth_key)
+        }
+        Err(err) => {
+            eprintln!("Error: Unable to renew the auth key - {}", err);
+            None
+        }
+    }
+}
+
+// Read the auth key with the access token, refreshing the tokens first once it has expired,
+// and return the refreshed ones. Errors are redacted of the tokens.
+async fn fetch_renewed_auth_key(
+    client: &Client,
+    base_url: &str,
+    tokens: &OAuthTokens,
+) -> Result<(Secret, Option<OAuthTokens>), String> {
+    let expired = match fetch_auth_key(client, base_url, tokens.access.expose()).await {
+        Ok(auth_key) => return Ok((auth_key, None)),
+        Err(err) => tokens.redact(&err.to_string()),
+    };
+    let Some(refresh) = &tokens.refresh else {
+        return Err(format!("{}, log in again with `login --code`", expired));
+    };
+    let renewed = fetch_oauth_tokens(client, base_url, ("refresh_token", refresh))
+        .await
+        .map_err(|err| tokens.redact(&err.to_string()))?;
+    let renewed = OAuthTokens {
+        // The cloud may keep the refresh token
+        refresh: renewed.refresh.or(tokens.refresh.clone()),
+        ..renewed
+    };
+    let auth_key = fetch_auth_key(client, base_url, renewed.access.expose())
+        .await
+        .map_err(|err| renewed.redact(&err.to_string()))?;
+    Ok((auth_key, Some(renewed)))
+}
+
+// Exchange an OAuth grant, the code of the flow or a refresh token, for new tokens
+async fn fetch_oauth_tokens(
+    client: &Client,
+    base_url: &str,
+    (grant_type, grant): (&str, &Secret),
+) -> Result<OAuthTokens, Box<dyn std::error::Error>> {
+    let response: Value = client
+        .post(format!("{}/oauth/auth", base_url))
+        .form(&[
+            ("client_id", OAUTH_CLIENT_ID),
+            ("grant_type", grant_type),
+            (grant_type, grant.expose()),
+        ])
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+    let access = response["access_token"]
+        .as_str()
+        .ok_or("OAuth response has no access token")?;
+    Ok(OAuthTokens {
+        access: Secret::new(access),
+        refresh: response["refresh_token"].as_str().map(Secret::new),
+    })
+}
+
+async fn fetch_auth_key(
+    client: &Client,
+    base_url: &str,
+    token: &str,
+) -> Result<Secret, Box<dyn std::error::Error>> {
+    let settings = client
+        .post(format!("{}/user/get_user_settings", base_url))
+        .bearer_auth(token)
+        .send()
+        .await?
+        .json::<CloudResponse>()
+        .await?
+        .into_data()?;
+    settings["auth_key"]
+        .as_str()
+        .map(Secret::new)
+        .ok_or_else(|| "User settings have no auth key".into())
+}
+
+fn credential_path(name: &str) -> Option<PathBuf> {
+    config_dir().map(|dir| dir.join(name))
+}
+
+// Credentials are only readable by the current user
+fn store(name: &str, value: &str) -> io::Result<()> {
+    let path = credential_path(name)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "No config directory"))?;
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(&path)?;
+    writeln!(file, "{}", value)
+}
+
+// Credentials go to the Secret Service when there is one, else to a file like `store`
+async fn store_secret(name: &str, secret: &Secret) -> io::Result<()> {
+    match keyring::store(name, secret).await {
+        Ok(()) => Ok(()),
+        Err(_) => store(name, secret.expose()),
+    }
+}
+
+async fn stored_secret(name: &str) -> Option<Secret> {
+    match keyring::lookup(name).await {
+        Some(secret) => Some(secret),
+        None => stored(name).map(Secret::new),
+    }
+}
+
+fn stored(name: &str) -> Option<String> {
+    let path = credential_path(name)?;
+    let value = fs::read_to_string(path).ok()?.trim().to_string();
+    (!value.is_empty()).then_some(value)
+}
+
+// Credential passed by systemd with LoadCredential=/LoadCredentialEncrypted=
+fn read_credential(dir: &Path, name: &str) -> Option<String> {
+    let value = fs::read_to_string(dir.join(name)).ok()?.trim().to_string();
+    (!value.is_empty()).then_some(value)
+}
+
+/// Resolves the auth key from the command line, the systemd credentials
+/// directory, the Secret Service, or a previous `login`.
+pub async fn resolve_auth_key(arg: Option<&Secret>) -> Result<Secret, Box<dyn std::error::Error>> {
+    if let Some(arg) = arg {
+        return Ok(Secret::new(resolve_input(arg.expose())?));
+    }
+    if let Some(key) = env::var_os("CREDENTIALS_DIRECTORY")
+        .and_then(|dir| read_credential(Path::new(&dir), SYSTEMD_CREDENTIAL))
+    {
+        return Ok(Secret::new(key));
+    }
+    if let Some(key) = keyring::lookup("auth_key").await {
+        return Ok(key);
+    }
+    stored("auth_key")
+        .map(Secret::new)
+        .ok_or_else(|| "No auth key found: pass --auth-key or run `shelly-waybar login`".into())
+}
+
+/// Resolves the optional state passphrase from the command line or the Secret Service.
+pub async fn resolve_state_passphrase(arg: Option<&Secret>) -> Result<Option<Secret>, io::Error> {
+    match arg {
+        Some(arg) => Ok(Some(Secret::new(resolve_input(arg.expose())?))),
+        None => Ok(keyring::lookup("state_passphrase").await),
+    }
+}
+
+/// Saves the auth key given on the command line, or typed at a prompt, in the Secret Service.
+pub async fn store_key(arg: Option<&Secret>) -> Result<(), Box<dyn std::error::Error>> {
+    let key = match arg {
+        Some(arg) => Secret::new(resolve_input(arg.expose())?),
+        None => Secret::new(rpassword::prompt_password("Shelly auth key: ")?),
+    };
+    keyring::store("auth_key", &key).await?;
+    println!("Auth key stored in the Secret Service");
+    Ok(())
+}
+
+/// Saves the password of a device on the LAN, read from a prompt, in the Secret Service.
+pub async fn store_password(device_id: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let password = Secret::new(rpassword::prompt_password(format!(
+        "Password of {}: ",
+        device_id
+    ))?);
+    keyring::store(&keyring::password_account(device_id), &password).await?;
+    println!("Password of {} stored in the Secret Service", device_id);
+    Ok(())
+}
+
+/// Resolves the server URL from the command line, a previous `login`, or the default.
+pub fn resolve_base_url(arg: Option<&str>) -> Result<String, io::Error> {
+    match arg {
+        Some(arg) => resolve_input(arg),
+        None => Ok(stored("base_url").unwrap_or_else(|| DEFAULT_BASE_URL.to_string())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use httpmock::MockServer;
+    use serde_json::json;
+
+    #[test]
+    fn test_secret_is_redacted() {
+        let secret = Secret::new("MTIzNDU2");
+
+        assert_eq!(format!("{}", secret), "[redacted]");
+        assert_eq!(format!("{:?}", Some(&secret)), "Some(Secret([redacted]))");
+        assert_eq!(
+            secret.redact("invalid auth_key MTIzNDU2"),
+            "invalid auth_key [redacted]"
+        );
+        assert_eq!(secret.expose(), "MTIzNDU2");
+        assert_eq!(Secret::new("").redact("no key"), "no key");
+    }
+
+    #[test]
+    fn test_read_credential() {
+        let dir = Path::new("/tmp/shelly-waybar-credentials");
+        fs::create_dir_all(dir).unwrap();
+        fs::write(dir.join(SYSTEMD_CREDENTIAL), "credential-key\n").unwrap();
+
+        assert_eq!(
+            read_credential(dir, SYSTEMD_CREDENTIAL),
+            Some("credential-key".to_string())
+        );
+        assert_eq!(read_credential(dir, "missing"), None);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_fetch_credentials() {
+        let server = MockServer::start_async().await;
+        let login = server.mock(|when, then| {
+            when.method("POST")
+                .path("/auth/login")
+                .body_contains("password=a94a8fe5ccb19ba61c4c0873d391e987982fbbd3");
+            then.status(200).json_body(json!({
+                "isok": true,
+                "data": { "token": "jwt", "user_api_url": server.base_url() + "/" }
+            }));
+        });
+        let settings = server.mock(|when, then| {
+            when.method("POST")
+                .path("/user/get_user_settings")
+                .header("authorization", "Bearer jwt");
+            then.status(200).json_body(json!({
+                "isok": true,
+                "data": { "auth_key": "secret-key" }
+            }));
+        });
+
+        let client = Client::new();
+        let login_url = format!("{}/auth/login", server.base_url());
+        let (auth_key, base_url) =
+            fetch_credentials(&client, &login_url, "me@example.com", &Secret::new("test"))
+                .await
+                .unwrap();
+
+        login.assert();
+        settings.assert();
+        assert_eq!(auth_key.expose(), "secret-key");
+        assert_eq!(base_url, server.base_url());
+    }
+
+    #[tokio::test]
+    async fn test_fetch_oauth_tokens() {
+        let server = MockServer::start_async().await;
+        let oauth = server.mock(|when, then| {
+            when.method("POST")
+                .path("/oauth/auth")
+                .body_contains("client_id=shelly-diy")
+                .body_contains("grant_type=code")
+                .body_contains("code=oauth-code");
+            then.status(200).json_body(json!({
+                "access_token": "access-jwt",
+                "refresh_token": "refresh-jwt"
+            }));
+        });
+        server.mock(|when, then| {
+            when.method("POST").path("/oauth/auth");
+            then.status(401);
+        });
+
+        let client = Client::new();
+        let code = Secret::new("oauth-code");
+        let tokens = fetch_oauth_tokens(&client, &server.base_url(), ("code", &code))
+            .await
+            .unwrap();
+        oauth.assert();
+        assert_eq!(tokens.access.expose(), "access-jwt");
+        assert_eq!(tokens.refresh, Some(Secret::new("refresh-jwt")));
+
+        let revoked = Secret::new("revoked");
+        let result = fetch_oauth_tokens(&client, &server.base_url(), ("code", &revoked)).await;
+        assert!(result.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_fetch_renewed_auth_key() {
+        let server = MockServer::start_async().await;
+        let refresh = server.mock(|when, then| {
+            when.method("POST")
+                .path("/oauth/auth")
+                .body_contains("grant_type=refresh_token")
+                .body_contains("refresh_token=refresh-jwt");
+            then.status(200)
+                .json_body(json!({ "access_token": "new-access-jwt" }));
+        });
+        server.mock(|when, then| {
+            when.method("POST")
+                .path("/user/get_user_settings")
+                .header("authorization", "Bearer new-access-jwt");
+            then.status(200).json_body(json!({
+                "isok": true,
+                "data": { "auth_key": "renewed-key" }
+            }));
+        });
+        server.mock(|when, then| {
+            when.method("POST").path("/user/get_user_settings");
+            then.status(401).json_body(json!({ "isok": false }));
+        });
+
+        // A valid access token reads the key as is
+        let client = Client::new();
+        let tokens = OAuthTokens {
+            access: Secret::new("new-access-jwt"),
+            refresh: None,
+        };
+        let (auth_key, refreshed) = fetch_renewed_auth_key(&client, &server.base_url(), &tokens)
+            .await
+            .unwrap();
+        assert_eq!(auth_key.expose(), "renewed-key");
+        assert!(refreshed.is_none());
+        refresh.assert_hits(0);
+
+        // An expired one is refreshed, never replaying the code of the flow
+        let tokens = OAuthTokens {
+            access: Secret::new("expired-jwt"),
+            refresh: Some(Secret::new("refresh-jwt")),
+        };
+        let (auth_key, refreshed) = fetch_renewed_auth_key(&client, &server.base_url(), &tokens)
+            .await
+            .unwrap();
+        assert_eq!(auth_key.expose(), "renewed-key");
+        let refreshed = refreshed.unwrap();
+        assert_eq!(refreshed.access.expose(), "new-access-jwt");
+        assert_eq!(refreshed.refresh, Some(Secret::new("refresh-jwt")));
+        refresh.assert();
+
+        let tokens = OAuthTokens {
+            access: Secret::new("expired-jwt"),
+            refresh: None,
+        };
+        let result = fetch_renewed_auth_key(&client, &server.base_url(), &tokens).await;
+        assert!(result.unwrap_err().contains("log in again"));
+    }
+
+    #[tokio::test]
+    async fn test_fetch_credentials_wrong_password() {
+        let server = MockServer::start_async().await;
+        server.mock(|when, then| {
+            when.method("POST").path("/auth/login");
+            then.status(200).json_body(json!({
+                "isok": false,
+                "errors": { "wrong_credentials": "Wrong credentials" }
+            }));
+        });
+
+        let client = Client::new();
+        let login_url = format!("{}/auth/login", server.base_url());
+        let result =
+            fetch_credentials(&client, &login_url, "me@example.com", &Secret::new("bad")).await;
+        assert!(result.is_err());
+    }
+}
@@ -0,0 +1,150 @@
+use crate::fields::strip_markup;
+use serde_json::{json, Value};
+
+/// Header of the i3bar protocol, opening the endless array of status lines.
+pub const I3BAR_HEADER: &str = "{\"version\":1}\n[";
+
+/// Block of a device in a status line of the i3bar protocol.
+#[derive(Debug)]
+pub struct Block {
+    pub instance: String,
+    pub full_text: String,
+    pub short_text: Option<String>,
+    pub color: Option<&'static str>,
+}
+
+/// Block of i3blocks: the full text, the short text shown when the bar runs out of space,
+/// and the text color, one per line.
+pub fn i3blocks(output: &Value, short_text: Option<&str>, color: Option<&str>) -> String {
+    let text = output["text"].as_str().unwrap_or_default();
+    format!(
+        "{}\n{}\n{}\n",
+        text,
+        short_text.unwrap_or(text),
+        color.unwrap_or_default()
+    )
+}
+
+/// Status line of the i3bar protocol (swaybar, i3bar), one block per device.
+pub fn i3bar(blocks: &[Block]) -> String {
+    let blocks: Vec<Value> = blocks
+        .iter()
+        .map(|block| {
+            let mut value = json!({
+                "name": "shelly",
+                "instance": block.instance,
+                "full_text": block.full_text,
+                "short_text": block.short_text.as_ref().unwrap_or(&block.full_text),
+                "markup": "pango",
+            });
+            if let Some(color) = block.color {
+                value["color"] = Value::from(color);
+            }
+            value
+        })
+        .collect();
+    format!("{},", Value::Array(blocks))
+}
+
+/// Line of an xmobar `CommandReader`: the devices joined with the separator, each one in
+/// its color with `<fc>` tags.
+pub fn xmobar(blocks: &[Block], separator: &str) -> String {
+    blocks
+        .iter()
+        .map(|block| {
+            let text = strip_markup(&block.full_text);
+            // Text that xmobar would take for a tag is shown as is
+            let text = match text.contains('<') {
+                true => format!("<raw={}:{}/>", text.chars().count(), text),
+                false => text,
+            };
+            match block.color {
+                Some(color) => format!("<fc={}>{}</fc>", color, text),
+                None => text,
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(separator)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_i3blocks() {
+        let output = json!({ "text": "T: 21.5°C (Balcony)", "tooltip": "" });
+
+        assert_eq!(
+            i3blocks(&output, Some("21.5°C"), Some("#f38ba8")),
+            "T: 21.5°C (Balcony)\n21.5°C\n#f38ba8\n"
+        );
+        assert_eq!(
+            i3blocks(&output, None, None),
+            "T: 21.5°C (Balcony)\nT: 21.5°C (Balcony)\n\n"
+        );
+    }
+
+    #[test]
+    fn test_i3bar() {
+        let blocks = [
+            Block {
+                instance: "12345".to_string(),
+                full_text: "Open (Front Door)".to_string(),
+                short_text: Some("Open".to_string()),
+                color: None,
+            },
+            Block {
+                instance: "67890".to_string(),
+                full_text: "Smoke!".to_string(),
+                short_text: None,
+                color: Some("#f38ba8"),
+            },
+        ];
+
+        let line: Value = serde_json::from_str(i3bar(&blocks).trim_end_matches(',')).unwrap();
+        assert_eq!(
+            line,
+            json!([
+                {
+                    "name": "shelly",
+                    "instance": "12345",
+                    "full_text": "Open (Front Door)",
+                    "short_text": "Open",
+                    "markup": "pango"
+                },
+                {
+                    "name": "shelly",
+                    "instance": "67890",
+                    "full_text": "Smoke!",
+                    "short_text": "Smoke!",
+                    "markup": "pango",
+                    "color": "#f38ba8"
+                }
+            ])
+        );
+    }
+
+    #[test]
+    fn test_xmobar() {
+        let blocks = [
+            Block {
+                instance: "12345".to_string(),
+                full_text: "T: 21.5°C (<b>Tom &amp; Jerry</b>)".to_string(),
+                short_text: None,
+                color: None,
+            },
+            Block {
+                instance: "67890".to_string(),
+                full_text: "Smoke! (a&lt;b)".to_string(),
+                short_text: None,
+                color: Some("#f38ba8"),
+            },
+        ];
+
+        assert_eq!(
+            xmobar(&blocks, " | "),
+            "T: 21.5°C (Tom & Jerry) | <fc=#f38ba8><raw=12:Smoke! (a<b)/></fc>"
+        );
+    }
+}
@@ -0,0 +1,180 @@
+use crate::auth::Secret;
+use crate::fetch_statuses;
+use crate::ratelimit::RateLimiter;
+use crate::retry::RetryPolicy;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Request, Response, Server};
+use reqwest::Client;
+use serde_json::{json, Value};
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Load test settings of the `bench` subcommand.
+pub struct Bench {
+    pub devices: usize,
+    pub cycles: usize,
+    pub latency: Duration,
+    pub concurrency: usize,
+    pub bulk: bool,
+}
+
+impl Bench {
+    /// Polls `devices` plugs from a local mock cloud and reports the time and
+    /// number of requests of each cycle.
+    pub async fn run(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let requests = Arc::new(AtomicUsize::new(0));
+        let addr = start_mock_cloud(self.devices, self.latency, requests.clone())?;
+        let base_url = format!("http://{}", addr);
+        let client = Client::new();
+        let auth_key = Secret::new("bench");
+        let device_ids: Vec<String> = (0..self.devices).map(device_id).collect();
+        let device_ids: Vec<&str> = device_ids.iter().map(String::as_str).collect();
+
+        for cycle in 1..=self.cycles {
+            requests.store(0, Ordering::SeqCst);
+            let start = Instant::now();
+            let statuses = fetch_statuses(
+                &client,
+                &base_url,
+                &auth_key,
+                &device_ids,
+                self.concurrency,
+                self.bulk,
+                // The mock cloud has no rate limit, nor transient failures
+                &RateLimiter::unlimited(),
+                &RetryPolicy::none(),
+            )
+            .await;
+            let fetched = statuses.values().filter(|status| status.is_ok()).count();
+            println!(
+                "cycle {}: {}/{} devices in {} ms, {} requests",
+                cycle,
+                fetched,
+                self.devices,
+                start.elapsed().as_millis(),
+                requests.load(Ordering::SeqCst)
+            );
+        }
+        Ok(())
+    }
+}
+
+fn device_id(index: usize) -> String {
+    format!("bench{:04}", index)
+}
+
+fn plug_status(index: usize) -> Value {
+    json!({
+        "switch:0": {
+            "output": true,
+            "apower": index as f64,
+            "voltage": 230.0,
+            "current": index as f64 / 230.0,
+        },
+        "wifi": { "rssi": -60 },
+    })
+}
+
+// Local stand-in for the cloud status endpoints, answering after `latency`
+fn start_mock_cloud(
+    devices: usize,
+    latency: Duration,
+    requests: Arc<AtomicUsize>,
+) -> hyper::Result<SocketAddr> {
+    let server = Server::try_bind(&SocketAddr::from(([127, 0, 0, 1], 0)))?.serve(make_service_fn(
+        move |_| {
+            let requests = requests.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req| {
+                    requests.fetch_add(1, Ordering::SeqCst);
+                    async move {
+                        tokio::time::sleep(latency).await;
+                        Ok::<_, Infallible>(mock_response(req, devices).await)
+                    }
+                }))
+            }
+        },
+    ));
+    let addr = server.local_addr();
+    tokio::spawn(server);
+    Ok(addr)
+}
+
+async fn mock_response(req: Request<Body>, devices: usize) -> Response<Body> {
+    let path = req.uri().path().to_string();
+    let body = hyper::body::to_bytes(req.into_body())
+        .await
+        .unwrap_or_default();
+    let form = String::from_utf8_lossy(&body);
+
+    let data = match path.as_str() {
+        "/device/all_status" => json!({
+            "devices_status": (0..devices)
+                .map(|index| (device_id(index), plug_status(index)))
+                .collect::<serde_json::Map<_, _>>(),
+        }),
+        _ => {
+            let index = form
+                .split('&')
+                .find_map(|pair| pair.strip_prefix("id=bench"))
+                .and_then(|index| index.parse().ok())
+                .unwrap_or(0);
+            json!({ "device_status": plug_status(index) })
+        }
+    };
+    Response::new(Body::from(
+        json!({ "isok": true, "data": data }).to_string(),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_mock_cloud() {
+        let requests = Arc::new(AtomicUsize::new(0));
+        let addr = start_mock_cloud(3, Duration::ZERO, requests.clone()).unwrap();
+        let base_url = format!("http://{}", addr);
+        let client = Client::new();
+        let auth_key = Secret::new("bench");
+        let device_ids = ["bench0001", "bench0002"];
+
+        let statuses = fetch_statuses(
+            &client,
+            &base_url,
+            &auth_key,
+            &device_ids,
+            2,
+            false,
+            &RateLimiter::unlimited(),
+            &RetryPolicy::none(),
+        )
+        .await;
+        assert_eq!(
+            statuses["bench0002"].as_ref().unwrap()["switch:0"]["apower"],
+            2.0
+        );
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+
+        let statuses = fetch_statuses(
+            &client,
+            &base_url,
+            &auth_key,
+            &device_ids,
+            1,
+            true,
+            &RateLimiter::unlimited(),
+            &RetryPolicy::none(),
+        )
+        .await;
+        assert_eq!(
+            statuses["bench0001"].as_ref().unwrap()["switch:0"]["apower"],
+            1.0
+        );
+        assert_eq!(requests.load(Ordering::SeqCst), 3);
+    }
+}
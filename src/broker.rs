@@ -0,0 +1,264 @@
+use crate::gen1;
+use crate::http::Webhook;
+use crate::mqtt;
+use rumqttc::{AsyncClient, Event, Packet, QoS};
+use serde_json::{json, Map, Value};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::mpsc::{self, UnboundedReceiver};
+
+/// Status topics of Gen2 devices, under their MQTT prefix (the device ID by default), and
+/// every topic of Gen1 devices.
+const TOPICS: [&str; 3] = ["+/status/+", "+/events/rpc", "shellies/#"];
+
+/// Device statuses assembled from the messages of an MQTT broker, keyed by the MQTT
+/// prefix of Gen2 devices or the `shellies/<device_id>` ID of Gen1 ones.
+#[derive(Clone, Default)]
+pub struct MqttSource {
+    statuses: Arc<Mutex<HashMap<String, Value>>>,
+}
+
+impl MqttSource {
+    /// Subscribes to the device topics of a `mqtt://[user[:password]@]host[:port]` broker,
+    /// returning the source and the devices whose status was published.
+    pub async fn connect(
+        url: &str,
+    ) -> Result<(Self, UnboundedReceiver<Webhook>), Box<dyn std::error::Error>> {
+        let options = mqtt::options(url, "shelly-waybar-source").await?;
+        let (client, mut eventloop) = AsyncClient::new(options, 64);
+        let source = MqttSource::default();
+        let (sender, receiver) = mpsc::unbounded_channel();
+
+        let statuses = source.clone();
+        tokio::spawn(async move {
+            loop {
+                match eventloop.poll().await {
+                    // Subscriptions are lost with the session, on each reconnection
+                    Ok(Event::Incoming(Packet::ConnAck(_))) => subscribe(&client).await,
+                    Ok(Event::Incoming(Packet::Publish(publish))) => {
+                        let Some(device_id) = statuses.apply(&publish.topic, &publish.payload)
+                        else {
+                            continue;
+                        };
+                        if sender
+                            .send(Webhook {
+                                device_id,
+                                event: None,
+                            })
+                            .is_err()
+                        {
+                            return;
+                        }
+                    }
+                    Ok(_) => {}
+                    Err(err) => {
+                        eprintln!("Error: MQTT source connection failed - {}", err);
+                        tokio::time::sleep(Duration::from_secs(5)).await;
+                    }
+                }
+            }
+        });
+        Ok((source, receiver))
+    }
+
+    /// Last known status of a device, in the Gen2 schema.
+    pub fn status(&self, device_id: &str) -> Option<Value> {
+        let statuses = self.statuses.lock().unwrap_or_else(|err| err.into_inner());
+        statuses.get(device_id).cloned().map(gen1::normalize)
+    }
+
+    /// Merges a message into the status of its device, returning the device ID.
+    fn apply(&self, topic: &str, payload: &[u8]) -> Option<String> {
+        let payload = std::str::from_utf8(payload).ok()?.trim();
+        let mut statuses = self.statuses.lock().unwrap_or_else(|err| err.into_inner());
+        match topic.split('/').collect::<Vec<_>>().as_slice() {
+            ["shellies", device_id, path @ ..] => {
+                let mut status = statuses
+                    .get(*device_id)
+                    .cloned()
+                    .unwrap_or_else(|| json!({}));
+                apply_gen1(&mut status, path, payload)?;
+                statuses.insert(device_id.to_string(), status);
+                Some(device_id.to_string())
+            }
+            [device_id, "status", component] => {
+                let value = serde_json::from_str(payload).ok()?;
+                let status = statuses
+                    .entry(device_id.to_string())
+                    .or_insert_with(|| json!({}));
+                status[*component] = value;
+                Some(device_id.to_string())
+            }
+            [device_id, "events", "rpc"] => {
+                let event: Value = serde_json::from_str(payload).ok()?;
+                let method = event["method"].as_str()?;
+                if method != "NotifyStatus" && method != "NotifyFullStatus" {
+                    return None;
+                }
+                let status = statuses
+                    .entry(device_id.to_string())
+                    .or_insert_with(|| json!({}));
+                merge_components(status, event["params"].as_object()?);
+                Some(device_id.to_string())
+            }
+            _ => None,
+        }
+    }
+}
+
+async fn subscribe(client: &AsyncClient) {
+    for topic in TOPICS {
+        if let Err(err) = client.subscribe(topic, QoS::AtMostOnce).await {
+            eprintln!("Error: Unable to subscribe to {} - {}", topic, err);
+        }
+    }
+}
+
+// Gen1 devices publish their whole status on `info`, and each reading on its own topic, e.g.
+// `shellies/shellyht-AB12CD/sensor/temperature`
+fn apply_gen1(status: &mut Value, path: &[&str], payload: &str) -> Option<()> {
+    let number = || payload.parse::<f64>().ok().map(Value::from);
+    match path {
+        // Only an object, which the readings below index into
+        ["info"] => *status = Value::Object(serde_json::from_str(payload).ok()?),
+        ["relay", id] => {
+            *gen1::element(status, "relays", id.parse().ok()?)? = json!({ "ison": payload == "on" })
+        }
+        ["relay", id, "power"] => {
+            gen1::element(status, "meters", id.parse().ok()?)?["power"] = number()?
+        }
+        ["relay", id, "energy"] => {
+            gen1::element(status, "meters", id.parse().ok()?)?["total"] = number()?
+        }
+        ["sensor", "temperature"] => status["tmp"] = json!({ "value": number()? }),
+        ["sensor", "humidity"] => status["hum"] = json!({ "value": number()? }),
+        ["sensor", "battery"] => status["bat"] = json!({ "value": payload.parse::<i64>().ok()? }),
+        ["sensor", "lux"] => status["lux"] = json!({ "value": number()? }),
+        ["sensor", "state"] => gen1::object(status, "sensor")["state"] = json!(payload),
+        ["sensor", "motion"] => gen1::object(status, "sensor")["motion"] = json!(payload == "true"),
+        ["sensor", "tilt"] => {
+            gen1::object(status, "accel")["tilt"] = json!(payload.parse::<i64>().ok()?)
+        }
+        ["sensor", "vibration"] => {
+            gen1::object(status, "accel")["vibration"] = json!(payload.parse::<i64>().ok()?)
+        }
+        _ => return None,
+    }
+    Some(())
+}
+
+/// Merges the components of a `NotifyStatus` or `NotifyFullStatus` notification, the former
+/// only carrying the changed keys of each component.
+pub fn merge_components(status: &mut Value, params: &Map<String, Value>) {
+    for (component, value) in params.iter().filter(|(key, _)| *key != "ts") {
+        match (status[component].as_object_mut(), value.as_object()) {
+            (Some(current), Some(changes)) => current.extend(
+                changes
+                    .iter()
+                    .map(|(key, value)| (key.clone(), value.clone())),
+            ),
+            _ => status[component] = value.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_apply_gen2() {
+        let source = MqttSource::default();
+        assert_eq!(
+            source.apply(
+                "shellyplusplugs-e8db84d6b7a4/status/switch:0",
+                br#"{"id":0,"output":true,"apower":42.5}"#
+            ),
+            Some("shellyplusplugs-e8db84d6b7a4".to_string())
+        );
+        source.apply(
+            "shellyplusplugs-e8db84d6b7a4/events/rpc",
+            br#"{"method":"NotifyStatus","params":{"ts":1.0,"switch:0":{"apower":10.0}}}"#,
+        );
+        source.apply(
+            "shellyplusplugs-e8db84d6b7a4/events/rpc",
+            br#"{"method":"NotifyEvent","params":{"events":[]}}"#,
+        );
+
+        let status = source.status("shellyplusplugs-e8db84d6b7a4").unwrap();
+        assert_eq!(status["switch:0"]["output"], true);
+        assert_eq!(status["switch:0"]["apower"], 10.0);
+        assert!(status.get("ts").is_none());
+        assert_eq!(source.apply("other/topic", b"{}"), None);
+    }
+
+    #[test]
+    fn test_apply_gen1() {
+        let source = MqttSource::default();
+        source.apply("shellies/shellyht-AB12CD/sensor/temperature", b"21.5");
+        source.apply("shellies/shellyht-AB12CD/sensor/humidity", b"48");
+        source.apply("shellies/shellyht-AB12CD/sensor/battery", b"87");
+        source.apply("shellies/shellyplug-s-1234/relay/0", b"on");
+        source.apply("shellies/shellyplug-s-1234/relay/0/power", b"12.3");
+        assert_eq!(
+            source.apply("shellies/shellyplug-s-1234/announce", b"{}"),
+            None
+        );
+
+        let ht = source.status("shellyht-AB12CD").unwrap();
+        assert_eq!(ht["temperature:0"]["tC"], 21.5);
+        assert_eq!(ht["humidity:0"]["rh"], 48.0);
+        assert_eq!(ht["devicepower:0"]["battery"]["percent"], 87);
+        let plug = source.status("shellyplug-s-1234").unwrap();
+        assert_eq!(plug["switch:0"]["output"], true);
+        assert_eq!(plug["switch:0"]["apower"], 12.3);
+
+        source.apply(
+            "shellies/shellyplug-s-1234/info",
+            br#"{"relays":[{"ison":false}],"meters":[{"power":0.0}]}"#,
+        );
+        let plug = source.status("shellyplug-s-1234").unwrap();
+        assert_eq!(plug["switch:0"]["output"], false);
+        assert_eq!(source.status("unknown"), None);
+    }
+
+    #[test]
+    fn test_apply_gen1_malformed() {
+        let source = MqttSource::default();
+        source.apply("shellies/shellyplug-s-1234/relay/0", b"on");
+
+        // An `info` that is not an object is dropped, keeping the last status
+        for info in [&b"[]"[..], b"42", b"\"on\"", b"null"] {
+            assert_eq!(source.apply("shellies/shellyplug-s-1234/info", info), None);
+        }
+        source.apply("shellies/shellyplug-s-1234/relay/0/power", b"5.0");
+        let plug = source.status("shellyplug-s-1234").unwrap();
+        assert_eq!(plug["switch:0"]["output"], true);
+        assert_eq!(plug["switch:0"]["apower"], 5.0);
+
+        // Channels past the last one are dropped rather than allocated
+        assert_eq!(
+            source.apply("shellies/shellyplug-s-1234/relay/4000000000", b"on"),
+            None
+        );
+        assert_eq!(
+            source.apply("shellies/shellyplug-s-1234/relay/16/power", b"1.0"),
+            None
+        );
+        let plug = source.status("shellyplug-s-1234").unwrap();
+        assert_eq!(plug["relays"].as_array().unwrap().len(), 1);
+
+        // Readings replace values of another shape from `info`
+        source.apply(
+            "shellies/shellydw2-1234/info",
+            br#"{"sensor":5,"meters":[1]}"#,
+        );
+        assert!(source
+            .apply("shellies/shellydw2-1234/sensor/state", b"open")
+            .is_some());
+        assert!(source
+            .apply("shellies/shellydw2-1234/relay/0/power", b"2.0")
+            .is_some());
+    }
+}
@@ -0,0 +1,211 @@
+use crate::gen1;
+use crate::http::Webhook;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr, UdpSocket};
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc::{self, UnboundedReceiver};
+
+/// Multicast group and port of the CoIoT status packets of Gen1 devices.
+const MULTICAST_ADDR: Ipv4Addr = Ipv4Addr::new(224, 0, 1, 187);
+const PORT: u16 = 5683;
+/// CoAP option of the device identity, e.g. `SHHT-1#AB12CD#2`.
+const DEVICE_OPTION: u16 = 3332;
+/// Code 0.30 of CoIoT status publications.
+const STATUS_CODE: u8 = 30;
+
+/// Device statuses decoded from the CoIoT packets heard on the LAN, keyed by device ID.
+#[derive(Clone, Default)]
+pub struct CoiotListener {
+    statuses: Arc<Mutex<HashMap<String, Value>>>,
+}
+
+impl CoiotListener {
+    /// Joins the CoIoT multicast group, returning the listener and the devices whose
+    /// status was received.
+    pub fn start() -> io::Result<(Self, UnboundedReceiver<Webhook>)> {
+        let socket = UdpSocket::bind(SocketAddr::from((Ipv4Addr::UNSPECIFIED, PORT)))?;
+        socket.join_multicast_v4(&MULTICAST_ADDR, &Ipv4Addr::UNSPECIFIED)?;
+        socket.set_nonblocking(true)?;
+        let socket = tokio::net::UdpSocket::from_std(socket)?;
+        let listener = CoiotListener::default();
+        let (sender, receiver) = mpsc::unbounded_channel();
+
+        let statuses = listener.clone();
+        tokio::spawn(async move {
+            let mut buffer = [0; 2048];
+            loop {
+                let length = match socket.recv(&mut buffer).await {
+                    Ok(length) => length,
+                    Err(err) => {
+                        eprintln!("Error: CoIoT listener stopped - {}", err);
+                        return;
+                    }
+                };
+                let Some(device_id) = statuses.apply(&buffer[..length]) else {
+                    continue;
+                };
+                if sender
+                    .send(Webhook {
+                        device_id,
+                        event: None,
+                    })
+                    .is_err()
+                {
+                    return;
+                }
+            }
+        });
+        Ok((listener, receiver))
+    }
+
+    /// Last known status of a device, in the Gen2 schema.
+    pub fn status(&self, device_id: &str) -> Option<Value> {
+        let statuses = self.statuses.lock().unwrap_or_else(|err| err.into_inner());
+        statuses.get(device_id).cloned().map(gen1::normalize)
+    }
+
+    /// Merges the readings of a packet into the status of its device, returning the device ID.
+    fn apply(&self, packet: &[u8]) -> Option<String> {
+        let (device_id, payload) = parse_packet(packet)?;
+        let payload: Value = serde_json::from_slice(payload).ok()?;
+        let readings = payload["G"].as_array()?;
+        let mut statuses = self.statuses.lock().unwrap_or_else(|err| err.into_inner());
+        let status = statuses
+            .entry(device_id.clone())
+            .or_insert_with(|| json!({}));
+        for reading in readings {
+            if let (Some(id), value) = (reading[1].as_u64(), &reading[2]) {
+                apply_reading(status, id, value);
+            }
+        }
+        Some(device_id)
+    }
+}
+
+// Device ID and payload of a CoAP status packet, see RFC 7252 for the layout
+fn parse_packet(packet: &[u8]) -> Option<(String, &[u8])> {
+    let (header, rest) = packet.split_first_chunk::<4>()?;
+    if header[0] >> 6 != 1 || header[1] != STATUS_CODE {
+        return None;
+    }
+    let mut rest = rest.get(usize::from(header[0] & 0x0f)..)?;
+    let mut option = 0u16;
+    let mut device_id = None;
+    while let Some((&byte, tail)) = rest.split_first() {
+        if byte == 0xff {
+            return Some((device_id?, tail));
+        }
+        let (delta, tail) = option_number(byte >> 4, tail)?;
+        let (length, tail) = option_number(byte & 0x0f, tail)?;
+        let value = tail.get(..usize::from(length))?;
+        option = option.checked_add(delta)?;
+        if option == DEVICE_OPTION {
+            // `<model>#<device_id>#<protocol version>`
+            let identity = std::str::from_utf8(value).ok()?;
+            device_id = identity.split('#').nth(1).map(str::to_string);
+        }
+        rest = &tail[usize::from(length)..];
+    }
+    None
+}
+
+// Option delta or length, extended by the next bytes when 13 or 14
+fn option_number(nibble: u8, bytes: &[u8]) -> Option<(u16, &[u8])> {
+    match nibble {
+        13 => Some((u16::from(*bytes.first()?) + 13, &bytes[1..])),
+        14 => {
+            let (extended, rest) = bytes.split_first_chunk::<2>()?;
+            Some((u16::from_be_bytes(*extended).checked_add(269)?, rest))
+        }
+        15 => None,
+        nibble => Some((u16::from(nibble), bytes)),
+    }
+}
+
+// Stores a reading under the key of the Gen1 HTTP status, by its CoIoT v2 sensor ID
+fn apply_reading(status: &mut Value, id: u64, value: &Value) {
+    match id {
+        1101 | 1201 => {
+            let relay = usize::from(id == 1201);
+            if let Some(relay) = gen1::element(status, "relays", relay) {
+                relay["ison"] = json!(value == 1);
+            }
+        }
+        4101 | 4201 => {
+            let meter = usize::from(id == 4201);
+            if let Some(meter) = gen1::element(status, "meters", meter) {
+                meter["power"] = value.clone();
+            }
+        }
+        3101 => status["tmp"] = json!({ "value": value, "units": "C" }),
+        3103 => status["hum"] = json!({ "value": value }),
+        3106 => status["lux"] = json!({ "value": value }),
+        3108 => status["sensor"]["state"] = json!(if value == 1 { "open" } else { "close" }),
+        3109 => status["accel"]["tilt"] = value.clone(),
+        3110 => status["accel"]["vibration"] = value.clone(),
+        3111 => status["bat"] = json!({ "value": value }),
+        6107 => status["sensor"]["motion"] = json!(value == 1),
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Non-confirmable 0.30 packet with the identity option and a payload
+    fn packet(identity: &str, payload: &str) -> Vec<u8> {
+        let mut packet = vec![0x50, STATUS_CODE, 0x00, 0x01];
+        // Option 3332: delta 14 (3332 - 269), length 13 + extension
+        packet.push(0xed);
+        packet.extend((DEVICE_OPTION - 269).to_be_bytes());
+        packet.push(identity.len() as u8 - 13);
+        packet.extend(identity.as_bytes());
+        packet.push(0xff);
+        packet.extend(payload.as_bytes());
+        packet
+    }
+
+    #[test]
+    fn test_parse_packet() {
+        let packet = packet("SHHT-1#AB12CD#2", r#"{"G":[]}"#);
+        let (device_id, payload) = parse_packet(&packet).unwrap();
+        assert_eq!(device_id, "AB12CD");
+        assert_eq!(payload, br#"{"G":[]}"#);
+
+        assert_eq!(parse_packet(&[0x50, 0x45, 0x00, 0x01]), None);
+        assert_eq!(parse_packet(&packet[..10]), None);
+    }
+
+    #[test]
+    fn test_apply() {
+        let listener = CoiotListener::default();
+        let ht = packet(
+            "SHHT-1#AB12CD#2",
+            r#"{"G":[[0,3101,21.5],[0,3102,70.7],[0,3103,48],[0,3111,87]]}"#,
+        );
+        assert_eq!(listener.apply(&ht), Some("AB12CD".to_string()));
+        let status = listener.status("AB12CD").unwrap();
+        assert_eq!(status["temperature:0"]["tC"], 21.5);
+        assert_eq!(status["humidity:0"]["rh"], 48.0);
+        assert_eq!(status["devicepower:0"]["battery"]["percent"], 87);
+
+        let door = packet(
+            "SHDW-2#1234567890AB#2",
+            r#"{"G":[[0,3108,1],[0,3106,120],[0,3109,12],[0,3111,70]]}"#,
+        );
+        listener.apply(&door);
+        let status = listener.status("1234567890AB").unwrap();
+        assert_eq!(status["window:0"]["open"], true);
+        assert_eq!(status["tilt:0"]["angle"], 12);
+
+        let plug = packet("SHPLG-S#C0FFEE#2", r#"{"G":[[0,1101,1],[0,4101,42.5]]}"#);
+        listener.apply(&plug);
+        let status = listener.status("C0FFEE").unwrap();
+        assert_eq!(status["switch:0"]["output"], true);
+        assert_eq!(status["switch:0"]["apower"], 42.5);
+        assert_eq!(listener.status("unknown"), None);
+    }
+}
@@ -0,0 +1,664 @@
+use crate::auth::Secret;
+use crate::control::Action;
+use crate::fields::{FieldSelection, Rounding};
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::{env, fs};
+
+/// Settings loaded from the optional TOML configuration file.
+#[derive(Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct Config {
+    /// Enables commands that switch devices
+    pub allow_control: bool,
+    /// Per-device settings, keyed by device ID
+    pub devices: HashMap<String, DeviceConfig>,
+    /// Rounding rules, keyed by field name (e.g. `humidity`, `power`)
+    pub rounding: HashMap<String, Rounding>,
+    pub mqtt: MqttConfig,
+    /// Command run with the event JSON on stdin when a state of any device changes
+    pub on_change: Option<String>,
+    /// Device types declared in the config, keyed by type name
+    pub types: BTreeMap<String, TypeConfig>,
+    /// Other names of device types, e.g. `climate = "temperature"`
+    pub aliases: HashMap<String, String>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct DeviceConfig {
+    #[serde(flatten)]
+    pub fields: FieldSelection,
+    /// Control actions allowed on this device; all of them when unset
+    pub actions: Option<Vec<Action>>,
+    /// Command run on state changes of this device, instead of the global one
+    pub on_change: Option<String>,
+    /// Text of this device with `{<field>}`, `{name}` and `{id}` placeholders, instead of
+    /// `--text-template`
+    pub text_template: Option<String>,
+    /// Tooltip of this device, with the same placeholders, instead of `--tooltip-template`
+    pub tooltip_template: Option<String>,
+    /// Address on the LAN (`192.168.1.20`, `plug.lan:8080`), polled directly instead of the cloud
+    pub host: Option<String>,
+    /// Fetch the device from the cloud when it does not answer on the LAN, e.g. away from home
+    pub cloud_fallback: bool,
+    /// Server of the cloud account of this device, when not the main one
+    pub base_url: Option<String>,
+    /// Auth key of that account, or the path of a file containing it
+    pub auth_key: Option<Secret>,
+    /// User of the HTTP basic auth of a protected Gen1 device on the LAN, `admin` by default
+    pub username: Option<String>,
+    /// Its password, or the path of a file containing it
+    pub password: Option<Secret>,
+    /// Desktop notification when a motion sensor detects motion
+    pub notify_motion: bool,
+    /// Desktop notification when a door/window sensor vibrates
+    pub notify_vibration: bool,
+    /// Names of the channels of a multi-channel relay or energy meter, e.g. `["grid", "solar"]`
+    pub channels: Vec<String>,
+    pub adc: AdcConfig,
+    /// Quantity measured by each BTHome sensor of a BLE gateway, keyed by sensor id
+    pub bthome: HashMap<String, BthomeKind>,
+    pub custom: CustomConfig,
+}
+
+/// Values of a `custom` device, read from its status with dot paths.
+#[derive(Deserialize, Debug, Default, Clone, PartialEq)]
+#[serde(default)]
+pub struct CustomConfig {
+    /// Text with `{<key>}` placeholders; the values are listed when unset
+    pub format: Option<String>,
+    /// Tooltip with `{<key>}` placeholders; the values are listed when unset
+    pub tooltip: Option<String>,
+    pub fields: Vec<CustomField>,
+}
+
+/// Device type declared in the config, rendered like a `custom` device.
+#[derive(Deserialize, Debug, Default, Clone, PartialEq)]
+#[serde(default)]
+pub struct TypeConfig {
+    /// Dot paths that must all exist in a status to autodetect this type
+    pub detect: Vec<String>,
+    #[serde(flatten)]
+    pub custom: CustomConfig,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct CustomField {
+    pub key: String,
+    /// Dot path in the status, e.g. `switch:0.aenergy.total` or `emeters.0.power`
+    pub path: String,
+    #[serde(default)]
+    pub unit: String,
+    /// Decimals of non-integer numbers
+    #[serde(default = "default_custom_digits")]
+    pub digits: usize,
+}
+
+fn default_custom_digits() -> usize {
+    2
+}
+
+/// Quantity of a BTHome sensor, which the gateway status does not tell.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum BthomeKind {
+    Temperature,
+    Humidity,
+    Illuminance,
+    Window,
+    Motion,
+    Battery,
+}
+
+/// Display of the analog input of a Shelly UNI, as `scale * voltage + offset`.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct AdcConfig {
+    /// Label replacing `ADC`/`Analog`, e.g. `Tank`
+    pub label: Option<String>,
+    pub unit: String,
+    pub scale: f64,
+    pub offset: f64,
+    /// Decimals displayed
+    pub digits: usize,
+}
+
+impl Default for AdcConfig {
+    fn default() -> Self {
+        AdcConfig {
+            label: None,
+            unit: "V".to_string(),
+            scale: 1.0,
+            offset: 0.0,
+            digits: 2,
+        }
+    }
+}
+
+/// Topics and options of the MQTT publisher.
+///
+/// Topics may use the `{device_id}`, `{name}` and `{field}` placeholders.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct MqttConfig {
+    /// Topic of each field value
+    pub topic: String,
+    /// Topic of the whole reading as a JSON object, not published when unset
+    pub reading_topic: Option<String>,
+    /// Announces the fields with Home Assistant discovery
+    pub discovery: bool,
+    pub retain: bool,
+}
+
+impl Default for MqttConfig {
+    fn default() -> Self {
+        MqttConfig {
+            topic: "shelly-waybar/{device_id}/{field}".to_string(),
+            reading_topic: None,
+            discovery: true,
+            retain: true,
+        }
+    }
+}
+
+impl Config {
+    /// Loads the configuration from `path`, or from the default location if it exists.
+    pub fn load(path: Option<&Path>) -> Result<Config, Box<dyn std::error::Error>> {
+        let path = match path {
+            Some(path) => path.to_path_buf(),
+            None => match config_dir().map(|dir| dir.join("config.toml")) {
+                Some(path) if path.exists() => path,
+                _ => return Ok(Config::default()),
+            },
+        };
+
+        let content = fs::read_to_string(&path)
+            .map_err(|err| format!("Unable to read config {}: {}", path.display(), err))?;
+        let mut config: Config = toml::from_str(&content)
+            .map_err(|err| format!("Invalid config {}: {}", path.display(), err))?;
+        config.read_auth_keys()?;
+        config.check_aliases()?;
+        Ok(config)
+    }
+
+    // Aliases are matched case-insensitively, so two differing only in case would be ambiguous
+    fn check_aliases(&self) -> Result<(), String> {
+        let mut aliases: Vec<&String> = self.aliases.keys().collect();
+        aliases.sort_unstable();
+        for (index, alias) in aliases.iter().enumerate() {
+            if let Some(other) = aliases[index + 1..]
+                .iter()
+                .find(|other| other.eq_ignore_ascii_case(alias))
+            {
+                return Err(format!(
+                    "Aliases '{}' and '{}' differ only in case",
+                    alias, other
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    // Auth keys of other accounts given as paths are read once, not on every polling cycle
+    fn read_auth_keys(&mut self) -> Result<(), String> {
+        for (device_id, device) in &mut self.devices {
+            if let Some(key) = &device.auth_key {
+                let key = crate::resolve_input(key.expose()).map_err(|err| {
+                    format!("Unable to read the auth key of {}: {}", device_id, err)
+                })?;
+                device.auth_key = Some(Secret::new(key));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn allows_action(&self, device_id: &str, action: Action) -> bool {
+        self.devices
+            .get(device_id)
+            .and_then(|device| device.actions.as_ref())
+            .is_none_or(|actions| actions.contains(&action))
+    }
+
+    pub fn on_change(&self, device_id: &str) -> Option<&str> {
+        self.devices
+            .get(device_id)
+            .and_then(|device| device.on_change.as_deref())
+            .or(self.on_change.as_deref())
+    }
+
+    pub fn text_template(&self, device_id: &str) -> Option<&str> {
+        self.devices
+            .get(device_id)
+            .and_then(|device| device.text_template.as_deref())
+    }
+
+    pub fn tooltip_template(&self, device_id: &str) -> Option<&str> {
+        self.devices
+            .get(device_id)
+            .and_then(|device| device.tooltip_template.as_deref())
+    }
+
+    /// Device type named by an alias of the config, matched case-insensitively.
+    pub fn resolve_alias<'a>(&'a self, device_type: &'a str) -> &'a str {
+        self.aliases
+            .iter()
+            .find(|(alias, _)| alias.eq_ignore_ascii_case(device_type))
+            .map_or(device_type, |(_, name)| name.as_str())
+    }
+
+    pub fn host(&self, device_id: &str) -> Option<&str> {
+        self.devices
+            .get(device_id)
+            .and_then(|device| device.host.as_deref())
+    }
+
+    /// Whether a device on the LAN is fetched from the cloud when it does not answer.
+    pub fn falls_back_to_cloud(&self, device_id: &str) -> bool {
+        self.devices
+            .get(device_id)
+            .is_some_and(|device| device.cloud_fallback)
+    }
+
+    /// Server and auth key of a device on another cloud account, when set.
+    pub fn account(&self, device_id: &str) -> (Option<&str>, Option<&Secret>) {
+        match self.devices.get(device_id) {
+            Some(device) => (device.base_url.as_deref(), device.auth_key.as_ref()),
+            None => (None, None),
+        }
+    }
+
+    /// User and password of a device on the LAN, when set.
+    pub fn login(&self, device_id: &str) -> (Option<&str>, Option<&Secret>) {
+        match self.devices.get(device_id) {
+            Some(device) => (device.username.as_deref(), device.password.as_ref()),
+            None => (None, None),
+        }
+    }
+
+    pub fn notifies_motion(&self, device_id: &str) -> bool {
+        self.devices
+            .get(device_id)
+            .is_some_and(|device| device.notify_motion)
+    }
+
+    pub fn notifies_vibration(&self, device_id: &str) -> bool {
+        self.devices
+            .get(device_id)
+            .is_some_and(|device| device.notify_vibration)
+    }
+
+    pub fn channel_names(&self, device_id: &str) -> &[String] {
+        self.devices
+            .get(device_id)
+            .map(|device| device.channels.as_slice())
+            .unwrap_or_default()
+    }
+
+    pub fn adc(&self, device_id: &str) -> AdcConfig {
+        self.devices
+            .get(device_id)
+            .map(|device| device.adc.clone())
+            .unwrap_or_default()
+    }
+
+    pub fn bthome_kinds(&self, device_id: &str) -> HashMap<String, BthomeKind> {
+        self.devices
+            .get(device_id)
+            .map(|device| device.bthome.clone())
+            .unwrap_or_default()
+    }
+
+    pub fn custom(&self, device_id: &str) -> CustomConfig {
+        self.devices
+            .get(device_id)
+            .map(|device| device.custom.clone())
+            .unwrap_or_default()
+    }
+
+    pub fn field_selection(&self, device_id: &str) -> FieldSelection {
+        self.devices
+            .get(device_id)
+            .map(|device| device.fields.clone())
+            .unwrap_or_default()
+    }
+}
+
+/// $XDG_CONFIG_HOME/shelly-waybar, falling back to ~/.config/shelly-waybar
+pub fn config_dir() -> Option<PathBuf> {
+    let config_home = env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))?;
+    Some(config_home.join("shelly-waybar"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fields::RoundingMode;
+
+    #[test]
+    fn test_parse_device_field_selection() {
+        let config: Config = toml::from_str(
+            r#"
+            [devices.67890]
+            text = ["power"]
+            tooltip = ["voltage", "current"]
+            hidden = ["rssi"]
+            "#,
+        )
+        .unwrap();
+
+        let selection = config.field_selection("67890");
+        assert_eq!(selection.text, Some(vec!["power".to_string()]));
+        assert_eq!(
+            selection.tooltip,
+            Some(vec!["voltage".to_string(), "current".to_string()])
+        );
+        assert_eq!(selection.hidden, vec!["rssi".to_string()]);
+        assert_eq!(config.field_selection("unknown"), FieldSelection::default());
+    }
+
+    #[test]
+    fn test_parse_rounding() {
+        let config: Config = toml::from_str(
+            r#"
+            [rounding]
+            humidity = { digits = 0 }
+            power = { mode = "floor" }
+            current = { mode = "significant", digits = 2 }
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.rounding["humidity"], Rounding::default());
+        assert_eq!(config.rounding["power"].mode, RoundingMode::Floor);
+        assert_eq!(
+            config.rounding["current"],
+            Rounding {
+                mode: RoundingMode::Significant,
+                digits: 2,
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_mqtt() {
+        let config: Config = toml::from_str(
+            r#"
+            [mqtt]
+            topic = "home/{name}/{field}"
+            reading_topic = "home/{name}"
+            discovery = false
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.mqtt.topic, "home/{name}/{field}");
+        assert_eq!(config.mqtt.reading_topic.as_deref(), Some("home/{name}"));
+        assert!(!config.mqtt.discovery);
+        assert!(config.mqtt.retain);
+        assert!(Config::default().mqtt.discovery);
+    }
+
+    #[test]
+    fn test_on_change() {
+        let config: Config = toml::from_str(
+            r#"
+            on_change = "~/bin/hook.sh"
+
+            [devices.12345]
+            on_change = "~/bin/door-hook.sh"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.on_change("12345"), Some("~/bin/door-hook.sh"));
+        assert_eq!(config.on_change("67890"), Some("~/bin/hook.sh"));
+        assert_eq!(Config::default().on_change("12345"), None);
+    }
+
+    #[test]
+    fn test_templates() {
+        let config: Config = toml::from_str(
+            r#"
+            [devices.12345]
+            text_template = "{state}"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.text_template("12345"), Some("{state}"));
+        assert_eq!(config.tooltip_template("12345"), None);
+        assert_eq!(config.text_template("67890"), None);
+    }
+
+    #[test]
+    fn test_host() {
+        let config: Config = toml::from_str(
+            r#"
+            [devices.12345]
+            host = "192.168.1.20"
+            cloud_fallback = true
+
+            [devices.67890]
+            notify_motion = true
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.host("12345"), Some("192.168.1.20"));
+        assert_eq!(config.host("67890"), None);
+        assert!(config.falls_back_to_cloud("12345"));
+        assert!(!config.falls_back_to_cloud("67890"));
+    }
+
+    #[test]
+    fn test_account() {
+        let config: Config = toml::from_str(
+            r#"
+            [devices.12345]
+            base_url = "https://shelly-103-eu.shelly.cloud"
+            auth_key = "/run/secrets/parents-key"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            config.account("12345"),
+            (
+                Some("https://shelly-103-eu.shelly.cloud"),
+                Some(&Secret::new("/run/secrets/parents-key"))
+            )
+        );
+        assert_eq!(config.account("67890"), (None, None));
+    }
+
+    #[test]
+    fn test_read_auth_keys() {
+        let path = env::temp_dir().join(format!("shelly-waybar-test-key-{}", std::process::id()));
+        fs::write(&path, "parents-key\n").unwrap();
+        let mut config: Config = toml::from_str(&format!(
+            "[devices.12345]\nauth_key = \"{}\"\n[devices.67890]\nauth_key = \"inline-key\"",
+            path.display()
+        ))
+        .unwrap();
+
+        config.read_auth_keys().unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(config.account("12345").1, Some(&Secret::new("parents-key")));
+        assert_eq!(config.account("67890").1, Some(&Secret::new("inline-key")));
+
+        let mut config: Config = toml::from_str("[devices.12345]\nauth_key = \"/\"").unwrap();
+        assert!(config.read_auth_keys().is_err());
+    }
+
+    #[test]
+    fn test_login() {
+        let config: Config = toml::from_str(
+            r#"
+            [devices.12345]
+            host = "192.168.1.20"
+            username = "shelly"
+            password = "secret"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            config.login("12345"),
+            (Some("shelly"), Some(&Secret::new("secret")))
+        );
+        assert_eq!(config.login("67890"), (None, None));
+        // Neither the password nor the auth key shows up when the config is printed
+        assert!(!format!("{:?}", config).contains("\"secret\""));
+    }
+
+    #[test]
+    fn test_notifies_motion() {
+        let config: Config = toml::from_str(
+            r#"
+            [devices.12345]
+            notify_motion = true
+
+            [devices.67890]
+            hidden = ["lux"]
+            "#,
+        )
+        .unwrap();
+
+        assert!(config.notifies_motion("12345"));
+        assert!(!config.notifies_motion("67890"));
+        assert!(!config.notifies_motion("13579"));
+        assert!(!config.notifies_vibration("12345"));
+    }
+
+    #[test]
+    fn test_channel_names() {
+        let config: Config = toml::from_str(
+            r#"
+            [devices.12345]
+            channels = ["grid", "solar"]
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.channel_names("12345"), ["grid", "solar"]);
+        assert!(config.channel_names("67890").is_empty());
+    }
+
+    #[test]
+    fn test_adc() {
+        let config: Config = toml::from_str(
+            r#"
+            [devices.12345.adc]
+            label = "Tank"
+            unit = "%"
+            scale = 10.0
+            "#,
+        )
+        .unwrap();
+
+        let adc = config.adc("12345");
+        assert_eq!(adc.label.as_deref(), Some("Tank"));
+        assert_eq!(adc.unit, "%");
+        assert_eq!(adc.scale, 10.0);
+        assert_eq!(adc.offset, 0.0);
+        assert_eq!(config.adc("67890"), AdcConfig::default());
+    }
+
+    #[test]
+    fn test_bthome_kinds() {
+        let config: Config = toml::from_str(
+            r#"
+            [devices.12345.bthome]
+            200 = "temperature"
+            202 = "window"
+            "#,
+        )
+        .unwrap();
+
+        let kinds = config.bthome_kinds("12345");
+        assert_eq!(kinds["200"], BthomeKind::Temperature);
+        assert_eq!(kinds["202"], BthomeKind::Window);
+        assert!(config.bthome_kinds("67890").is_empty());
+    }
+
+    #[test]
+    fn test_custom() {
+        let config: Config = toml::from_str(
+            r#"
+            [devices.12345.custom]
+            format = "{power}"
+            fields = [
+              { key = "power", path = "switch:0.apower", unit = "W" },
+              { key = "energy", path = "switch:0.aenergy.total", digits = 0 },
+            ]
+            "#,
+        )
+        .unwrap();
+
+        let custom = config.custom("12345");
+        assert_eq!(custom.format.as_deref(), Some("{power}"));
+        assert_eq!(custom.fields[0].path, "switch:0.apower");
+        assert_eq!(custom.fields[0].digits, 2);
+        assert_eq!(custom.fields[1].digits, 0);
+        assert_eq!(config.custom("67890"), CustomConfig::default());
+    }
+
+    #[test]
+    fn test_resolve_alias() {
+        let config: Config = toml::from_str(
+            r#"
+            [aliases]
+            climate = "temperature"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.resolve_alias("Climate"), "temperature");
+        assert_eq!(config.resolve_alias("plug"), "plug");
+        assert_eq!(config.check_aliases(), Ok(()));
+
+        let config: Config = toml::from_str(
+            r#"
+            [aliases]
+            climate = "temperature"
+            Climate = "display"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            config.check_aliases(),
+            Err("Aliases 'Climate' and 'climate' differ only in case".to_string())
+        );
+    }
+
+    #[test]
+    fn test_types() {
+        let config: Config = toml::from_str(
+            r#"
+            [types.pool]
+            detect = ["temperature:0", "switch:0"]
+            format = "{water}"
+            tooltip = "Pump: {pump}"
+            fields = [
+              { key = "water", path = "temperature:0.tC", unit = "°C" },
+              { key = "pump", path = "switch:0.output" },
+            ]
+            "#,
+        )
+        .unwrap();
+
+        let pool = &config.types["pool"];
+        assert_eq!(pool.detect, ["temperature:0", "switch:0"]);
+        assert_eq!(pool.custom.tooltip.as_deref(), Some("Pump: {pump}"));
+        assert_eq!(pool.custom.fields.len(), 2);
+    }
+
+    #[test]
+    fn test_load_missing_explicit_path() {
+        assert!(Config::load(Some(Path::new("/tmp/non_existent_config.toml"))).is_err());
+    }
+}
@@ -0,0 +1,160 @@
+use crate::auth::Secret;
+use crate::config::Config;
+use crate::ratelimit::RateLimiter;
+use crate::retry::RetryPolicy;
+use crate::{fetch_device_status, ShellyResponse};
+use reqwest::Client;
+use serde::Deserialize;
+use strum_macros::Display;
+
+/// Actions that change the state of a device.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Display)]
+#[serde(rename_all = "lowercase")]
+#[strum(serialize_all = "lowercase")]
+pub enum Action {
+    Toggle,
+}
+
+/// Fails unless device control was explicitly enabled, so that a monitoring
+/// setup cannot be used to switch devices, and the device allows the action.
+pub fn ensure_allowed(
+    config: &Config,
+    allow_control: bool,
+    device_id: &str,
+    action: Action,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if !(allow_control || config.allow_control) {
+        return Err("Control commands are disabled: set `allow_control = true` in the config or pass --allow-control".into());
+    }
+    if !config.allows_action(device_id, action) {
+        return Err(format!(
+            "Action '{}' is not allowed for device {}",
+            action, device_id
+        )
+        .into());
+    }
+    Ok(())
+}
+
+/// Switches a relay channel to the opposite of its current state and returns the new state.
+/// Both requests wait for `limiter`, shared with the polls of the same account.
+pub async fn toggle(
+    client: &Client,
+    base_url: &str,
+    auth_key: &Secret,
+    device_id: &str,
+    channel: u32,
+    limiter: &RateLimiter,
+) -> Result<bool, Box<dyn std::error::Error>> {
+    let device_status = fetch_device_status(
+        client,
+        base_url,
+        device_id,
+        auth_key,
+        limiter,
+        &RetryPolicy::none(),
+    )
+    .await
+    .map_err(|_| format!("Unable to fetch the status of device {}", device_id))?;
+    let is_on = device_status[format!("switch:{}", channel)]["output"]
+        .as_bool()
+        .ok_or_else(|| format!("Device {} has no switch channel {}", device_id, channel))?;
+
+    limiter.acquire().await;
+    set_relay(client, base_url, auth_key, device_id, channel, !is_on).await?;
+    Ok(!is_on)
+}
+
+async fn set_relay(
+    client: &Client,
+    base_url: &str,
+    auth_key: &Secret,
+    device_id: &str,
+    channel: u32,
+    on: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let channel = channel.to_string();
+    let response: ShellyResponse = client
+        .post(format!("{}/device/relay/control", base_url))
+        .form(&[
+            ("id", device_id),
+            ("auth_key", auth_key.expose()),
+            ("channel", &channel),
+            ("turn", if on { "on" } else { "off" }),
+        ])
+        .send()
+        .await?
+        .json()
+        .await?;
+
+    if !response.isok {
+        let errors = response.errors.unwrap_or_default().to_string();
+        return Err(format!(
+            "Unable to switch device {} - {}",
+            device_id,
+            auth_key.redact(&errors)
+        )
+        .into());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use httpmock::MockServer;
+    use serde_json::json;
+
+    #[test]
+    fn test_ensure_allowed() {
+        let config: Config = toml::from_str(
+            r#"
+            [devices.plug]
+            actions = ["toggle"]
+
+            [devices.heater]
+            actions = []
+            "#,
+        )
+        .unwrap();
+
+        assert!(ensure_allowed(&config, false, "plug", Action::Toggle).is_err());
+        assert!(ensure_allowed(&config, true, "plug", Action::Toggle).is_ok());
+        assert!(ensure_allowed(&config, true, "heater", Action::Toggle).is_err());
+        assert!(ensure_allowed(&config, true, "other", Action::Toggle).is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_toggle() {
+        let server = MockServer::start_async().await;
+        server.mock(|when, then| {
+            when.method("POST").path("/device/status");
+            then.status(200).json_body(json!({
+                "isok": true,
+                "data": { "device_status": { "switch:0": { "output": true } } }
+            }));
+        });
+        let control = server.mock(|when, then| {
+            when.method("POST")
+                .path("/device/relay/control")
+                .body_contains("channel=0")
+                .body_contains("turn=off");
+            then.status(200).json_body(json!({ "isok": true }));
+        });
+
+        let client = Client::new();
+        let auth_key = Secret::new("mock-auth-key");
+        let limiter = RateLimiter::unlimited();
+        let is_on = toggle(&client, &server.base_url(), &auth_key, "67890", 0, &limiter)
+            .await
+            .unwrap();
+
+        control.assert();
+        assert!(!is_on);
+        assert!(
+            toggle(&client, &server.base_url(), &auth_key, "67890", 1, &limiter)
+                .await
+                .is_err()
+        );
+    }
+}
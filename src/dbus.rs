@@ -0,0 +1,339 @@
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap};
+use zbus::fdo::ObjectManager;
+use zbus::zvariant::{OwnedValue, Str};
+use zbus::{connection, interface, Connection, SignalContext};
+
+const NAME: &str = "org.shelly_waybar.Devices";
+const PATH: &str = "/org/shelly_waybar/Devices";
+/// Name of the service of the device objects, owned by the same connection
+const SERVICE_NAME: &str = "org.shelly.Waybar";
+
+/// Session bus object exposing the latest state of each device.
+#[derive(Debug, Default)]
+struct Devices {
+    states: BTreeMap<String, Value>,
+}
+
+#[interface(name = "org.shelly_waybar.Devices")]
+impl Devices {
+    /// JSON object with the latest output of every device
+    fn get_states(&self) -> String {
+        serde_json::to_string(&self.states).unwrap_or_default()
+    }
+
+    /// JSON output of a single device, empty if unknown
+    fn get_state(&self, device: &str) -> String {
+        self.states
+            .get(device)
+            .map(Value::to_string)
+            .unwrap_or_default()
+    }
+
+    #[zbus(property)]
+    fn devices(&self) -> Vec<String> {
+        self.states.keys().cloned().collect()
+    }
+
+    #[zbus(signal)]
+    async fn state_changed(ctxt: &SignalContext<'_>, device: &str, state: &str)
+        -> zbus::Result<()>;
+}
+
+/// Object of a device below the devices object, its latest output and readings as properties.
+#[derive(Debug, Default)]
+struct Device {
+    device: String,
+    state: Value,
+    /// Readings of the device, as published on MQTT with its type
+    record: Value,
+}
+
+#[interface(name = "org.shelly.Waybar.Device")]
+impl Device {
+    /// Entry of the device in --devices
+    #[zbus(property)]
+    fn device(&self) -> String {
+        self.device.clone()
+    }
+
+    #[zbus(property)]
+    fn name(&self) -> String {
+        self.string(&self.record["name"])
+    }
+
+    #[zbus(property, name = "Type")]
+    fn device_type(&self) -> String {
+        self.string(&self.record["type"])
+    }
+
+    /// Text of the Waybar output, with Pango markup
+    #[zbus(property)]
+    fn text(&self) -> String {
+        self.string(&self.state["text"])
+    }
+
+    #[zbus(property)]
+    fn tooltip(&self) -> String {
+        self.string(&self.state["tooltip"])
+    }
+
+    /// CSS classes of the Waybar output, e.g. `alarm`, or `error` once stale
+    #[zbus(property)]
+    fn class(&self) -> Vec<String> {
+        match &self.state["class"] {
+            Value::Array(classes) => classes.iter().map(|class| self.string(class)).collect(),
+            Value::String(class) if !class.is_empty() => vec![class.clone()],
+            _ => Vec::new(),
+        }
+    }
+
+    /// Readings by key, numbers as doubles
+    #[zbus(property)]
+    fn values(&self) -> HashMap<String, OwnedValue> {
+        let Value::Object(values) = &self.record["values"] else {
+            return HashMap::new();
+        };
+        values
+            .iter()
+            .map(|(key, value)| (key.clone(), variant(value)))
+            .collect()
+    }
+
+    #[zbus(property)]
+    fn units(&self) -> HashMap<String, String> {
+        let Value::Object(units) = &self.record["units"] else {
+            return HashMap::new();
+        };
+        units
+            .iter()
+            .map(|(key, unit)| (key.clone(), self.string(unit)))
+            .collect()
+    }
+
+    /// Time of the reading, as seconds since the epoch
+    #[zbus(property)]
+    fn updated_at(&self) -> i64 {
+        self.record["updated_at"]
+            .as_str()
+            .and_then(|time| chrono::DateTime::parse_from_rfc3339(time).ok())
+            .map_or(0, |time| time.timestamp())
+    }
+
+    /// Emitted with the text and JSON output of the device whenever it changes
+    #[zbus(signal)]
+    async fn status_changed(ctxt: &SignalContext<'_>, text: &str, state: &str) -> zbus::Result<()>;
+}
+
+impl Device {
+    fn string(&self, value: &Value) -> String {
+        value.as_str().unwrap_or_default().to_string()
+    }
+}
+
+// Reading as a D-Bus variant, numbers always as doubles for a stable signature
+fn variant(value: &Value) -> OwnedValue {
+    match value {
+        Value::Bool(value) => OwnedValue::from(*value),
+        Value::Number(value) => OwnedValue::from(value.as_f64().unwrap_or_default()),
+        Value::String(value) => OwnedValue::from(Str::from(value.clone())),
+        value => OwnedValue::from(Str::from(value.to_string())),
+    }
+}
+
+// Object of a device, named after its ID and channel so that it stays the same across runs,
+// e.g. `/org/shelly_waybar/Devices/shellyplug_s_1234` or `.../Devices/a8032ab12345_1`
+fn device_path(device: &str, device_id: &str) -> String {
+    let channel = device
+        .rsplit_once('#')
+        .map(|(_, channel)| channel)
+        .filter(|channel| channel.parse::<usize>().is_ok());
+    let name = match (device_id, channel) {
+        ("", _) => device.to_string(),
+        (_, Some(channel)) => format!("{}_{}", device_id, channel),
+        (_, None) => device_id.to_string(),
+    };
+    let element: String = name
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    format!("{}/{}", PATH, element)
+}
+
+/// Publishes device states on the session D-Bus.
+pub struct DbusService {
+    connection: Connection,
+}
+
+impl DbusService {
+    pub async fn start() -> zbus::Result<Self> {
+        let connection = connection::Builder::session()?
+            .name(NAME)?
+            .name(SERVICE_NAME)?
+            .serve_at(PATH, Devices::default())?
+            .serve_at(PATH, ObjectManager)?
+            .build()
+            .await?;
+        Ok(DbusService { connection })
+    }
+
+    /// Stores the state of a device, emitting `StateChanged` when it differs from the previous one,
+    /// and updates its object, emitting `StatusChanged` along with `PropertiesChanged` for the
+    /// changed properties.
+    pub async fn publish(&self, device: &str, state: &Value, record: &Value) -> zbus::Result<()> {
+        self.publish_device(device, state, record).await?;
+
+        let iface = self
+            .connection
+            .object_server()
+            .interface::<_, Devices>(PATH)
+            .await?;
+
+        let mut devices = iface.get_mut().await;
+        if devices.states.get(device) == Some(state) {
+            return Ok(());
+        }
+        let is_new = !devices.states.contains_key(device);
+        devices.states.insert(device.to_string(), state.clone());
+        if is_new {
+            devices.devices_changed(iface.signal_context()).await?;
+        }
+        drop(devices);
+
+        Devices::state_changed(iface.signal_context(), device, &state.to_string()).await
+    }
+
+    async fn publish_device(
+        &self,
+        device: &str,
+        state: &Value,
+        record: &Value,
+    ) -> zbus::Result<()> {
+        let path = device_path(device, record["device_id"].as_str().unwrap_or_default());
+        let object = Device {
+            device: device.to_string(),
+            state: state.clone(),
+            record: record.clone(),
+        };
+        let server = self.connection.object_server();
+        let Ok(iface) = server.interface::<_, Device>(path.as_str()).await else {
+            // Announced by InterfacesAdded of the object manager
+            server.at(path, object).await?;
+            return Ok(());
+        };
+        let mut device = iface.get_mut().await;
+        if device.state == object.state && device.record == object.record {
+            return Ok(());
+        }
+        let previous = std::mem::replace(&mut *device, object);
+        let ctxt = iface.signal_context();
+        if device.text() != previous.text() {
+            device.text_changed(ctxt).await?;
+        }
+        if device.tooltip() != previous.tooltip() {
+            device.tooltip_changed(ctxt).await?;
+        }
+        if device.class() != previous.class() {
+            device.class_changed(ctxt).await?;
+        }
+        if device.record["values"] != previous.record["values"] {
+            device.values_changed(ctxt).await?;
+        }
+        if device.units() != previous.units() {
+            device.units_changed(ctxt).await?;
+        }
+        if device.updated_at() != previous.updated_at() {
+            device.updated_at_changed(ctxt).await?;
+        }
+        let (text, state) = (device.text(), device.state.to_string());
+        drop(device);
+
+        Device::status_changed(ctxt, &text, &state).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_devices_states() {
+        let mut devices = Devices::default();
+        devices.states.insert(
+            "plug:67890".to_string(),
+            json!({ "text": "P: 50.0W", "tooltip": "" }),
+        );
+
+        assert_eq!(devices.devices(), vec!["plug:67890".to_string()]);
+        assert_eq!(
+            devices.get_state("plug:67890"),
+            r#"{"text":"P: 50.0W","tooltip":""}"#
+        );
+        assert_eq!(devices.get_state("unknown"), "");
+        assert_eq!(
+            devices.get_states(),
+            r#"{"plug:67890":{"text":"P: 50.0W","tooltip":""}}"#
+        );
+    }
+
+    #[test]
+    fn test_device_properties() {
+        let device = Device {
+            device: "plug:67890:Desk".to_string(),
+            state: json!({ "text": "P: 50.0W", "tooltip": "", "class": ["on", "error"] }),
+            record: json!({
+                "device_id": "67890",
+                "name": "Desk",
+                "type": "plug",
+                "updated_at": "2024-01-15T10:20:30Z",
+                "values": { "power": 50, "output": "ON" },
+                "units": { "power": "W" },
+            }),
+        };
+
+        assert_eq!(device.name(), "Desk");
+        assert_eq!(device.device_type(), "plug");
+        assert_eq!(device.text(), "P: 50.0W");
+        assert_eq!(device.class(), ["on", "error"]);
+        assert_eq!(device.updated_at(), 1705314030);
+        assert_eq!(
+            device.units(),
+            HashMap::from([("power".to_string(), "W".to_string())])
+        );
+
+        let values = device.values();
+        assert_eq!(f64::try_from(&values["power"]).unwrap(), 50.0);
+        assert_eq!(<&str>::try_from(&values["output"]).unwrap(), "ON");
+
+        let device = Device {
+            state: json!({ "text": "", "class": "error" }),
+            ..Device::default()
+        };
+        assert_eq!(device.class(), ["error"]);
+        assert!(device.values().is_empty());
+        assert_eq!(device.updated_at(), 0);
+
+        let device = Device {
+            state: json!({ "text": "" }),
+            ..Device::default()
+        };
+        assert!(device.class().is_empty());
+    }
+
+    #[test]
+    fn test_device_path() {
+        assert_eq!(
+            device_path("plug:shellyplug-s-1234:Desk", "shellyplug-s-1234"),
+            "/org/shelly_waybar/Devices/shellyplug_s_1234"
+        );
+        assert_eq!(
+            device_path("plug:a8032ab12345:Office#1", "a8032ab12345"),
+            "/org/shelly_waybar/Devices/a8032ab12345_1"
+        );
+        assert_eq!(
+            device_path("plug:12345", ""),
+            "/org/shelly_waybar/Devices/plug_12345"
+        );
+    }
+}
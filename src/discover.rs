@@ -0,0 +1,307 @@
+use crate::auth::Secret;
+use crate::ratelimit::RateLimiter;
+use crate::{local, FetchError};
+use futures::future;
+use futures::stream::{self, StreamExt};
+use mdns_sd::{ServiceDaemon, ServiceEvent, ServiceInfo};
+use reqwest::Client;
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// mDNS services of Gen2 devices, and the web server every Gen1 device announces.
+const SERVICE_TYPES: [&str; 2] = ["_shelly._tcp.local.", "_http._tcp.local."];
+
+/// Device found by `discover`.
+#[derive(Debug, PartialEq)]
+pub struct Discovered {
+    pub id: String,
+    /// Name given in the Shelly app
+    pub name: Option<String>,
+    pub model: String,
+    /// Address on the LAN
+    pub host: Option<String>,
+    /// Type autodetected from its status, when it answered
+    pub device_type: Option<String>,
+}
+
+/// Browses the Shelly devices announced with mDNS on the LAN for `duration`, then
+/// fetches their status to detect their type.
+pub async fn browse_local(
+    client: &Client,
+    duration: Duration,
+) -> Result<Vec<Discovered>, mdns_sd::Error> {
+    let daemon = ServiceDaemon::new()?;
+    let mut events = Vec::new();
+    for service_type in SERVICE_TYPES {
+        events.push(daemon.browse(service_type)?.into_stream());
+    }
+    let mut events = stream::select_all(events);
+
+    let deadline = Instant::now() + duration;
+    let mut found = BTreeMap::new();
+    while let Ok(Some(event)) = tokio::time::timeout_at(deadline, events.next()).await {
+        if let ServiceEvent::ServiceResolved(info) = event {
+            if let Some((id, model, host)) = parse_service(&info) {
+                found.insert(id, (model, host));
+            }
+        }
+    }
+    // Shutting down only fails when the daemon is already gone
+    let _ = daemon.shutdown();
+
+    Ok(
+        future::join_all(found.into_iter().map(|(id, (model, host))| async move {
+            let device_type = local::fetch_status(client, &host, None)
+                .await
+                .ok()
+                .and_then(|status| crate::autodetect_device_type(&status))
+                .map(|device_type| device_type.to_string());
+            Discovered {
+                id,
+                name: None,
+                model,
+                host: Some(host),
+                device_type,
+            }
+        }))
+        .await,
+    )
+}
+
+/// Lists the devices of the cloud account, detecting their type from their last status.
+pub async fn list_cloud(
+    client: &Client,
+    base_url: &str,
+    auth_key: &Secret,
+    limiter: &RateLimiter,
+) -> Result<Vec<Discovered>, FetchError> {
+    let url = format!("{}/interface/device/list", base_url);
+    let form = [("auth_key", auth_key.expose())];
+    let devices = crate::post_cloud(client, &url, &form, auth_key, limiter)
+        .await?
+        .devices
+        .ok_or(FetchError::Failed)?;
+    // Offline devices have no status, their type is left to autodetect
+    let statuses = crate::fetch_all_statuses(client, base_url, auth_key, limiter)
+        .await
+        .unwrap_or_default();
+
+    let devices: BTreeMap<String, Value> = devices.into_iter().collect();
+    Ok(devices
+        .into_iter()
+        .map(|(id, device)| Discovered {
+            name: device["name"].as_str().map(str::to_string),
+            model: device["type"].as_str().unwrap_or("-").to_string(),
+            host: device["ip"].as_str().map(str::to_string),
+            device_type: statuses
+                .get(&id)
+                .and_then(crate::autodetect_device_type)
+                .map(|device_type| device_type.to_string()),
+            id,
+        })
+        .collect())
+}
+
+// ID, model and address of a resolved service, e.g. `shellyplusplugs-e8db84d6b7a4`,
+// `PlusPlugS` and `192.168.1.20`
+fn parse_service(info: &ServiceInfo) -> Option<(String, String, String)> {
+    let id = info
+        .get_fullname()
+        .strip_suffix(info.get_type())?
+        .trim_end_matches('.');
+    // Gen1 devices only announce a web server, named after the device
+    let model = match info.get_property_val_str("app") {
+        Some(app) => app.to_string(),
+        None if id.starts_with("shelly") => id.rsplit_once('-')?.0.to_string(),
+        None => return None,
+    };
+    let address = info.get_addresses_v4().into_iter().min()?;
+    let host = match info.get_port() {
+        80 | 0 => address.to_string(),
+        port => format!("{}:{}", address, port),
+    };
+    Some((id.to_string(), model, host))
+}
+
+/// Prints the devices found on the LAN, with ready-to-paste arguments and config entries.
+pub fn print_local(devices: &[Discovered]) {
+    if devices.is_empty() {
+        println!("No Shelly device found on the LAN");
+        return;
+    }
+    print_table(devices);
+    println!("\n# Arguments, with --local");
+    for device in devices {
+        let host = device.host.as_deref().unwrap_or_default();
+        println!("{}", device_argument(device, host));
+    }
+    println!("\n# Or config entries, with --devices <type>:<id>");
+    for device in devices {
+        let host = device.host.as_deref().unwrap_or_default();
+        println!("[devices.\"{}\"]\nhost = \"{}\"", device.id, host);
+    }
+}
+
+/// Prints the devices of the cloud account, with ready-to-paste arguments.
+pub fn print_cloud(devices: &[Discovered]) {
+    if devices.is_empty() {
+        println!("No device in the Shelly cloud account");
+        return;
+    }
+    print_table(devices);
+    println!("\n# Arguments");
+    for device in devices {
+        println!("{}", device_argument(device, &device.id));
+    }
+}
+
+fn print_table(devices: &[Discovered]) {
+    let width = |column: fn(&Discovered) -> &str| {
+        devices
+            .iter()
+            .map(|device| column(device).len())
+            .max()
+            .unwrap_or(0)
+    };
+    fn name(device: &Discovered) -> &str {
+        device.name.as_deref().unwrap_or("-")
+    }
+    fn host(device: &Discovered) -> &str {
+        device.host.as_deref().unwrap_or("-")
+    }
+    let id_width = width(|device| &device.id);
+    let name_width = width(name);
+    let model_width = width(|device| &device.model);
+    let host_width = width(host);
+    for device in devices {
+        println!(
+            "{:id_width$}  {:name_width$}  {:model_width$}  {:host_width$}  {}",
+            device.id,
+            name(device),
+            device.model,
+            host(device),
+            device.device_type.as_deref().unwrap_or("-"),
+        );
+    }
+}
+
+// `--devices <type>:<device_id>:<name>`, leaving the type empty to autodetect it
+fn device_argument(device: &Discovered, device_id: &str) -> String {
+    format!(
+        "--devices '{}:{}:{}'",
+        device.device_type.as_deref().unwrap_or(""),
+        device_id,
+        device.name.as_deref().unwrap_or(&device.model)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use httpmock::MockServer;
+    use serde_json::json;
+    use std::collections::HashMap;
+
+    fn service(service_type: &str, name: &str, properties: &[(&str, &str)]) -> ServiceInfo {
+        let properties: HashMap<String, String> = properties
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect();
+        ServiceInfo::new(
+            service_type,
+            name,
+            &format!("{}.local.", name),
+            "192.168.1.20",
+            80,
+            properties,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_parse_service() {
+        let gen2 = service(
+            "_shelly._tcp.local.",
+            "shellyplusplugs-e8db84d6b7a4",
+            &[("gen", "2"), ("app", "PlusPlugS")],
+        );
+        assert_eq!(
+            parse_service(&gen2),
+            Some((
+                "shellyplusplugs-e8db84d6b7a4".to_string(),
+                "PlusPlugS".to_string(),
+                "192.168.1.20".to_string()
+            ))
+        );
+
+        let gen1 = service("_http._tcp.local.", "shellyht-AB12CD", &[]);
+        assert_eq!(
+            parse_service(&gen1).map(|(_, model, _)| model),
+            Some("shellyht".to_string())
+        );
+        let printer = service("_http._tcp.local.", "printer", &[]);
+        assert_eq!(parse_service(&printer), None);
+    }
+
+    #[test]
+    fn test_device_argument() {
+        let mut device = Discovered {
+            id: "shellyplusplugs-e8db84d6b7a4".to_string(),
+            name: None,
+            model: "PlusPlugS".to_string(),
+            host: Some("192.168.1.20".to_string()),
+            device_type: Some("plug".to_string()),
+        };
+        assert_eq!(
+            device_argument(&device, "192.168.1.20"),
+            "--devices 'plug:192.168.1.20:PlusPlugS'"
+        );
+        device.device_type = None;
+        device.name = Some("Desk".to_string());
+        assert_eq!(
+            device_argument(&device, &device.id),
+            "--devices ':shellyplusplugs-e8db84d6b7a4:Desk'"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_list_cloud() {
+        let server = MockServer::start_async().await;
+        server.mock(|when, then| {
+            when.method("POST").path("/interface/device/list");
+            then.status(200).json_body(json!({
+                "isok": true,
+                "data": { "devices": {
+                    "e8db84d6b7a4": { "id": "e8db84d6b7a4", "name": "Desk", "type": "SNPL-00116EU" },
+                    "ab12cd": { "id": "ab12cd", "name": "Bedroom", "type": "SHHT-1" },
+                } }
+            }));
+        });
+        server.mock(|when, then| {
+            when.method("POST").path("/device/all_status");
+            then.status(200).json_body(json!({
+                "isok": true,
+                "data": { "devices_status": {
+                    "e8db84d6b7a4": { "switch:0": { "output": true, "apower": 5.0 } },
+                } }
+            }));
+        });
+
+        let devices = list_cloud(
+            &Client::new(),
+            &server.base_url(),
+            &Secret::new("key"),
+            &RateLimiter::unlimited(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(devices.len(), 2);
+        assert_eq!(devices[0].id, "ab12cd");
+        assert_eq!(devices[0].device_type, None);
+        assert_eq!(devices[1].name.as_deref(), Some("Desk"));
+        assert_eq!(devices[1].model, "SNPL-00116EU");
+        assert_eq!(devices[1].device_type.as_deref(), Some("plug"));
+    }
+}
@@ -0,0 +1,675 @@
+use crate::OutputFormat;
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::borrow::Cow;
+use std::collections::HashMap;
+
+/// Where a field is rendered in the Waybar output.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Slot {
+    Text,
+    Tooltip,
+    /// Only kept in the samples, unless selected in the config
+    Hidden,
+}
+
+/// Value of a field, kept unformatted until rendering.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Metric {
+    Float(f64, usize),
+    Int(i64),
+    Text(String),
+}
+
+impl Metric {
+    fn render(&self) -> String {
+        match self {
+            Metric::Float(value, precision) => format!("{:.*}", precision, value),
+            Metric::Int(value) => value.to_string(),
+            Metric::Text(value) => value.clone(),
+        }
+    }
+
+    /// JSON value as displayed, floats rounded to their precision.
+    fn to_json(&self) -> Value {
+        match self {
+            Metric::Float(value, precision) => {
+                let factor = 10f64.powi(*precision as i32);
+                serde_json::json!((value * factor).round() / factor)
+            }
+            Metric::Int(value) => Value::from(*value),
+            Metric::Text(value) => Value::String(value.clone()),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum RoundingMode {
+    #[default]
+    Round,
+    Floor,
+    Significant,
+}
+
+/// Rounding rule for one metric: `digits` is the number of decimals, or of
+/// significant figures in `significant` mode.
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq)]
+#[serde(default)]
+pub struct Rounding {
+    pub mode: RoundingMode,
+    pub digits: u32,
+}
+
+impl Rounding {
+    pub fn apply(&self, metric: &Metric) -> Metric {
+        match (self.mode, metric) {
+            (RoundingMode::Round, Metric::Float(value, _)) => {
+                Metric::Float(*value, self.digits as usize)
+            }
+            (RoundingMode::Floor, Metric::Float(value, _)) => {
+                let factor = 10f64.powi(self.digits as i32);
+                Metric::Float((value * factor).floor() / factor, self.digits as usize)
+            }
+            (RoundingMode::Significant, Metric::Float(value, _)) => {
+                let decimals = self.significant_decimals(*value);
+                let factor = 10f64.powi(decimals);
+                Metric::Float((value * factor).round() / factor, decimals.max(0) as usize)
+            }
+            (RoundingMode::Significant, Metric::Int(value)) => {
+                let factor = 10f64.powi(self.significant_decimals(*value as f64));
+                Metric::Int(((*value as f64 * factor).round() / factor) as i64)
+            }
+            _ => metric.clone(),
+        }
+    }
+
+    // Decimal position of the last significant digit, negative for tens, hundreds...
+    fn significant_decimals(&self, value: f64) -> i32 {
+        if value == 0.0 {
+            return 0;
+        }
+        self.digits.max(1) as i32 - 1 - value.abs().log10().floor() as i32
+    }
+}
+
+/// Applies the per-metric rounding rules, keyed by field key. Keys of
+/// numbered fields such as `temperature_100` fall back to their base name.
+pub fn apply_rounding(fields: &mut [Field], rounding: &HashMap<String, Rounding>) {
+    for field in fields.iter_mut() {
+        let rule = rounding
+            .get(field.key.as_ref())
+            .or_else(|| rounding.get(base_key(&field.key)));
+        if let Some(rule) = rule {
+            field.value = rule.apply(&field.value);
+        }
+    }
+}
+
+// `power_1` -> `power`, other keys unchanged
+fn base_key(key: &str) -> &str {
+    match key.rsplit_once('_') {
+        Some((base, index)) if index.bytes().all(|byte| byte.is_ascii_digit()) => base,
+        _ => key,
+    }
+}
+
+/// A single labelled reading extracted from a device status.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Field {
+    pub key: Cow<'static, str>,
+    pub label: String,
+    pub value: Metric,
+    pub unit: Cow<'static, str>,
+    pub sep: &'static str,
+    pub slot: Slot,
+    /// Machine-readable value, when the displayed one is a symbol
+    pub raw: Option<Value>,
+}
+
+impl Field {
+    pub fn text(key: impl Into<Cow<'static, str>>, label: String, value: Metric) -> Self {
+        Field {
+            key: key.into(),
+            label,
+            value,
+            unit: Cow::Borrowed(""),
+            sep: " ",
+            slot: Slot::Text,
+            raw: None,
+        }
+    }
+
+    pub fn tooltip(key: impl Into<Cow<'static, str>>, label: String, value: Metric) -> Self {
+        Field {
+            slot: Slot::Tooltip,
+            ..Field::text(key, label, value)
+        }
+    }
+
+    pub fn unit(mut self, unit: impl Into<Cow<'static, str>>) -> Self {
+        self.unit = unit.into();
+        self
+    }
+
+    /// Separator inserted before this field when it follows another one.
+    pub fn sep(mut self, sep: &'static str) -> Self {
+        self.sep = sep;
+        self
+    }
+
+    pub fn raw(mut self, raw: Value) -> Self {
+        self.raw = Some(raw);
+        self
+    }
+
+    pub fn render(&self) -> String {
+        format!("{}{}{}", self.label, self.value.render(), self.unit)
+    }
+
+    /// Renders the field as Pango markup, the unit in small caps.
+    pub fn render_markup(&self) -> String {
+        let unit = if self.unit.is_empty() {
+            String::new()
+        } else {
+            format!(
+                "<span font_variant=\"small-caps\">{}</span>",
+                escape_markup(&self.unit)
+            )
+        };
+        // Labels are already markup, such as the color swatch of RGBW lights
+        format!(
+            "{}{}{}",
+            self.label,
+            escape_markup(&self.value.render()),
+            unit
+        )
+    }
+
+    pub fn sample(&self) -> Sample {
+        Sample {
+            key: self.key.to_string(),
+            value: self.raw.clone().unwrap_or_else(|| self.value.to_json()),
+            unit: self.unit.to_string(),
+        }
+    }
+}
+
+/// Unlabelled value of a field, kept for consumers other than Waybar.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Sample {
+    pub key: String,
+    pub value: Value,
+    pub unit: String,
+}
+
+/// Glyph set used by the icons output format.
+#[derive(Debug, Clone, Copy, Default, PartialEq, ValueEnum)]
+pub enum IconSet {
+    /// Emoji and Nerd Font glyphs
+    #[default]
+    Emoji,
+    /// Plain ASCII labels for fonts without emoji coverage
+    Ascii,
+}
+
+/// Symbols displayed in front of values in the icons output format.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Icon {
+    Temperature,
+    Humidity,
+    Battery,
+    Signal,
+    Power,
+    Voltage,
+    Current,
+    Output,
+    Lux,
+    Tilt,
+    Open,
+    Closed,
+    Moving,
+    Position,
+    Target,
+    Valve,
+    Alarm,
+    Clear,
+    Gas,
+    Sensor,
+    SelfTest,
+    Motion,
+    Still,
+    Brightness,
+    Energy,
+    Input,
+    Usb,
+    Vibration,
+    ColorTemp,
+    Hot,
+    Dark,
+    Twilight,
+    Bright,
+    Ethernet,
+}
+
+impl Icon {
+    pub fn glyph(self, icon_set: IconSet) -> &'static str {
+        match icon_set {
+            IconSet::Emoji => match self {
+                Icon::Temperature => "\u{f2c9}",
+                Icon::Humidity => "💧",
+                Icon::Battery => "🔋",
+                Icon::Signal => "📶",
+                Icon::Power => "⚡",
+                Icon::Voltage => "🔌",
+                Icon::Current => "🔋",
+                Icon::Output => "🔆",
+                Icon::Lux => "🔆",
+                Icon::Tilt => "📐",
+                Icon::Open => "🟢",
+                Icon::Closed => "🔴",
+                Icon::Moving => "↕️",
+                Icon::Position => "🪟",
+                Icon::Target => "🎯",
+                Icon::Valve => "🔧",
+                Icon::Alarm => "🚨",
+                Icon::Clear => "✅",
+                Icon::Gas => "💨",
+                Icon::Sensor => "📟",
+                Icon::SelfTest => "🧪",
+                Icon::Motion => "🏃",
+                Icon::Still => "💤",
+                Icon::Brightness => "💡",
+                Icon::Energy => "📊",
+                Icon::Input => "🔘",
+                Icon::Usb => "🔌",
+                Icon::Vibration => "📳",
+                Icon::ColorTemp => "🌅",
+                Icon::Hot => "🔥",
+                Icon::Dark => "🌑",
+                Icon::Twilight => "🌗",
+                Icon::Bright => "☀️",
+                Icon::Ethernet => "🖧",
+            },
+            IconSet::Ascii => match self {
+                Icon::Temperature => "T:",
+                Icon::Humidity => "H:",
+                Icon::Battery => "B:",
+                Icon::Signal => "S:",
+                Icon::Power => "P:",
+                Icon::Voltage => "V:",
+                Icon::Current => "I:",
+                Icon::Output => "O:",
+                Icon::Lux => "L:",
+                Icon::Tilt => "A:",
+                Icon::Open => "OPEN",
+                Icon::Closed => "CLOSED",
+                Icon::Moving => "MOVING",
+                Icon::Position => "POS:",
+                Icon::Target => "SET:",
+                Icon::Valve => "VLV:",
+                Icon::Alarm => "ALARM",
+                Icon::Clear => "OK",
+                Icon::Gas => "GAS:",
+                Icon::Sensor => "SNS:",
+                Icon::SelfTest => "TEST:",
+                Icon::Motion => "MOTION",
+                Icon::Still => "IDLE",
+                Icon::Brightness => "BR:",
+                Icon::Energy => "E:",
+                Icon::Input => "IN",
+                Icon::Usb => "USB",
+                Icon::Vibration => "VIB",
+                Icon::ColorTemp => "CT:",
+                Icon::Hot => "HOT",
+                Icon::Dark => "DARK",
+                Icon::Twilight => "DUSK",
+                Icon::Bright => "BRIGHT",
+                Icon::Ethernet => "NET:",
+            },
+        }
+    }
+}
+
+/// Output format and icon set used to label fields.
+#[derive(Debug, Clone, Default)]
+pub struct Style {
+    pub format: OutputFormat,
+    pub icon_set: IconSet,
+    /// Values without labels, leaving icons to the Waybar `format-icons`
+    pub bare: bool,
+}
+
+impl From<OutputFormat> for Style {
+    fn from(format: OutputFormat) -> Self {
+        Style {
+            format,
+            ..Style::default()
+        }
+    }
+}
+
+impl Style {
+    /// Builds the label prefix of a field for the selected output format.
+    pub fn label(&self, short: &str, long: &str, icon: Icon) -> String {
+        if self.bare {
+            return String::new();
+        }
+        let label = match self.format {
+            OutputFormat::Short => short,
+            OutputFormat::Long => long,
+            OutputFormat::Icons => return icon.glyph(self.icon_set).to_string(),
+        };
+        if label.is_empty() {
+            String::new()
+        } else {
+            format!("{}: ", label)
+        }
+    }
+
+    pub fn icon(&self, icon: Icon) -> &'static str {
+        icon.glyph(self.icon_set)
+    }
+}
+
+/// Per-device override of which fields go to the text, the tooltip or nowhere.
+///
+/// An explicit `text` or `tooltip` list defines the exact content and order of
+/// that slot; fields not listed anywhere keep their default slot unless that
+/// slot has an explicit list.
+#[derive(Deserialize, Debug, Default, Clone, PartialEq)]
+#[serde(default)]
+pub struct FieldSelection {
+    pub text: Option<Vec<String>>,
+    pub tooltip: Option<Vec<String>>,
+    pub hidden: Vec<String>,
+}
+
+impl FieldSelection {
+    fn is_hidden(&self, key: &str) -> bool {
+        self.hidden.iter().any(|hidden| hidden == key)
+    }
+
+    fn place<'a>(&self, fields: &'a [Field], slot: Slot) -> Vec<&'a Field> {
+        let (explicit, other) = match slot {
+            Slot::Text => (&self.text, &self.tooltip),
+            Slot::Tooltip | Slot::Hidden => (&self.tooltip, &self.text),
+        };
+
+        match explicit {
+            Some(keys) => keys
+                .iter()
+                .filter(|key| !self.is_hidden(key))
+                .filter_map(|key| fields.iter().find(|field| field.key == key.as_str()))
+                .collect(),
+            None => fields
+                .iter()
+                .filter(|field| field.slot == slot && !self.is_hidden(&field.key))
+                .filter(|field| {
+                    other
+                        .as_ref()
+                        .is_none_or(|keys| !keys.iter().any(|key| field.key == key.as_str()))
+                })
+                .collect(),
+        }
+    }
+}
+
+fn join(fields: &[&Field], render: fn(&Field) -> String) -> String {
+    let mut output = String::new();
+    for (index, field) in fields.iter().enumerate() {
+        if index > 0 {
+            output.push_str(field.sep);
+        }
+        output.push_str(&render(field));
+    }
+    output
+}
+
+/// Renders fields into a Waybar `text`/`tooltip` object.
+pub fn render_fields(fields: &[Field], selection: &FieldSelection) -> Value {
+    serde_json::json!({
+        "text": join(&selection.place(fields, Slot::Text), Field::render),
+        "tooltip": join(&selection.place(fields, Slot::Tooltip), Field::render),
+    })
+}
+
+/// Renders fields into a Waybar `text`/`tooltip` object in Pango markup.
+pub fn render_fields_markup(fields: &[Field], selection: &FieldSelection) -> Value {
+    serde_json::json!({
+        "text": join(&selection.place(fields, Slot::Text), Field::render_markup),
+        "tooltip": join(&selection.place(fields, Slot::Tooltip), Field::render_markup),
+    })
+}
+
+/// Escapes the characters of Pango markup, e.g. in device names.
+pub fn escape_markup(text: &str) -> Cow<'_, str> {
+    if !text.contains(['&', '<', '>', '"', '\'']) {
+        return Cow::Borrowed(text);
+    }
+    let mut escaped = String::with_capacity(text.len());
+    for character in text.chars() {
+        match character {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            character => escaped.push(character),
+        }
+    }
+    Cow::Owned(escaped)
+}
+
+/// Plain text of Pango markup, for outputs not rendering it: tags dropped, entities unescaped.
+pub fn strip_markup(markup: &str) -> String {
+    let mut text = String::with_capacity(markup.len());
+    let mut in_tag = false;
+    for character in markup.chars() {
+        match character {
+            '<' => in_tag = true,
+            '>' if in_tag => in_tag = false,
+            character if !in_tag => text.push(character),
+            _ => {}
+        }
+    }
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn plug_fields() -> Vec<Field> {
+        vec![
+            Field::text("power", "P: ".to_string(), Metric::Float(50.0, 1)).unit("W"),
+            Field::text("voltage", "V: ".to_string(), Metric::Float(230.0, 1)).unit("V"),
+            Field::tooltip("current", "I: ".to_string(), Metric::Float(0.217, 3)).unit("A"),
+            Field::tooltip("output", "O: ".to_string(), Metric::Text("ON".to_string())),
+        ]
+    }
+
+    #[test]
+    fn test_label() {
+        let ascii = Style {
+            format: OutputFormat::Icons,
+            icon_set: IconSet::Ascii,
+            ..Style::default()
+        };
+
+        assert_eq!(
+            Style::from(OutputFormat::Short).label("P", "Power", Icon::Power),
+            "P: "
+        );
+        assert_eq!(
+            Style::from(OutputFormat::Long).label("P", "Power", Icon::Power),
+            "Power: "
+        );
+        assert_eq!(
+            Style::from(OutputFormat::Icons).label("P", "Power", Icon::Power),
+            "⚡"
+        );
+        assert_eq!(ascii.label("P", "Power", Icon::Power), "P:");
+        assert_eq!(ascii.icon(Icon::Open), "OPEN");
+        assert_eq!(
+            Style::from(OutputFormat::Long).label("", "", Icon::Open),
+            ""
+        );
+        let bare = Style {
+            bare: true,
+            ..Style::default()
+        };
+        assert_eq!(bare.label("P", "Power", Icon::Power), "");
+    }
+
+    #[test]
+    fn test_render_fields_default() {
+        let output = render_fields(&plug_fields(), &FieldSelection::default());
+        assert_eq!(output["text"], "P: 50.0W V: 230.0V");
+        assert_eq!(output["tooltip"], "I: 0.217A O: ON");
+    }
+
+    #[test]
+    fn test_render_fields_with_selection() {
+        let selection = FieldSelection {
+            text: Some(vec!["power".to_string()]),
+            tooltip: Some(vec!["voltage".to_string(), "current".to_string()]),
+            hidden: vec![],
+        };
+        let output = render_fields(&plug_fields(), &selection);
+        assert_eq!(output["text"], "P: 50.0W");
+        assert_eq!(output["tooltip"], "V: 230.0V I: 0.217A");
+
+        let selection = FieldSelection {
+            text: None,
+            tooltip: Some(vec!["power".to_string()]),
+            hidden: vec!["output".to_string()],
+        };
+        let output = render_fields(&plug_fields(), &selection);
+        assert_eq!(output["text"], "V: 230.0V");
+        assert_eq!(output["tooltip"], "P: 50.0W");
+    }
+
+    #[test]
+    fn test_rounding_apply() {
+        let round = Rounding {
+            mode: RoundingMode::Round,
+            digits: 0,
+        };
+        let floor = Rounding {
+            mode: RoundingMode::Floor,
+            digits: 1,
+        };
+        let significant = Rounding {
+            mode: RoundingMode::Significant,
+            digits: 2,
+        };
+
+        assert_eq!(round.apply(&Metric::Float(49.6, 1)).render(), "50");
+        assert_eq!(floor.apply(&Metric::Float(22.58, 1)).render(), "22.5");
+        assert_eq!(
+            significant.apply(&Metric::Float(0.2174, 3)).render(),
+            "0.22"
+        );
+        assert_eq!(
+            significant.apply(&Metric::Float(1234.5, 1)).render(),
+            "1200"
+        );
+        assert_eq!(significant.apply(&Metric::Int(-67)).render(), "-67");
+        assert_eq!(round.apply(&Metric::Int(50)).render(), "50");
+        assert_eq!(round.apply(&Metric::Text("ON".to_string())).render(), "ON");
+    }
+
+    #[test]
+    fn test_apply_rounding() {
+        let mut fields = plug_fields();
+        let rounding = HashMap::from([(
+            "power".to_string(),
+            Rounding {
+                mode: RoundingMode::Round,
+                digits: 0,
+            },
+        )]);
+
+        apply_rounding(&mut fields, &rounding);
+        let output = render_fields(&fields, &FieldSelection::default());
+        assert_eq!(output["text"], "P: 50W V: 230.0V");
+
+        let mut probes = vec![Field::text(
+            "temperature_100",
+            "T100: ".to_string(),
+            Metric::Float(21.56, 1),
+        )];
+        let rounding = HashMap::from([(
+            "temperature".to_string(),
+            Rounding {
+                mode: RoundingMode::Round,
+                digits: 0,
+            },
+        )]);
+        apply_rounding(&mut probes, &rounding);
+        assert_eq!(probes[0].value, Metric::Float(21.56, 0));
+        assert_eq!(base_key("last_motion"), "last_motion");
+    }
+
+    #[test]
+    fn test_sample() {
+        let fields = plug_fields();
+        assert_eq!(
+            fields[2].sample(),
+            Sample {
+                key: "current".to_string(),
+                value: serde_json::json!(0.217),
+                unit: "A".to_string(),
+            }
+        );
+        assert_eq!(fields[3].sample().value, "ON");
+
+        let state = Field::text("state", String::new(), Metric::Text("🟢".to_string()))
+            .raw(Value::String("open".to_string()));
+        assert_eq!(state.sample().value, "open");
+    }
+
+    #[test]
+    fn test_render_fields_markup() {
+        let mut fields = plug_fields();
+        fields.push(Field::tooltip(
+            "name",
+            "Name: ".to_string(),
+            Metric::Text("Tom & Jerry <3".to_string()),
+        ));
+        let output = render_fields_markup(&fields, &FieldSelection::default());
+        assert_eq!(
+            output["text"],
+            "P: 50.0<span font_variant=\"small-caps\">W</span> V: 230.0<span font_variant=\"small-caps\">V</span>"
+        );
+        assert!(output["tooltip"]
+            .as_str()
+            .unwrap()
+            .ends_with("Name: Tom &amp; Jerry &lt;3"));
+        assert_eq!(escape_markup("Office"), "Office");
+        assert_eq!(escape_markup("\"Kid's\""), "&quot;Kid&apos;s&quot;");
+        assert_eq!(
+            strip_markup(
+                "<b>Tom &amp; Jerry &lt;3</b> 50.0<span font_variant=\"small-caps\">W</span>"
+            ),
+            "Tom & Jerry <3 50.0W"
+        );
+    }
+
+    #[test]
+    fn test_render_fields_separator() {
+        let fields = vec![
+            Field::text("state", String::new(), Metric::Text("Open".to_string())),
+            Field::text("lux", "L: ".to_string(), Metric::Int(100)).sep(": "),
+        ];
+        let output = render_fields(&fields, &FieldSelection::default());
+        assert_eq!(output["text"], "Open: L: 100");
+        assert_eq!(output["tooltip"], "");
+    }
+}
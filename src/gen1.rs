@@ -0,0 +1,198 @@
+use serde_json::{json, Map, Value};
+
+/// Top-level keys of the Gen1 status schema, which has no `<component>:<id>` keys.
+const GEN1_KEYS: [&str; 7] = [
+    "tmp", "hum", "relays", "meters", "sensor", "bat", "wifi_sta",
+];
+
+/// Channels of a Gen1 list, well above the 4 of the Shelly 4Pro.
+const MAX_CHANNELS: usize = 16;
+
+/// Whether a status follows the legacy Gen1 schema (H&T, Plug S, Door/Window 2...).
+pub fn is_gen1(status: &Value) -> bool {
+    let Some(object) = status.as_object() else {
+        return false;
+    };
+    !object.keys().any(|key| key.contains(':'))
+        && GEN1_KEYS.iter().any(|key| object.contains_key(*key))
+}
+
+/// Adds the Gen2 components matching the readings of a Gen1 status, so that
+/// the device parsers handle both generations. Gen1 keys are kept for the
+/// parsers of Gen1-only devices.
+pub fn normalize(status: Value) -> Value {
+    if !is_gen1(&status) {
+        return status;
+    }
+    let Value::Object(mut object) = status else {
+        return status;
+    };
+    let gen1 = Value::Object(object.clone());
+
+    if let Some(temperature) = temperature(&gen1["tmp"]) {
+        insert(&mut object, "temperature:0", temperature);
+    }
+    if let Some(rh) = gen1["hum"]["value"].as_f64() {
+        insert(&mut object, "humidity:0", json!({ "rh": rh }));
+    }
+    if let Some(percent) = gen1["bat"]["value"].as_i64() {
+        insert(
+            &mut object,
+            "devicepower:0",
+            json!({ "battery": { "percent": percent } }),
+        );
+    }
+    if let Some(rssi) = gen1["wifi_sta"]["rssi"].as_i64() {
+        // Battery devices report it as `reporter`, mains ones as `wifi`
+        insert(&mut object, "reporter", json!({ "rssi": rssi }));
+        insert(&mut object, "wifi", json!({ "rssi": rssi }));
+    }
+
+    if let Some(relays) = gen1["relays"].as_array() {
+        for (id, relay) in relays.iter().enumerate() {
+            let meter = &gen1["meters"][id];
+            insert(
+                &mut object,
+                &format!("switch:{}", id),
+                json!({
+                    "output": relay["ison"].as_bool().unwrap_or(false),
+                    "apower": meter["power"].as_f64().unwrap_or(0.0),
+                    // Only some relays (Shelly 2.5) measure the voltage, none the current
+                    "voltage": gen1["voltage"].as_f64().unwrap_or(0.0),
+                    "current": 0.0,
+                }),
+            );
+        }
+    }
+
+    if let Some(state) = gen1["sensor"]["state"].as_str() {
+        insert(&mut object, "window:0", json!({ "open": state == "open" }));
+        if let Some(lux) = gen1["lux"]["value"].as_f64() {
+            insert(
+                &mut object,
+                "illuminance:0",
+                json!({ "lux": lux.round() as u64 }),
+            );
+        }
+        // -1 until the sensor is calibrated
+        if let Some(angle) = gen1["accel"]["tilt"].as_i64().filter(|tilt| *tilt >= 0) {
+            insert(&mut object, "tilt:0", json!({ "angle": angle }));
+        }
+    }
+
+    Value::Object(object)
+}
+
+/// Element `index` of the Gen1 list `key` of a status being built, growing the list as
+/// needed, or None past the last channel so that a malformed message cannot allocate it.
+pub(crate) fn element<'a>(status: &'a mut Value, key: &str, index: usize) -> Option<&'a mut Value> {
+    if index >= MAX_CHANNELS {
+        return None;
+    }
+    if !status[key].is_array() {
+        status[key] = json!([]);
+    }
+    let array = status[key].as_array_mut()?;
+    if array.len() <= index {
+        array.resize(index + 1, json!({}));
+    }
+    Some(object_value(&mut array[index]))
+}
+
+/// Object `key` of a status being built, replacing any other value, e.g. from a Gen1 `info`.
+pub(crate) fn object<'a>(status: &'a mut Value, key: &str) -> &'a mut Value {
+    object_value(&mut status[key])
+}
+
+fn object_value(value: &mut Value) -> &mut Value {
+    if !value.is_object() {
+        *value = json!({});
+    }
+    value
+}
+
+fn insert(object: &mut Map<String, Value>, key: &str, value: Value) {
+    object.entry(key).or_insert(value);
+}
+
+// Older firmwares only give the value in the configured unit
+fn temperature(tmp: &Value) -> Option<Value> {
+    let value = tmp["value"].as_f64()?;
+    let (t_c, t_f) = match tmp["units"].as_str() {
+        Some("F") => ((value - 32.0) * 5.0 / 9.0, value),
+        _ => (value, value * 9.0 / 5.0 + 32.0),
+    };
+    Some(json!({
+        "tC": tmp["tC"].as_f64().unwrap_or(t_c),
+        "tF": tmp["tF"].as_f64().unwrap_or(t_f),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_gen1() {
+        assert!(is_gen1(&json!({ "tmp": { "value": 21.0 } })));
+        assert!(!is_gen1(&json!({ "switch:0": {}, "wifi": {} })));
+        assert!(!is_gen1(&json!({})));
+    }
+
+    #[test]
+    fn test_element() {
+        let mut status = json!({ "relays": 1, "meters": [2] });
+        *element(&mut status, "relays", 1).unwrap() = json!({ "ison": true });
+        element(&mut status, "meters", 0).unwrap()["power"] = json!(5.0);
+        assert_eq!(status["relays"], json!([{}, { "ison": true }]));
+        assert_eq!(status["meters"], json!([{ "power": 5.0 }]));
+        assert!(element(&mut status, "relays", MAX_CHANNELS).is_none());
+
+        let mut status = json!({ "sensor": 5 });
+        object(&mut status, "sensor")["state"] = json!("open");
+        assert_eq!(status["sensor"], json!({ "state": "open" }));
+    }
+
+    #[test]
+    fn test_normalize_ht() {
+        let status = normalize(json!({
+            "tmp": { "value": 50.0, "units": "F", "is_valid": true },
+            "hum": { "value": 45.5 },
+            "bat": { "value": 90, "voltage": 2.9 },
+            "wifi_sta": { "connected": true, "rssi": -65 }
+        }));
+
+        assert_eq!(status["temperature:0"]["tC"], 10.0);
+        assert_eq!(status["temperature:0"]["tF"], 50.0);
+        assert_eq!(status["humidity:0"]["rh"], 45.5);
+        assert_eq!(status["devicepower:0"]["battery"]["percent"], 90);
+        assert_eq!(status["reporter"]["rssi"], -65);
+        assert_eq!(status["tmp"]["value"], 50.0);
+    }
+
+    #[test]
+    fn test_normalize_plug_and_door() {
+        let plug = normalize(json!({
+            "relays": [{ "ison": true }],
+            "meters": [{ "power": 42.5, "total": 1000 }],
+            "wifi_sta": { "rssi": -50 }
+        }));
+        assert_eq!(plug["switch:0"]["output"], true);
+        assert_eq!(plug["switch:0"]["apower"], 42.5);
+        assert_eq!(plug["wifi"]["rssi"], -50);
+
+        let door = normalize(json!({
+            "sensor": { "state": "open", "is_valid": true },
+            "lux": { "value": 120.4 },
+            "accel": { "tilt": -1, "vibration": 0 },
+            "bat": { "value": 70 }
+        }));
+        assert_eq!(door["window:0"]["open"], true);
+        assert_eq!(door["illuminance:0"]["lux"], 120);
+        assert!(door.get("tilt:0").is_none());
+
+        // Motion sensors also report a `sensor`, without state
+        let motion = normalize(json!({ "sensor": { "motion": true } }));
+        assert!(motion.get("window:0").is_none());
+    }
+}
@@ -0,0 +1,118 @@
+use chrono::{DateTime, Utc};
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::{Arc, Mutex};
+
+/// Health of the polling loop, shared with the `/healthz` endpoint.
+#[derive(Debug, Default)]
+pub struct Health {
+    last_success: Option<DateTime<Utc>>,
+    /// Failed fetches since start, keyed by device ID
+    errors: BTreeMap<String, u64>,
+    /// Devices whose last fetch failed
+    failing: BTreeSet<String>,
+    auth_required: bool,
+}
+
+pub type SharedHealth = Arc<Mutex<Health>>;
+
+#[derive(Debug, PartialEq)]
+pub enum Status {
+    Ok,
+    /// Some devices fail to fetch
+    Degraded,
+    /// Nothing was fetched recently, or the auth key was rejected
+    Down,
+}
+
+impl Health {
+    pub fn record(&mut self, device_id: &str, success: bool, now: DateTime<Utc>) {
+        if success {
+            self.last_success = Some(now);
+            self.failing.remove(device_id);
+        } else {
+            *self.errors.entry(device_id.to_string()).or_default() += 1;
+            self.failing.insert(device_id.to_string());
+        }
+    }
+
+    pub fn set_auth_required(&mut self, auth_required: bool) {
+        self.auth_required = auth_required;
+    }
+
+    /// Overall status, down when the last successful fetch is older than `max_age` seconds.
+    pub fn status(&self, now: DateTime<Utc>, max_age: u64) -> Status {
+        let fresh = self
+            .last_success
+            .is_some_and(|last| (now - last).num_seconds() <= max_age as i64);
+        if self.auth_required || !fresh {
+            Status::Down
+        } else if !self.failing.is_empty() {
+            Status::Degraded
+        } else {
+            Status::Ok
+        }
+    }
+
+    pub fn report(&self, now: DateTime<Utc>, max_age: u64) -> Value {
+        let status = match self.status(now, max_age) {
+            Status::Ok => "ok",
+            Status::Degraded => "degraded",
+            Status::Down => "down",
+        };
+        json!({
+            "status": status,
+            "last_success": self.last_success,
+            "auth_required": self.auth_required,
+            "errors": self.errors,
+            "failing": self.failing,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_status() {
+        let now = Utc::now();
+        let mut health = Health::default();
+        assert_eq!(health.status(now, 60), Status::Down);
+
+        health.record("12345", true, now);
+        health.record("67890", true, now);
+        assert_eq!(health.status(now, 60), Status::Ok);
+
+        health.record("67890", false, now);
+        assert_eq!(health.status(now, 60), Status::Degraded);
+        assert_eq!(
+            health.status(now + chrono::Duration::seconds(61), 60),
+            Status::Down
+        );
+
+        health.record("67890", true, now);
+        health.set_auth_required(true);
+        assert_eq!(health.status(now, 60), Status::Down);
+    }
+
+    #[test]
+    fn test_report() {
+        let now = DateTime::from_timestamp(1705314030, 0).unwrap();
+        let mut health = Health::default();
+        health.record("12345", true, now);
+        health.record("67890", false, now);
+        health.record("67890", false, now);
+
+        assert_eq!(
+            health.report(now, 60),
+            json!({
+                "status": "degraded",
+                "last_success": "2024-01-15T10:20:30Z",
+                "auth_required": false,
+                "errors": { "67890": 2 },
+                "failing": ["67890"],
+            })
+        );
+    }
+}
@@ -0,0 +1,212 @@
+use crate::auth::Secret;
+use crate::{gen1, FetchError};
+use reqwest::Client;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+
+/// Home Assistant instance whose entities stand in for the devices, e.g. those of its
+/// Shelly integration.
+#[derive(Debug, Clone)]
+pub struct HomeAssistant {
+    /// Base URL, e.g. http://homeassistant.local:8123
+    pub url: String,
+    /// Long-lived access token
+    pub token: Secret,
+}
+
+impl HomeAssistant {
+    /// Fetches every entity state in one request and assembles the status of each device
+    /// from its entities, a device being the common prefix of their object IDs, e.g.
+    /// `shellyht_ab12cd` for `sensor.shellyht_ab12cd_temperature`.
+    pub async fn fetch_statuses(
+        &self,
+        client: &Client,
+        device_ids: &[&str],
+    ) -> HashMap<String, Result<Value, FetchError>> {
+        let states = match self.fetch_states(client).await {
+            Ok(states) => states,
+            Err(err) => {
+                eprintln!(
+                    "Error: Unable to fetch the Home Assistant states - {}",
+                    self.token.redact(&err.to_string())
+                );
+                return device_ids
+                    .iter()
+                    .map(|device_id| (device_id.to_string(), Err(FetchError::Failed)))
+                    .collect();
+            }
+        };
+        device_ids
+            .iter()
+            .map(|device_id| {
+                let status = device_status(&states, device_id).ok_or_else(|| {
+                    eprintln!("Error: No Home Assistant entity of device {}", device_id);
+                    FetchError::Failed
+                });
+                (device_id.to_string(), status)
+            })
+            .collect()
+    }
+
+    async fn fetch_states(&self, client: &Client) -> Result<Vec<Value>, reqwest::Error> {
+        client
+            .get(format!("{}/api/states", self.url.trim_end_matches('/')))
+            .bearer_auth(self.token.expose())
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await
+    }
+}
+
+// Status of a device in the Gen1 shape, normalized like those of the MQTT source
+fn device_status(states: &[Value], device_id: &str) -> Option<Value> {
+    let prefix = format!("{}_", device_id);
+    let mut status = json!({});
+    let mut found = false;
+    for state in states {
+        let Some((domain, object_id)) = state["entity_id"]
+            .as_str()
+            .and_then(|entity_id| entity_id.split_once('.'))
+        else {
+            continue;
+        };
+        if object_id == device_id || object_id.starts_with(&prefix) {
+            found = true;
+            // Unavailable entities have no reading
+            apply_entity(&mut status, domain, state);
+        }
+    }
+    found.then(|| gen1::normalize(status))
+}
+
+// Stores the state of an entity under the key of the Gen1 HTTP status, by its device class
+fn apply_entity(status: &mut Value, domain: &str, state: &Value) -> Option<()> {
+    let value = state["state"].as_str()?;
+    let attributes = &state["attributes"];
+    let unit = attributes["unit_of_measurement"].as_str();
+    let number = || value.parse::<f64>().ok();
+    match (domain, attributes["device_class"].as_str()) {
+        ("switch" | "light", _) => {
+            let relay = status["relays"].as_array().map_or(0, Vec::len);
+            *gen1::element(status, "relays", relay)? = json!({ "ison": value == "on" });
+        }
+        ("sensor", Some("temperature")) => {
+            let units = if unit == Some("°F") { "F" } else { "C" };
+            status["tmp"] = json!({ "value": number()?, "units": units });
+        }
+        ("sensor", Some("humidity")) => status["hum"] = json!({ "value": number()? }),
+        ("sensor", Some("battery")) => status["bat"] = json!({ "value": number()?.round() as i64 }),
+        ("sensor", Some("illuminance")) => status["lux"] = json!({ "value": number()? }),
+        ("sensor", Some("power")) => {
+            gen1::element(status, "meters", 0)?["power"] = json!(number()?)
+        }
+        ("sensor", Some("energy")) => {
+            let scale = if unit == Some("kWh") { 1000.0 } else { 1.0 };
+            gen1::element(status, "meters", 0)?["total"] = json!(number()? * scale);
+        }
+        ("sensor", Some("voltage")) => status["voltage"] = json!(number()?),
+        ("binary_sensor", Some("door" | "window" | "opening")) => {
+            status["sensor"]["state"] = json!(if value == "on" { "open" } else { "close" })
+        }
+        ("binary_sensor", Some("motion" | "occupancy")) => {
+            status["sensor"]["motion"] = json!(value == "on")
+        }
+        _ => return None,
+    }
+    Some(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use httpmock::MockServer;
+
+    fn entity(entity_id: &str, state: &str, attributes: Value) -> Value {
+        json!({ "entity_id": entity_id, "state": state, "attributes": attributes })
+    }
+
+    #[test]
+    fn test_device_status() {
+        let states = [
+            entity(
+                "sensor.shellyht_ab12cd_temperature",
+                "70.7",
+                json!({ "device_class": "temperature", "unit_of_measurement": "°F" }),
+            ),
+            entity(
+                "sensor.shellyht_ab12cd_humidity",
+                "48",
+                json!({ "device_class": "humidity" }),
+            ),
+            entity(
+                "sensor.shellyht_ab12cd_battery",
+                "87",
+                json!({ "device_class": "battery" }),
+            ),
+            entity("switch.shellyplug_s_1234", "on", json!({})),
+            entity(
+                "sensor.shellyplug_s_1234_power",
+                "42.5",
+                json!({ "device_class": "power" }),
+            ),
+            entity(
+                "binary_sensor.shellydw2_5678_door",
+                "on",
+                json!({ "device_class": "door" }),
+            ),
+        ];
+
+        let ht = device_status(&states, "shellyht_ab12cd").unwrap();
+        assert_eq!(ht["temperature:0"]["tF"], 70.7);
+        assert_eq!(ht["humidity:0"]["rh"], 48.0);
+        assert_eq!(ht["devicepower:0"]["battery"]["percent"], 87);
+
+        let plug = device_status(&states, "shellyplug_s_1234").unwrap();
+        assert_eq!(plug["switch:0"]["output"], true);
+        assert_eq!(plug["switch:0"]["apower"], 42.5);
+
+        let door = device_status(&states, "shellydw2_5678").unwrap();
+        assert_eq!(door["window:0"]["open"], true);
+
+        assert_eq!(device_status(&states, "shellyplus1"), None);
+    }
+
+    #[tokio::test]
+    async fn test_fetch_statuses() {
+        let server = MockServer::start_async().await;
+        server.mock(|when, then| {
+            when.method("GET")
+                .path("/api/states")
+                .header("Authorization", "Bearer token");
+            then.status(200).json_body(json!([entity(
+                "switch.shellyplug_s_1234",
+                "off",
+                json!({})
+            )]));
+        });
+
+        let home_assistant = HomeAssistant {
+            url: server.base_url(),
+            token: Secret::new("token"),
+        };
+        let statuses = home_assistant
+            .fetch_statuses(&Client::new(), &["shellyplug_s_1234", "unknown"])
+            .await;
+        assert_eq!(
+            statuses["shellyplug_s_1234"].as_ref().unwrap()["switch:0"]["output"],
+            false
+        );
+        assert_eq!(statuses["unknown"], Err(FetchError::Failed));
+
+        let home_assistant = HomeAssistant {
+            token: Secret::new("wrong"),
+            ..home_assistant
+        };
+        let statuses = home_assistant
+            .fetch_statuses(&Client::new(), &["shellyplug_s_1234"])
+            .await;
+        assert_eq!(statuses["shellyplug_s_1234"], Err(FetchError::Failed));
+    }
+}
@@ -0,0 +1,139 @@
+use crate::Reading;
+use chrono::{DateTime, Utc};
+use serde_json::{json, Value};
+use std::process::Stdio;
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+
+/// Change of a tracked state, i.e. a non-numeric field such as a door state or a relay output.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StateChange {
+    pub field: String,
+    pub previous: Value,
+    pub current: Value,
+}
+
+/// Lists the tracked states that differ between two readings of a device.
+pub fn changes(previous: &Reading, current: &Reading) -> Vec<StateChange> {
+    current
+        .samples
+        .iter()
+        .filter(|sample| !sample.value.is_number())
+        .filter_map(|sample| {
+            let old = previous.samples.iter().find(|old| old.key == sample.key)?;
+            (old.value != sample.value).then(|| StateChange {
+                field: sample.key.clone(),
+                previous: old.value.clone(),
+                current: sample.value.clone(),
+            })
+        })
+        .collect()
+}
+
+/// JSON event written to the hook command.
+pub fn event(
+    device_id: &str,
+    device_name: Option<&str>,
+    change: &StateChange,
+    timestamp: DateTime<Utc>,
+) -> Value {
+    json!({
+        "device_id": device_id,
+        "name": device_name,
+        "field": change.field,
+        "previous": change.previous,
+        "current": change.current,
+        "timestamp": timestamp,
+    })
+}
+
+/// Runs a hook command through `sh -c` with the event on stdin, without waiting for it.
+pub fn run(command: &str, event: &Value) {
+    let child = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .stdin(Stdio::piped())
+        .spawn();
+    let mut child = match child {
+        Ok(child) => child,
+        Err(err) => {
+            eprintln!("Error: Unable to run hook '{}' - {}", command, err);
+            return;
+        }
+    };
+
+    let command = command.to_string();
+    let payload = event.to_string();
+    tokio::spawn(async move {
+        if let Some(mut stdin) = child.stdin.take() {
+            let _ = stdin.write_all(payload.as_bytes()).await;
+        }
+        match child.wait().await {
+            Ok(status) if !status.success() => {
+                eprintln!("Error: Hook '{}' exited with {}", command, status)
+            }
+            Err(err) => eprintln!("Error: Hook '{}' failed - {}", command, err),
+            _ => {}
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fields::Sample;
+
+    fn reading(samples: &[(&str, Value)]) -> Reading {
+        Reading {
+            output: json!({ "text": "", "tooltip": "" }),
+            updated_at: Utc::now(),
+            short_text: None,
+            device_type: String::new(),
+            samples: samples
+                .iter()
+                .map(|(key, value)| Sample {
+                    key: key.to_string(),
+                    value: value.clone(),
+                    unit: String::new(),
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn test_changes() {
+        let previous = reading(&[("state", json!("closed")), ("lux", json!(10))]);
+        let current = reading(&[("state", json!("open")), ("lux", json!(20))]);
+
+        assert_eq!(
+            changes(&previous, &current),
+            vec![StateChange {
+                field: "state".to_string(),
+                previous: json!("closed"),
+                current: json!("open"),
+            }]
+        );
+        assert!(changes(&current, &current).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_run() {
+        let path = std::env::temp_dir().join(format!("shelly-waybar-hook-{}", std::process::id()));
+        run(
+            &format!("cat > {}", path.display()),
+            &json!({ "field": "state" }),
+        );
+
+        for _ in 0..50 {
+            if let Ok(content) = std::fs::read_to_string(&path) {
+                if !content.is_empty() {
+                    assert_eq!(content, r#"{"field":"state"}"#);
+                    std::fs::remove_file(&path).unwrap();
+                    return;
+                }
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+        }
+        panic!("hook did not run");
+    }
+}
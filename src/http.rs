@@ -0,0 +1,237 @@
+use crate::health::{SharedHealth, Status};
+use chrono::Utc;
+use hyper::header::CONTENT_TYPE;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Request, Response, Server, StatusCode};
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+
+/// Call of a device action URL, e.g. `/webhook/12345?event=open`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Webhook {
+    pub device_id: String,
+    pub event: Option<String>,
+}
+
+/// State the listener answers from.
+#[derive(Clone)]
+struct Context {
+    webhooks: UnboundedSender<Webhook>,
+    /// Shared token webhook calls must carry as `token=`, when set
+    token: Option<String>,
+    health: SharedHealth,
+    /// Age in seconds of the last successful fetch after which `/healthz` reports down
+    max_age: u64,
+}
+
+/// Starts the HTTP listener serving `/healthz` and returns the webhook calls it receives.
+pub fn start(
+    addr: SocketAddr,
+    token: Option<String>,
+    health: SharedHealth,
+    max_age: u64,
+) -> Result<UnboundedReceiver<Webhook>, Box<dyn std::error::Error>> {
+    // Webhooks trigger refreshes and notifications with any text, so only local callers go
+    // without a token
+    if token.is_none() && !addr.ip().is_loopback() {
+        return Err(format!("--listen on {} requires --listen-token", addr).into());
+    }
+    let (webhooks, receiver) = mpsc::unbounded_channel();
+    let context = Context {
+        webhooks,
+        token,
+        health,
+        max_age,
+    };
+    let server = Server::try_bind(&addr)?.serve(make_service_fn(move |_| {
+        let context = context.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req| {
+                let response = route(&req, &context);
+                async move { Ok::<_, Infallible>(response) }
+            }))
+        }
+    }));
+
+    tokio::spawn(async move {
+        if let Err(err) = server.await {
+            eprintln!("Error: HTTP listener stopped - {}", err);
+        }
+    });
+    Ok(receiver)
+}
+
+fn route(req: &Request<Body>, context: &Context) -> Response<Body> {
+    if req.uri().path() == "/healthz" {
+        return healthz(context);
+    }
+    match parse_webhook(req) {
+        Some((_, token)) if context.token.is_some() && token != context.token => {
+            respond(StatusCode::FORBIDDEN, "forbidden")
+        }
+        Some((webhook, _)) => {
+            let _ = context.webhooks.send(webhook);
+            respond(StatusCode::OK, "ok")
+        }
+        None => respond(StatusCode::NOT_FOUND, "not found"),
+    }
+}
+
+fn healthz(context: &Context) -> Response<Body> {
+    let now = Utc::now();
+    let health = context.health.lock().unwrap_or_else(|err| err.into_inner());
+    let status = match health.status(now, context.max_age) {
+        Status::Down => StatusCode::SERVICE_UNAVAILABLE,
+        _ => StatusCode::OK,
+    };
+    let mut response = respond(status, health.report(now, context.max_age).to_string());
+    response
+        .headers_mut()
+        .insert(CONTENT_TYPE, "application/json".parse().unwrap());
+    response
+}
+
+fn respond(status: StatusCode, body: impl Into<Body>) -> Response<Body> {
+    let mut response = Response::new(body.into());
+    *response.status_mut() = status;
+    response
+}
+
+// Shelly action URLs are plain GET requests, so the event and the token come in the query
+// string
+fn parse_webhook(req: &Request<Body>) -> Option<(Webhook, Option<String>)> {
+    let device_id = req.uri().path().strip_prefix("/webhook/")?;
+    if device_id.is_empty() || device_id.contains('/') {
+        return None;
+    }
+    let mut event = None;
+    let mut token = None;
+    let query = req.uri().query().unwrap_or_default();
+    for (key, value) in form_urlencoded::parse(query.as_bytes()) {
+        match key.as_ref() {
+            "event" => event = Some(value.into_owned()),
+            "token" => token = Some(value.into_owned()),
+            _ => {}
+        }
+    }
+
+    let webhook = Webhook {
+        device_id: device_id.to_string(),
+        event,
+    };
+    Some((webhook, token))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(uri: &str) -> Request<Body> {
+        Request::get(uri).body(Body::empty()).unwrap()
+    }
+
+    #[test]
+    fn test_parse_webhook() {
+        assert_eq!(
+            parse_webhook(&request("/webhook/12345?event=open")),
+            Some((
+                Webhook {
+                    device_id: "12345".to_string(),
+                    event: Some("open".to_string()),
+                },
+                None
+            ))
+        );
+        assert_eq!(
+            parse_webhook(&request("/webhook/12345")),
+            Some((
+                Webhook {
+                    device_id: "12345".to_string(),
+                    event: None,
+                },
+                None
+            ))
+        );
+        assert_eq!(
+            parse_webhook(&request("/webhook/12345?token=s3cr%2Bt&event=door%20open")),
+            Some((
+                Webhook {
+                    device_id: "12345".to_string(),
+                    event: Some("door open".to_string()),
+                },
+                Some("s3cr+t".to_string())
+            ))
+        );
+        assert_eq!(parse_webhook(&request("/webhook/")), None);
+        assert_eq!(parse_webhook(&request("/other/12345")), None);
+    }
+
+    fn context() -> (Context, UnboundedReceiver<Webhook>) {
+        let (webhooks, receiver) = mpsc::unbounded_channel();
+        let context = Context {
+            webhooks,
+            token: None,
+            health: SharedHealth::default(),
+            max_age: 60,
+        };
+        (context, receiver)
+    }
+
+    #[test]
+    fn test_route() {
+        let (context, mut receiver) = context();
+
+        let response = route(&request("/webhook/12345?event=overpower"), &context);
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            receiver.try_recv().unwrap().event.as_deref(),
+            Some("overpower")
+        );
+
+        let response = route(&request("/"), &context);
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert!(receiver.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_route_token() {
+        let (mut context, mut receiver) = context();
+        context.token = Some("s3cret".to_string());
+
+        let response = route(&request("/webhook/12345?event=open"), &context);
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        let response = route(&request("/webhook/12345?event=open&token=wrong"), &context);
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        assert!(receiver.try_recv().is_err());
+
+        let response = route(&request("/webhook/12345?event=open&token=s3cret"), &context);
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(receiver.try_recv().unwrap().device_id, "12345");
+    }
+
+    #[tokio::test]
+    async fn test_start_requires_token_off_loopback() {
+        let addr = "0.0.0.0:0".parse().unwrap();
+        assert!(start(addr, None, SharedHealth::default(), 60).is_err());
+        let addr = "127.0.0.1:0".parse().unwrap();
+        assert!(start(addr, None, SharedHealth::default(), 60).is_ok());
+    }
+
+    #[test]
+    fn test_healthz() {
+        let (context, _receiver) = context();
+
+        let response = route(&request("/healthz"), &context);
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+
+        context
+            .health
+            .lock()
+            .unwrap()
+            .record("12345", true, Utc::now());
+        let response = route(&request("/healthz"), &context);
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[CONTENT_TYPE], "application/json");
+    }
+}
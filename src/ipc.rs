@@ -0,0 +1,147 @@
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use std::{env, fs, io};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::watch;
+
+/// $XDG_RUNTIME_DIR/shelly-waybar.sock, falling back to the temporary directory
+pub fn default_socket_path() -> PathBuf {
+    env::var_os("XDG_RUNTIME_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_else(env::temp_dir)
+        .join("shelly-waybar.sock")
+}
+
+/// Serves the latest rendered output to `shelly-waybar client` instances over a
+/// Unix socket: each client gets the current line on connect, then every new one.
+pub struct IpcServer {
+    latest: watch::Sender<Option<String>>,
+}
+
+impl IpcServer {
+    pub fn start(path: &Path) -> io::Result<Self> {
+        if is_served(path) {
+            return Err(io::Error::new(
+                io::ErrorKind::AddrInUse,
+                format!("{} is already served by another daemon", path.display()),
+            ));
+        }
+        // A previous daemon may have left its socket behind
+        let _ = fs::remove_file(path);
+        let listener = UnixListener::bind(path)?;
+        fs::set_permissions(path, fs::Permissions::from_mode(0o600))?;
+
+        let (latest, _) = watch::channel(None);
+        let sender = latest.clone();
+        tokio::spawn(async move {
+            loop {
+                match listener.accept().await {
+                    Ok((stream, _)) => {
+                        tokio::spawn(serve_client(stream, sender.subscribe()));
+                    }
+                    Err(err) => eprintln!("Error: Unable to accept IPC client - {}", err),
+                }
+            }
+        });
+
+        Ok(IpcServer { latest })
+    }
+
+    pub fn publish(&self, line: String) {
+        self.latest.send_replace(Some(line));
+    }
+}
+
+async fn serve_client(mut stream: UnixStream, mut latest: watch::Receiver<Option<String>>) {
+    loop {
+        let line = latest.borrow_and_update().clone();
+        if let Some(line) = line {
+            if stream
+                .write_all(format!("{}\n", line).as_bytes())
+                .await
+                .is_err()
+            {
+                return;
+            }
+        }
+        if latest.changed().await.is_err() {
+            return;
+        }
+    }
+}
+
+// Whether a process is listening on the socket
+fn is_served(path: &Path) -> bool {
+    std::os::unix::net::UnixStream::connect(path).is_ok()
+}
+
+/// Prints every line served by the daemon, reconnecting when it goes away.
+pub async fn run_client(path: &Path) -> io::Result<()> {
+    loop {
+        match UnixStream::connect(path).await {
+            Ok(stream) => {
+                let mut lines = BufReader::new(stream).lines();
+                loop {
+                    match lines.next_line().await {
+                        Ok(Some(line)) => println!("{}", line),
+                        Ok(None) => break,
+                        // e.g. a reset connection, retried like a failed connect
+                        Err(err) => {
+                            eprintln!("Error: Unable to read from {} - {}", path.display(), err);
+                            break;
+                        }
+                    }
+                }
+            }
+            Err(err) => eprintln!("Error: Unable to connect to {} - {}", path.display(), err),
+        }
+        tokio::time::sleep(Duration::from_secs(2)).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_serves_latest_line() {
+        let path = env::temp_dir().join(format!("shelly-waybar-test-{}.sock", std::process::id()));
+        let server = IpcServer::start(&path).unwrap();
+        server.publish("first".to_string());
+        server.publish("second".to_string());
+
+        let stream = UnixStream::connect(&path).await.unwrap();
+        let mut lines = BufReader::new(stream).lines();
+        assert_eq!(lines.next_line().await.unwrap().unwrap(), "second");
+
+        server.publish("third".to_string());
+        assert_eq!(lines.next_line().await.unwrap().unwrap(), "third");
+
+        assert!(IpcServer::start(&path).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_client_reconnects_after_read_error() {
+        let path = env::temp_dir().join(format!(
+            "shelly-waybar-test-client-{}.sock",
+            std::process::id()
+        ));
+        let listener = UnixListener::bind(&path).unwrap();
+        let client_path = path.clone();
+        let client = tokio::spawn(async move { run_client(&client_path).await });
+
+        // A line that is not UTF-8 fails the read
+        let (mut stream, _) = listener.accept().await.unwrap();
+        stream.write_all(b"\xff\n").await.unwrap();
+
+        // The client connects again rather than exiting
+        let reconnected = tokio::time::timeout(Duration::from_secs(5), listener.accept()).await;
+        assert!(reconnected.is_ok());
+        assert!(!client.is_finished());
+        client.abort();
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,84 @@
+use crate::auth::Secret;
+#[cfg(feature = "keyring")]
+use std::collections::HashMap;
+
+#[cfg(feature = "keyring")]
+const SERVICE: &str = "shelly-waybar";
+
+/// Account of the password of a device on the LAN, e.g. `password_shellyplug-s-1234`.
+pub fn password_account(device_id: &str) -> String {
+    format!("password_{}", device_id)
+}
+
+// Attributes identifying a shelly-waybar credential, e.g. `auth_key`
+#[cfg(feature = "keyring")]
+fn attributes(account: &str) -> HashMap<&str, &str> {
+    HashMap::from([("service", SERVICE), ("account", account)])
+}
+
+/// Looks up a credential in the freedesktop Secret Service, unlocking it if needed.
+#[cfg(feature = "keyring")]
+pub async fn lookup(account: &str) -> Option<Secret> {
+    use secret_service::{EncryptionType, SecretService};
+
+    let service = SecretService::connect(EncryptionType::Dh).await.ok()?;
+    let items = service.search_items(attributes(account)).await.ok()?;
+    let item = match items.unlocked.into_iter().next() {
+        Some(item) => item,
+        None => {
+            let item = items.locked.into_iter().next()?;
+            item.unlock().await.ok()?;
+            item
+        }
+    };
+    let secret = item.get_secret().await.ok()?;
+    String::from_utf8(secret).ok().map(Secret::new)
+}
+
+#[cfg(not(feature = "keyring"))]
+pub async fn lookup(_account: &str) -> Option<Secret> {
+    None
+}
+
+/// Stores a credential in the default Secret Service collection, replacing any previous one.
+#[cfg(feature = "keyring")]
+pub async fn store(account: &str, secret: &Secret) -> Result<(), Box<dyn std::error::Error>> {
+    use secret_service::{EncryptionType, SecretService};
+
+    let service = SecretService::connect(EncryptionType::Dh).await?;
+    let collection = service.get_default_collection().await?;
+    collection.ensure_unlocked().await?;
+    collection
+        .create_item(
+            &format!("{} {}", SERVICE, account),
+            attributes(account),
+            secret.expose().as_bytes(),
+            true,
+            "text/plain",
+        )
+        .await?;
+    Ok(())
+}
+
+#[cfg(not(feature = "keyring"))]
+pub async fn store(_account: &str, _secret: &Secret) -> Result<(), Box<dyn std::error::Error>> {
+    Err("shelly-waybar was built without keyring support".into())
+}
+
+#[cfg(all(test, feature = "keyring"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_attributes() {
+        let attributes = attributes("auth_key");
+        assert_eq!(attributes["service"], "shelly-waybar");
+        assert_eq!(attributes["account"], "auth_key");
+
+        let account = password_account("shellyplug-s-1234");
+        assert_eq!(
+            super::attributes(&account)["account"],
+            "password_shellyplug-s-1234"
+        );
+    }
+}
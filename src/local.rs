@@ -0,0 +1,318 @@
+use crate::auth::Secret;
+use crate::{gen1, FetchError};
+use futures::stream::{self, StreamExt};
+use reqwest::{Client, StatusCode, Url};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::io;
+use std::time::Duration;
+use tokio::net::UdpSocket;
+
+/// RPC over UDP of Gen2 devices, on the port set in their `sys.rpc_udp.listen_port` config.
+#[derive(Debug, Clone, Copy)]
+pub struct UdpRpc {
+    pub port: u16,
+    pub timeout: Duration,
+}
+
+/// HTTP basic auth of a protected Gen1 device.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Credentials {
+    pub username: String,
+    pub password: Secret,
+}
+
+/// Device polled on the LAN.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LocalDevice<'a> {
+    pub device_id: &'a str,
+    pub host: &'a str,
+    pub credentials: Option<Credentials>,
+}
+
+/// Fetches the status of devices on the LAN with up to `concurrency` requests in flight.
+/// With `udp`, devices are asked over UDP first, falling back to HTTP for those not
+/// answering, such as Gen1 ones.
+pub async fn fetch_statuses(
+    client: &Client,
+    devices: &[LocalDevice<'_>],
+    concurrency: usize,
+    udp: Option<UdpRpc>,
+) -> HashMap<String, Result<Value, FetchError>> {
+    stream::iter(devices)
+        .map(|device| async move {
+            let device_id = device.device_id.to_string();
+            if let Some(udp) = udp {
+                if let Ok(status) = fetch_status_udp(device.host, udp).await {
+                    return (device_id, Ok(status));
+                }
+            }
+            let status = fetch_status(client, device.host, device.credentials.as_ref())
+                .await
+                .map_err(|err| {
+                    let err = match &device.credentials {
+                        Some(credentials) => credentials.password.redact(&err.to_string()),
+                        None => err.to_string(),
+                    };
+                    eprintln!(
+                        "Error: Unable to fetch the status of {} - {}",
+                        device.host, err
+                    );
+                    FetchError::Failed
+                });
+            (device_id, status)
+        })
+        .buffer_unordered(concurrency.max(1))
+        .collect()
+        .await
+}
+
+/// Fetches the status of a device from its own HTTP API, with the Gen2 RPC or else the
+/// Gen1 `/status` endpoint.
+pub async fn fetch_status(
+    client: &Client,
+    host: &str,
+    credentials: Option<&Credentials>,
+) -> Result<Value, reqwest::Error> {
+    let response = get(client, host, credentials, "/rpc/Shelly.GetStatus")
+        .send()
+        .await?;
+    // Gen1 devices have no RPC API
+    let response = if response.status() == StatusCode::NOT_FOUND {
+        get(client, host, credentials, "/status").send().await?
+    } else {
+        response
+    };
+    let status = response.error_for_status()?.json().await?;
+    Ok(gen1::normalize(status))
+}
+
+/// Switches a relay channel of a device on the LAN to the opposite of its current state and
+/// returns the new state, with the Gen2 `Switch.Toggle` RPC or else the Gen1 relay endpoint.
+pub async fn toggle(
+    client: &Client,
+    host: &str,
+    credentials: Option<&Credentials>,
+    channel: usize,
+) -> Result<bool, reqwest::Error> {
+    let path = format!("/rpc/Switch.Toggle?id={}", channel);
+    let response = get(client, host, credentials, &path).send().await?;
+    if response.status() != StatusCode::NOT_FOUND {
+        let result: Value = response.error_for_status()?.json().await?;
+        return Ok(result["was_on"] == false);
+    }
+    let path = format!("/relay/{}?turn=toggle", channel);
+    let response = get(client, host, credentials, &path).send().await?;
+    let relay: Value = response.error_for_status()?.json().await?;
+    Ok(relay["ison"] == true)
+}
+
+// Protected Gen1 devices answer 401 to any path without the credentials
+fn get(
+    client: &Client,
+    host: &str,
+    credentials: Option<&Credentials>,
+    path: &str,
+) -> reqwest::RequestBuilder {
+    let request = client.get(format!("{}{}", base_url(host), path));
+    match credentials {
+        Some(credentials) => {
+            request.basic_auth(&credentials.username, Some(credentials.password.expose()))
+        }
+        None => request,
+    }
+}
+
+/// Fetches the status of a Gen2 device with a `Shelly.GetStatus` RPC over UDP.
+pub async fn fetch_status_udp(host: &str, udp: UdpRpc) -> io::Result<Value> {
+    let url = Url::parse(&base_url(host)).map_err(|err| io::Error::other(err.to_string()))?;
+    let address = url.host_str().ok_or_else(|| io::Error::other("no host"))?;
+    let socket = UdpSocket::bind("0.0.0.0:0").await?;
+    socket.connect((address, udp.port)).await?;
+    let request = json!({ "id": 1, "src": "shelly-waybar", "method": "Shelly.GetStatus" });
+    socket.send(request.to_string().as_bytes()).await?;
+
+    let mut buffer = vec![0; 65536];
+    let length = tokio::time::timeout(udp.timeout, socket.recv(&mut buffer))
+        .await
+        .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))??;
+    let mut response: Value = serde_json::from_slice(&buffer[..length])?;
+    match response.get_mut("result") {
+        Some(result) => Ok(result.take()),
+        None => Err(io::Error::other(format!("RPC error {}", response["error"]))),
+    }
+}
+
+// `192.168.1.20` or `shelly-plug.lan:8080`, or a full URL
+fn base_url(host: &str) -> String {
+    if host.starts_with("http://") || host.starts_with("https://") {
+        host.trim_end_matches('/').to_string()
+    } else {
+        format!("http://{}", host)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use httpmock::MockServer;
+    use serde_json::json;
+
+    fn local_device<'a>(device_id: &'a str, host: &'a str) -> LocalDevice<'a> {
+        LocalDevice {
+            device_id,
+            host,
+            credentials: None,
+        }
+    }
+
+    #[test]
+    fn test_base_url() {
+        assert_eq!(base_url("192.168.1.20"), "http://192.168.1.20");
+        assert_eq!(base_url("https://plug.lan/"), "https://plug.lan");
+    }
+
+    #[tokio::test]
+    async fn test_fetch_status() {
+        let gen2 = MockServer::start_async().await;
+        gen2.mock(|when, then| {
+            when.method("GET").path("/rpc/Shelly.GetStatus");
+            then.status(200)
+                .json_body(json!({ "switch:0": { "apower": 50.0 } }));
+        });
+        let gen1 = MockServer::start_async().await;
+        gen1.mock(|when, then| {
+            when.method("GET").path("/status");
+            then.status(200)
+                .json_body(json!({ "relays": [{ "ison": true }], "meters": [{ "power": 5.0 }] }));
+        });
+
+        let client = Client::new();
+        let status = fetch_status(&client, &gen2.base_url(), None).await.unwrap();
+        assert_eq!(status["switch:0"]["apower"], 50.0);
+        let status = fetch_status(&client, &gen1.base_url(), None).await.unwrap();
+        assert_eq!(status["switch:0"]["output"], true);
+
+        let statuses = fetch_statuses(
+            &client,
+            &[
+                local_device("plug", &gen2.base_url()),
+                local_device("down", "127.0.0.1:1"),
+            ],
+            2,
+            None,
+        )
+        .await;
+        assert!(statuses["plug"].is_ok());
+        assert_eq!(statuses["down"], Err(FetchError::Failed));
+    }
+
+    #[tokio::test]
+    async fn test_fetch_status_basic_auth() {
+        let gen1 = MockServer::start_async().await;
+        gen1.mock(|when, then| {
+            // admin:secret
+            when.method("GET")
+                .path("/status")
+                .header("authorization", "Basic YWRtaW46c2VjcmV0");
+            then.status(200)
+                .json_body(json!({ "relays": [{ "ison": true }] }));
+        });
+        gen1.mock(|when, then| {
+            when.method("GET").path("/status");
+            then.status(401);
+        });
+
+        let client = Client::new();
+        let credentials = Credentials {
+            username: "admin".to_string(),
+            password: Secret::new("secret"),
+        };
+        let status = fetch_status(&client, &gen1.base_url(), Some(&credentials))
+            .await
+            .unwrap();
+        assert_eq!(status["switch:0"]["output"], true);
+        assert!(fetch_status(&client, &gen1.base_url(), None).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_toggle() {
+        let gen2 = MockServer::start_async().await;
+        let rpc = gen2.mock(|when, then| {
+            when.method("GET")
+                .path("/rpc/Switch.Toggle")
+                .query_param("id", "1");
+            then.status(200).json_body(json!({ "was_on": true }));
+        });
+        let gen1 = MockServer::start_async().await;
+        gen1.mock(|when, then| {
+            when.method("GET").path("/rpc/Switch.Toggle");
+            then.status(404);
+        });
+        let relay = gen1.mock(|when, then| {
+            when.method("GET")
+                .path("/relay/0")
+                .query_param("turn", "toggle");
+            then.status(200).json_body(json!({ "ison": true }));
+        });
+
+        let client = Client::new();
+        assert_eq!(
+            toggle(&client, &gen2.base_url(), None, 1).await.ok(),
+            Some(false)
+        );
+        assert_eq!(
+            toggle(&client, &gen1.base_url(), None, 0).await.ok(),
+            Some(true)
+        );
+        rpc.assert();
+        relay.assert();
+    }
+
+    #[tokio::test]
+    async fn test_fetch_status_udp() {
+        let device = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let port = device.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let mut buffer = [0; 1024];
+            let (length, peer) = device.recv_from(&mut buffer).await.unwrap();
+            let request: Value = serde_json::from_slice(&buffer[..length]).unwrap();
+            assert_eq!(request["method"], "Shelly.GetStatus");
+            let response = json!({ "id": 1, "result": { "switch:0": { "apower": 7.5 } } });
+            device
+                .send_to(response.to_string().as_bytes(), peer)
+                .await
+                .unwrap();
+        });
+
+        let udp = UdpRpc {
+            port,
+            timeout: Duration::from_secs(5),
+        };
+        let status = fetch_status_udp("127.0.0.1", udp).await.unwrap();
+        assert_eq!(status["switch:0"]["apower"], 7.5);
+
+        // A device not listening is polled over HTTP instead
+        let gen1 = MockServer::start_async().await;
+        gen1.mock(|when, then| {
+            when.method("GET").path("/status");
+            then.status(200)
+                .json_body(json!({ "relays": [{ "ison": true }] }));
+        });
+        let udp = UdpRpc {
+            port,
+            timeout: Duration::from_millis(100),
+        };
+        let statuses = fetch_statuses(
+            &Client::new(),
+            &[local_device("gen1", &gen1.base_url())],
+            1,
+            Some(udp),
+        )
+        .await;
+        assert_eq!(
+            statuses["gen1"].as_ref().unwrap()["switch:0"]["output"],
+            true
+        );
+    }
+}
@@ -1,57 +1,689 @@
-use clap::{Parser, ValueEnum};
-use notify_rust::Notification;
-use reqwest::Client;
-use serde::Deserialize;
+use chrono::{DateTime, Local, NaiveDateTime, Utc};
+use chrono_tz::Tz;
+use clap::error::ErrorKind;
+use clap::{CommandFactory, Parser, Subcommand, ValueEnum};
+use futures::stream::{self, StreamExt};
+use reqwest::header::RETRY_AFTER;
+use reqwest::{Client, StatusCode};
+use serde::{Deserialize, Serialize};
 use serde_json::Value;
-use std::path::Path;
-use std::{collections::HashMap, thread, time::Duration};
-use std::{fs, io};
+use std::collections::{BTreeMap, HashMap};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use std::{env, fs, io};
 use strum_macros::{Display, EnumString};
+use tokio::sync::mpsc::{self, UnboundedReceiver};
+use tokio::time::Instant;
+
+#[cfg(not(any(feature = "native-tls", feature = "rustls")))]
+compile_error!("Enable a TLS backend: the native-tls (default) or rustls feature");
+
+mod auth;
+mod bars;
+mod bench;
+mod broker;
+mod coiot;
+mod config;
+mod control;
+mod dbus;
+mod discover;
+mod fields;
+mod gen1;
+mod health;
+mod homeassistant;
+mod hooks;
+mod http;
+mod ipc;
+mod keyring;
+mod local;
+mod mqtt;
+mod notify;
+mod outbound;
+mod output_dir;
+mod push;
+mod ratelimit;
+mod retry;
+mod schema;
+mod state;
+mod table;
+mod tray;
+mod tui;
+
+use auth::Secret;
+use bench::Bench;
+use broker::MqttSource;
+use coiot::CoiotListener;
+use config::{AdcConfig, BthomeKind, Config, CustomConfig, TypeConfig};
+use control::Action;
+use dbus::DbusService;
+use fields::{
+    apply_rounding, escape_markup, render_fields, render_fields_markup, strip_markup, Field, Icon,
+    IconSet, Metric, Sample, Slot, Style,
+};
+use health::SharedHealth;
+use homeassistant::HomeAssistant;
+use http::Webhook;
+use ipc::IpcServer;
+use local::{Credentials, LocalDevice, UdpRpc};
+use mqtt::MqttPublisher;
+use notify::{Notifier, Urgency};
+use outbound::OutboundServer;
+use output_dir::OutputDir;
+use ratelimit::RateLimiter;
+use retry::RetryPolicy;
+use state::StateStore;
+use tray::{TrayDevice, TrayIcon, TrayState};
+use tui::{Dashboard, DashboardDevice, DashboardState};
 
 #[derive(Parser, Debug)]
+#[command(subcommand_negates_reqs = true)]
 struct Args {
-    /// Path to a file containing the auth key for the Shelly API
-    #[arg(short, long, required = true, env = "SHELLY_AUTH_KEY")]
-    auth_key: String,
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// Path to a file containing the auth key for the Shelly API (defaults to the key stored by `login`)
+    #[arg(short, long, env = "SHELLY_AUTH_KEY", hide_env_values = true)]
+    auth_key: Option<Secret>,
+
+    /// Path to a TOML config file (defaults to $XDG_CONFIG_HOME/shelly-waybar/config.toml)
+    #[arg(short, long, env = "SHELLY_WAYBAR_CONFIG")]
+    config: Option<PathBuf>,
 
     /// List of devices in the format <device_type>:<device_id>:<device_name>
-    #[arg(short, long, required = true, num_args(1..))]
+    #[arg(short, long, global = true, num_args(1..))]
     devices: Vec<String>,
 
-    /// Base URL of the Shelly server
-    #[arg(
-        short,
-        long,
-        default_value = "https://shelly-001-eu.shelly.cloud",
-        env = "SHELLY_BASE_URL"
-    )]
-    base_url: String,
+    /// Save the auth key (from --auth-key or a prompt) in the Secret Service and exit
+    #[arg(long)]
+    store_key: bool,
+
+    /// Save the password of a device on the LAN (from a prompt) in the Secret Service and exit
+    #[arg(long, value_name = "DEVICE_ID")]
+    store_password: Option<String>,
+
+    /// File where door states and last readings are kept across restarts
+    #[arg(long, env = "SHELLY_WAYBAR_STATE_FILE")]
+    state_file: Option<PathBuf>,
+
+    /// Passphrase, or path to a file containing it, used to encrypt the state file
+    #[arg(long, env = "SHELLY_WAYBAR_STATE_PASSPHRASE", hide_env_values = true)]
+    state_passphrase: Option<Secret>,
+
+    /// Show a system tray icon listing the devices, with toggles when control is allowed
+    #[arg(long)]
+    tray: bool,
+
+    /// Publish device states on the session D-Bus as org.shelly_waybar.Devices, with an object
+    /// per device
+    #[arg(long)]
+    dbus: bool,
+
+    /// MQTT broker to publish readings to, as mqtt://[user[:password]@]host[:port]
+    #[arg(long, env = "SHELLY_WAYBAR_MQTT_URL", hide_env_values = true)]
+    mqtt_url: Option<String>,
+
+    /// MQTT broker to read the device statuses from instead of the cloud, as
+    /// mqtt://[user[:password]@]host[:port]; devices are given by their MQTT ID
+    #[arg(long, env = "SHELLY_WAYBAR_MQTT_SOURCE", hide_env_values = true)]
+    mqtt_source: Option<String>,
+
+    /// Home Assistant instance to read the device statuses from instead of the cloud, e.g.
+    /// http://homeassistant.local:8123; devices are given by the object ID prefix of their entities
+    #[arg(long, env = "SHELLY_WAYBAR_HA_URL", requires = "ha_token")]
+    ha_url: Option<String>,
+
+    /// Long-lived access token of Home Assistant (path to file or direct value)
+    #[arg(long, env = "SHELLY_WAYBAR_HA_TOKEN", hide_env_values = true)]
+    ha_token: Option<Secret>,
+
+    /// Topic prefix of Home Assistant MQTT discovery
+    #[arg(long, default_value = "homeassistant")]
+    mqtt_discovery_prefix: String,
+
+    /// Notification backend: freedesktop, command (see --notify-command) or none
+    #[arg(long, default_value = "freedesktop", value_enum)]
+    notifier: notify::Backend,
+
+    /// Command run by the command notifier, e.g. "notify-send -u {urgency}" or
+    /// "terminal-notifier -title {summary} -message {body}"
+    #[arg(long, env = "SHELLY_WAYBAR_NOTIFY_COMMAND")]
+    notify_command: Option<String>,
+
+    /// Serve the output to `shelly-waybar client` instances over the IPC socket
+    #[arg(long)]
+    serve: bool,
+
+    /// Directory where the output of each device is also written to its own `<name>.json` file,
+    /// or FIFO of that name, for one Waybar module per device
+    #[arg(long, env = "SHELLY_WAYBAR_OUTPUT_DIR")]
+    output_dir: Option<PathBuf>,
+
+    /// Check every payload, device output of --output-dir and jsonl record against its JSON
+    /// Schema, exiting on the first invalid one
+    #[arg(long)]
+    validate_output: bool,
+
+    /// Address of the HTTP listener receiving device action webhooks and serving /healthz, e.g. 0.0.0.0:8089
+    #[arg(long, env = "SHELLY_WAYBAR_LISTEN")]
+    listen: Option<SocketAddr>,
+
+    /// Token webhook calls must pass as `token=` in their query string, required to listen on
+    /// another address than loopback
+    #[arg(long, env = "SHELLY_WAYBAR_LISTEN_TOKEN")]
+    listen_token: Option<String>,
+
+    /// IPC socket path (defaults to $SHELLY_WAYBAR_SOCKET, then
+    /// $XDG_RUNTIME_DIR/shelly-waybar.sock); giving it serves the output there, as --serve does
+    #[arg(long, global = true)]
+    socket: Option<PathBuf>,
+
+    /// Enable commands that switch devices, such as `toggle`
+    #[arg(long, global = true)]
+    allow_control: bool,
+
+    /// PEM file of a CA certificate trusted by the HTTP client, e.g. of a local proxy
+    #[arg(long, global = true, env = "SHELLY_WAYBAR_CA_CERT")]
+    ca_cert: Option<PathBuf>,
+
+    /// Accept invalid TLS certificates, for debugging only
+    #[arg(long, global = true)]
+    insecure: bool,
+
+    /// Time in seconds after which an HTTP request is abandoned, failing that device only
+    #[arg(long, global = true, default_value_t = 10)]
+    timeout: u64,
+
+    /// Time in seconds to wait for the connection of an HTTP request
+    #[arg(long, global = true, default_value_t = 5)]
+    connect_timeout: u64,
+
+    /// Do not ask for gzip or brotli compressed responses
+    #[arg(long, global = true)]
+    no_compression: bool,
+
+    /// Time in seconds an idle connection is kept open for the next requests
+    #[arg(long, global = true, default_value_t = 90)]
+    pool_idle_timeout: u64,
+
+    /// Idle connections kept open to each server, unlimited by default
+    #[arg(long, global = true)]
+    pool_max_idle: Option<usize>,
+
+    /// Interval in seconds of the TCP keep-alive probes of open connections
+    #[arg(long, global = true)]
+    tcp_keepalive: Option<u64>,
+
+    /// Base URL of the Shelly server (defaults to the server stored by `login`, or https://shelly-001-eu.shelly.cloud)
+    #[arg(short, long, env = "SHELLY_BASE_URL")]
+    base_url: Option<String>,
 
     /// Separator for devices in Waybar output
     #[arg(short, long, default_value = " | ")]
     waybar_separator: String,
 
+    /// Maximum number of status requests in flight
+    #[arg(long, global = true, default_value_t = 1)]
+    concurrency: usize,
+
+    /// Requests per second sent to each cloud account, unlimited when 0
+    #[arg(long, global = true, default_value_t = 1.0, value_parser = parse_rate)]
+    rate_limit: f64,
+
+    /// Requests sent at once before --rate-limit spaces them out
+    #[arg(long, global = true, default_value_t = 1)]
+    rate_burst: u32,
+
+    /// Attempts of a device status request failing with a server error, timeout or lost connection
+    #[arg(long, global = true, default_value_t = 3)]
+    retry_attempts: u32,
+
+    /// Time in milliseconds before the first retry, doubled for each next one
+    #[arg(long, global = true, default_value_t = 500)]
+    retry_backoff: u64,
+
+    /// Fraction of the retry delay added at random, from 0 to 1
+    #[arg(long, global = true, default_value_t = 0.5)]
+    retry_jitter: f64,
+
+    /// Access token of the cloud real-time events, refreshing devices as soon as their status changes
+    #[arg(long, env = "SHELLY_ACCESS_TOKEN", hide_env_values = true)]
+    push_token: Option<Secret>,
+
+    /// Server-Sent Events endpoint relaying the cloud real-time events, used instead of the
+    /// WebSocket where it is not available
+    #[arg(long, env = "SHELLY_WAYBAR_PUSH_SSE_URL", requires = "push_token")]
+    push_sse_url: Option<String>,
+
+    /// Query the Gen2 devices on the LAN with RPC over UDP on this port, as set in their
+    /// `sys.rpc_udp.listen_port` config, falling back to HTTP when they do not answer
+    #[arg(long)]
+    udp_port: Option<u16>,
+
+    /// Time in milliseconds to wait for an answer over UDP
+    #[arg(long, default_value_t = 1000)]
+    udp_timeout: u64,
+
+    /// Address accepting the outbound WebSocket of Gen2 devices, e.g. 0.0.0.0:8090, showing
+    /// them from the statuses they stream instead of polling them
+    #[arg(long, env = "SHELLY_WAYBAR_WS_LISTEN")]
+    ws_listen: Option<SocketAddr>,
+
+    /// Listen to the CoIoT status packets of Gen1 devices on the LAN, using the last one heard
+    /// from a device instead of polling it
+    #[arg(long)]
+    coiot: bool,
+
+    /// Query the devices on the LAN instead of the cloud, the device IDs being their IP address or host name
+    #[arg(long)]
+    local: bool,
+
+    /// Fetch all devices in a single request to the cloud bulk status endpoint
+    #[arg(long, global = true)]
+    bulk: bool,
+
     /// Interval in seconds between each loop
     #[arg(short, long, default_value_t = 30)]
     interval: u64,
 
+    /// Poll once, print the output and exit, e.g. for `watch` or a script
+    #[arg(long)]
+    once: bool,
+
+    /// Bar the output is printed for: waybar, i3blocks (printed once, as the full text, the
+    /// short text in the short format and the color), i3bar (the status line protocol of
+    /// swaybar and i3bar, a block per device), xmobar (a line with `<fc>` color tags), plain
+    /// (the text alone, without markup), or jsonl (a JSON object with the values of each device)
+    #[arg(long, default_value = "waybar", value_enum)]
+    output: OutputMode,
+
     /// Output format: short, long, or icons
     #[arg(long, default_value = "long", value_enum)]
     format: OutputFormat,
 
+    /// Text of each device, e.g. "{temperature} {humidity}", with a `{<field>}` placeholder per
+    /// field key and `{name}` and `{id}`, instead of the --format labels
+    #[arg(long)]
+    text_template: Option<String>,
+
+    /// Tooltip of each device, with the same placeholders as --text-template
+    #[arg(long)]
+    tooltip_template: Option<String>,
+
+    /// Print Pango markup: device names in bold, units in small caps, and alarms in color
+    #[arg(long)]
+    markup: bool,
+
+    /// Print values without labels, leaving icons to the Waybar `format-icons` keyed by `alt`
+    #[arg(long)]
+    bare: bool,
+
+    /// Icon set used by the icons format: emoji, or ascii for fonts without emoji
+    #[arg(long, default_value = "emoji", value_enum)]
+    icon_set: IconSet,
+
     /// Unit for temperature (C or F)
     #[arg(short, long, default_value = "C", value_parser = ["C", "F"])]
     unit: String,
+
+    /// Age in seconds after which a reading is flagged as stale
+    #[arg(long, default_value_t = 3600)]
+    stale_after: u64,
+
+    /// Battery level in percent under which a device gets the low-battery CSS class
+    #[arg(long, default_value_t = 20)]
+    low_battery: i64,
+
+    /// Show the total energy of plugs and meters in the text instead of the tooltip
+    #[arg(long)]
+    energy_in_text: bool,
+
+    /// List the scripts of Gen2 devices, running, stopped or crashed, in the tooltip
+    #[arg(long)]
+    show_scripts: bool,
+
+    /// Internal temperature in °C above which a plug is flagged as overheating
+    #[arg(long, default_value_t = 80.0)]
+    overheat_threshold: f64,
+
+    /// Maximum tilt angle in degrees still reported as closed
+    #[arg(long, default_value_t = 5)]
+    tilt_closed_max: u64,
+
+    /// Minimum tilt angle in degrees reported as wide open
+    #[arg(long, default_value_t = 30)]
+    tilt_wide_open_min: u64,
+
+    /// Clock format used for timestamps: 24h or 12h
+    #[arg(long, default_value = "24h", value_enum)]
+    clock: ClockFormat,
+
+    /// Timezone used for timestamps: local, UTC or an IANA name such as Europe/Paris
+    #[arg(long, default_value = "local", value_parser = parse_timezone, env = "SHELLY_TIMEZONE")]
+    timezone: Timezone,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Log in to the Shelly cloud and store the auth key and server URL
+    Login {
+        /// Email address of the Shelly account
+        #[arg(long, required_unless_present = "code")]
+        email: Option<String>,
+
+        /// Code of the cloud OAuth flow, from the redirect of
+        /// https://my.shelly.cloud/oauth_login.html?client_id=shelly-diy; exchanged for tokens
+        /// kept to renew the auth key
+        #[arg(
+            long,
+            conflicts_with = "email",
+            env = "SHELLY_OAUTH_CODE",
+            hide_env_values = true
+        )]
+        code: Option<Secret>,
+
+        /// Login endpoint of the Shelly cloud
+        #[arg(long, default_value = auth::LOGIN_URL, env = "SHELLY_LOGIN_URL")]
+        login_url: String,
+    },
+
+    /// Toggle a relay (requires --allow-control or `allow_control = true`)
+    Toggle {
+        /// ID of the device to switch
+        device_id: String,
+
+        /// Relay channel of the device
+        #[arg(long, default_value_t = 0)]
+        channel: u32,
+    },
+
+    /// Print the output of a daemon started with --serve, for one more bar
+    Client,
+
+    /// Poll the devices once and print a table of their readings
+    Status,
+
+    /// Live dashboard of the devices in the terminal, with sparklines and toggles (requires
+    /// the tui feature)
+    Tui,
+
+    /// Print the JSON Schema of the output
+    Schema {
+        /// Output described: the Waybar payload, a device of --output-dir or a jsonl record
+        #[arg(value_enum, default_value = "waybar")]
+        output: SchemaOutput,
+    },
+
+    /// List the devices of the cloud account, or those announced on the LAN, with ready-to-paste
+    /// --devices arguments
+    Discover {
+        /// Browse the devices announced on the LAN with mDNS instead
+        #[arg(long)]
+        local: bool,
+
+        /// Time in seconds spent listening for announcements
+        #[arg(long, default_value_t = 5)]
+        wait: u64,
+    },
+
+    /// Load test the fetch pipeline against a local mock cloud, honouring --concurrency and --bulk
+    Bench {
+        /// Number of simulated devices
+        #[arg(long, default_value_t = 100)]
+        count: usize,
+
+        /// Number of polling cycles
+        #[arg(long, default_value_t = 3)]
+        cycles: usize,
+
+        /// Response time of the mock cloud in milliseconds
+        #[arg(long, default_value_t = 50)]
+        latency_ms: u64,
+    },
+}
+
+impl Args {
+    fn style(&self) -> Style {
+        Style {
+            format: self.format.clone(),
+            icon_set: self.icon_set,
+            bare: self.bare,
+        }
+    }
+
+    fn is_one_shot(&self) -> bool {
+        self.once || self.output_mode().is_one_shot()
+    }
+
+    // The `status` and `tui` commands show the devices their own way, whatever --output says
+    fn output_mode(&self) -> OutputMode {
+        match self.command {
+            Some(Command::Status) => OutputMode::Table,
+            Some(Command::Tui) => OutputMode::Dashboard,
+            _ => self.output,
+        }
+    }
+
+    // Consumers such as eww connect to the socket, without any `client`. Only an explicit
+    // --socket serves it: SHELLY_WAYBAR_SOCKET is exported for the clients too.
+    fn serves_socket(&self) -> bool {
+        self.serve || self.socket.is_some()
+    }
+
+    // Overlapping thresholds would report an angle both closed and wide open
+    fn check_tilt_thresholds(&self) -> Result<(), String> {
+        if self.tilt_closed_max >= self.tilt_wide_open_min {
+            return Err(format!(
+                "--tilt-closed-max ({}) must be below --tilt-wide-open-min ({})",
+                self.tilt_closed_max, self.tilt_wide_open_min
+            ));
+        }
+        Ok(())
+    }
+
+    fn socket_path(&self) -> PathBuf {
+        self.socket
+            .clone()
+            .or_else(|| env::var_os("SHELLY_WAYBAR_SOCKET").map(PathBuf::from))
+            .unwrap_or_else(ipc::default_socket_path)
+    }
+
+    fn http_client(&self) -> Result<Client, Box<dyn std::error::Error>> {
+        let builder = self
+            .http_client_builder()?
+            .timeout(Duration::from_secs(self.timeout));
+        Ok(builder.build()?)
+    }
+
+    // Client of long-lived responses, such as event streams, only bounding the connection
+    fn streaming_http_client(&self) -> Result<Client, Box<dyn std::error::Error>> {
+        Ok(self.http_client_builder()?.build()?)
+    }
+
+    fn http_client_builder(&self) -> Result<reqwest::ClientBuilder, Box<dyn std::error::Error>> {
+        let mut builder = Client::builder()
+            .danger_accept_invalid_certs(self.insecure)
+            .connect_timeout(Duration::from_secs(self.connect_timeout))
+            .gzip(!self.no_compression)
+            .brotli(!self.no_compression)
+            .pool_idle_timeout(Duration::from_secs(self.pool_idle_timeout))
+            .pool_max_idle_per_host(self.pool_max_idle.unwrap_or(usize::MAX))
+            .tcp_keepalive(self.tcp_keepalive.map(Duration::from_secs));
+        if let Some(path) = &self.ca_cert {
+            let pem = fs::read(path)
+                .map_err(|err| format!("Unable to read {} - {}", path.display(), err))?;
+            builder = builder.add_root_certificate(reqwest::Certificate::from_pem(&pem)?);
+        }
+        Ok(builder)
+    }
+
+    fn home_assistant(&self) -> Result<Option<HomeAssistant>, io::Error> {
+        let (Some(url), Some(token)) = (&self.ha_url, &self.ha_token) else {
+            return Ok(None);
+        };
+        Ok(Some(HomeAssistant {
+            url: url.clone(),
+            token: Secret::new(resolve_input(token.expose())?),
+        }))
+    }
+
+    fn rate_limiter(&self) -> RateLimiter {
+        RateLimiter::new(self.rate_limit, self.rate_burst)
+    }
+
+    fn retry_policy(&self) -> RetryPolicy {
+        RetryPolicy {
+            attempts: self.retry_attempts.max(1),
+            backoff: Duration::from_millis(self.retry_backoff),
+            jitter: self.retry_jitter,
+        }
+    }
+
+    fn udp_rpc(&self) -> Option<UdpRpc> {
+        self.udp_port.map(|port| UdpRpc {
+            port,
+            timeout: Duration::from_millis(self.udp_timeout),
+        })
+    }
+
+    fn timestamp_format(&self) -> TimestampFormat {
+        TimestampFormat {
+            clock: self.clock.clone(),
+            timezone: self.timezone.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, ValueEnum)]
+enum ClockFormat {
+    #[value(name = "24h")]
+    H24,
+    #[value(name = "12h")]
+    H12,
+}
+
+#[derive(Debug, Clone)]
+enum Timezone {
+    Local,
+    Named(Tz),
+}
+
+fn parse_rate(value: &str) -> Result<f64, String> {
+    match value.parse::<f64>() {
+        Ok(rate) if rate.is_finite() && rate >= 0.0 => Ok(rate),
+        _ => Err(format!("'{}' is not a finite number >= 0", value)),
+    }
+}
+
+fn parse_timezone(value: &str) -> Result<Timezone, String> {
+    if value.eq_ignore_ascii_case("local") {
+        return Ok(Timezone::Local);
+    }
+    if value.eq_ignore_ascii_case("utc") {
+        return Ok(Timezone::Named(Tz::UTC));
+    }
+    value
+        .parse::<Tz>()
+        .map(Timezone::Named)
+        .map_err(|_| format!("unknown timezone '{}'", value))
+}
+
+/// Clock and timezone settings applied wherever a timestamp is displayed.
+#[derive(Debug, Clone)]
+struct TimestampFormat {
+    clock: ClockFormat,
+    timezone: Timezone,
+}
+
+impl TimestampFormat {
+    fn format(&self, timestamp: DateTime<Utc>) -> String {
+        let pattern = match self.clock {
+            ClockFormat::H24 => "%H:%M:%S",
+            ClockFormat::H12 => "%I:%M:%S %p",
+        };
+        match &self.timezone {
+            Timezone::Local => timestamp.with_timezone(&Local).format(pattern).to_string(),
+            Timezone::Named(tz) => timestamp.with_timezone(tz).format(pattern).to_string(),
+        }
+    }
+}
+
+/// Angle thresholds used to turn a window tilt into a textual state.
+#[derive(Debug, Clone, Copy)]
+struct TiltThresholds {
+    closed_max: u64,
+    wide_open_min: u64,
+}
+
+impl TiltThresholds {
+    fn state(&self, angle: u64) -> &'static str {
+        if angle <= self.closed_max {
+            "closed"
+        } else if angle < self.wide_open_min {
+            "tilted"
+        } else {
+            "wide open"
+        }
+    }
 }
 
-#[derive(Debug, Clone, ValueEnum, EnumString)]
+#[derive(Debug, Clone, Default, ValueEnum, EnumString)]
 #[strum(serialize_all = "lowercase")]
 enum OutputFormat {
     Short,
+    #[default]
     Long,
     Icons,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+enum SchemaOutput {
+    Waybar,
+    Device,
+    Jsonl,
+}
+
+impl SchemaOutput {
+    fn schema(self) -> &'static str {
+        match self {
+            SchemaOutput::Waybar => schema::OUTPUT_SCHEMA,
+            SchemaOutput::Device => schema::DEVICE_OUTPUT_SCHEMA,
+            SchemaOutput::Jsonl => schema::DEVICE_RECORD_SCHEMA,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, ValueEnum)]
+enum OutputMode {
+    #[default]
+    Waybar,
+    I3blocks,
+    I3bar,
+    Xmobar,
+    Plain,
+    Jsonl,
+    /// Aligned table of the `status` command
+    #[value(skip)]
+    Table,
+    /// Terminal dashboard of the `tui` command
+    #[value(skip)]
+    Dashboard,
+}
+
+impl OutputMode {
+    fn has_short_text(self) -> bool {
+        matches!(
+            self,
+            OutputMode::I3blocks | OutputMode::I3bar | OutputMode::Table | OutputMode::Dashboard
+        )
+    }
+
+    // Outputs printed once, e.g. by bars running the command again for each update
+    fn is_one_shot(self) -> bool {
+        matches!(self, OutputMode::I3blocks | OutputMode::Table)
+    }
+}
+
 #[derive(Debug, EnumString, Display, PartialEq)]
 #[strum(serialize_all = "lowercase")]
 enum DeviceType {
@@ -59,6 +691,23 @@ enum DeviceType {
     Plug,
     Door,
     Window,
+    Cover,
+    Trv,
+    Smoke,
+    Gas,
+    Motion,
+    Dimmer,
+    Light,
+    Rgbw,
+    Em3,
+    Em,
+    Input,
+    Uni,
+    Blu,
+    Display,
+    Lux,
+    Button,
+    Custom,
 }
 
 #[derive(Deserialize, Debug)]
@@ -71,12 +720,142 @@ struct ShellyResponse {
 #[derive(Deserialize, Debug)]
 struct ShellyData {
     device_status: Option<Value>,
+    /// Statuses keyed by device ID, from the bulk endpoint
+    devices_status: Option<HashMap<String, Value>>,
+    /// Devices of the account keyed by ID, from the device list endpoint
+    devices: Option<HashMap<String, Value>>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum FetchError {
+    /// The cloud rejected the auth key
+    InvalidToken,
+    /// Server error, timeout or lost connection, worth retrying
+    Unavailable,
+    /// Network, decoding or any other API error
+    Failed,
+}
+
+/// State carried across polling cycles, persisted with `--state-file`.
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct PollState {
+    door_status_map: HashMap<String, bool>,
+    /// Last alarm state of safety devices, keyed like `door_status_map`
+    #[serde(default)]
+    alarm_status_map: HashMap<String, bool>,
+    readings: HashMap<String, Reading>,
+    #[serde(skip)]
+    auth_required: bool,
+    /// Last status fetched for each device, to skip rendering unchanged ones
+    #[serde(skip)]
+    statuses: HashMap<String, Value>,
+}
+
+/// Last rendered output of a device along with the time its data was produced.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct Reading {
+    output: Value,
+    updated_at: DateTime<Utc>,
+    /// Values of every field, including hidden ones
+    #[serde(default)]
+    samples: Vec<Sample>,
+    /// Text in the short format, for bars showing it when space runs out
+    #[serde(default)]
+    short_text: Option<String>,
+    /// Built-in type of the device, `custom` for the types of the config
+    #[serde(default)]
+    device_type: String,
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let args = Args::parse();
-    process_devices_loop(&args).await?;
+    match &args.command {
+        Some(Command::Login {
+            code: Some(code), ..
+        }) => {
+            let base_url = auth::resolve_base_url(args.base_url.as_deref())?;
+            return auth::login_oauth(&args.http_client()?, &base_url, code).await;
+        }
+        Some(Command::Login {
+            email: Some(email),
+            login_url,
+            ..
+        }) => return auth::login(&args.http_client()?, login_url, email).await,
+        _ => {}
+    }
+    if args.store_key {
+        return auth::store_key(args.auth_key.as_ref()).await;
+    }
+    if let Some(device_id) = &args.store_password {
+        return auth::store_password(device_id).await;
+    }
+
+    let config = Config::load(args.config.as_deref())?;
+    match &args.command {
+        Some(Command::Toggle { device_id, channel }) => {
+            control::ensure_allowed(&config, args.allow_control, device_id, Action::Toggle)?;
+            let client = args.http_client()?;
+            let auth_key = auth::resolve_auth_key(args.auth_key.as_ref()).await?;
+            let base_url = auth::resolve_base_url(args.base_url.as_deref())?;
+            let limiter = args.rate_limiter();
+            let is_on =
+                control::toggle(&client, &base_url, &auth_key, device_id, *channel, &limiter)
+                    .await?;
+            println!("{} is now {}", device_id, if is_on { "ON" } else { "OFF" });
+        }
+        Some(Command::Client) => ipc::run_client(&args.socket_path()).await?,
+        Some(Command::Schema { output }) => print!("{}", output.schema()),
+        Some(Command::Discover { local: true, wait }) => {
+            let devices =
+                discover::browse_local(&args.http_client()?, Duration::from_secs(*wait)).await?;
+            discover::print_local(&devices);
+        }
+        Some(Command::Discover { local: false, .. }) => {
+            let auth_key = auth::resolve_auth_key(args.auth_key.as_ref()).await?;
+            let base_url = auth::resolve_base_url(args.base_url.as_deref())?;
+            let devices = discover::list_cloud(
+                &args.http_client()?,
+                &base_url,
+                &auth_key,
+                &args.rate_limiter(),
+            )
+            .await
+            .map_err(|_| "Unable to list the devices of the account")?;
+            discover::print_cloud(&devices);
+        }
+        Some(Command::Bench {
+            count,
+            cycles,
+            latency_ms,
+        }) => {
+            let bench = Bench {
+                devices: *count,
+                cycles: *cycles,
+                latency: Duration::from_millis(*latency_ms),
+                concurrency: args.concurrency,
+                bulk: args.bulk,
+            };
+            bench.run().await?;
+        }
+        _ => {
+            // Global to be given after `status` too, which clap does not allow to require
+            if args.devices.is_empty() {
+                Args::command()
+                    .error(
+                        ErrorKind::MissingRequiredArgument,
+                        "the following required arguments were not provided:\n  --devices <DEVICES>...",
+                    )
+                    .exit();
+            }
+            if let Err(err) = args.check_tilt_thresholds() {
+                Args::command()
+                    .error(ErrorKind::ValueValidation, err)
+                    .exit();
+            }
+            process_devices_loop(&args, &config).await?
+        }
+    }
     Ok(())
 }
 
@@ -92,30 +871,341 @@ fn resolve_input(input: &str) -> Result<String, io::Error> {
     }
 }
 
-async fn process_devices_loop(args: &Args) -> Result<(), Box<dyn std::error::Error>> {
-    let client = Client::new();
-    let auth_key = resolve_input(&args.auth_key)?;
-    let base_url = resolve_input(&args.base_url)?;
-    let mut door_status_map: HashMap<String, bool> = HashMap::new();
+async fn process_devices_loop(
+    args: &Args,
+    config: &Config,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let client = args.http_client()?;
+    let home_assistant = args.home_assistant()?;
+    // Devices on the LAN, an MQTT broker or Home Assistant need no cloud account
+    let mut auth_key = if args.local || args.mqtt_source.is_some() || home_assistant.is_some() {
+        Secret::new("")
+    } else {
+        auth::resolve_auth_key(args.auth_key.as_ref()).await?
+    };
+    let base_url = auth::resolve_base_url(args.base_url.as_deref())?;
+    let state_store = match &args.state_file {
+        Some(path) => Some(StateStore {
+            path: path.clone(),
+            passphrase: auth::resolve_state_passphrase(args.state_passphrase.as_ref()).await?,
+        }),
+        None => None,
+    };
+    let mut state = match &state_store {
+        Some(store) => store.load()?,
+        None => PollState::default(),
+    };
+    let timestamps = args.timestamp_format();
+    let notifier = notify::notifier(args.notifier, args.notify_command.as_deref())?;
+    let dbus = if args.dbus {
+        Some(DbusService::start().await?)
+    } else {
+        None
+    };
+    let mut mqtt = match &args.mqtt_url {
+        Some(url) => {
+            Some(MqttPublisher::connect(url, &config.mqtt, &args.mqtt_discovery_prefix).await?)
+        }
+        None => None,
+    };
+    let (source, updates) = match &args.mqtt_source {
+        Some(url) => {
+            let (source, updates) = MqttSource::connect(url).await?;
+            (Some(source), Some(updates))
+        }
+        None => (None, None),
+    };
+    let (coiot, announces) = if args.coiot {
+        let (coiot, announces) = CoiotListener::start()?;
+        (Some(coiot), Some(announces))
+    } else {
+        (None, None)
+    };
+    let (outbound, streams) = match args.ws_listen {
+        Some(addr) => {
+            let (outbound, streams) = OutboundServer::start(addr).await?;
+            (Some(outbound), Some(streams))
+        }
+        None => (None, None),
+    };
+    let ipc = if args.serves_socket() {
+        Some(IpcServer::start(&args.socket_path())?)
+    } else {
+        None
+    };
+    let mut output_dir = match &args.output_dir {
+        Some(path) => Some(OutputDir::create(path)?),
+        None => None,
+    };
+    if args.output_mode() == OutputMode::I3bar {
+        println!("{}", bars::I3BAR_HEADER);
+    }
+    let (toggle_sender, toggles) = mpsc::unbounded_channel();
+    let tray = if args.tray {
+        Some(TrayIcon::start(toggle_sender.clone()).await?)
+    } else {
+        None
+    };
+    let health = SharedHealth::default();
+    let mut wakeups = Wakeups {
+        webhooks: match args.listen {
+            Some(addr) => Some(http::start(
+                addr,
+                args.listen_token.clone(),
+                health.clone(),
+                args.stale_after,
+            )?),
+            None => None,
+        },
+        pushes: match &args.push_token {
+            Some(token) => {
+                let transport = match &args.push_sse_url {
+                    Some(url) => push::Transport::Sse(args.streaming_http_client()?, url.clone()),
+                    None => push::Transport::WebSocket(push::events_url(&base_url)),
+                };
+                Some(push::start(transport, token.clone()))
+            }
+            None => None,
+        },
+        updates,
+        announces,
+        streams,
+        toggles: (tray.is_some() || args.output_mode() == OutputMode::Dashboard).then_some(toggles),
+        next_poll: Instant::now() + Duration::from_secs(args.interval),
+    };
+    // Webhook that cut the last wait short, restricting the next cycle to its device
+    let mut webhook: Option<Webhook> = None;
+    let mut connections = Connections {
+        credentials: devices_credentials(args, config).await?,
+        limiters: HashMap::new(),
+    };
+    let retry = args.retry_policy();
+    // Started last, so that startup errors are printed on a usable terminal
+    let dashboard = if args.output_mode() == OutputMode::Dashboard {
+        Some(Dashboard::start(toggle_sender)?)
+    } else {
+        None
+    };
 
     loop {
-        let mut outputs = Vec::new();
+        let was_auth_required = state.auth_required;
+        if was_auth_required {
+            // Renew the key with the OAuth tokens of `login --code`, or pick up a key refreshed
+            // meanwhile, e.g. by `shelly-waybar login`
+            if let Some(key) = auth::renew_auth_key(&client, &base_url).await {
+                auth_key = key;
+            } else if let Ok(key) = auth::resolve_auth_key(args.auth_key.as_ref()).await {
+                auth_key = key;
+            }
+        }
+        state.auth_required = false;
 
-        for device in &args.devices {
-            if let Some(output) = process_device(
-                device,
-                &auth_key,
-                &base_url,
-                args,
+        let devices: Vec<&String> = args
+            .devices
+            .iter()
+            .filter(|device| {
+                webhook.as_ref().is_none_or(|webhook| {
+                    parse_device_info(device).is_some_and(|(_, id, _)| id == webhook.device_id)
+                })
+            })
+            .collect();
+        let mut device_ids: Vec<&str> = devices
+            .iter()
+            .filter_map(|device| parse_device_info(device))
+            .map(|(_, device_id, _)| device_id)
+            .collect();
+        device_ids.sort_unstable();
+        device_ids.dedup();
+        let mut local_devices = Vec::new();
+        let mut cloud_ids = Vec::new();
+        let mut home_assistant_ids = Vec::new();
+        let mut statuses = HashMap::new();
+        for device_id in &device_ids {
+            let pushed = coiot
+                .as_ref()
+                .and_then(|coiot| coiot.status(device_id))
+                .or_else(|| outbound.as_ref()?.status(device_id));
+            if let Some(status) = pushed {
+                statuses.insert(device_id.to_string(), Ok(status));
+                continue;
+            }
+            // Devices not published on the broker yet are left out until they are
+            if let Some(source) = &source {
+                if let Some(status) = source.status(device_id) {
+                    statuses.insert(device_id.to_string(), Ok(status));
+                }
+                continue;
+            }
+            if home_assistant.is_some() {
+                home_assistant_ids.push(*device_id);
+                continue;
+            }
+            match local_host(args, config, device_id) {
+                Some(host) => local_devices.push(LocalDevice {
+                    device_id,
+                    host,
+                    credentials: connections.credentials.get(*device_id).cloned(),
+                }),
+                None => cloud_ids.push(*device_id),
+            }
+        }
+        for (device_id, status) in
+            local::fetch_statuses(&client, &local_devices, args.concurrency, args.udp_rpc()).await
+        {
+            match device_ids.iter().find(|id| **id == device_id) {
+                Some(device_id)
+                    if status.is_err() && !args.local && config.falls_back_to_cloud(device_id) =>
+                {
+                    cloud_ids.push(*device_id)
+                }
+                _ => {
+                    statuses.insert(device_id, status);
+                }
+            }
+        }
+        // Devices of other accounts are fetched from their own server with their own key
+        let mut accounts: BTreeMap<(&str, Option<&str>), Vec<&str>> = BTreeMap::new();
+        for device_id in cloud_ids {
+            let (account_url, account_key) = config.account(device_id);
+            accounts
+                .entry((
+                    account_url.unwrap_or(&base_url),
+                    account_key.map(Secret::expose),
+                ))
+                .or_default()
+                .push(device_id);
+        }
+        for ((account_url, account_key), device_ids) in accounts {
+            let limiter = connections.limiter(args, account_url, account_key);
+            let key = match account_key {
+                Some(key) => Secret::new(key),
+                None => auth_key.clone(),
+            };
+            let mut account_statuses = fetch_statuses(
                 &client,
-                &mut door_status_map,
+                account_url,
+                &key,
+                &device_ids,
+                args.concurrency,
+                args.bulk,
+                &limiter,
+                &retry,
             )
-            .await
+            .await;
+            // Logging in again only renews the main key
+            if account_key.is_some() {
+                for status in account_statuses.values_mut() {
+                    if *status == Err(FetchError::InvalidToken) {
+                        *status = Err(FetchError::Failed);
+                    }
+                }
+            }
+            statuses.extend(account_statuses);
+        }
+        if let Some(home_assistant) = &home_assistant {
+            if !home_assistant_ids.is_empty() {
+                statuses.extend(
+                    home_assistant
+                        .fetch_statuses(&client, &home_assistant_ids)
+                        .await,
+                );
+            }
+        }
+        state.auth_required = statuses
+            .values()
+            .any(|status| status == &Err(FetchError::InvalidToken));
+        record_health(&health, &statuses, state.auth_required);
+
+        if state.auth_required {
+            if !was_auth_required {
+                notify_auth_required(notifier.as_ref());
+            }
+            let output = auth_required_output();
+            emit(args, &output, None, ipc.as_ref())?;
+            if let Some(dashboard) = &dashboard {
+                dashboard.update(DashboardState {
+                    auth_required: true,
+                    ..DashboardState::default()
+                });
+            }
+            if let Some(tray) = &tray {
+                tray.update(TrayState {
+                    summary: output["tooltip"].as_str().unwrap_or_default().to_string(),
+                    auth_required: true,
+                    ..TrayState::default()
+                })
+                .await;
+            }
+            if args.is_one_shot() {
+                return Err("The auth key was rejected by the Shelly cloud".into());
+            }
+            webhook = wakeups
+                .wait(
+                    args,
+                    config,
+                    &client,
+                    &base_url,
+                    &auth_key,
+                    &mut connections,
+                )
+                .await;
+            continue;
+        }
+
+        for device in devices {
+            if let Some(webhook) = &webhook {
+                notify_webhook(notifier.as_ref(), webhook, device, &timestamps);
+            }
+            let Some((_, device_id, device_name)) = parse_device_info(device) else {
+                continue;
+            };
+            let Some(Ok(status)) = statuses.get(device_id) else {
+                continue;
+            };
+            // An unchanged status keeps its rendered reading, only the timestamp moves on;
+            // motion sensors show the age of the last motion, so they are always rendered
+            if state.statuses.get(device) == Some(status) && status["sensor"]["timestamp"].is_null()
             {
-                outputs.push(output);
+                if let Some(reading) = state.readings.get_mut(device) {
+                    reading.updated_at = extract_timestamp(status).unwrap_or_else(Utc::now);
+                    continue;
+                }
+            }
+            if let Some(reading) = process_device(
+                device,
+                status.clone(),
+                args,
+                config,
+                &mut state,
+                notifier.as_ref(),
+            ) {
+                if let (Some(previous), Some(command)) =
+                    (state.readings.get(device), config.on_change(device_id))
+                {
+                    for change in hooks::changes(previous, &reading) {
+                        let event = hooks::event(
+                            device_id,
+                            device_name.as_deref(),
+                            &change,
+                            reading.updated_at,
+                        );
+                        hooks::run(command, &event);
+                    }
+                }
+                state.readings.insert(device.clone(), reading);
+                state.statuses.insert(device.clone(), status.clone());
             }
         }
 
+        // Devices that failed this cycle keep their last reading, aged accordingly
+        let now = Utc::now();
+        let outputs: Vec<Value> = args
+            .devices
+            .iter()
+            .filter_map(|device| state.readings.get(device))
+            .map(|reading| render_age(reading, now, args.stale_after, &timestamps))
+            .collect();
+
         if outputs.is_empty() {
             eprintln!("Error: No valid device data found.");
         } else {
@@ -129,69 +1219,911 @@ async fn process_devices_loop(args: &Args) -> Result<(), Box<dyn std::error::Err
                 .map(|obj| obj["tooltip"].as_str().unwrap_or_default())
                 .collect::<Vec<_>>()
                 .join("\n");
-            let merged_output = serde_json::json!({
+            let mut merged_output = serde_json::json!({
                 "text": merged_text,
                 "tooltip": merged_tooltip
             });
-            println!("{merged_output}");
+            // Classes of every device, each one once, e.g. `open` when any door is open
+            let mut merged_classes: Vec<&str> = Vec::new();
+            for class in outputs.iter().flat_map(classes) {
+                if !merged_classes.contains(&class) {
+                    merged_classes.push(class);
+                }
+            }
+            if !merged_classes.is_empty() {
+                merged_output["class"] = serde_json::json!(merged_classes);
+            }
+            if let Some(alt) = outputs.first().and_then(|obj| obj.get("alt")) {
+                merged_output["alt"] = alt.clone();
+            }
+            // A single bar, the one of the first device having one
+            if let Some(percentage) = outputs.iter().find_map(|obj| obj.get("percentage")) {
+                merged_output["percentage"] = percentage.clone();
+            }
+            emit(args, &merged_output, Some((&state, now)), ipc.as_ref())?;
+
+            if let Some(tray) = &tray {
+                tray.update(tray_state(args, config, &state, merged_tooltip, now))
+                    .await;
+            }
+            if let Some(dashboard) = &dashboard {
+                dashboard.update(dashboard_state(args, config, &state, now));
+            }
         }
 
-        thread::sleep(Duration::from_secs(args.interval));
-    }
-}
+        if let Some(output_dir) = &mut output_dir {
+            for device in &args.devices {
+                let (Some(reading), Some((_, device_id, device_name))) =
+                    (state.readings.get(device), parse_device_info(device))
+                else {
+                    continue;
+                };
+                let output = render_age(reading, now, args.stale_after, &timestamps);
+                if args.validate_output {
+                    schema::validate_device_output(&output).map_err(|err| {
+                        format!("Invalid output of {} {} - {}", device_id, output, err)
+                    })?;
+                }
+                if let Err(err) = output_dir
+                    .write(device_id, device_name.as_deref(), &output)
+                    .await
+                {
+                    eprintln!(
+                        "Error: Unable to write the output of {} - {}",
+                        device_id, err
+                    );
+                }
+            }
+        }
 
-async fn process_device(
-    device: &str,
-    auth_key: &str,
-    base_url: &str,
+        if let Some(dbus) = &dbus {
+            for device in &args.devices {
+                let Some(reading) = state.readings.get(device) else {
+                    continue;
+                };
+                // The same aged output as the bars, flagged `error` once stale
+                let output = render_age(reading, now, args.stale_after, &timestamps);
+                let record = device_record(device, reading).unwrap_or_default();
+                if let Err(err) = dbus.publish(device, &output, &record).await {
+                    eprintln!("Error: Unable to publish on D-Bus - {}", err);
+                }
+            }
+        }
+
+        if let Some(mqtt) = &mut mqtt {
+            for device in &args.devices {
+                let (Some(reading), Some((_, device_id, device_name))) =
+                    (state.readings.get(device), parse_device_info(device))
+                else {
+                    continue;
+                };
+                if let Err(err) = mqtt.publish(device_id, device_name.as_deref(), reading) {
+                    eprintln!("Error: Unable to publish to MQTT - {}", err);
+                }
+            }
+        }
+
+        if let Some(store) = &state_store {
+            if let Err(err) = store.save(&state) {
+                eprintln!("Error: Unable to save state - {}", err);
+            }
+        }
+
+        if args.is_one_shot() {
+            // Scripts running --once tell a failed poll from the exit status
+            if !statuses.is_empty() && !statuses.values().any(Result::is_ok) {
+                return Err("No device data could be fetched".into());
+            }
+            return Ok(());
+        }
+        webhook = wakeups
+            .wait(
+                args,
+                config,
+                &client,
+                &base_url,
+                &auth_key,
+                &mut connections,
+            )
+            .await;
+    }
+}
+
+// Short text of the devices, for i3blocks
+fn merged_short_text(args: &Args, state: &PollState) -> String {
+    args.devices
+        .iter()
+        .filter_map(|device| state.readings.get(device))
+        .map(|reading| match &reading.short_text {
+            Some(short_text) => short_text.as_str(),
+            None => reading.output["text"].as_str().unwrap_or_default(),
+        })
+        .collect::<Vec<_>>()
+        .join(&args.waybar_separator)
+}
+
+// Rows of the `status` table, one per device with a reading
+fn device_rows(args: &Args, state: &PollState, now: DateTime<Utc>) -> Vec<table::Row> {
+    args.devices
+        .iter()
+        .filter_map(|device| device_row(args, device, state.readings.get(device)?, now))
+        .collect()
+}
+
+fn device_row(
+    args: &Args,
+    device: &str,
+    reading: &Reading,
+    now: DateTime<Utc>,
+) -> Option<table::Row> {
+    let (_, device_id, device_name) = parse_device_info(device)?;
+    let sample = |key: &str| {
+        let sample = reading.samples.iter().find(|sample| sample.key == key)?;
+        let value = match &sample.value {
+            Value::String(value) => value.clone(),
+            value => value.to_string(),
+        };
+        Some(format!("{}{}", value, sample.unit))
+    };
+    let age = (now - reading.updated_at).num_seconds();
+    let mut updated = format!(
+        "{} ago ({})",
+        humanize_age(age),
+        args.timestamp_format().format(reading.updated_at)
+    );
+    if age > args.stale_after as i64 {
+        updated.push_str(" stale");
+    }
+    Some(table::Row {
+        name: device_name.unwrap_or_else(|| device_id.to_string()),
+        device_type: reading.device_type.clone(),
+        metrics: strip_markup(reading.short_text.as_deref().unwrap_or_default()),
+        battery: sample("battery").unwrap_or_default(),
+        rssi: sample("rssi").unwrap_or_default(),
+        updated,
+    })
+}
+
+// Dashboard content: the row of each device, its values drawn as sparklines and whether it
+// can be toggled
+fn dashboard_state(
+    args: &Args,
+    config: &Config,
+    state: &PollState,
+    now: DateTime<Utc>,
+) -> DashboardState {
+    let devices = args
+        .devices
+        .iter()
+        .filter_map(|device| {
+            let reading = state.readings.get(device)?;
+            let (_, device_id, _) = parse_device_info(device)?;
+            let value = |key: &str| {
+                let sample = reading.samples.iter().find(|sample| sample.key == key)?;
+                sample.value.as_f64()
+            };
+            Some(DashboardDevice {
+                device: device.to_string(),
+                row: device_row(args, device, reading, now)?,
+                can_toggle: can_toggle(args, config, reading, device_id),
+                temperature: value("temperature"),
+                power: value("power"),
+            })
+        })
+        .collect();
+    DashboardState {
+        devices,
+        auth_required: false,
+    }
+}
+
+// Whether a device has a relay that control allows to toggle. Devices read from an MQTT
+// broker or Home Assistant are switched there, not from here.
+fn can_toggle(args: &Args, config: &Config, reading: &Reading, device_id: &str) -> bool {
+    let has_relay = reading.samples.iter().any(|sample| sample.key == "output");
+    let switchable = args.mqtt_source.is_none() && args.ha_url.is_none();
+    has_relay
+        && switchable
+        && control::ensure_allowed(config, args.allow_control, device_id, Action::Toggle).is_ok()
+}
+
+// Blocks of the i3bar protocol and xmobar, one per device with a reading
+fn device_blocks(
+    args: &Args,
+    state: &PollState,
+    now: DateTime<Utc>,
+    timestamps: &TimestampFormat,
+) -> Vec<bars::Block> {
+    args.devices
+        .iter()
+        .filter_map(|device| {
+            let reading = state.readings.get(device)?;
+            let (_, device_id, _) = parse_device_info(device)?;
+            let output = render_age(reading, now, args.stale_after, timestamps);
+            Some(bars::Block {
+                instance: device_id.to_string(),
+                full_text: output["text"].as_str().unwrap_or_default().to_string(),
+                short_text: reading.short_text.clone(),
+                color: alert_color(&classes(&output)),
+            })
+        })
+        .collect()
+}
+
+// Objects of the jsonl output: the values of each device with a reading, as published on MQTT
+fn device_records(args: &Args, state: &PollState, now: DateTime<Utc>) -> Vec<Value> {
+    args.devices
+        .iter()
+        .filter_map(|device| {
+            let reading = state.readings.get(device)?;
+            let mut record = device_record(device, reading)?;
+            record["stale"] =
+                Value::Bool((now - reading.updated_at).num_seconds() > args.stale_after as i64);
+            record["timestamp"] = serde_json::json!(now);
+            Some(record)
+        })
+        .collect()
+}
+
+// Values of a device with its type, as published on MQTT
+fn device_record(device: &str, reading: &Reading) -> Option<Value> {
+    let (_, device_id, device_name) = parse_device_info(device)?;
+    let mut record = mqtt::reading_payload(device_id, device_name.as_deref(), reading);
+    record["type"] = Value::String(reading.device_type.clone());
+    Some(record)
+}
+
+// Tray menu content: the rendered text of each device and whether it can be toggled
+fn tray_state(
+    args: &Args,
+    config: &Config,
+    state: &PollState,
+    summary: String,
+    now: DateTime<Utc>,
+) -> TrayState {
+    let timestamps = args.timestamp_format();
+    let devices = args
+        .devices
+        .iter()
+        .filter_map(|device| {
+            let reading = state.readings.get(device)?;
+            let (_, device_id, _) = parse_device_info(device)?;
+            let output = render_age(reading, now, args.stale_after, &timestamps);
+            Some(TrayDevice {
+                device: device.to_string(),
+                label: output["text"].as_str().unwrap_or_default().to_string(),
+                can_toggle: can_toggle(args, config, reading, device_id),
+            })
+        })
+        .collect();
+    TrayState {
+        devices,
+        summary,
+        auth_required: false,
+    }
+}
+
+// Record the outcome of the fetches for /healthz
+fn record_health(
+    health: &SharedHealth,
+    statuses: &HashMap<String, Result<Value, FetchError>>,
+    auth_required: bool,
+) {
+    let now = Utc::now();
+    let mut health = health.lock().unwrap_or_else(|err| err.into_inner());
+    for (device_id, status) in statuses {
+        health.record(device_id, status.is_ok(), now);
+    }
+    health.set_auth_required(auth_required);
+}
+
+/// Sources of events cutting the wait for the next polling cycle short.
+struct Wakeups {
+    webhooks: Option<UnboundedReceiver<Webhook>>,
+    /// Devices whose status changed, from the cloud events WebSocket
+    pushes: Option<UnboundedReceiver<Webhook>>,
+    /// Devices whose status was published on the MQTT source broker
+    updates: Option<UnboundedReceiver<Webhook>>,
+    /// Devices whose CoIoT status packet was heard on the LAN
+    announces: Option<UnboundedReceiver<Webhook>>,
+    /// Devices whose status changed, from their outbound WebSocket
+    streams: Option<UnboundedReceiver<Webhook>>,
+    /// Entries of the devices to toggle, from the tray menu or the dashboard
+    toggles: Option<UnboundedReceiver<String>>,
+    /// Deadline of the next cycle polling every device, kept across events
+    next_poll: Instant,
+}
+
+impl Wakeups {
+    /// Waits for the next polling cycle, returning the webhook restricting it to
+    /// one device; a device toggled from the tray is switched and refreshed alone. Events
+    /// refresh their device on top of the full polls, which keep their own schedule.
+    async fn wait(
+        &mut self,
+        args: &Args,
+        config: &Config,
+        client: &Client,
+        base_url: &str,
+        auth_key: &Secret,
+        connections: &mut Connections,
+    ) -> Option<Webhook> {
+        tokio::select! {
+            _ = tokio::time::sleep_until(self.next_poll) => {
+                self.next_poll = Instant::now() + Duration::from_secs(args.interval);
+                None
+            }
+            Some(webhook) = recv(self.webhooks.as_mut()) => Some(webhook),
+            Some(push) = recv(self.pushes.as_mut()) => Some(push),
+            Some(update) = recv(self.updates.as_mut()) => Some(update),
+            Some(announce) = recv(self.announces.as_mut()) => Some(announce),
+            Some(stream) = recv(self.streams.as_mut()) => Some(stream),
+            Some(device) = recv(self.toggles.as_mut()) => {
+                let (_, device_id, _) = parse_device_info(&device)?;
+                if let Err(err) = toggle_device(args, config, client, base_url, auth_key, connections, &device).await {
+                    eprintln!("Error: Unable to toggle {} - {}", device, err);
+                }
+                Some(Webhook { device_id: device_id.to_string(), event: None })
+            }
+        }
+    }
+}
+
+// Switch the relay selected by a device entry, reaching the device the way it is polled: on
+// the LAN at its host, falling back to the cloud like polling does, or else with the cloud
+// account of its config
+async fn toggle_device(
     args: &Args,
+    config: &Config,
     client: &Client,
-    door_status_map: &mut HashMap<String, bool>,
-) -> Option<Value> {
+    base_url: &str,
+    auth_key: &Secret,
+    connections: &mut Connections,
+    device: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let (_, device_id, _) =
+        parse_device_info(device).ok_or_else(|| format!("Invalid device format: {}", device))?;
+    control::ensure_allowed(config, args.allow_control, device_id, Action::Toggle)?;
+    let channel = split_channel_selector(device).1.unwrap_or(0);
+    if let Some(host) = local_host(args, config, device_id) {
+        let login = connections.credentials.get(device_id);
+        match local::toggle(client, host, login, channel).await {
+            Ok(_) => return Ok(()),
+            Err(err) if args.local || !config.falls_back_to_cloud(device_id) => {
+                let err = match login {
+                    Some(login) => login.password.redact(&err.to_string()),
+                    None => err.to_string(),
+                };
+                return Err(err.into());
+            }
+            Err(_) => {}
+        }
+    }
+    let (account_url, account_key) = config.account(device_id);
+    let key = account_key.unwrap_or(auth_key).clone();
+    let base_url = account_url.unwrap_or(base_url);
+    let limiter = connections.limiter(args, base_url, account_key.map(Secret::expose));
+    let channel = u32::try_from(channel)?;
+    control::toggle(client, base_url, &key, device_id, channel, &limiter).await?;
+    Ok(())
+}
+
+/// Ways of reaching the devices, kept across polling cycles.
+#[derive(Default)]
+struct Connections {
+    /// Basic auth of the devices on the LAN
+    credentials: HashMap<String, Credentials>,
+    /// Requests to each cloud account, keyed by server and auth key
+    limiters: HashMap<(String, Option<String>), RateLimiter>,
+}
+
+impl Connections {
+    /// Rate limiter shared by every request to a cloud account, polls and toggles alike.
+    fn limiter(&mut self, args: &Args, base_url: &str, auth_key: Option<&str>) -> RateLimiter {
+        self.limiters
+            .entry((base_url.to_string(), auth_key.map(str::to_string)))
+            .or_insert_with(|| args.rate_limiter())
+            .clone()
+    }
+}
+
+// Receive from an optional channel, never resolving without one
+async fn recv<T>(receiver: Option<&mut UnboundedReceiver<T>>) -> Option<T> {
+    match receiver {
+        Some(receiver) => receiver.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+// Notify the event reported by a device action, if any
+fn notify_webhook(
+    notifier: &dyn Notifier,
+    webhook: &Webhook,
+    device: &str,
+    timestamps: &TimestampFormat,
+) {
+    let Some(event) = &webhook.event else {
+        return;
+    };
+    let name = parse_device_info(device)
+        .and_then(|(_, _, name)| name)
+        .unwrap_or_else(|| webhook.device_id.clone());
+    let summary = format!("Shelly: {}", name);
+    let body = format!("Event: {} ({})", event, timestamps.format(Utc::now()));
+    if let Err(err) = notifier.notify(&summary, &body, Urgency::Normal) {
+        eprintln!("Error: Unable to send notification - {}", err);
+    }
+}
+
+// Print an output for the bar and hand it to the connected clients; bars showing each device
+// on its own take them from the polling state, missing when the auth key was rejected
+fn emit(
+    args: &Args,
+    output: &Value,
+    devices: Option<(&PollState, DateTime<Utc>)>,
+    ipc: Option<&IpcServer>,
+) -> Result<(), String> {
+    if args.validate_output {
+        schema::validate_output(output)
+            .map_err(|err| format!("Invalid output {} - {}", output, err))?;
+    }
+    let timestamps = args.timestamp_format();
+    let blocks = || match devices {
+        Some((state, now)) => device_blocks(args, state, now, &timestamps),
+        None => vec![bars::Block {
+            instance: "shelly".to_string(),
+            full_text: output["text"].as_str().unwrap_or_default().to_string(),
+            short_text: None,
+            color: alert_color(&classes(output)),
+        }],
+    };
+    match args.output_mode() {
+        OutputMode::Waybar => println!("{}", output),
+        OutputMode::I3blocks => print!(
+            "{}",
+            bars::i3blocks(
+                output,
+                devices
+                    .map(|(state, _)| merged_short_text(args, state))
+                    .as_deref(),
+                alert_color(&classes(output))
+            )
+        ),
+        OutputMode::I3bar => println!("{}", bars::i3bar(&blocks())),
+        OutputMode::Xmobar => println!("{}", bars::xmobar(&blocks(), &args.waybar_separator)),
+        OutputMode::Plain => println!(
+            "{}",
+            strip_markup(output["text"].as_str().unwrap_or_default())
+        ),
+        OutputMode::Jsonl => {
+            let Some((state, now)) = devices else {
+                return Ok(());
+            };
+            for record in device_records(args, state, now) {
+                if args.validate_output {
+                    schema::validate_device_record(&record)
+                        .map_err(|err| format!("Invalid record {} - {}", record, err))?;
+                }
+                println!("{}", record);
+            }
+        }
+        // Drawn by the dashboard itself
+        OutputMode::Dashboard => {}
+        OutputMode::Table => match devices {
+            Some((state, now)) => print!("{}", table::render(&device_rows(args, state, now))),
+            None => println!(
+                "{}",
+                strip_markup(output["tooltip"].as_str().unwrap_or_default())
+            ),
+        },
+    }
+    if let Some(ipc) = ipc {
+        ipc.publish(output.to_string());
+    }
+    Ok(())
+}
+
+/// Text colors of devices in alarm or warning with --markup, and of i3blocks.
+const ALARM_COLOR: &str = "#f38ba8";
+const WARNING_COLOR: &str = "#fab387";
+
+fn alert_color(classes: &[&str]) -> Option<&'static str> {
+    if classes.contains(&"alarm") || classes.contains(&"auth-required") {
+        Some(ALARM_COLOR)
+    } else if classes.contains(&"warning") {
+        Some(WARNING_COLOR)
+    } else {
+        None
+    }
+}
+
+fn process_device(
+    device: &str,
+    device_status: Value,
+    args: &Args,
+    config: &Config,
+    state: &mut PollState,
+    notifier: &dyn Notifier,
+) -> Option<Reading> {
     let (device_type_str, device_id, device_name) = parse_device_info(device)?;
-    let device_status = fetch_device_status(client, base_url, device_id, auth_key).await?;
+    let updated_at = extract_timestamp(&device_status).unwrap_or_else(Utc::now);
+    let device_status = match split_channel_selector(device) {
+        (_, Some(channel)) => select_channel(device_status, channel),
+        (_, None) => device_status,
+    };
 
-    let device_type = if device_type_str.is_empty() {
-        autodetect_device_type(&device_status)?
+    let device_type_str = config.resolve_alias(device_type_str);
+    // Types declared in the config come first, so that they can override a built-in one
+    let config_type = if device_type_str.is_empty() {
+        detect_config_type(&device_status, config)
     } else {
-        match_device_type(device_type_str)?
+        config.types.get(device_type_str)
+    };
+    let device_type = match config_type {
+        Some(_) => DeviceType::Custom,
+        None if device_type_str.is_empty() => autodetect_device_type(&device_status)?,
+        None => match_device_type(device_type_str)?,
     };
 
-    let mut output = match device_type {
-        DeviceType::Temperature => {
-            parse_temperature_data(device_status, args.format.clone(), &args.unit)
+    // Notifications first, the status being only read afterwards
+    match device_type {
+        DeviceType::Plug => {
+            handle_overheat_status(
+                device_id,
+                device_name.as_deref(),
+                &device_status,
+                args.overheat_threshold,
+                state.readings.get(device),
+                &args.timestamp_format(),
+                notifier,
+            );
+            handle_alarm_status(
+                device_id,
+                device_name.clone(),
+                device_trip_errors(&device_status)
+                    .map(|errors| format!("Tripped: {}", errors))
+                    .as_deref(),
+                &mut state.alarm_status_map,
+                &args.timestamp_format(),
+                notifier,
+            );
         }
-        DeviceType::Plug => parse_plug_data(device_status, args.format.clone()),
         DeviceType::Door => {
             handle_door_status(
                 device_id,
                 device_name.clone(),
                 &device_status,
-                door_status_map,
+                &mut state.door_status_map,
+                &args.timestamp_format(),
+                notifier,
             )?;
-            parse_window_or_door_data(device_status, false, args.format.clone())
+            if config.notifies_vibration(device_id) {
+                handle_vibration_status(
+                    device_id,
+                    device_name.as_deref(),
+                    &device_status,
+                    state.readings.get(device),
+                    &args.timestamp_format(),
+                    notifier,
+                );
+            }
+        }
+        DeviceType::Window if config.notifies_vibration(device_id) => {
+            handle_vibration_status(
+                device_id,
+                device_name.as_deref(),
+                &device_status,
+                state.readings.get(device),
+                &args.timestamp_format(),
+                notifier,
+            );
+        }
+        DeviceType::Cover => {
+            handle_alarm_status(
+                device_id,
+                device_name.clone(),
+                cover_fault(&device_status["cover:0"]),
+                &mut state.alarm_status_map,
+                &args.timestamp_format(),
+                notifier,
+            );
+        }
+        DeviceType::Smoke => {
+            handle_alarm_status(
+                device_id,
+                device_name.clone(),
+                device_status["smoke:0"]["alarm"]
+                    .as_bool()
+                    .unwrap_or(false)
+                    .then_some("Smoke detected"),
+                &mut state.alarm_status_map,
+                &args.timestamp_format(),
+                notifier,
+            );
+        }
+        DeviceType::Gas => {
+            let level = gas_alarm_level(&device_status);
+            let ppm = device_status["concentration"]["ppm"].as_i64().unwrap_or(0);
+            handle_alarm_status(
+                device_id,
+                device_name.clone(),
+                (level != "normal")
+                    .then(|| format!("Gas {}: {} ppm", level, ppm))
+                    .as_deref(),
+                &mut state.alarm_status_map,
+                &args.timestamp_format(),
+                notifier,
+            );
+            handle_valve_status(
+                device_id,
+                device_name.as_deref(),
+                &device_status,
+                state.readings.get(device),
+                &args.timestamp_format(),
+                notifier,
+            );
+        }
+        DeviceType::Motion if config.notifies_motion(device_id) => {
+            handle_motion_status(
+                device_id,
+                device_name.as_deref(),
+                &device_status,
+                state.readings.get(device),
+                &args.timestamp_format(),
+                notifier,
+            );
         }
-        DeviceType::Window => parse_window_or_door_data(device_status, true, args.format.clone()),
+        _ => {}
+    }
+
+    let parsed = ParsedDevice {
+        device,
+        device_id,
+        device_type: &device_type,
+        config_type,
+        updated_at,
     };
+    let selection = config.field_selection(device_id);
+    // Bars with a short text get the fields of the short format too
+    let short_text = args.output_mode().has_short_text().then(|| {
+        let style = Style {
+            format: OutputFormat::Short,
+            ..args.style()
+        };
+        let fields = device_fields(&parsed, device_status.clone(), args, config, &style);
+        render_fields(&fields, &selection)["text"]
+            .as_str()
+            .unwrap_or_default()
+            .to_string()
+    });
+    let fields = device_fields(&parsed, device_status, args, config, &args.style());
+    let samples = fields.iter().map(Field::sample).collect();
+    let mut output = if args.markup {
+        render_fields_markup(&fields, &selection)
+    } else {
+        render_fields(&fields, &selection)
+    };
+    let classes: Vec<&str> = output_class(&fields)
+        .into_iter()
+        .chain(state_classes(&fields, args.low_battery))
+        .collect();
+    set_classes(&mut output, &classes);
+    if let Some(percentage) = percentage(&fields) {
+        output["percentage"] = Value::from(percentage);
+    }
+    output["alt"] = Value::String(alt(&device_type, &classes));
 
-    if let Some(name) = device_name {
-        output["text"] = serde_json::Value::String(format!(
-            "{} ({})",
-            output["text"].as_str().unwrap_or_default(),
-            name
-        ));
-        output["tooltip"] = serde_json::Value::String(format!(
-            "Device: {}\n{}",
-            name,
-            output["tooltip"].as_str().unwrap_or_default()
+    let text_template = config
+        .text_template(device_id)
+        .or(args.text_template.as_deref());
+    let tooltip_template = config
+        .tooltip_template(device_id)
+        .or(args.tooltip_template.as_deref());
+    // Templates name the device themselves, with `{name}`
+    let render = |template| render_template(template, &fields, device_id, device_name.as_deref());
+    // Waybar parses the text as Pango markup, which `&` or `<` in a name would break
+    let display_name = device_name.as_deref().map(|name| match args.markup {
+        true => format!("<b>{}</b>", escape_markup(name)),
+        false => escape_markup(name).into_owned(),
+    });
+    match text_template {
+        Some(template) => output["text"] = Value::String(render(template)),
+        None => {
+            if let Some(name) = &display_name {
+                output["text"] = serde_json::Value::String(format!(
+                    "{} ({})",
+                    output["text"].as_str().unwrap_or_default(),
+                    name
+                ));
+            }
+        }
+    }
+    match tooltip_template {
+        Some(template) => output["tooltip"] = Value::String(render(template)),
+        None => {
+            if let Some(name) = &display_name {
+                output["tooltip"] = serde_json::Value::String(format!(
+                    "Device: {}\n{}",
+                    name,
+                    output["tooltip"].as_str().unwrap_or_default()
+                ));
+            }
+        }
+    }
+    if args.markup {
+        if let Some(color) = alert_color(&classes) {
+            output["text"] = Value::String(format!(
+                "<span color=\"{}\">{}</span>",
+                color,
+                output["text"].as_str().unwrap_or_default()
+            ));
+        }
+    }
+
+    Some(Reading {
+        output,
+        updated_at,
+        samples,
+        short_text,
+        device_type: device_type.to_string(),
+    })
+}
+
+/// Device entry resolved by `process_device`, whose status is turned into fields.
+struct ParsedDevice<'a> {
+    device: &'a str,
+    device_id: &'a str,
+    device_type: &'a DeviceType,
+    config_type: Option<&'a TypeConfig>,
+    updated_at: DateTime<Utc>,
+}
+
+// Fields of a device status in the given style, as shown in the text and tooltip
+fn device_fields(
+    parsed: &ParsedDevice,
+    device_status: Value,
+    args: &Args,
+    config: &Config,
+    style: &Style,
+) -> Vec<Field> {
+    let ParsedDevice {
+        device, device_id, ..
+    } = *parsed;
+    let tilt_thresholds = TiltThresholds {
+        closed_max: args.tilt_closed_max,
+        wide_open_min: args.tilt_wide_open_min,
+    };
+    let ethernet_ip = ethernet_ip(&device_status).map(str::to_string);
+    let scripts = if args.show_scripts {
+        script_fields(&device_status, style)
+    } else {
+        Vec::new()
+    };
+    let mut fields = match parsed.device_type {
+        DeviceType::Temperature => parse_temperature_data(device_status, style, &args.unit),
+        DeviceType::Plug => {
+            let mut protections = tripped_fields(&device_status, style);
+            protections.extend(overheat_fields(
+                &device_status,
+                style,
+                &args.unit,
+                args.overheat_threshold,
+            ));
+            let mut fields = parse_plug_data(
+                device_status,
+                style,
+                &channel_names(device, device_id, config),
+            );
+            fields.extend(protections);
+            fields
+        }
+        DeviceType::Door => parse_window_or_door_data(device_status, None, style),
+        DeviceType::Window => {
+            parse_window_or_door_data(device_status, Some(tilt_thresholds), style)
+        }
+        DeviceType::Cover => parse_cover_data(device_status, style),
+        DeviceType::Trv => parse_trv_data(device_status, style, &args.unit),
+        DeviceType::Smoke => parse_smoke_data(device_status, style),
+        DeviceType::Gas => parse_gas_data(device_status, style),
+        DeviceType::Motion => parse_motion_data(device_status, style, Utc::now()),
+        DeviceType::Dimmer => parse_dimmer_data(device_status, style),
+        DeviceType::Light => parse_light_data(device_status, style),
+        DeviceType::Rgbw => parse_rgbw_data(device_status, style),
+        DeviceType::Em3 => parse_em3_data(device_status, style),
+        DeviceType::Input => parse_input_data(device_status, style),
+        DeviceType::Uni => parse_uni_data(device_status, style, &config.adc(device_id)),
+        DeviceType::Button => parse_button_data(
+            device_status,
+            style,
+            &args.timestamp_format(),
+            parsed.updated_at,
+        ),
+        DeviceType::Display => parse_display_data(device_status, style, &args.unit),
+        DeviceType::Lux => parse_lux_data(device_status, style),
+        DeviceType::Blu => parse_blu_data(device_status, style, &config.bthome_kinds(device_id)),
+        DeviceType::Custom => match parsed.config_type {
+            Some(config_type) => parse_custom_data(device_status, &config_type.custom),
+            None => parse_custom_data(device_status, &config.custom(device_id)),
+        },
+        DeviceType::Em => parse_em_data(
+            device_status,
+            style,
+            &channel_names(device, device_id, config),
+        ),
+    };
+    if let Some(ip) = ethernet_ip {
+        use_ethernet(&mut fields, ip, style);
+    }
+    fields.extend(scripts);
+    if args.energy_in_text {
+        let is_energy = |key: &str| key == "energy" || key.starts_with("energy_");
+        for field in fields.iter_mut().filter(|field| is_energy(&field.key)) {
+            field.slot = Slot::Text;
+        }
+    }
+    apply_rounding(&mut fields, &config.rounding);
+    fields
+}
+
+// Extract the time the status was produced, from the cloud or the device clock
+fn extract_timestamp(device_status: &Value) -> Option<DateTime<Utc>> {
+    if let Some(updated) = device_status["_updated"].as_str() {
+        if let Ok(naive) = NaiveDateTime::parse_from_str(updated, "%Y-%m-%d %H:%M:%S") {
+            return Some(naive.and_utc());
+        }
+    }
+    device_status["sys"]["unixtime"]
+        .as_i64()
+        .or_else(|| device_status["unixtime"].as_i64())
+        .and_then(|ts| DateTime::from_timestamp(ts, 0))
+}
+
+// Format a duration in seconds as a short human readable age
+fn humanize_age(seconds: i64) -> String {
+    let seconds = seconds.max(0);
+    match seconds {
+        0..=59 => format!("{} s", seconds),
+        60..=3599 => format!("{} min", seconds / 60),
+        3600..=86399 => format!("{} h", seconds / 3600),
+        _ => format!("{} d", seconds / 86400),
+    }
+}
+
+// Annotate a reading with its age, flagging it as stale past the threshold
+fn render_age(
+    reading: &Reading,
+    now: DateTime<Utc>,
+    stale_after: u64,
+    timestamps: &TimestampFormat,
+) -> Value {
+    let age = (now - reading.updated_at).num_seconds();
+    let updated_at = timestamps.format(reading.updated_at);
+    let mut output = reading.output.clone();
+    let text = output["text"].as_str().unwrap_or_default().to_string();
+    let tooltip = output["tooltip"].as_str().unwrap_or_default().to_string();
+
+    if age > stale_after as i64 {
+        // The device stopped answering
+        let mut stale_classes = classes(&reading.output);
+        stale_classes.push("error");
+        set_classes(&mut output, &stale_classes);
+        let label = format!("stale: {}", humanize_age(age));
+        output["text"] = Value::String(format!("{} ({})", text, label));
+        output["tooltip"] = Value::String(format!("{}\n{} ({})", tooltip, label, updated_at));
+    } else {
+        output["tooltip"] = Value::String(format!(
+            "{}\nupdated {} ago ({})",
+            tooltip,
+            humanize_age(age),
+            updated_at
         ));
     }
 
-    Some(output)
+    output
 }
 
 // Parse device information from input string
 fn parse_device_info(device: &str) -> Option<(&str, &str, Option<String>)> {
+    let (device, _) = split_channel_selector(device);
     let parts: Vec<&str> = device.splitn(3, ':').collect();
     if parts.len() < 2 {
         eprintln!("Invalid device format: {}", device);
@@ -200,81 +2132,479 @@ fn parse_device_info(device: &str) -> Option<(&str, &str, Option<String>)> {
 
     let device_type_str = parts[0];
     let device_id = parts[1];
-    let device_name = parts.get(2).map(|s| s.to_string());
+    let mut device_name = parts.get(2).map(|s| s.to_string());
+    // Multi-channel devices take their channel names after the device name
+    if CHANNEL_TYPES.contains(&device_type_str) {
+        device_name = device_name
+            .and_then(|name| name.split(':').next().map(str::to_string))
+            .filter(|name| !name.is_empty());
+    }
 
     Some((device_type_str, device_id, device_name))
 }
 
+// Split the `#<channel>` suffix binding an entry to one channel, e.g. `plug:<device_id>:Office#1`
+fn split_channel_selector(device: &str) -> (&str, Option<usize>) {
+    match device.rsplit_once('#') {
+        Some((rest, channel)) => match channel.parse() {
+            Ok(channel) => (rest, Some(channel)),
+            Err(_) => (device, None),
+        },
+        None => (device, None),
+    }
+}
+
+/// Gen1 lists of channels, indexed by channel.
+const GEN1_CHANNEL_LISTS: [&str; 4] = ["relays", "meters", "emeters", "inputs"];
+
+// Keep only the selected channel of each multi-channel component, as channel 0, so that the
+// parsers render it like a single-channel device
+fn select_channel(status: Value, channel: usize) -> Value {
+    let Value::Object(mut object) = status else {
+        return status;
+    };
+    let selected: Vec<(String, Value)> = object
+        .iter()
+        .filter_map(|(key, value)| {
+            let (component, id) = key.split_once(':')?;
+            (id.parse() == Ok(channel)).then(|| (component.to_string(), value.clone()))
+        })
+        .collect();
+    for (component, value) in selected {
+        object.retain(|key, _| {
+            key.split_once(':')
+                .is_none_or(|(name, _)| name != component)
+        });
+        object.insert(format!("{}:0", component), value);
+    }
+    for list in GEN1_CHANNEL_LISTS {
+        if let Some(Value::Array(items)) = object.get_mut(list) {
+            if channel < items.len() {
+                *items = vec![items.swap_remove(channel)];
+            }
+        }
+    }
+    Value::Object(object)
+}
+
+// Channel names from the device argument, or else from the config
+fn channel_names(device: &str, device_id: &str, config: &Config) -> Vec<String> {
+    match parse_channel_names(device) {
+        names if names.is_empty() => config.channel_names(device_id).to_vec(),
+        names => names,
+    }
+}
+
+/// Device types accepting channel names in the device argument.
+const CHANNEL_TYPES: [&str; 2] = ["em", "plug"];
+
+// Channel names of a multi-channel device, from `<device_type>:<device_id>:<device_name>:<channel>,<channel>`
+fn parse_channel_names(device: &str) -> Vec<String> {
+    let (device, _) = split_channel_selector(device);
+    let parts: Vec<&str> = device.splitn(4, ':').collect();
+    match parts.as_slice() {
+        [device_type, _, _, channels] if CHANNEL_TYPES.contains(device_type) => channels
+            .split(',')
+            .map(|name| name.trim().to_string())
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+// Address of a device polled on the LAN, from its config or its ID with --local
+fn local_host<'a>(args: &Args, config: &'a Config, device_id: &'a str) -> Option<&'a str> {
+    config
+        .host(device_id)
+        .or_else(|| args.local.then_some(device_id))
+}
+
+// Basic auth of the devices on the LAN, read once so that an unreadable password file stops
+// shelly-waybar at startup rather than in the middle of polling. Devices without a password in
+// their config or environment get the one saved with --store-password, if any.
+async fn devices_credentials(
+    args: &Args,
+    config: &Config,
+) -> Result<HashMap<String, Credentials>, io::Error> {
+    let mut credentials = HashMap::new();
+    for device in &args.devices {
+        let Some((_, device_id, _)) = parse_device_info(device) else {
+            continue;
+        };
+        if local_host(args, config, device_id).is_none() || credentials.contains_key(device_id) {
+            continue;
+        }
+        let login = match local_credentials(config, device_id)? {
+            Some(login) => login,
+            None => match keyring::lookup(&keyring::password_account(device_id)).await {
+                Some(password) => Credentials {
+                    username: config.login(device_id).0.unwrap_or("admin").to_string(),
+                    password,
+                },
+                None => continue,
+            },
+        };
+        credentials.insert(device_id.to_string(), login);
+    }
+    Ok(credentials)
+}
+
+// Basic auth of a protected Gen1 device on the LAN, with the password from its config or the
+// SHELLY_WAYBAR_PASSWORD_<DEVICE_ID> environment variable
+fn local_credentials(config: &Config, device_id: &str) -> Result<Option<Credentials>, io::Error> {
+    let (username, password) = config.login(device_id);
+    let password = match password {
+        Some(password) => resolve_input(password.expose())?,
+        None => match env::var(password_variable(device_id)) {
+            Ok(password) => password,
+            Err(_) => return Ok(None),
+        },
+    };
+    Ok(Some(Credentials {
+        username: username.unwrap_or("admin").to_string(),
+        password: Secret::new(password),
+    }))
+}
+
+// e.g. `SHELLY_WAYBAR_PASSWORD_SHELLYPLUG_S_1234` for `shellyplug-s-1234`
+fn password_variable(device_id: &str) -> String {
+    let device_id: String = device_id
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() {
+                c.to_ascii_uppercase()
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    format!("SHELLY_WAYBAR_PASSWORD_{}", device_id)
+}
+
+/// Fetches the status of each device, from the bulk endpoint or with up to
+/// `concurrency` requests in flight.
+#[allow(clippy::too_many_arguments)]
+async fn fetch_statuses(
+    client: &Client,
+    base_url: &str,
+    auth_key: &Secret,
+    device_ids: &[&str],
+    concurrency: usize,
+    bulk: bool,
+    limiter: &RateLimiter,
+    retry: &RetryPolicy,
+) -> HashMap<String, Result<Value, FetchError>> {
+    if bulk {
+        let mut all = match fetch_all_statuses(client, base_url, auth_key, limiter).await {
+            Ok(all) => all,
+            Err(err) => {
+                return device_ids
+                    .iter()
+                    .map(|device_id| (device_id.to_string(), Err(err.clone())))
+                    .collect()
+            }
+        };
+        return device_ids
+            .iter()
+            .map(|device_id| {
+                let status = all.remove(*device_id).ok_or_else(|| {
+                    eprintln!("Error: Device {} missing from the bulk status", device_id);
+                    FetchError::Failed
+                });
+                (device_id.to_string(), status)
+            })
+            .collect();
+    }
+
+    stream::iter(device_ids)
+        .map(|device_id| async move {
+            let status =
+                fetch_device_status(client, base_url, device_id, auth_key, limiter, retry).await;
+            (device_id.to_string(), status)
+        })
+        .buffer_unordered(concurrency.max(1))
+        .collect()
+        .await
+}
+
 // Fetch device status from API
 async fn fetch_device_status(
     client: &Client,
     base_url: &str,
     device_id: &str,
-    auth_key: &str,
-) -> Option<Value> {
-    let full_url = format!("{}/device/status", base_url);
-
-    let response = client
-        .post(&full_url)
-        .form(&[("id", device_id), ("auth_key", auth_key)])
-        .send()
-        .await
-        .ok()?;
+    auth_key: &Secret,
+    limiter: &RateLimiter,
+    retry: &RetryPolicy,
+) -> Result<Value, FetchError> {
+    let url = format!("{}/device/status", base_url);
+    let form = [("id", device_id), ("auth_key", auth_key.expose())];
+    retry
+        .run(
+            || post_cloud(client, &url, &form, auth_key, limiter),
+            |err| *err == FetchError::Unavailable,
+        )
+        .await?
+        .device_status
+        .map(gen1::normalize)
+        .ok_or(FetchError::Failed)
+}
+
+// Fetch the status of every device of the account in a single request
+async fn fetch_all_statuses(
+    client: &Client,
+    base_url: &str,
+    auth_key: &Secret,
+    limiter: &RateLimiter,
+) -> Result<HashMap<String, Value>, FetchError> {
+    let url = format!("{}/device/all_status", base_url);
+    let form = [("auth_key", auth_key.expose())];
+    let statuses = post_cloud(client, &url, &form, auth_key, limiter)
+        .await?
+        .devices_status
+        .ok_or(FetchError::Failed)?;
+    Ok(statuses
+        .into_iter()
+        .map(|(device_id, status)| (device_id, gen1::normalize(status)))
+        .collect())
+}
+
+/// Pause in seconds after a 429 response without `Retry-After`.
+const DEFAULT_RETRY_AFTER: u64 = 5;
+
+/// Longest pause in seconds honoured from a `Retry-After`, so polling resumes.
+const MAX_RETRY_AFTER: u64 = 300;
+
+// Post a form to the cloud API and unwrap its data, reporting API errors
+async fn post_cloud(
+    client: &Client,
+    url: &str,
+    form: &[(&str, &str)],
+    auth_key: &Secret,
+    limiter: &RateLimiter,
+) -> Result<ShellyData, FetchError> {
+    limiter.acquire().await;
+    let response = client.post(url).form(form).send().await.map_err(|err| {
+        if err.is_timeout() {
+            eprintln!("Error: Cloud request timed out - {}", url);
+        }
+        FetchError::Unavailable
+    })?;
+
+    if response.status().is_server_error() {
+        eprintln!("Error: Cloud answered {} - {}", response.status(), url);
+        return Err(FetchError::Unavailable);
+    }
 
-    let status: ShellyResponse = response.json().await.ok()?;
+    if response.status() == StatusCode::TOO_MANY_REQUESTS {
+        let retry_after = response
+            .headers()
+            .get(RETRY_AFTER)
+            .and_then(|value| value.to_str().ok()?.parse().ok())
+            .unwrap_or(DEFAULT_RETRY_AFTER)
+            .min(MAX_RETRY_AFTER);
+        eprintln!(
+            "Error: Cloud rate limit reached, pausing requests for {} s",
+            retry_after
+        );
+        limiter.pause(Duration::from_secs(retry_after));
+        return Err(FetchError::Failed);
+    }
+
+    let status: ShellyResponse = response.json().await.map_err(|err| {
+        if err.is_timeout() {
+            eprintln!("Error: Cloud request timed out - {}", url);
+            return FetchError::Unavailable;
+        }
+        FetchError::Failed
+    })?;
 
     if !status.isok {
         if let Some(errors) = status.errors {
             if let Some(error_message) = errors.get("invalid_token") {
                 eprintln!(
                     "Error: Invalid token - {}",
-                    error_message.as_str().unwrap_or("Unknown error")
+                    auth_key.redact(error_message.as_str().unwrap_or("Unknown error"))
                 );
+                return Err(FetchError::InvalidToken);
             } else {
-                eprintln!("Error: API returned an error - {errors}");
+                eprintln!(
+                    "Error: API returned an error - {}",
+                    auth_key.redact(&errors.to_string())
+                );
             }
         } else {
             eprintln!("Error: Unknown error occurred.");
         }
-        return None;
+        return Err(FetchError::Failed);
     }
 
-    status.data?.device_status
+    status.data.ok_or(FetchError::Failed)
+}
+
+// Output shown in place of the devices while the cloud rejects the auth key
+fn auth_required_output() -> Value {
+    serde_json::json!({
+        "text": "Shelly: auth required",
+        "tooltip": "The Shelly cloud rejected the auth key.\nRun `shelly-waybar login` or update --auth-key.",
+        "class": "auth-required"
+    })
+}
+
+// Notify once when the auth key gets rejected
+fn notify_auth_required(notifier: &dyn Notifier) {
+    if let Err(err) = notifier.notify(
+        "Shelly: authentication required",
+        "The Shelly cloud rejected the auth key. Run `shelly-waybar login` to get a new one.",
+        Urgency::Critical,
+    ) {
+        eprintln!("Error: Unable to send notification - {}", err);
+    }
 }
 
 // Match device type from string
 fn match_device_type(device_type_str: &str) -> Option<DeviceType> {
-    match device_type_str.to_lowercase().as_str() {
-        "temperature" => Some(DeviceType::Temperature),
-        "plug" => Some(DeviceType::Plug),
+    let name = device_type_str.to_lowercase();
+    // Plural forms, e.g. `doors` or `switches`
+    let device_type = device_type_by_name(&name)
+        .or_else(|| name.strip_suffix('s').and_then(device_type_by_name))
+        .or_else(|| name.strip_suffix("es").and_then(device_type_by_name));
+    if device_type.is_none() {
+        eprintln!(
+            "Unsupported device type: '{}'. Supported types are: temperature, humidity, plug, door, window, cover, trv, smoke, gas, motion, dimmer, light, rgbw, em3, em, input, uni, blu, display, lux, button, custom.",
+            device_type_str
+        );
+    }
+    device_type
+}
+
+fn device_type_by_name(name: &str) -> Option<DeviceType> {
+    match name {
+        "temperature" | "humidity" | "temp" | "ht" => Some(DeviceType::Temperature),
+        "plug" | "socket" | "relay" | "switch" => Some(DeviceType::Plug),
         "door" => Some(DeviceType::Door),
         "window" => Some(DeviceType::Window),
-        _ => {
-            eprintln!(
-                "Unsupported device type: '{}'. Supported types are: temperature, plug, door, window.",
-                device_type_str
-            );
-            None
-        }
+        "cover" | "shutter" | "roller" => Some(DeviceType::Cover),
+        "trv" | "thermostat" => Some(DeviceType::Trv),
+        "smoke" => Some(DeviceType::Smoke),
+        "gas" => Some(DeviceType::Gas),
+        "motion" => Some(DeviceType::Motion),
+        "dimmer" => Some(DeviceType::Dimmer),
+        "light" | "bulb" => Some(DeviceType::Light),
+        "rgbw" => Some(DeviceType::Rgbw),
+        "em3" => Some(DeviceType::Em3),
+        "em" => Some(DeviceType::Em),
+        "input" => Some(DeviceType::Input),
+        "uni" => Some(DeviceType::Uni),
+        "blu" => Some(DeviceType::Blu),
+        "display" => Some(DeviceType::Display),
+        "lux" => Some(DeviceType::Lux),
+        "button" => Some(DeviceType::Button),
+        "custom" => Some(DeviceType::Custom),
+        _ => None,
     }
 }
 
 // Autodetect device type from JSON
 fn autodetect_device_type(json: &Value) -> Option<DeviceType> {
-    if json.get("temperature:0").is_some() || json.get("humidity:0").is_some() {
-        return Some(DeviceType::Temperature);
+    // The first of the best scored candidates
+    let best = device_type_scores(json)
+        .into_iter()
+        .fold(None, |best, (device_type, score)| match best {
+            Some((_, best_score)) if best_score >= score => best,
+            _ => Some((device_type, score)),
+        });
+    if best.is_none() {
+        eprintln!("Unable to autodetect device type.");
     }
-    if json.get("switch:0").is_some() {
-        return Some(DeviceType::Plug);
+    best.map(|(device_type, _)| device_type)
+}
+
+// Candidate types of a status, scored by how specific their components are, since a Wall
+// Display also has a relay and a temperature. Components are matched at any index, e.g. an
+// Add-on probe at `temperature:100`.
+fn device_type_scores(json: &Value) -> Vec<(DeviceType, u32)> {
+    let has = |component: &str| !component_ids(json, component).is_empty();
+    let relay = has("switch") || has("pm1");
+    let mut scores = Vec::new();
+
+    if trv_status(json).is_some() {
+        scores.push((DeviceType::Trv, 100));
     }
-    if json.get("window:0").is_some() {
-        return Some(DeviceType::Door);
+    if has("smoke") {
+        scores.push((DeviceType::Smoke, 90));
     }
-    if json.get("tilt:0").is_some() {
-        return Some(DeviceType::Window);
+    if json.get("gas_sensor").is_some() {
+        scores.push((DeviceType::Gas, 90));
     }
-    eprintln!("Unable to autodetect device type.");
-    None
+    if json["sensor"].get("motion").is_some() {
+        scores.push((DeviceType::Motion, 80));
+    }
+    if has("light") {
+        // Bulbs with a white channel report its color temperature
+        let white = component_ids(json, "light").iter().any(|id| {
+            let light = &json[format!("light:{}", id)];
+            light.get("ct").is_some() || light.get("white").is_some()
+        });
+        scores.push((
+            if white {
+                DeviceType::Light
+            } else {
+                DeviceType::Dimmer
+            },
+            70,
+        ));
+    }
+    if has("rgbw") || has("rgb") {
+        scores.push((DeviceType::Rgbw, 70));
+    }
+    if has("em") {
+        scores.push((DeviceType::Em3, 70));
+    }
+    if json.get("emeters").is_some() || has("em1") {
+        scores.push((DeviceType::Em, 70));
+    }
+    if json.get("adcs").is_some() {
+        scores.push((DeviceType::Uni, 70));
+    }
+    if has("temperature") && has("illuminance") {
+        scores.push((DeviceType::Display, 60));
+    }
+    // Relays with an Add-on report its probes from id 100, which do not make them sensors
+    let sensor = match relay {
+        true => json.get("temperature:0").is_some() || json.get("humidity:0").is_some(),
+        false => has("temperature") || has("humidity"),
+    };
+    if sensor {
+        scores.push((DeviceType::Temperature, 50));
+    }
+    if has("cover") {
+        scores.push((DeviceType::Cover, 50));
+    }
+    if relay {
+        scores.push((DeviceType::Plug, 40));
+    }
+    // Relays have inputs too, only input-only devices such as the i4 are inputs
+    if component_ids(json, "input").len() > 1 && !relay {
+        scores.push((DeviceType::Input, 30));
+    }
+    // Button1 and i3 report their last event per input
+    if json["inputs"][0].get("event").is_some() {
+        scores.push((DeviceType::Button, 30));
+    }
+    // Gateways that are also relays need the explicit `blu` type
+    if has("bthomesensor") {
+        scores.push((DeviceType::Blu, 30));
+    }
+    // Door/Window sensors report a `window`, those measuring the tilt a `tilt` as well
+    if has("tilt") {
+        scores.push((DeviceType::Window, 25));
+    }
+    if has("window") {
+        scores.push((DeviceType::Door, 20));
+    }
+    if has("illuminance") {
+        scores.push((DeviceType::Lux, 10));
+    }
+    scores
 }
 
 // Handle door status changes and notifications
@@ -283,6 +2613,8 @@ fn handle_door_status(
     device_name: Option<String>,
     device_status: &Value,
     door_status_map: &mut HashMap<String, bool>,
+    timestamps: &TimestampFormat,
+    notifier: &dyn Notifier,
 ) -> Option<()> {
     let is_open = device_status["window:0"]["open"].as_bool().unwrap_or(false);
     let status_key = format!("{}:{}", device_id, device_name.clone().unwrap_or_default());
@@ -291,10 +2623,16 @@ fn handle_door_status(
         if *prev_status != is_open {
             let state = if is_open { "Open" } else { "Closed" };
             let name = device_name.unwrap_or_else(|| "Unnamed Door".to_string());
-            Notification::new()
-                .summary(&format!("Door Status Changed: {}", name))
-                .body(&format!("The door is now {}", state))
-                .show()
+            notifier
+                .notify(
+                    &format!("Door Status Changed: {}", name),
+                    &format!(
+                        "The door is now {} ({})",
+                        state,
+                        timestamps.format(Utc::now())
+                    ),
+                    Urgency::Normal,
+                )
                 .ok()?;
         }
     }
@@ -303,296 +2641,4920 @@ fn handle_door_status(
     Some(())
 }
 
-// Parsing functions remain the same
-fn parse_temperature_data(device_status: Value, format: OutputFormat, unit: &str) -> Value {
-    let temp_c = device_status["temperature:0"]["tC"].as_f64().unwrap_or(0.0);
-    let temp_f = device_status["temperature:0"]["tF"].as_f64().unwrap_or(0.0);
-    let humidity = device_status["humidity:0"]["rh"].as_u64().unwrap_or(0);
-    let battery = device_status["devicepower:0"]["battery"]["percent"]
-        .as_u64()
-        .unwrap_or(0);
-    let rssi = device_status["reporter"]["rssi"].as_i64().unwrap_or(0);
+// Critical notification whenever a safety device alarm starts or stops,
+// `alarm` describing what is going on while it rings
+fn handle_alarm_status(
+    device_id: &str,
+    device_name: Option<String>,
+    alarm: Option<&str>,
+    alarm_status_map: &mut HashMap<String, bool>,
+    timestamps: &TimestampFormat,
+    notifier: &dyn Notifier,
+) {
+    let status_key = format!("{}:{}", device_id, device_name.clone().unwrap_or_default());
+    // An alarm already ringing at startup is worth a notification too
+    let previous = alarm_status_map
+        .insert(status_key, alarm.is_some())
+        .unwrap_or(false);
+    if previous == alarm.is_some() {
+        return;
+    }
 
-    let (temp, unit_label) = if unit == "F" {
-        (temp_f, "°F")
-    } else {
-        (temp_c, "°C")
+    let name = device_name.unwrap_or_else(|| device_id.to_string());
+    let (summary, body) = match alarm {
+        Some(description) => (format!("Alarm: {}", name), description),
+        None => (format!("Alarm cleared: {}", name), "Back to normal"),
     };
-
-    match format {
-        OutputFormat::Short => serde_json::json!({
-            "text": format!("T: {:.1}{} H: {}%", temp, unit_label, humidity),
-            "tooltip": format!("B: {}% RSSI: {}dBm", battery, rssi)
-        }),
-        OutputFormat::Long => serde_json::json!({
-            "text": format!("Temp: {:.1}{} Humidity: {}%", temp, unit_label, humidity),
-            "tooltip": format!("Battery: {}% RSSI: {}dBm", battery, rssi)
-        }),
-        OutputFormat::Icons => serde_json::json!({
-            "text": format!("{:.1}{} 💧{}%", temp, unit_label, humidity),
-            "tooltip": format!("🔋{}% 📶{}dBm", battery, rssi)
-        }),
+    if let Err(err) = notifier.notify(
+        &summary,
+        &format!("{} ({})", body, timestamps.format(Utc::now())),
+        Urgency::Critical,
+    ) {
+        eprintln!("Error: Unable to send notification - {}", err);
     }
 }
 
-fn parse_plug_data(device_status: Value, format: OutputFormat) -> Value {
-    let power = device_status["switch:0"]["apower"].as_f64().unwrap_or(0.0);
-    let voltage = device_status["switch:0"]["voltage"].as_f64().unwrap_or(0.0);
-    let current = device_status["switch:0"]["current"].as_f64().unwrap_or(0.0);
-    let output = device_status["switch:0"]["output"]
-        .as_bool()
-        .unwrap_or(false);
-    let rssi = device_status["wifi"]["rssi"].as_i64().unwrap_or(0);
-
-    let output_state = if output { "ON" } else { "OFF" };
+// Notify when a motion sensor goes from no motion to motion
+fn handle_motion_status(
+    device_id: &str,
+    device_name: Option<&str>,
+    device_status: &Value,
+    previous: Option<&Reading>,
+    timestamps: &TimestampFormat,
+    notifier: &dyn Notifier,
+) {
+    let motion = device_status["sensor"]["motion"].as_bool().unwrap_or(false);
+    if !motion || was_active(previous, "motion") {
+        return;
+    }
 
-    match format {
-        OutputFormat::Short => serde_json::json!({
-            "text": format!("P: {:.1}W V: {:.1}V", power, voltage),
-            "tooltip": format!("I: {:.3}A RSSI: {}dBm O: {}", current, rssi, output_state)
-        }),
-        OutputFormat::Long => serde_json::json!({
-            "text": format!("Power: {:.1}W Voltage: {:.1}V", power, voltage),
-            "tooltip": format!("Current: {:.3}A WiFi RSSI: {}dBm Output: {}", current, rssi, output_state)
-        }),
-        OutputFormat::Icons => serde_json::json!({
-            "text": format!("⚡{:.1}W 🔌{:.1}V", power, voltage),
-            "tooltip": format!("🔋{:.3}A 📶{}dBm 🔆{}", current, rssi, output_state)
-        }),
+    let name = device_name.unwrap_or(device_id);
+    if let Err(err) = notifier.notify(
+        &format!("Motion: {}", name),
+        &format!("Motion detected ({})", timestamps.format(Utc::now())),
+        Urgency::Normal,
+    ) {
+        eprintln!("Error: Unable to send notification - {}", err);
     }
 }
 
-fn parse_window_or_door_data(device_status: Value, is_window: bool, format: OutputFormat) -> Value {
-    let is_open = device_status["window:0"]["open"].as_bool().unwrap_or(false);
-    let lux = device_status["illuminance:0"]["lux"].as_u64().unwrap_or(0);
-    let battery = device_status["devicepower:0"]["battery"]["percent"]
-        .as_u64()
-        .unwrap_or(0);
-    let rssi = device_status["reporter"]["rssi"].as_i64().unwrap_or(0);
-
-    let state = if is_open { "Open" } else { "Closed" };
-    let tilt = if is_window {
-        format!(
-            ", Tilt: {}",
-            device_status["tilt:0"]["angle"].as_u64().unwrap_or(0)
-        )
-    } else {
-        "".to_string()
-    };
+// Notify when a Door/Window 2 starts vibrating, a sign of tampering
+fn handle_vibration_status(
+    device_id: &str,
+    device_name: Option<&str>,
+    device_status: &Value,
+    previous: Option<&Reading>,
+    timestamps: &TimestampFormat,
+    notifier: &dyn Notifier,
+) {
+    if device_status["accel"]["vibration"].as_i64() != Some(1) || was_active(previous, "vibration")
+    {
+        return;
+    }
 
-    match format {
-        OutputFormat::Short => serde_json::json!({
-            "text": format!("{}: L: {}{}", state, lux, tilt),
-            "tooltip": format!("B: {}% RSSI: {}dBm", battery, rssi)
-        }),
-        OutputFormat::Long => serde_json::json!({
-            "text": format!("{}, Lux: {}{}", state, lux, tilt),
-            "tooltip": format!("Battery: {}% RSSI: {}dBm", battery, rssi)
-        }),
-        OutputFormat::Icons => serde_json::json!({
-            "text": format!("{} 🔆{}{}", if is_open { "🟢" } else { "🔴" }, lux, tilt),
-            "tooltip": format!("🔋{}% 📶{}dBm", battery, rssi)
-        }),
+    let name = device_name.unwrap_or(device_id);
+    if let Err(err) = notifier.notify(
+        &format!("Vibration: {}", name),
+        &format!("Vibration detected ({})", timestamps.format(Utc::now())),
+        Urgency::Critical,
+    ) {
+        eprintln!("Error: Unable to send notification - {}", err);
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use serde_json::json;
+// Notify when the valve of a Shelly Gas closes, usually on its own after an alarm
+fn handle_valve_status(
+    device_id: &str,
+    device_name: Option<&str>,
+    device_status: &Value,
+    previous: Option<&Reading>,
+    timestamps: &TimestampFormat,
+    notifier: &dyn Notifier,
+) {
+    let was_open = previous
+        .and_then(|reading| reading.samples.iter().find(|sample| sample.key == "valve"))
+        .is_some_and(|sample| sample.value != "closed");
+    if valve_state(device_status) != Some("closed") || !was_open {
+        return;
+    }
 
-    // Test: Autodetect Device Type
-    #[test]
-    fn test_autodetect_device_type() {
-        let temp_json = json!({ "temperature:0": { "tC": 22.5 } });
-        let plug_json = json!({ "switch:0": { "apower": 50.0 } });
-        let door_json = json!({ "window:0": { "open": true } });
-        let window_json = json!({ "tilt:0": { "angle": 30 } });
-        let unknown_json = json!({});
+    let name = device_name.unwrap_or(device_id);
+    if let Err(err) = notifier.notify(
+        &format!("Valve closed: {}", name),
+        &format!("The gas valve closed ({})", timestamps.format(Utc::now())),
+        Urgency::Critical,
+    ) {
+        eprintln!("Error: Unable to send notification - {}", err);
+    }
+}
 
-        assert_eq!(
-            autodetect_device_type(&temp_json),
-            Some(DeviceType::Temperature)
-        );
-        assert_eq!(autodetect_device_type(&plug_json), Some(DeviceType::Plug));
-        assert_eq!(autodetect_device_type(&door_json), Some(DeviceType::Door));
-        assert_eq!(
-            autodetect_device_type(&window_json),
-            Some(DeviceType::Window)
-        );
-        assert_eq!(autodetect_device_type(&unknown_json), None);
+// Notify when the internal temperature of a relay goes over the threshold
+fn handle_overheat_status(
+    device_id: &str,
+    device_name: Option<&str>,
+    device_status: &Value,
+    threshold: f64,
+    previous: Option<&Reading>,
+    timestamps: &TimestampFormat,
+    notifier: &dyn Notifier,
+) {
+    let Some(temperature) = switch_temperature(device_status, "tC") else {
+        return;
+    };
+    if temperature <= threshold || was_active(previous, "overheat") {
+        return;
     }
 
-    // Test: Match Device Type
-    #[test]
-    fn test_match_device_type() {
-        assert_eq!(
-            match_device_type("temperature"),
-            Some(DeviceType::Temperature)
-        );
-        assert_eq!(match_device_type("plug"), Some(DeviceType::Plug));
-        assert_eq!(match_device_type("door"), Some(DeviceType::Door));
-        assert_eq!(match_device_type("window"), Some(DeviceType::Window));
-        assert_eq!(match_device_type("unknown"), None);
+    let name = device_name.unwrap_or(device_id);
+    if let Err(err) = notifier.notify(
+        &format!("Overheating: {}", name),
+        &format!(
+            "Internal temperature {:.1}°C ({})",
+            temperature,
+            timestamps.format(Utc::now())
+        ),
+        Urgency::Critical,
+    ) {
+        eprintln!("Error: Unable to send notification - {}", err);
     }
+}
 
-    // Test: Parse Device Info
-    #[test]
-    fn test_parse_device_info() {
-        let device = "temperature:12345:Living Room";
-        let device_with_no_name = "plug:67890";
-        let invalid_device = "invalidformat";
+// Whether a boolean field was already true in the previous reading
+fn was_active(previous: Option<&Reading>, key: &str) -> bool {
+    previous
+        .and_then(|reading| reading.samples.iter().find(|sample| sample.key == key))
+        .is_some_and(|sample| sample.value == Value::Bool(true))
+}
 
-        assert_eq!(
-            parse_device_info(device),
-            Some(("temperature", "12345", Some("Living Room".to_string())))
-        );
-        assert_eq!(
-            parse_device_info(device_with_no_name),
-            Some(("plug", "67890", None))
-        );
-        assert_eq!(parse_device_info(invalid_device), None);
+// CSS class of a device output, so Waybar styles can highlight alarms
+fn output_class(fields: &[Field]) -> Option<&'static str> {
+    let raw = |key: &str| fields.iter().find(|field| field.key == key)?.raw.as_ref();
+    match raw("alarm") {
+        Some(Value::Bool(true)) => return Some("alarm"),
+        Some(Value::String(level)) if level == "alarm" => return Some("alarm"),
+        Some(Value::String(level)) if level == "warning" => return Some("warning"),
+        _ => {}
     }
+    (raw("overheat") == Some(&Value::Bool(true))).then_some("warning")
+}
 
-    // Test: Parse Temperature Data
-    #[test]
-    fn test_parse_temperature_data() {
-        let device_status = json!({
-            "temperature:0": { "tC": 22.5, "tF": 72.5 },
-            "humidity:0": { "rh": 50 },
-            "devicepower:0": { "battery": { "percent": 80 } },
-            "reporter": { "rssi": -60 }
-        });
+// CSS classes of the state of a device, so Waybar styles can tell an open door or a plug
+// switched off
+fn state_classes(fields: &[Field], low_battery: i64) -> Vec<&'static str> {
+    let mut classes = Vec::new();
+    let outputs: Vec<&Metric> = fields
+        .iter()
+        .filter(|field| field.key == "output" || field.key.starts_with("output_"))
+        .map(|field| &field.value)
+        .collect();
+    if !outputs.is_empty() {
+        let on = outputs
+            .iter()
+            .any(|value| **value == Metric::Text("ON".to_string()));
+        classes.push(if on { "on" } else { "off" });
+    }
+    match fields
+        .iter()
+        .find(|field| field.key == "state")
+        .and_then(|field| field.raw.as_ref()?.as_str())
+    {
+        Some("open") => classes.push("open"),
+        Some("closed") => classes.push("closed"),
+        _ => {}
+    }
+    let battery = fields.iter().find(|field| field.key == "battery");
+    if let Some(Metric::Int(percent)) = battery.map(|field| &field.value) {
+        if *percent < low_battery {
+            classes.push("low-battery");
+        }
+    }
+    classes
+}
 
-        let output = parse_temperature_data(device_status.clone(), OutputFormat::Short, "C");
-        assert_eq!(output["text"], "T: 22.5°C H: 50%");
-        assert_eq!(output["tooltip"], "B: 80% RSSI: -60dBm");
+// Waybar `alt` of a device, its type and main state, e.g. `door-open`, `plug-off`, `smoke-alarm`
+fn alt(device_type: &DeviceType, classes: &[&str]) -> String {
+    match classes.iter().find(|class| **class != "low-battery") {
+        Some(state) => format!("{}-{}", device_type, state),
+        None => device_type.to_string(),
+    }
+}
 
-        let output = parse_temperature_data(device_status.clone(), OutputFormat::Long, "F");
-        assert_eq!(output["text"], "Temp: 72.5°F Humidity: 50%");
-        assert_eq!(output["tooltip"], "Battery: 80% RSSI: -60dBm");
+// Fields filling the Waybar `percentage`, by priority: a battery is only shown for devices
+// with nothing else to show as a bar
+const PERCENTAGE_KEYS: [&str; 4] = ["position", "brightness", "humidity", "battery"];
 
-        let output = parse_temperature_data(device_status, OutputFormat::Icons, "C");
-        assert_eq!(output["text"], "22.5°C 💧50%");
-        assert_eq!(output["tooltip"], "🔋80% 📶-60dBm");
+// Value of the Waybar `percentage`, for `{percentage}` and `format-icons` ramps
+fn percentage(fields: &[Field]) -> Option<i64> {
+    PERCENTAGE_KEYS.iter().find_map(|key| {
+        let field = fields.iter().find(|field| field.key == *key)?;
+        let value = match field.value {
+            Metric::Int(value) => value,
+            Metric::Float(value, _) => value.round() as i64,
+            Metric::Text(_) => return None,
+        };
+        Some(value.clamp(0, 100))
+    })
+}
+
+// Sets the `class` of an output, a single one as a string like Waybar examples
+fn set_classes(output: &mut Value, classes: &[&str]) {
+    match classes {
+        [] => {}
+        [class] => output["class"] = Value::String(class.to_string()),
+        classes => output["class"] = serde_json::json!(classes),
     }
+}
 
-    // Test: Parse Plug Data
-    #[test]
-    fn test_parse_plug_data() {
-        let device_status = json!({
-            "switch:0": { "apower": 50.0, "voltage": 230.0, "current": 0.217, "output": true },
-            "wifi": { "rssi": -70 }
-        });
+// Classes of an output, whether a string or an array
+fn classes(output: &Value) -> Vec<&str> {
+    match &output["class"] {
+        Value::String(class) => vec![class],
+        Value::Array(classes) => classes.iter().filter_map(Value::as_str).collect(),
+        _ => Vec::new(),
+    }
+}
 
-        let output = parse_plug_data(device_status.clone(), OutputFormat::Short);
-        assert_eq!(output["text"], "P: 50.0W V: 230.0V");
-        assert_eq!(output["tooltip"], "I: 0.217A RSSI: -70dBm O: ON");
+// Battery level from `devicepower:0`, or the power source of devices that can run
+// on mains or USB (H&T Gen3, Plus Smoke...), since missing batteries would read 0%
+fn power_fields(device_status: &Value, style: &Style) -> Vec<Field> {
+    let devicepower = &device_status["devicepower:0"];
+    let battery = devicepower["battery"]["percent"].as_i64().unwrap_or(0);
+    let external_power = devicepower["external"]["present"].as_bool();
 
-        let output = parse_plug_data(device_status.clone(), OutputFormat::Long);
-        assert_eq!(output["text"], "Power: 50.0W Voltage: 230.0V");
-        assert_eq!(
-            output["tooltip"],
-            "Current: 0.217A WiFi RSSI: -70dBm Output: ON"
+    let mut fields = Vec::new();
+    if let Some(external_power) = external_power {
+        let source = match (&style.format, external_power) {
+            (OutputFormat::Icons, true) => style.icon(Icon::Usb),
+            (OutputFormat::Icons, false) => style.icon(Icon::Battery),
+            (OutputFormat::Short, true) => "ext",
+            (OutputFormat::Long, true) => "External",
+            (_, false) => "Battery",
+        };
+        fields.push(
+            Field::tooltip(
+                "power_source",
+                style.label("Src", "Power", Icon::Power),
+                Metric::Text(source.to_string()),
+            )
+            .raw(Value::String(
+                if external_power {
+                    "external"
+                } else {
+                    "battery"
+                }
+                .to_string(),
+            )),
+        );
+    }
+    if external_power != Some(true) {
+        fields.push(
+            Field::tooltip(
+                "battery",
+                style.label("B", "Battery", Icon::Battery),
+                Metric::Int(battery),
+            )
+            .unit("%"),
         );
-
-        let output = parse_plug_data(device_status, OutputFormat::Icons);
-        assert_eq!(output["text"], "⚡50.0W 🔌230.0V");
-        assert_eq!(output["tooltip"], "🔋0.217A 📶-70dBm 🔆ON");
     }
+    if devicepower["battery"]["charging"].as_bool() == Some(true) {
+        fields.push(Field::tooltip(
+            "charging",
+            String::new(),
+            Metric::Text("charging".to_string()),
+        ));
+    }
+    fields
+}
 
-    // Test: Parse Window/Door Data
-    #[test]
-    fn test_parse_window_or_door_data() {
-        let device_status = json!({
-            "window:0": { "open": true },
-            "illuminance:0": { "lux": 100 },
-            "devicepower:0": { "battery": { "percent": 90 } },
-            "reporter": { "rssi": -65 },
-            "tilt:0": { "angle": 30 }
-        });
+// Ids of the `<component>:<id>` entries of a status, in order
+fn component_ids(device_status: &Value, component: &str) -> Vec<u64> {
+    let prefix = format!("{}:", component);
+    let mut ids: Vec<u64> = device_status
+        .as_object()
+        .map(|object| {
+            object
+                .keys()
+                .filter_map(|key| key.strip_prefix(&prefix)?.parse().ok())
+                .collect()
+        })
+        .unwrap_or_default();
+    ids.sort_unstable();
+    ids
+}
 
-        let output = parse_window_or_door_data(device_status.clone(), true, OutputFormat::Short);
-        assert_eq!(output["text"], "Open: L: 100, Tilt: 30");
-        assert_eq!(output["tooltip"], "B: 90% RSSI: -65dBm");
+fn parse_temperature_data(device_status: Value, style: &Style, unit: &str) -> Vec<Field> {
+    let rssi = device_status["reporter"]["rssi"].as_i64().unwrap_or(0);
 
-        let output = parse_window_or_door_data(device_status.clone(), false, OutputFormat::Long);
-        assert_eq!(output["text"], "Open, Lux: 100");
-        assert_eq!(output["tooltip"], "Battery: 90% RSSI: -65dBm");
+    // The device sensor comes first, then Add-on probes (temperature:100, humidity:100...)
+    let mut ids = component_ids(&device_status, "temperature");
+    // Humidity-only sensors have no temperature to show, not even 0.0
+    let humidity_only = ids.is_empty() && device_status.get("humidity:0").is_some();
+    ids.extend(component_ids(&device_status, "humidity"));
+    ids.sort_unstable();
+    ids.dedup();
+    if ids.is_empty() {
+        ids.push(0);
+    }
+    let primary = ids[0];
+
+    let mut fields = Vec::new();
+    for id in ids {
+        let temperature = &device_status[format!("temperature:{}", id)];
+        let humidity = &device_status[format!("humidity:{}", id)];
+        // Probes are told apart by their id
+        let (key_suffix, label) = if id == primary {
+            (
+                String::new(),
+                (
+                    style.label("T", "Temp", Icon::Temperature),
+                    style.label("H", "Humidity", Icon::Humidity),
+                ),
+            )
+        } else {
+            let label = |short: &str, long: &str, icon: Icon| match style.format {
+                OutputFormat::Short => format!("{}{}: ", short, id),
+                OutputFormat::Long => format!("{} {}: ", long, id),
+                OutputFormat::Icons => format!("{}{} ", style.icon(icon), id),
+            };
+            (
+                format!("_{}", id),
+                (
+                    label("T", "Temp", Icon::Temperature),
+                    label("H", "Humidity", Icon::Humidity),
+                ),
+            )
+        };
 
-        let output = parse_window_or_door_data(device_status, true, OutputFormat::Icons);
-        assert_eq!(output["text"], "🟢 🔆100, Tilt: 30");
-        assert_eq!(output["tooltip"], "🔋90% 📶-65dBm");
+        if (id == primary && !humidity_only) || !temperature.is_null() {
+            let (temp, unit_label) = if unit == "F" {
+                (temperature["tF"].as_f64().unwrap_or(0.0), "°F")
+            } else {
+                (temperature["tC"].as_f64().unwrap_or(0.0), "°C")
+            };
+            fields.push(
+                Field::text(
+                    format!("temperature{}", key_suffix),
+                    label.0,
+                    Metric::Float(temp, 1),
+                )
+                .unit(unit_label),
+            );
+        }
+        if id == primary || !humidity.is_null() {
+            fields.push(
+                Field::text(
+                    format!("humidity{}", key_suffix),
+                    label.1,
+                    Metric::Int(humidity["rh"].as_f64().unwrap_or(0.0).round() as i64),
+                )
+                .unit("%"),
+            );
+        }
     }
 
-    // Test: Door Status Change Notification
-    #[test]
-    fn test_handle_door_status() {
-        let mut door_status_map = HashMap::new();
-        let device_status_open = json!({
-            "window:0": { "open": true }
-        });
-        let device_status_closed = json!({
-            "window:0": { "open": false }
-        });
+    fields.extend(power_fields(&device_status, style));
+    fields.push(
+        Field::tooltip(
+            "rssi",
+            style.label("RSSI", "RSSI", Icon::Signal),
+            Metric::Int(rssi),
+        )
+        .unit("dBm"),
+    );
+    fields
+}
 
-        let device_id = "door-12345";
-        let device_name = Some("Front Door".to_string());
+// Gen1 event codes: S for a short push, L for a long one
+fn button_event_label(event: &str) -> &str {
+    match event {
+        "S" => "Single push",
+        "SS" => "Double push",
+        "SSS" => "Triple push",
+        "L" => "Long push",
+        "SL" => "Short-long push",
+        "LS" => "Long-short push",
+        other => other,
+    }
+}
 
-        // Test status change from None to Open
-        let notification = handle_door_status(
-            device_id,
-            device_name.clone(),
-            &device_status_open,
-            &mut door_status_map,
+// Last event of each input, received when the device woke up to report it
+fn parse_button_data(
+    device_status: Value,
+    style: &Style,
+    timestamps: &TimestampFormat,
+    updated_at: DateTime<Utc>,
+) -> Vec<Field> {
+    let inputs = device_status["inputs"]
+        .as_array()
+        .cloned()
+        .unwrap_or_default();
+
+    let mut fields = Vec::new();
+    for (id, input) in inputs.iter().enumerate() {
+        let event = input["event"].as_str().unwrap_or_default();
+        let label = match (inputs.len(), &style.format) {
+            (1, _) => String::new(),
+            (_, OutputFormat::Short) => format!("I{}: ", id + 1),
+            (_, OutputFormat::Long) => format!("Input {}: ", id + 1),
+            (_, OutputFormat::Icons) => format!("{}{} ", style.icon(Icon::Input), id + 1),
+        };
+        let text = match event {
+            "" => "-",
+            event => match style.format {
+                OutputFormat::Long => button_event_label(event),
+                _ => event,
+            },
+        };
+        fields.push(
+            Field::text(
+                format!("event_{}", id),
+                label,
+                Metric::Text(text.to_string()),
+            )
+            .raw(Value::String(event.to_string())),
         );
-        assert!(notification.is_some());
-        assert!(door_status_map[&format!("{}:{}", device_id, device_name.clone().unwrap())]);
+    }
 
-        // Test status change from Open to Closed
-        let notification = handle_door_status(
-            device_id,
-            device_name.clone(),
-            &device_status_closed,
-            &mut door_status_map,
+    fields.push(Field::tooltip(
+        "received",
+        style.label("At", "Received", Icon::Input),
+        Metric::Text(timestamps.format(updated_at)),
+    ));
+    if let Some(battery) = device_status["bat"]["value"].as_i64() {
+        fields.push(
+            Field::tooltip(
+                "battery",
+                style.label("B", "Battery", Icon::Battery),
+                Metric::Int(battery),
+            )
+            .unit("%"),
         );
-        assert!(notification.is_some());
-        assert!(!door_status_map[&format!("{}:{}", device_id, device_name.clone().unwrap())]);
     }
+    fields
+}
 
-    // Test: Fetch Device Status Mock
-    #[tokio::test]
-    async fn test_fetch_device_status() {
-        use httpmock::MockServer;
+/// Upper bounds in lux of the dark and twilight levels, when the device does not tell.
+const DARK_MAX_LUX: f64 = 50.0;
+const TWILIGHT_MAX_LUX: f64 = 500.0;
 
-        let server = MockServer::start_async().await;
-        let mock_response = json!({
-            "isok": true,
-            "data": {
-                "device_status": {
-                    "temperature:0": { "tC": 22.5, "tF": 72.5 },
-                    "humidity:0": { "rh": 50 }
-                }
-            }
-        });
+// Light sensors: the illuminance, with the light level as label
+fn parse_lux_data(device_status: Value, style: &Style) -> Vec<Field> {
+    let illuminance = &device_status["illuminance:0"];
+    let lux = illuminance["lux"].as_f64().unwrap_or(0.0);
+    let level = match illuminance["illumination"].as_str() {
+        Some(level @ ("dark" | "twilight" | "bright")) => level,
+        _ if lux <= DARK_MAX_LUX => "dark",
+        _ if lux <= TWILIGHT_MAX_LUX => "twilight",
+        _ => "bright",
+    };
 
-        let mock = server.mock(|when, then| {
-            when.method("POST").path("/device/status");
-            then.status(200).json_body(mock_response.clone());
-        });
+    let (short, long, icon) = match level {
+        "dark" => ("Dark", "Dark", Icon::Dark),
+        "twilight" => ("Dusk", "Twilight", Icon::Twilight),
+        _ => ("Bright", "Bright", Icon::Bright),
+    };
+    let label = match style.format {
+        OutputFormat::Short => format!("{}: ", short),
+        OutputFormat::Long => format!("{}: ", long),
+        OutputFormat::Icons => style.icon(icon).to_string(),
+    };
 
-        let client = Client::new();
-        let response =
-            fetch_device_status(&client, &server.base_url(), "12345", "mock-auth-key").await;
+    let mut fields = vec![Field::text("lux", label, Metric::Int(lux.round() as i64))
+        .unit("lx")
+        .raw(Value::from(lux))];
+    fields.push(Field::tooltip(
+        "illumination",
+        style.label("Level", "Level", Icon::Lux),
+        Metric::Text(level.to_string()),
+    ));
+    // Mains-powered sensors have no battery
+    if device_status.get("devicepower:0").is_some() {
+        fields.extend(power_fields(&device_status, style));
+    }
+    fields
+}
+
+// Wall Display: its sensors in the text, its relay in the tooltip
+fn parse_display_data(device_status: Value, style: &Style, unit: &str) -> Vec<Field> {
+    let temperature = &device_status["temperature:0"];
+    let humidity = device_status["humidity:0"]["rh"].as_f64().unwrap_or(0.0);
+    let lux = device_status["illuminance:0"]["lux"]
+        .as_f64()
+        .unwrap_or(0.0);
+    let rssi = device_status["wifi"]["rssi"].as_i64().unwrap_or(0);
+
+    let (temp, unit_label) = if unit == "F" {
+        (temperature["tF"].as_f64().unwrap_or(0.0), "°F")
+    } else {
+        (temperature["tC"].as_f64().unwrap_or(0.0), "°C")
+    };
+
+    let mut fields = vec![
+        Field::text(
+            "temperature",
+            style.label("T", "Temp", Icon::Temperature),
+            Metric::Float(temp, 1),
+        )
+        .unit(unit_label),
+        Field::text(
+            "humidity",
+            style.label("H", "Humidity", Icon::Humidity),
+            Metric::Int(humidity.round() as i64),
+        )
+        .unit("%"),
+        Field::text(
+            "lux",
+            style.label("L", "Lux", Icon::Lux),
+            Metric::Int(lux.round() as i64),
+        ),
+    ];
+    if let Some(output) = device_status["switch:0"]["output"].as_bool() {
+        fields.push(Field::tooltip(
+            "output",
+            style.label("O", "Output", Icon::Output),
+            Metric::Text(if output { "ON" } else { "OFF" }.to_string()),
+        ));
+    }
+    fields.push(
+        Field::tooltip(
+            "rssi",
+            style.label("RSSI", "WiFi RSSI", Icon::Signal),
+            Metric::Int(rssi),
+        )
+        .unit("dBm"),
+    );
+    fields
+}
+
+fn parse_plug_data(device_status: Value, style: &Style, channel_names: &[String]) -> Vec<Field> {
+    let channels = component_ids(&device_status, "switch");
+    if channels.len() > 1 {
+        return parse_switch_channels(&device_status, style, &channels, channel_names);
+    }
+    if channels.is_empty() && device_status.get("pm1:0").is_some() {
+        return parse_power_meter_data(&device_status, style);
+    }
+
+    let power = device_status["switch:0"]["apower"].as_f64().unwrap_or(0.0);
+    let voltage = device_status["switch:0"]["voltage"].as_f64().unwrap_or(0.0);
+    let current = device_status["switch:0"]["current"].as_f64().unwrap_or(0.0);
+    let output = device_status["switch:0"]["output"]
+        .as_bool()
+        .unwrap_or(false);
+    // Counted in Wh since the last reset, missing on Gen1 relays
+    let energy = device_status["switch:0"]["aenergy"]["total"].as_f64();
+    let rssi = device_status["wifi"]["rssi"].as_i64().unwrap_or(0);
+
+    let output_state = match output {
+        _ if is_tripped(&device_status["switch:0"]) => "TRIPPED",
+        true => "ON",
+        false => "OFF",
+    };
+
+    let mut fields = vec![
+        Field::text(
+            "power",
+            style.label("P", "Power", Icon::Power),
+            Metric::Float(power, 1),
+        )
+        .unit("W"),
+        Field::text(
+            "voltage",
+            style.label("V", "Voltage", Icon::Voltage),
+            Metric::Float(voltage, 1),
+        )
+        .unit("V"),
+        Field::tooltip(
+            "current",
+            style.label("I", "Current", Icon::Current),
+            Metric::Float(current, 3),
+        )
+        .unit("A"),
+    ];
+    if let Some(energy) = energy {
+        fields.push(
+            Field::tooltip(
+                "energy",
+                style.label("E", "Energy", Icon::Energy),
+                Metric::Float(energy / 1000.0, 2),
+            )
+            .unit("kWh"),
+        );
+    }
+    fields.extend([
+        Field::tooltip(
+            "rssi",
+            style.label("RSSI", "WiFi RSSI", Icon::Signal),
+            Metric::Int(rssi),
+        )
+        .unit("dBm"),
+        Field::tooltip(
+            "output",
+            style.label("O", "Output", Icon::Output),
+            Metric::Text(output_state.to_string()),
+        ),
+    ]);
+    // The wall switch, which only follows the output when not detached
+    if let Some(input) = device_status["input:0"]["state"].as_bool() {
+        let label = match style.format {
+            OutputFormat::Icons => format!("{} ", style.icon(Icon::Input)),
+            _ => style.label("In", "Input", Icon::Input),
+        };
+        fields.push(Field::tooltip(
+            "input",
+            label,
+            Metric::Text(if input { "ON" } else { "OFF" }.to_string()),
+        ));
+    }
+    fields
+}
+
+// PM Mini / EM Mini: a `pm1:0` meter, without relay
+fn parse_power_meter_data(device_status: &Value, style: &Style) -> Vec<Field> {
+    let meter = &device_status["pm1:0"];
+    let power = meter["apower"].as_f64().unwrap_or(0.0);
+    let voltage = meter["voltage"].as_f64().unwrap_or(0.0);
+    let current = meter["current"].as_f64().unwrap_or(0.0);
+    // Counted in Wh
+    let energy = meter["aenergy"]["total"].as_f64().unwrap_or(0.0);
+    let rssi = device_status["wifi"]["rssi"].as_i64().unwrap_or(0);
+
+    vec![
+        Field::text(
+            "power",
+            style.label("P", "Power", Icon::Power),
+            Metric::Float(power, 1),
+        )
+        .unit("W"),
+        Field::text(
+            "voltage",
+            style.label("V", "Voltage", Icon::Voltage),
+            Metric::Float(voltage, 1),
+        )
+        .unit("V"),
+        Field::tooltip(
+            "current",
+            style.label("I", "Current", Icon::Current),
+            Metric::Float(current, 3),
+        )
+        .unit("A"),
+        Field::tooltip(
+            "energy",
+            style.label("E", "Energy", Icon::Energy),
+            Metric::Float(energy / 1000.0, 2),
+        )
+        .unit("kWh"),
+        Field::tooltip(
+            "rssi",
+            style.label("RSSI", "WiFi RSSI", Icon::Signal),
+            Metric::Int(rssi),
+        )
+        .unit("dBm"),
+    ]
+}
+
+/// Errors of a relay that switch it off until it is turned on again.
+const TRIP_ERRORS: [&str; 4] = ["overpower", "overvoltage", "undervoltage", "overtemp"];
+
+// Protections that switched a relay off
+fn trip_errors(switch: &Value) -> Vec<&str> {
+    switch["errors"]
+        .as_array()
+        .map(|errors| {
+            errors
+                .iter()
+                .filter_map(Value::as_str)
+                .filter(|error| TRIP_ERRORS.contains(error))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+fn is_tripped(switch: &Value) -> bool {
+    !trip_errors(switch).is_empty()
+}
+
+// Protections that tripped any relay of a device, e.g. `overpower, overtemp`
+fn device_trip_errors(device_status: &Value) -> Option<String> {
+    let mut errors: Vec<&str> = component_ids(device_status, "switch")
+        .into_iter()
+        .flat_map(|id| trip_errors(&device_status[format!("switch:{}", id)]))
+        .collect();
+    errors.sort_unstable();
+    errors.dedup();
+    (!errors.is_empty()).then(|| errors.join(", "))
+}
+
+// Tripped relays, flagged with the `alarm` class
+fn tripped_fields(device_status: &Value, style: &Style) -> Vec<Field> {
+    let Some(errors) = device_trip_errors(device_status) else {
+        return Vec::new();
+    };
+    let label = match style.format {
+        OutputFormat::Icons => style.icon(Icon::Alarm).to_string(),
+        _ => "TRIPPED: ".to_string(),
+    };
+    vec![Field::text("alarm", label, Metric::Text(errors)).raw(Value::Bool(true))]
+}
+
+// Hottest internal temperature of the relays of a device, in `tC` or `tF`
+fn switch_temperature(device_status: &Value, unit_key: &str) -> Option<f64> {
+    component_ids(device_status, "switch")
+        .into_iter()
+        .filter_map(|id| device_status[format!("switch:{}", id)]["temperature"][unit_key].as_f64())
+        .reduce(f64::max)
+}
+
+// Internal temperature of a relay, flagged when over the threshold (in °C) since plugs
+// throttle or switch off when hot
+fn overheat_fields(device_status: &Value, style: &Style, unit: &str, threshold: f64) -> Vec<Field> {
+    let Some(celsius) = switch_temperature(device_status, "tC") else {
+        return Vec::new();
+    };
+    let temperature = match unit {
+        "F" => switch_temperature(device_status, "tF").unwrap_or(celsius * 9.0 / 5.0 + 32.0),
+        _ => celsius,
+    };
+    let overheat = celsius > threshold;
+
+    let mut fields = Vec::new();
+    if overheat {
+        let label = match style.format {
+            OutputFormat::Short => "HOT",
+            OutputFormat::Long => "Overheating",
+            OutputFormat::Icons => style.icon(Icon::Hot),
+        };
+        fields.push(
+            Field::text("overheat", String::new(), Metric::Text(label.to_string()))
+                .raw(Value::Bool(true)),
+        );
+    }
+    fields.push(
+        Field::tooltip(
+            "device_temperature",
+            style.label("Int", "Internal", Icon::Temperature),
+            Metric::Float(temperature, 1),
+        )
+        .unit(format!("°{}", unit)),
+    );
+    fields
+}
+
+/// Relays with this many channels (Pro 3, Pro 4PM) show their total power, with the power of
+/// each channel in the tooltip only.
+const AGGREGATE_MIN_CHANNELS: usize = 3;
+
+// Pro 2PM, Pro 4PM...: the power of each channel in the text, one tooltip line per channel.
+// Channel 0 keeps the keys of single-channel plugs, the others get their id as suffix.
+fn parse_switch_channels(
+    device_status: &Value,
+    style: &Style,
+    channels: &[u64],
+    channel_names: &[String],
+) -> Vec<Field> {
+    let rssi = device_status["wifi"]["rssi"].as_i64().unwrap_or(0);
+    let aggregate = channels.len() >= AGGREGATE_MIN_CHANNELS;
+
+    let mut text = Vec::new();
+    let mut tooltip = Vec::new();
+    let mut total_power = 0.0;
+    for (index, id) in channels.iter().enumerate() {
+        let switch = &device_status[format!("switch:{}", id)];
+        let suffix = if *id == 0 {
+            String::new()
+        } else {
+            format!("_{}", id)
+        };
+        let name = match channel_names.get(index) {
+            Some(name) => name.clone(),
+            None => match style.format {
+                OutputFormat::Long => format!("Channel {}", id + 1),
+                _ => format!("Ch{}", id + 1),
+            },
+        };
+        let label = |icon: Icon| match style.format {
+            OutputFormat::Icons => format!("{}{} ", style.icon(icon), name),
+            _ => format!("{}: ", name),
+        };
+        let output_state = match switch["output"].as_bool().unwrap_or(false) {
+            _ if is_tripped(switch) => "TRIPPED",
+            true => "ON",
+            false => "OFF",
+        };
+
+        let power = switch["apower"].as_f64().unwrap_or(0.0);
+        total_power += power;
+
+        if !aggregate {
+            text.push(
+                Field::text(
+                    format!("power{}", suffix),
+                    label(Icon::Power),
+                    Metric::Float(power, 1),
+                )
+                .unit("W"),
+            );
+        }
+        tooltip.push(
+            Field::tooltip(
+                format!("output{}", suffix),
+                label(Icon::Output),
+                Metric::Text(output_state.to_string()),
+            )
+            .sep("\n"),
+        );
+        if aggregate {
+            tooltip.push(
+                Field::tooltip(
+                    format!("power{}", suffix),
+                    String::new(),
+                    Metric::Float(power, 1),
+                )
+                .unit("W"),
+            );
+        }
+        tooltip.push(
+            Field::tooltip(
+                format!("voltage{}", suffix),
+                String::new(),
+                Metric::Float(switch["voltage"].as_f64().unwrap_or(0.0), 1),
+            )
+            .unit("V"),
+        );
+        tooltip.push(
+            Field::tooltip(
+                format!("current{}", suffix),
+                String::new(),
+                Metric::Float(switch["current"].as_f64().unwrap_or(0.0), 3),
+            )
+            .unit("A"),
+        );
+    }
+    tooltip.push(
+        Field::tooltip(
+            "rssi",
+            style.label("RSSI", "WiFi RSSI", Icon::Signal),
+            Metric::Int(rssi),
+        )
+        .unit("dBm")
+        .sep("\n"),
+    );
+    if aggregate {
+        text.push(
+            Field::text(
+                "power_total",
+                style.label("P", "Power", Icon::Power),
+                Metric::Float(total_power, 1),
+            )
+            .unit("W"),
+        );
+    }
+    text.extend(tooltip);
+    text
+}
+
+fn parse_dimmer_data(device_status: Value, style: &Style) -> Vec<Field> {
+    let light = &device_status["light:0"];
+    let output = light["output"].as_bool().unwrap_or(false);
+    let brightness = light["brightness"].as_f64().unwrap_or(0.0).round() as i64;
+    let power = light["apower"].as_f64().unwrap_or(0.0);
+    let rssi = device_status["wifi"]["rssi"].as_i64().unwrap_or(0);
+
+    let output_state = if output { "ON" } else { "OFF" };
+
+    vec![
+        // Not `output`: lights cannot be toggled like relays
+        Field::text(
+            "light",
+            style.label("O", "Output", Icon::Output),
+            Metric::Text(output_state.to_string()),
+        ),
+        Field::text(
+            "brightness",
+            style.label("Br", "Brightness", Icon::Brightness),
+            Metric::Int(brightness),
+        )
+        .unit("%"),
+        Field::tooltip(
+            "power",
+            style.label("P", "Power", Icon::Power),
+            Metric::Float(power, 1),
+        )
+        .unit("W"),
+        Field::tooltip(
+            "rssi",
+            style.label("RSSI", "WiFi RSSI", Icon::Signal),
+            Metric::Int(rssi),
+        )
+        .unit("dBm"),
+    ]
+}
+
+// Duo / Vintage bulbs: a dimmer with the color temperature of its white channel
+fn parse_light_data(device_status: Value, style: &Style) -> Vec<Field> {
+    let light = &device_status["light:0"];
+    let ct = light["ct"].as_i64();
+    let white = light["white"].as_f64();
+    let mut fields = parse_dimmer_data(device_status.clone(), style);
+
+    let mut extra = Vec::new();
+    if let Some(ct) = ct {
+        extra.push(
+            Field::text(
+                "ct",
+                style.label("CT", "Color temp", Icon::ColorTemp),
+                Metric::Int(ct),
+            )
+            .unit("K"),
+        );
+    }
+    if let Some(white) = white {
+        extra.push(
+            Field::tooltip(
+                "white",
+                style.label("W", "White", Icon::Brightness),
+                Metric::Int(white.round() as i64),
+            )
+            .unit("%"),
+        );
+    }
+    // Right after the brightness
+    fields.splice(2..2, extra);
+    fields
+}
+
+fn parse_rgbw_data(device_status: Value, style: &Style) -> Vec<Field> {
+    // RGBW devices in RGB profile report an `rgb:0` component, without white channel
+    let light = match device_status.get("rgbw:0") {
+        Some(light) => light,
+        None => &device_status["rgb:0"],
+    };
+    let output = light["output"].as_bool().unwrap_or(false);
+    let brightness = light["brightness"].as_f64().unwrap_or(0.0).round() as i64;
+    let power = light["apower"].as_f64().unwrap_or(0.0);
+    let channel = |index: usize| light["rgb"][index].as_u64().unwrap_or(0).min(255);
+    let color = format!("#{:02x}{:02x}{:02x}", channel(0), channel(1), channel(2));
+
+    let output_state = if output { "ON" } else { "OFF" };
+    // A swatch of the color itself, Waybar renders Pango markup in tooltips
+    let color_label = match (&style.format, style.icon_set) {
+        (OutputFormat::Short, _) => "RGB: ".to_string(),
+        (OutputFormat::Long, _) => "Color: ".to_string(),
+        (OutputFormat::Icons, IconSet::Emoji) => format!("<span color=\"{}\">●</span> ", color),
+        (OutputFormat::Icons, IconSet::Ascii) => "RGB:".to_string(),
+    };
+
+    let mut fields = vec![
+        Field::text(
+            "light",
+            style.label("O", "Output", Icon::Output),
+            Metric::Text(output_state.to_string()),
+        ),
+        Field::text(
+            "brightness",
+            style.label("Br", "Brightness", Icon::Brightness),
+            Metric::Int(brightness),
+        )
+        .unit("%"),
+        Field::tooltip("color", color_label, Metric::Text(color)),
+    ];
+    if let Some(white) = light["white"].as_u64() {
+        fields.push(
+            Field::tooltip(
+                "white",
+                style.label("W", "White", Icon::Brightness),
+                Metric::Int((white.min(255) * 100 / 255) as i64),
+            )
+            .unit("%"),
+        );
+    }
+    fields.push(
+        Field::tooltip(
+            "power",
+            style.label("P", "Power", Icon::Power),
+            Metric::Float(power, 1),
+        )
+        .unit("W"),
+    );
+    fields
+}
+
+fn parse_em3_data(device_status: Value, style: &Style) -> Vec<Field> {
+    let em = &device_status["em:0"];
+    let total_power = em["total_act_power"].as_f64().unwrap_or(0.0);
+    let total_current = em["total_current"].as_f64().unwrap_or(0.0);
+
+    let mut fields = vec![
+        Field::text(
+            "power",
+            style.label("P", "Power", Icon::Power),
+            Metric::Float(total_power, 1),
+        )
+        .unit("W"),
+        Field::tooltip(
+            "current",
+            style.label("I", "Current", Icon::Current),
+            Metric::Float(total_current, 3),
+        )
+        .unit("A"),
+    ];
+
+    // One tooltip line per phase: power, voltage and current
+    let phases = [
+        ("A", ["a_power", "a_voltage", "a_current"]),
+        ("B", ["b_power", "b_voltage", "b_current"]),
+        ("C", ["c_power", "c_voltage", "c_current"]),
+    ];
+    for (phase, [power_key, voltage_key, current_key]) in phases {
+        let prefix = phase.to_lowercase();
+        let value = |name: &str| em[format!("{}_{}", prefix, name)].as_f64().unwrap_or(0.0);
+        let label = match style.format {
+            OutputFormat::Short => format!("{}: ", phase),
+            OutputFormat::Long => format!("Phase {}: ", phase),
+            OutputFormat::Icons => format!("{}{} ", style.icon(Icon::Power), phase),
+        };
+        fields.push(
+            Field::tooltip(power_key, label, Metric::Float(value("act_power"), 1))
+                .unit("W")
+                .sep("\n"),
+        );
+        fields.push(
+            Field::tooltip(
+                voltage_key,
+                String::new(),
+                Metric::Float(value("voltage"), 1),
+            )
+            .unit("V"),
+        );
+        fields.push(
+            Field::tooltip(
+                current_key,
+                String::new(),
+                Metric::Float(value("current"), 3),
+            )
+            .unit("A"),
+        );
+    }
+    fields
+}
+
+fn parse_em_data(device_status: Value, style: &Style, channel_names: &[String]) -> Vec<Field> {
+    // Gen1 EM reports an `emeters` array, Pro EM one `em1:<id>` and `em1data:<id>` per channel
+    let channels: Vec<(f64, f64)> = match device_status["emeters"].as_array() {
+        Some(emeters) => emeters
+            .iter()
+            .map(|emeter| {
+                (
+                    emeter["power"].as_f64().unwrap_or(0.0),
+                    emeter["total"].as_f64().unwrap_or(0.0),
+                )
+            })
+            .collect(),
+        None => (0..)
+            .map_while(|id| {
+                let em1 = device_status.get(format!("em1:{}", id))?;
+                let energy = &device_status[format!("em1data:{}", id)]["total_act_energy"];
+                Some((
+                    em1["act_power"].as_f64().unwrap_or(0.0),
+                    energy.as_f64().unwrap_or(0.0),
+                ))
+            })
+            .collect(),
+    };
+
+    let mut fields = Vec::new();
+    let keys = [("power_0", "energy_0"), ("power_1", "energy_1")];
+    for (index, ((power, energy), (power_key, energy_key))) in
+        channels.into_iter().zip(keys).enumerate()
+    {
+        let name = match channel_names.get(index) {
+            Some(name) => name.clone(),
+            None => match style.format {
+                OutputFormat::Long => format!("Channel {}", index + 1),
+                _ => format!("Ch{}", index + 1),
+            },
+        };
+        let label = |icon: Icon| match style.format {
+            OutputFormat::Icons => format!("{}{} ", style.icon(icon), name),
+            _ => format!("{}: ", name),
+        };
+        fields.push(Field::text(power_key, label(Icon::Power), Metric::Float(power, 1)).unit("W"));
+        // Totals are counted in Wh
+        fields.push(
+            Field::tooltip(
+                energy_key,
+                label(Icon::Energy),
+                Metric::Float(energy / 1000.0, 2),
+            )
+            .unit("kWh"),
+        );
+    }
+    fields
+}
+
+fn parse_input_data(device_status: Value, style: &Style) -> Vec<Field> {
+    let keys = ["input_0", "input_1", "input_2", "input_3"];
+    keys.iter()
+        .enumerate()
+        .filter_map(|(id, key)| {
+            let input = device_status.get(format!("input:{}", id))?;
+            // Inputs in button mode are stateless, they only emit push events
+            let state = match input["state"].as_bool() {
+                Some(true) => "ON",
+                Some(false) => "OFF",
+                None => "BTN",
+            };
+            let label = match style.format {
+                OutputFormat::Short => format!("I{}: ", id + 1),
+                OutputFormat::Long => format!("Input {}: ", id + 1),
+                OutputFormat::Icons => format!("{}{} ", style.icon(Icon::Input), id + 1),
+            };
+            Some(
+                Field::text(*key, label, Metric::Text(state.to_string()))
+                    .raw(input["state"].clone()),
+            )
+        })
+        .collect()
+}
+
+fn parse_uni_data(device_status: Value, style: &Style, adc: &AdcConfig) -> Vec<Field> {
+    let voltage = device_status["adcs"][0]["voltage"].as_f64().unwrap_or(0.0);
+    let label = match &adc.label {
+        Some(label) => format!("{}: ", label),
+        None => {
+            let label = style.label("ADC", "Analog", Icon::Voltage);
+            // The icon alone would read as the supply voltage
+            match style.format {
+                OutputFormat::Icons => format!("{}ADC ", label),
+                _ => label,
+            }
+        }
+    };
+
+    let mut fields = vec![Field::text(
+        "adc",
+        label,
+        Metric::Float(voltage * adc.scale + adc.offset, adc.digits),
+    )
+    .unit(adc.unit.clone())];
+
+    let on_off = |on: bool| Metric::Text(if on { "ON" } else { "OFF" }.to_string());
+    for (id, key) in ["input_0", "input_1"].into_iter().enumerate() {
+        if let Some(input) = device_status["inputs"][id]["input"].as_i64() {
+            let label = match style.format {
+                OutputFormat::Short => format!("I{}: ", id + 1),
+                OutputFormat::Long => format!("Input {}: ", id + 1),
+                OutputFormat::Icons => format!("{}{} ", style.icon(Icon::Input), id + 1),
+            };
+            fields.push(Field::tooltip(key, label, on_off(input == 1)));
+        }
+    }
+    for (id, key) in ["output_0", "output_1"].into_iter().enumerate() {
+        if let Some(is_on) = device_status["relays"][id]["ison"].as_bool() {
+            let label = match style.format {
+                OutputFormat::Short => format!("O{}: ", id + 1),
+                OutputFormat::Long => format!("Output {}: ", id + 1),
+                OutputFormat::Icons => format!("{}{} ", style.icon(Icon::Output), id + 1),
+            };
+            fields.push(Field::tooltip(key, label, on_off(is_on)));
+        }
+    }
+    fields
+}
+
+// Devices without a parser: values read with the dot paths of the `custom` config, shown
+// through its format
+fn parse_custom_data(device_status: Value, custom: &CustomConfig) -> Vec<Field> {
+    let mut fields: Vec<Field> = custom
+        .fields
+        .iter()
+        .map(|spec| {
+            let value = lookup_path(&device_status, &spec.path)
+                .cloned()
+                .unwrap_or(Value::Null);
+            let metric = match &value {
+                Value::Number(number) => match number.as_i64() {
+                    Some(value) => Metric::Int(value),
+                    None => Metric::Float(number.as_f64().unwrap_or(0.0), spec.digits),
+                },
+                Value::String(value) => Metric::Text(value.clone()),
+                Value::Bool(on) => Metric::Text(if *on { "ON" } else { "OFF" }.to_string()),
+                Value::Null => Metric::Text("-".to_string()),
+                other => Metric::Text(other.to_string()),
+            };
+            Field::text(spec.key.clone(), format!("{}: ", spec.key), metric)
+                .unit(spec.unit.clone())
+                .sep(", ")
+                .raw(value)
+        })
+        .collect();
+
+    let tooltip = custom
+        .tooltip
+        .as_ref()
+        .map(|template| fill_template(template, &fields));
+    if let Some(format) = &custom.format {
+        let text = fill_template(format, &fields);
+        for field in &mut fields {
+            field.slot = Slot::Tooltip;
+            field.sep = "\n";
+        }
+        fields.insert(0, Field::text("custom", String::new(), Metric::Text(text)));
+    }
+    if let Some(tooltip) = tooltip {
+        for field in fields
+            .iter_mut()
+            .filter(|field| field.slot == Slot::Tooltip)
+        {
+            field.slot = Slot::Hidden;
+        }
+        fields.push(Field::tooltip(
+            "custom_tooltip",
+            String::new(),
+            Metric::Text(tooltip),
+        ));
+    }
+    fields
+}
+
+// Replace the `{<key>}` placeholders of a template with the values of the fields
+fn fill_template(template: &str, fields: &[Field]) -> String {
+    fields.iter().fold(template.to_string(), |text, field| {
+        let value = Field {
+            label: String::new(),
+            ..field.clone()
+        };
+        text.replace(&format!("{{{}}}", field.key), &value.render())
+    })
+}
+
+// Fill a --text-template or --tooltip-template, the device being named by its ID when unnamed
+fn render_template(
+    template: &str,
+    fields: &[Field],
+    device_id: &str,
+    device_name: Option<&str>,
+) -> String {
+    fill_template(template, fields)
+        .replace("{name}", device_name.unwrap_or(device_id))
+        .replace("{id}", device_id)
+}
+
+// Device type of the config whose `detect` paths all exist in the status
+fn detect_config_type<'a>(status: &Value, config: &'a Config) -> Option<&'a TypeConfig> {
+    config.types.values().find(|device_type| {
+        !device_type.detect.is_empty()
+            && device_type
+                .detect
+                .iter()
+                .all(|path| lookup_path(status, path).is_some())
+    })
+}
+
+// IP address of Pro devices connected over Ethernet, which report no WiFi RSSI
+fn ethernet_ip(device_status: &Value) -> Option<&str> {
+    if device_status["wifi"]["rssi"].is_i64() {
+        return None;
+    }
+    device_status["eth"]["ip"].as_str()
+}
+
+// Show the wired connection instead of the RSSI, 0 without WiFi
+fn use_ethernet(fields: &mut Vec<Field>, ip: String, style: &Style) {
+    let Some(index) = fields.iter().position(|field| field.key == "rssi") else {
+        return;
+    };
+    let (slot, sep) = (fields[index].slot, fields[index].sep);
+    fields[index] = Field {
+        slot,
+        sep,
+        ..Field::text(
+            "network",
+            style.label("Net", "Network", Icon::Ethernet),
+            Metric::Text("LAN".to_string()),
+        )
+    };
+    let label = match style.format {
+        OutputFormat::Icons => "IP ".to_string(),
+        _ => "IP: ".to_string(),
+    };
+    fields.insert(
+        index + 1,
+        Field {
+            slot,
+            ..Field::text("ip", label, Metric::Text(ip))
+        },
+    );
+}
+
+// Gen2 scripts, one tooltip line each, with the errors of crashed ones
+fn script_fields(device_status: &Value, style: &Style) -> Vec<Field> {
+    component_ids(device_status, "script")
+        .into_iter()
+        .map(|id| {
+            let script = &device_status[format!("script:{}", id)];
+            let errors: Vec<&str> = script["errors"]
+                .as_array()
+                .map(|errors| errors.iter().filter_map(Value::as_str).collect())
+                .unwrap_or_default();
+            let state = match script["running"].as_bool() {
+                _ if !errors.is_empty() => format!("error ({})", errors.join(", ")),
+                Some(true) => "running".to_string(),
+                _ => "stopped".to_string(),
+            };
+            let label = match style.format {
+                OutputFormat::Short => format!("S{}: ", id),
+                _ => format!("Script {}: ", id),
+            };
+            Field::tooltip(format!("script_{}", id), label, Metric::Text(state))
+                .sep("\n")
+                .raw(Value::Bool(errors.is_empty()))
+        })
+        .collect()
+}
+
+// Value at a dot path such as `switch:0.aenergy.total` or `$.emeters[0].power`
+fn lookup_path<'a>(value: &'a Value, path: &str) -> Option<&'a Value> {
+    let path = path
+        .trim_start_matches('$')
+        .replace('[', ".")
+        .replace(']', "");
+    path.split('.')
+        .filter(|segment| !segment.is_empty())
+        .try_fold(value, |value, segment| match value {
+            Value::Array(items) => items.get(segment.parse::<usize>().ok()?),
+            _ => value.get(segment),
+        })
+}
+
+// BLU sensors paired with a gateway: each `bthomesensor:<id>` holds a bare value,
+// whose quantity comes from the `bthome` config of the gateway
+fn parse_blu_data(
+    device_status: Value,
+    style: &Style,
+    kinds: &HashMap<String, BthomeKind>,
+) -> Vec<Field> {
+    let mut fields = Vec::new();
+    for id in component_ids(&device_status, "bthomesensor") {
+        let value = &device_status[format!("bthomesensor:{}", id)]["value"];
+        let field = match kinds.get(&id.to_string()) {
+            Some(BthomeKind::Temperature) => Field::text(
+                format!("temperature_{}", id),
+                style.label("T", "Temp", Icon::Temperature),
+                Metric::Float(value.as_f64().unwrap_or(0.0), 1),
+            )
+            .unit("°C"),
+            Some(BthomeKind::Humidity) => Field::text(
+                format!("humidity_{}", id),
+                style.label("H", "Humidity", Icon::Humidity),
+                Metric::Int(value.as_f64().unwrap_or(0.0).round() as i64),
+            )
+            .unit("%"),
+            Some(BthomeKind::Illuminance) => Field::text(
+                format!("lux_{}", id),
+                style.label("L", "Lux", Icon::Lux),
+                Metric::Int(value.as_f64().unwrap_or(0.0).round() as i64),
+            ),
+            Some(BthomeKind::Window) => {
+                let is_open = value.as_bool().unwrap_or(false);
+                let state = match (&style.format, is_open) {
+                    (OutputFormat::Icons, true) => style.icon(Icon::Open),
+                    (OutputFormat::Icons, false) => style.icon(Icon::Closed),
+                    (_, true) => "Open",
+                    (_, false) => "Closed",
+                };
+                Field::text(
+                    format!("state_{}", id),
+                    String::new(),
+                    Metric::Text(state.to_string()),
+                )
+                .raw(Value::String(
+                    if is_open { "open" } else { "closed" }.to_string(),
+                ))
+            }
+            Some(BthomeKind::Motion) => {
+                let motion = value.as_bool().unwrap_or(false);
+                let state = match style.format {
+                    OutputFormat::Icons if motion => style.icon(Icon::Motion),
+                    OutputFormat::Icons => style.icon(Icon::Still),
+                    _ if motion => "Motion",
+                    _ => "Idle",
+                };
+                Field::text(
+                    format!("motion_{}", id),
+                    String::new(),
+                    Metric::Text(state.to_string()),
+                )
+                .raw(Value::Bool(motion))
+            }
+            Some(BthomeKind::Battery) => Field::tooltip(
+                format!("battery_{}", id),
+                style.label("B", "Battery", Icon::Battery),
+                Metric::Int(value.as_i64().unwrap_or(0)),
+            )
+            .unit("%"),
+            // Unknown quantity: the raw value, labelled with the sensor id
+            None => {
+                let label = match style.format {
+                    OutputFormat::Long => format!("Sensor {}: ", id),
+                    _ => format!("S{}: ", id),
+                };
+                let metric = match value {
+                    Value::Number(number) => Metric::Float(number.as_f64().unwrap_or(0.0), 1),
+                    Value::Bool(true) => Metric::Text("ON".to_string()),
+                    Value::Bool(false) => Metric::Text("OFF".to_string()),
+                    other => Metric::Text(other.to_string()),
+                };
+                Field::text(format!("sensor_{}", id), label, metric)
+            }
+        };
+        fields.push(field);
+    }
+
+    // Battery and signal of each paired device
+    let devices = component_ids(&device_status, "bthomedevice");
+    for id in &devices {
+        let device = &device_status[format!("bthomedevice:{}", id)];
+        let label = |short: &str, long: &str, icon: Icon| match (devices.len(), &style.format) {
+            (1, _) => style.label(short, long, icon),
+            (_, OutputFormat::Icons) => format!("{}{} ", style.icon(icon), id),
+            (_, OutputFormat::Short) => format!("{}{}: ", short, id),
+            (_, OutputFormat::Long) => format!("{} {}: ", long, id),
+        };
+        if let Some(battery) = device["battery"].as_i64() {
+            fields.push(
+                Field::tooltip(
+                    format!("battery_{}", id),
+                    label("B", "Battery", Icon::Battery),
+                    Metric::Int(battery),
+                )
+                .unit("%"),
+            );
+        }
+        if let Some(rssi) = device["rssi"].as_i64() {
+            fields.push(
+                Field::tooltip(
+                    format!("rssi_{}", id),
+                    label("RSSI", "RSSI", Icon::Signal),
+                    Metric::Int(rssi),
+                )
+                .unit("dBm"),
+            );
+        }
+    }
+    fields
+}
+
+fn parse_window_or_door_data(
+    device_status: Value,
+    tilt_thresholds: Option<TiltThresholds>,
+    style: &Style,
+) -> Vec<Field> {
+    let is_open = device_status["window:0"]["open"].as_bool().unwrap_or(false);
+    let lux = device_status["illuminance:0"]["lux"].as_u64().unwrap_or(0);
+    let rssi = device_status["reporter"]["rssi"].as_i64().unwrap_or(0);
+
+    let state = match (&style.format, is_open) {
+        (OutputFormat::Icons, true) => style.icon(Icon::Open),
+        (OutputFormat::Icons, false) => style.icon(Icon::Closed),
+        (_, true) => "Open",
+        (_, false) => "Closed",
+    };
+    let lux_sep = match style.format {
+        OutputFormat::Short => ": ",
+        OutputFormat::Long => ", ",
+        OutputFormat::Icons => " ",
+    };
+
+    let mut fields = vec![
+        Field::text("state", String::new(), Metric::Text(state.to_string())).raw(Value::String(
+            if is_open { "open" } else { "closed" }.to_string(),
+        )),
+        Field::text(
+            "lux",
+            style.label("L", "Lux", Icon::Lux),
+            Metric::Int(lux as i64),
+        )
+        .sep(lux_sep),
+    ];
+    // Door/Window 2 only, -1 until the accelerometer is calibrated
+    match device_status["accel"]["vibration"].as_i64() {
+        Some(1) => {
+            let indicator = match style.format {
+                OutputFormat::Icons => style.icon(Icon::Vibration),
+                OutputFormat::Short => "VIB",
+                OutputFormat::Long => "Vibration!",
+            };
+            fields.push(
+                Field::text(
+                    "vibration",
+                    String::new(),
+                    Metric::Text(indicator.to_string()),
+                )
+                .sep(", ")
+                .raw(Value::Bool(true)),
+            );
+        }
+        Some(0) => fields.push(
+            Field::tooltip(
+                "vibration",
+                style.label("Vib", "Vibration", Icon::Vibration),
+                Metric::Text("no".to_string()),
+            )
+            .raw(Value::Bool(false)),
+        ),
+        _ => {}
+    }
+    fields.extend(power_fields(&device_status, style));
+    fields.push(
+        Field::tooltip(
+            "rssi",
+            style.label("RSSI", "RSSI", Icon::Signal),
+            Metric::Int(rssi),
+        )
+        .unit("dBm"),
+    );
+
+    // Windows show a textual tilt state in the text and keep the raw angle in the tooltip
+    if let Some(thresholds) = tilt_thresholds {
+        let angle = device_status["tilt:0"]["angle"].as_u64().unwrap_or(0);
+        fields.push(
+            Field::text(
+                "tilt",
+                String::new(),
+                Metric::Text(thresholds.state(angle).to_string()),
+            )
+            .sep(", "),
+        );
+        fields.push(
+            Field::tooltip(
+                "angle",
+                style.label("Tilt", "Tilt", Icon::Tilt),
+                Metric::Int(angle as i64),
+            )
+            .unit("°"),
+        );
+    }
+
+    fields
+}
+
+// Why a cover stopped on its own: an obstacle, the safety switch, or a protection
+fn cover_fault(cover: &Value) -> Option<&'static str> {
+    let errors: Vec<&str> = cover["errors"]
+        .as_array()
+        .map(|errors| errors.iter().filter_map(Value::as_str).collect())
+        .unwrap_or_default();
+    // Gen1 rollers give the reason of the last stop
+    let stop_reason = cover["stop_reason"].as_str().unwrap_or("normal");
+    if errors.contains(&"obstruction") || stop_reason == "obstacle" {
+        Some("Obstruction detected")
+    } else if errors.contains(&"safety_switch")
+        || stop_reason == "safety_switch"
+        || cover["safety_switch"].as_bool() == Some(true)
+    {
+        Some("Safety switch engaged")
+    } else if !errors.is_empty() || stop_reason == "overpower" {
+        Some("Protection stop")
+    } else {
+        None
+    }
+}
+
+fn parse_cover_data(device_status: Value, style: &Style) -> Vec<Field> {
+    let cover = &device_status["cover:0"];
+    let state = cover["state"].as_str().unwrap_or("stopped");
+    // Unset until the cover is calibrated
+    let position = cover["current_pos"].as_i64();
+    let power = cover["apower"].as_f64().unwrap_or(0.0);
+    let rssi = device_status["wifi"]["rssi"].as_i64().unwrap_or(0);
+
+    let (label, icon) = match state {
+        "opening" | "closing" => ("Moving", Icon::Moving),
+        "closed" => ("Closed", Icon::Closed),
+        "open" => ("Open", Icon::Open),
+        // Stopped halfway, or calibrating
+        _ if position == Some(0) => ("Closed", Icon::Closed),
+        _ => ("Open", Icon::Open),
+    };
+    let label = match style.format {
+        OutputFormat::Icons => style.icon(icon),
+        _ => label,
+    };
+    let sep = match style.format {
+        OutputFormat::Icons => " ",
+        _ => ", ",
+    };
+
+    let mut fields = vec![
+        Field::text("state", String::new(), Metric::Text(label.to_string()))
+            .raw(Value::String(state.to_string())),
+    ];
+    if let Some(position) = position {
+        fields.push(
+            Field::text(
+                "position",
+                style.label("Pos", "Position", Icon::Position),
+                Metric::Int(position),
+            )
+            .unit("%")
+            .sep(sep),
+        );
+    }
+    if let Some(fault) = cover_fault(cover) {
+        let label = match style.format {
+            OutputFormat::Short => "STOP",
+            OutputFormat::Long => fault,
+            OutputFormat::Icons => style.icon(Icon::Alarm),
+        };
+        fields.push(
+            Field::text("alarm", String::new(), Metric::Text(label.to_string()))
+                .raw(Value::String("warning".to_string()))
+                .sep(sep),
+        );
+    }
+    if let Some(direction) = cover["last_direction"].as_str() {
+        fields.push(Field::tooltip(
+            "last_direction",
+            style.label("Last", "Last direction", Icon::Moving),
+            Metric::Text(direction.to_string()),
+        ));
+    }
+    fields.push(
+        Field::tooltip(
+            "power",
+            style.label("P", "Power", Icon::Power),
+            Metric::Float(power, 1),
+        )
+        .unit("W"),
+    );
+    fields.push(
+        Field::tooltip(
+            "rssi",
+            style.label("RSSI", "WiFi RSSI", Icon::Signal),
+            Metric::Int(rssi),
+        )
+        .unit("dBm"),
+    );
+    fields
+}
+
+fn parse_smoke_data(device_status: Value, style: &Style) -> Vec<Field> {
+    let alarm = device_status["smoke:0"]["alarm"].as_bool().unwrap_or(false);
+
+    let (short, long, icon) = if alarm {
+        ("ALARM", "SMOKE ALARM", Icon::Alarm)
+    } else {
+        ("OK", "No smoke", Icon::Clear)
+    };
+    let label = match style.format {
+        OutputFormat::Short => short,
+        OutputFormat::Long => long,
+        OutputFormat::Icons => style.icon(icon),
+    };
+
+    let mut fields = vec![
+        Field::text("alarm", String::new(), Metric::Text(label.to_string()))
+            .raw(Value::Bool(alarm)),
+    ];
+    fields.extend(power_fields(&device_status, style));
+    fields
+}
+
+// Gas alarm as normal, warning (mild concentration) or alarm (heavy); the alarm
+// sounded by a self-test is not one
+fn gas_alarm_level(device_status: &Value) -> &'static str {
+    match device_status["gas_sensor"]["alarm_state"].as_str() {
+        Some("mild") => "warning",
+        Some("heavy") => "alarm",
+        _ => "normal",
+    }
+}
+
+// State of the Valve add-on of a Shelly Gas, when one is connected
+fn valve_state(device_status: &Value) -> Option<&str> {
+    device_status["valves"][0]["state"]
+        .as_str()
+        .filter(|state| !matches!(*state, "none" | "not_connected"))
+}
+
+fn parse_gas_data(device_status: Value, style: &Style) -> Vec<Field> {
+    let sensor = &device_status["gas_sensor"];
+    let level = gas_alarm_level(&device_status);
+    let ppm = device_status["concentration"]["ppm"].as_i64().unwrap_or(0);
+    let sensor_state = sensor["sensor_state"].as_str().unwrap_or("unknown");
+    let self_test = sensor["self_test_state"].as_str().unwrap_or("unknown");
+
+    let (short, long, icon) = match level {
+        "warning" => ("WARN", "Gas warning", Icon::Alarm),
+        "alarm" => ("ALARM", "GAS ALARM", Icon::Alarm),
+        _ => ("OK", "No gas", Icon::Clear),
+    };
+    let label = match style.format {
+        OutputFormat::Short => short,
+        OutputFormat::Long => long,
+        OutputFormat::Icons => style.icon(icon),
+    };
+
+    let mut fields = vec![
+        Field::text("alarm", String::new(), Metric::Text(label.to_string()))
+            .raw(Value::String(level.to_string())),
+        Field::text(
+            "ppm",
+            style.label("Gas", "Concentration", Icon::Gas),
+            Metric::Int(ppm),
+        )
+        .unit("ppm"),
+    ];
+    if let Some(valve) = valve_state(&device_status) {
+        // `opened`, `closed`, or transitions such as `closing` and `checking`
+        let value = match valve {
+            "opened" => "open",
+            state => state,
+        };
+        fields.push(
+            Field::text(
+                "valve",
+                style.label("Valve", "Valve", Icon::Valve),
+                Metric::Text(value.replace('_', " ")),
+            )
+            .raw(Value::String(valve.to_string())),
+        );
+    }
+    fields.extend([
+        Field::tooltip(
+            "sensor",
+            style.label("Sensor", "Sensor", Icon::Sensor),
+            Metric::Text(sensor_state.to_string()),
+        ),
+        Field::tooltip(
+            "self_test",
+            style.label("Test", "Self-test", Icon::SelfTest),
+            Metric::Text(self_test.replace('_', " ")),
+        )
+        .raw(Value::String(self_test.to_string())),
+    ]);
+    fields
+}
+
+fn parse_motion_data(device_status: Value, style: &Style, now: DateTime<Utc>) -> Vec<Field> {
+    let sensor = &device_status["sensor"];
+    let motion = sensor["motion"].as_bool().unwrap_or(false);
+    let lux = device_status["lux"]["value"].as_i64().unwrap_or(0);
+    let battery = device_status["bat"]["value"].as_i64().unwrap_or(0);
+    // Unix time of the last motion, 0 until the first one
+    let last_motion = sensor["timestamp"]
+        .as_i64()
+        .filter(|timestamp| *timestamp > 0)
+        .and_then(|timestamp| DateTime::from_timestamp(timestamp, 0));
+
+    let (short, long, icon) = if motion {
+        ("Motion", "Motion", Icon::Motion)
+    } else {
+        ("Idle", "No motion", Icon::Still)
+    };
+    let label = match style.format {
+        OutputFormat::Short => short,
+        OutputFormat::Long => long,
+        OutputFormat::Icons => style.icon(icon),
+    };
+
+    let mut fields = vec![
+        Field::text("motion", String::new(), Metric::Text(label.to_string()))
+            .raw(Value::Bool(motion)),
+        Field::text("lux", style.label("L", "Lux", Icon::Lux), Metric::Int(lux)),
+        Field::tooltip(
+            "battery",
+            style.label("B", "Battery", Icon::Battery),
+            Metric::Int(battery),
+        )
+        .unit("%"),
+    ];
+    if let Some(last_motion) = last_motion {
+        fields.push(
+            Field::tooltip(
+                "last_motion",
+                style.label("Last", "Last motion", Icon::Motion),
+                Metric::Text(format!(
+                    "{} ago",
+                    humanize_age((now - last_motion).num_seconds())
+                )),
+            )
+            .raw(Value::String(last_motion.to_rfc3339())),
+        );
+    }
+    fields
+}
+
+/// Readings of a thermostatic radiator valve, temperatures in °C.
+#[derive(Debug, PartialEq)]
+struct TrvStatus {
+    temperature: f64,
+    target: f64,
+    valve: i64,
+    battery: Option<i64>,
+}
+
+// Gen1 TRVs report a `thermostats` array, BLU TRVs a `blutrv:<id>` component on their gateway
+fn trv_status(device_status: &Value) -> Option<TrvStatus> {
+    if let Some(thermostat) = device_status["thermostats"].get(0) {
+        return Some(TrvStatus {
+            temperature: thermostat["tmp"]["value"].as_f64().unwrap_or(0.0),
+            target: thermostat["target_t"]["value"].as_f64().unwrap_or(0.0),
+            valve: thermostat["pos"].as_f64().unwrap_or(0.0).round() as i64,
+            battery: device_status["bat"]["value"].as_i64(),
+        });
+    }
+
+    let (_, blutrv) = device_status
+        .as_object()?
+        .iter()
+        .find(|(key, _)| key.starts_with("blutrv:"))?;
+    Some(TrvStatus {
+        temperature: blutrv["current_C"].as_f64().unwrap_or(0.0),
+        target: blutrv["target_C"].as_f64().unwrap_or(0.0),
+        valve: blutrv["pos"].as_i64().unwrap_or(0),
+        battery: blutrv["battery"].as_i64(),
+    })
+}
+
+fn parse_trv_data(device_status: Value, style: &Style, unit: &str) -> Vec<Field> {
+    let Some(trv) = trv_status(&device_status) else {
+        return Vec::new();
+    };
+    let (convert, unit_label): (fn(f64) -> f64, _) = if unit == "F" {
+        (|celsius| celsius * 9.0 / 5.0 + 32.0, "°F")
+    } else {
+        (|celsius| celsius, "°C")
+    };
+
+    let mut fields = vec![
+        Field::text(
+            "temperature",
+            style.label("T", "Temp", Icon::Temperature),
+            Metric::Float(convert(trv.temperature), 1),
+        )
+        .unit(unit_label),
+        Field::text(
+            "target",
+            style.label("Set", "Target", Icon::Target),
+            Metric::Float(convert(trv.target), 1),
+        )
+        .unit(unit_label),
+        Field::text(
+            "valve",
+            style.label("Valve", "Valve", Icon::Valve),
+            Metric::Int(trv.valve),
+        )
+        .unit("%"),
+    ];
+    if let Some(battery) = trv.battery {
+        fields.push(
+            Field::tooltip(
+                "battery",
+                style.label("B", "Battery", Icon::Battery),
+                Metric::Int(battery),
+            )
+            .unit("%"),
+        );
+    }
+    fields
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use fields::FieldSelection;
+    use serde_json::json;
+
+    fn render(fields: Vec<Field>) -> Value {
+        render_fields(&fields, &FieldSelection::default())
+    }
+
+    // Test: Autodetect Device Type
+    #[test]
+    fn test_autodetect_device_type() {
+        let temp_json = json!({ "temperature:0": { "tC": 22.5 } });
+        let plug_json = json!({ "switch:0": { "apower": 50.0 } });
+        let door_json = json!({ "window:0": { "open": true } });
+        let window_json = json!({ "tilt:0": { "angle": 30 } });
+        let cover_json = json!({ "cover:0": { "state": "open" } });
+        let trv_json = json!({ "thermostats": [{ "pos": 40.0 }] });
+        let smoke_json = json!({ "smoke:0": { "alarm": false } });
+        let gas_json = json!({ "gas_sensor": { "alarm_state": "none" } });
+        let motion_json = json!({ "sensor": { "motion": false }, "lux": { "value": 10 } });
+        let dimmer_json = json!({ "light:0": { "output": true, "brightness": 50 } });
+        let rgbw_json = json!({ "rgbw:0": { "output": true } });
+        let rgb_json = json!({ "rgb:0": { "output": true } });
+        let em3_json = json!({ "em:0": { "total_act_power": 100.0 } });
+        let em_json = json!({ "emeters": [{ "power": 10.0 }] });
+        let input_json = json!({ "input:0": { "state": true }, "input:1": { "state": false } });
+        let relay_json = json!({ "input:0": {}, "input:1": {}, "switch:0": {} });
+        let uni_json = json!({ "adcs": [{ "voltage": 3.2 }], "relays": [] });
+        let gen1_ht_json =
+            gen1::normalize(json!({ "tmp": { "value": 21.0 }, "hum": { "value": 40 } }));
+        let button_json = json!({ "inputs": [{ "input": 0, "event": "S", "event_cnt": 3 }] });
+        let pm_json = json!({ "pm1:0": { "apower": 12.0 } });
+        let display_json = json!({
+            "temperature:0": { "tC": 21.0 },
+            "humidity:0": { "rh": 40 },
+            "illuminance:0": { "lux": 120 },
+            "switch:0": { "output": false }
+        });
+        let blu_json = json!({ "bthomesensor:200": { "value": 21.5 }, "bthomedevice:200": {} });
+        let gen1_door_json = gen1::normalize(json!({ "sensor": { "state": "close" } }));
+        let blutrv_json = json!({ "blutrv:200": { "pos": 40 }, "switch:0": {} });
+        let unknown_json = json!({});
+
+        assert_eq!(
+            autodetect_device_type(&temp_json),
+            Some(DeviceType::Temperature)
+        );
+        assert_eq!(autodetect_device_type(&plug_json), Some(DeviceType::Plug));
+        assert_eq!(autodetect_device_type(&door_json), Some(DeviceType::Door));
+        assert_eq!(
+            autodetect_device_type(&window_json),
+            Some(DeviceType::Window)
+        );
+        assert_eq!(autodetect_device_type(&cover_json), Some(DeviceType::Cover));
+        let lux_json = json!({ "illuminance:0": { "lux": 120 } });
+        assert_eq!(autodetect_device_type(&lux_json), Some(DeviceType::Lux));
+        assert_eq!(autodetect_device_type(&trv_json), Some(DeviceType::Trv));
+        assert_eq!(autodetect_device_type(&blutrv_json), Some(DeviceType::Trv));
+        assert_eq!(autodetect_device_type(&smoke_json), Some(DeviceType::Smoke));
+        assert_eq!(autodetect_device_type(&gas_json), Some(DeviceType::Gas));
+        assert_eq!(
+            autodetect_device_type(&motion_json),
+            Some(DeviceType::Motion)
+        );
+        assert_eq!(
+            autodetect_device_type(&dimmer_json),
+            Some(DeviceType::Dimmer)
+        );
+        let light_json = json!({ "light:0": { "output": true, "brightness": 50, "ct": 2700 } });
+        assert_eq!(autodetect_device_type(&light_json), Some(DeviceType::Light));
+        assert_eq!(autodetect_device_type(&rgbw_json), Some(DeviceType::Rgbw));
+        assert_eq!(autodetect_device_type(&rgb_json), Some(DeviceType::Rgbw));
+        assert_eq!(autodetect_device_type(&em3_json), Some(DeviceType::Em3));
+        assert_eq!(autodetect_device_type(&em_json), Some(DeviceType::Em));
+        assert_eq!(autodetect_device_type(&input_json), Some(DeviceType::Input));
+        assert_eq!(autodetect_device_type(&relay_json), Some(DeviceType::Plug));
+        assert_eq!(autodetect_device_type(&uni_json), Some(DeviceType::Uni));
+        assert_eq!(autodetect_device_type(&blu_json), Some(DeviceType::Blu));
+        assert_eq!(autodetect_device_type(&pm_json), Some(DeviceType::Plug));
+        assert_eq!(
+            autodetect_device_type(&button_json),
+            Some(DeviceType::Button)
+        );
+        assert_eq!(
+            autodetect_device_type(&display_json),
+            Some(DeviceType::Display)
+        );
+        assert_eq!(
+            autodetect_device_type(&gen1_ht_json),
+            Some(DeviceType::Temperature)
+        );
+        assert_eq!(
+            autodetect_device_type(&gen1_door_json),
+            Some(DeviceType::Door)
+        );
+        assert_eq!(autodetect_device_type(&unknown_json), None);
+    }
+
+    // Test: Autodetect Device Type Any Index
+    #[test]
+    fn test_autodetect_device_type_any_index() {
+        // Door/Window 2 measuring its tilt, Gen2 and Gen1
+        let tilted = json!({
+            "window:0": { "open": true },
+            "tilt:0": { "angle": 12 },
+            "illuminance:0": { "lux": 40 }
+        });
+        assert_eq!(autodetect_device_type(&tilted), Some(DeviceType::Window));
+        let gen1_tilted = gen1::normalize(json!({
+            "sensor": { "state": "open" },
+            "accel": { "tilt": 12, "vibration": 0 },
+            "lux": { "value": 40 }
+        }));
+        assert_eq!(
+            autodetect_device_type(&gen1_tilted),
+            Some(DeviceType::Window)
+        );
+        let door = json!({ "window:0": { "open": false }, "illuminance:0": { "lux": 40 } });
+        assert_eq!(autodetect_device_type(&door), Some(DeviceType::Door));
+
+        // Add-on probes only, from id 100
+        let addon = json!({ "temperature:100": { "tC": 21.0 }, "sys": {} });
+        assert_eq!(
+            autodetect_device_type(&addon),
+            Some(DeviceType::Temperature)
+        );
+        let second_cover = json!({ "cover:1": { "state": "open" }, "input:0": {}, "input:1": {} });
+        assert_eq!(
+            autodetect_device_type(&second_cover),
+            Some(DeviceType::Cover)
+        );
+
+        // Relays with an Add-on probe stay relays, keeping their power readings
+        let relay_probe = json!({ "switch:0": {}, "temperature:100": { "tC": 21.0 } });
+        assert_eq!(autodetect_device_type(&relay_probe), Some(DeviceType::Plug));
+        assert_eq!(
+            device_type_scores(&relay_probe),
+            vec![(DeviceType::Plug, 40)]
+        );
+        let pm_probe = json!({ "pm1:0": {}, "humidity:100": { "rh": 40 } });
+        assert_eq!(autodetect_device_type(&pm_probe), Some(DeviceType::Plug));
+    }
+
+    // Test: Autodetect Gen1 Device Type
+    #[test]
+    fn test_autodetect_gen1_device_type() {
+        let detect = |status: Value| autodetect_device_type(&gen1::normalize(status));
+
+        // Plug S
+        let plug = json!({
+            "relays": [{ "ison": true }],
+            "meters": [{ "power": 50.2 }],
+            "wifi_sta": { "rssi": -60 }
+        });
+        assert_eq!(detect(plug), Some(DeviceType::Plug));
+        // Shelly 2.5 in relay mode
+        let relays = json!({
+            "relays": [{ "ison": true }, { "ison": false }],
+            "meters": [{ "power": 12.0 }, { "power": 0.0 }],
+            "voltage": 231.2
+        });
+        assert_eq!(detect(relays), Some(DeviceType::Plug));
+        // Shelly 1PM with an Add-on probe
+        let relay_probe = json!({
+            "relays": [{ "ison": false }],
+            "meters": [{ "power": 0.0 }],
+            "ext_temperature": { "0": { "tC": 21.5 } }
+        });
+        assert_eq!(detect(relay_probe), Some(DeviceType::Plug));
+        // H&T set to Fahrenheit
+        let ht = json!({
+            "tmp": { "value": 70.7, "units": "F", "is_valid": true },
+            "hum": { "value": 45.5 },
+            "bat": { "value": 90 }
+        });
+        assert_eq!(detect(ht), Some(DeviceType::Temperature));
+        // Door/Window 2 not calibrated yet, without a tilt
+        let door = json!({
+            "sensor": { "state": "open" },
+            "accel": { "tilt": -1, "vibration": 0 },
+            "lux": { "value": 40 },
+            "bat": { "value": 80 }
+        });
+        assert_eq!(detect(door), Some(DeviceType::Door));
+        // Motion
+        let motion =
+            json!({ "sensor": { "motion": true, "vibration": false }, "lux": { "value": 12 } });
+        assert_eq!(detect(motion), Some(DeviceType::Motion));
+    }
+
+    // Test: Match Device Type
+    #[test]
+    fn test_match_device_type() {
+        assert_eq!(
+            match_device_type("temperature"),
+            Some(DeviceType::Temperature)
+        );
+        assert_eq!(match_device_type("humidity"), Some(DeviceType::Temperature));
+        assert_eq!(match_device_type("plug"), Some(DeviceType::Plug));
+        assert_eq!(match_device_type("door"), Some(DeviceType::Door));
+        assert_eq!(match_device_type("window"), Some(DeviceType::Window));
+        assert_eq!(match_device_type("cover"), Some(DeviceType::Cover));
+        assert_eq!(match_device_type("trv"), Some(DeviceType::Trv));
+        assert_eq!(match_device_type("smoke"), Some(DeviceType::Smoke));
+        assert_eq!(match_device_type("gas"), Some(DeviceType::Gas));
+        assert_eq!(match_device_type("motion"), Some(DeviceType::Motion));
+        assert_eq!(match_device_type("dimmer"), Some(DeviceType::Dimmer));
+        assert_eq!(match_device_type("light"), Some(DeviceType::Light));
+        assert_eq!(match_device_type("lux"), Some(DeviceType::Lux));
+        assert_eq!(match_device_type("Temp"), Some(DeviceType::Temperature));
+        assert_eq!(match_device_type("ht"), Some(DeviceType::Temperature));
+        assert_eq!(match_device_type("socket"), Some(DeviceType::Plug));
+        assert_eq!(match_device_type("Doors"), Some(DeviceType::Door));
+        assert_eq!(match_device_type("gas"), Some(DeviceType::Gas));
+        assert_eq!(match_device_type("sockets"), Some(DeviceType::Plug));
+        assert_eq!(match_device_type("Switches"), Some(DeviceType::Plug));
+        assert_eq!(match_device_type("rgbw"), Some(DeviceType::Rgbw));
+        assert_eq!(match_device_type("em3"), Some(DeviceType::Em3));
+        assert_eq!(match_device_type("em"), Some(DeviceType::Em));
+        assert_eq!(match_device_type("input"), Some(DeviceType::Input));
+        assert_eq!(match_device_type("uni"), Some(DeviceType::Uni));
+        assert_eq!(match_device_type("blu"), Some(DeviceType::Blu));
+        assert_eq!(match_device_type("display"), Some(DeviceType::Display));
+        assert_eq!(match_device_type("button"), Some(DeviceType::Button));
+        assert_eq!(match_device_type("custom"), Some(DeviceType::Custom));
+        assert_eq!(match_device_type("unknown"), None);
+    }
+
+    // Test: Parse Device Info
+    #[test]
+    fn test_parse_device_info() {
+        let device = "temperature:12345:Living Room";
+        let device_with_no_name = "plug:67890";
+        let invalid_device = "invalidformat";
+
+        assert_eq!(
+            parse_device_info(device),
+            Some(("temperature", "12345", Some("Living Room".to_string())))
+        );
+        assert_eq!(
+            parse_device_info(device_with_no_name),
+            Some(("plug", "67890", None))
+        );
+        assert_eq!(parse_device_info(invalid_device), None);
+        assert_eq!(
+            parse_device_info("em:13579:House:grid,solar"),
+            Some(("em", "13579", Some("House".to_string())))
+        );
+        assert_eq!(
+            parse_device_info("em:13579::grid,solar"),
+            Some(("em", "13579", None))
+        );
+        assert_eq!(
+            parse_device_info("plug:67890:Office#1"),
+            Some(("plug", "67890", Some("Office".to_string())))
+        );
+        assert_eq!(
+            parse_device_info("plug:67890#1"),
+            Some(("plug", "67890", None))
+        );
+    }
+
+    // Test: HTTP Client
+    #[test]
+    fn test_http_client() {
+        let args = Args::parse_from(["shelly-waybar", "--devices", "plug:1", "--insecure"]);
+        assert!(args.http_client().is_ok());
+        assert_eq!(args.timeout, 10);
+
+        let args = Args::parse_from([
+            "shelly-waybar",
+            "--devices",
+            "plug:1",
+            "--timeout",
+            "3",
+            "--connect-timeout",
+            "1",
+        ]);
+        assert!(args.http_client().is_ok());
+
+        let args = Args::parse_from([
+            "shelly-waybar",
+            "--devices",
+            "plug:1",
+            "--ca-cert",
+            "/nonexistent/ca.pem",
+        ]);
+        assert!(args.http_client().is_err());
+    }
+
+    // Test: Events Keep The Poll Schedule
+    #[tokio::test(start_paused = true)]
+    async fn test_wakeups_keep_poll_schedule() {
+        let args = Args::parse_from(["shelly-waybar", "--devices", "plug:1", "--interval", "60"]);
+        let (sender, webhooks) = mpsc::unbounded_channel();
+        let mut wakeups = Wakeups {
+            webhooks: Some(webhooks),
+            pushes: None,
+            updates: None,
+            announces: None,
+            streams: None,
+            toggles: None,
+            next_poll: Instant::now() + Duration::from_secs(60),
+        };
+        let (config, client, key) = (Config::default(), Client::new(), Secret::new(""));
+        let start = Instant::now();
+
+        // An event every 10 s refreshes its device without pushing back the full poll
+        for _ in 0..5 {
+            tokio::time::sleep(Duration::from_secs(10)).await;
+            let webhook = Webhook {
+                device_id: "1".to_string(),
+                event: None,
+            };
+            sender.send(webhook.clone()).unwrap();
+            assert_eq!(
+                wakeups
+                    .wait(
+                        &args,
+                        &config,
+                        &client,
+                        "",
+                        &key,
+                        &mut Connections::default()
+                    )
+                    .await,
+                Some(webhook)
+            );
+        }
+        assert_eq!(
+            wakeups
+                .wait(
+                    &args,
+                    &config,
+                    &client,
+                    "",
+                    &key,
+                    &mut Connections::default()
+                )
+                .await,
+            None
+        );
+        assert_eq!(start.elapsed(), Duration::from_secs(60));
+    }
+
+    // Test: Check Tilt Thresholds
+    #[test]
+    fn test_check_tilt_thresholds() {
+        let args = Args::parse_from(["shelly-waybar", "--devices", "window:1"]);
+        assert_eq!(args.check_tilt_thresholds(), Ok(()));
+
+        let args = Args::parse_from([
+            "shelly-waybar",
+            "--devices",
+            "window:1",
+            "--tilt-closed-max",
+            "30",
+        ]);
+        assert_eq!(
+            args.check_tilt_thresholds(),
+            Err("--tilt-closed-max (30) must be below --tilt-wide-open-min (30)".to_string())
+        );
+    }
+
+    // Test: Serves Socket
+    #[test]
+    fn test_serves_socket() {
+        let args = Args::parse_from(["shelly-waybar", "--devices", "plug:1"]);
+        assert!(!args.serves_socket());
+
+        let args = Args::parse_from(["shelly-waybar", "--devices", "plug:1", "--serve"]);
+        assert!(args.serves_socket());
+        assert_eq!(args.socket_path(), ipc::default_socket_path());
+
+        let args = Args::parse_from([
+            "shelly-waybar",
+            "--socket",
+            "/tmp/shelly.sock",
+            "--devices",
+            "plug:1",
+        ]);
+        assert!(args.serves_socket());
+        assert_eq!(args.socket_path(), PathBuf::from("/tmp/shelly.sock"));
+
+        // The variable of the clients only picks the path
+        env::set_var("SHELLY_WAYBAR_SOCKET", "/tmp/shelly-env.sock");
+        let args = Args::parse_from(["shelly-waybar", "--devices", "plug:1"]);
+        assert!(!args.serves_socket());
+        assert_eq!(args.socket_path(), PathBuf::from("/tmp/shelly-env.sock"));
+        env::remove_var("SHELLY_WAYBAR_SOCKET");
+    }
+
+    // Test: HTTP Client Compression
+    #[tokio::test]
+    async fn test_http_client_compression() {
+        use httpmock::MockServer;
+
+        let server = MockServer::start_async().await;
+        let compressed = server.mock(|when, then| {
+            when.path("/").header("accept-encoding", "gzip, br");
+            then.status(200);
+        });
+
+        let args = Args::parse_from(["shelly-waybar", "--devices", "plug:1"]);
+        let client = args.http_client().unwrap();
+        client.get(server.base_url()).send().await.unwrap();
+        compressed.assert();
+
+        let args = Args::parse_from(["shelly-waybar", "--devices", "plug:1", "--no-compression"]);
+        let client = args.http_client().unwrap();
+        let response = client.get(server.base_url()).send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    // Test: Process Device Short Text
+    #[test]
+    fn test_process_device_short_text() {
+        let status = json!({
+            "temperature:0": { "tC": 22.5, "tF": 72.5 },
+            "humidity:0": { "rh": 50 }
+        });
+        let device = "temperature:12345:Balcony".to_string();
+
+        let args = Args::parse_from(["shelly-waybar", "--devices", &device]);
+        let reading = process_device(
+            &device,
+            status.clone(),
+            &args,
+            &Config::default(),
+            &mut PollState::default(),
+            &notify::Noop,
+        )
+        .unwrap();
+        assert_eq!(reading.short_text, None);
+
+        let args = Args::parse_from([
+            "shelly-waybar",
+            "--devices",
+            &device,
+            "--output",
+            "i3blocks",
+        ]);
+        let reading = process_device(
+            &device,
+            status,
+            &args,
+            &Config::default(),
+            &mut PollState::default(),
+            &notify::Noop,
+        )
+        .unwrap();
+        assert_eq!(
+            reading.output["text"],
+            "Temp: 22.5°C Humidity: 50% (Balcony)"
+        );
+        assert_eq!(reading.short_text.as_deref(), Some("T: 22.5°C H: 50%"));
+        assert_eq!(reading.device_type, "temperature");
+    }
+
+    // Test: Device Records
+    #[test]
+    fn test_device_records() {
+        let device = "plug:67890:Desk".to_string();
+        let args = Args::parse_from(["shelly-waybar", "--devices", &device, "--output", "jsonl"]);
+        let now = Utc::now();
+        let mut state = PollState::default();
+        state.readings.insert(
+            device,
+            Reading {
+                output: json!({ "text": "", "tooltip": "" }),
+                updated_at: now - chrono::Duration::hours(2),
+                samples: vec![Sample {
+                    key: "power".to_string(),
+                    value: json!(50.2),
+                    unit: "W".to_string(),
+                }],
+                short_text: None,
+                device_type: "plug".to_string(),
+            },
+        );
+
+        let records = device_records(&args, &state, now);
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0]["device_id"], "67890");
+        assert_eq!(records[0]["name"], "Desk");
+        assert_eq!(records[0]["type"], "plug");
+        assert_eq!(records[0]["values"], json!({ "power": 50.2 }));
+        assert_eq!(records[0]["units"], json!({ "power": "W" }));
+        assert_eq!(records[0]["stale"], true);
+        assert_eq!(schema::validate_device_record(&records[0]), Ok(()));
+    }
+
+    // Test: Local Credentials
+    #[test]
+    fn test_local_credentials() {
+        let config: Config = toml::from_str(
+            r#"
+            [devices.shellyplug-s-1234]
+            host = "192.168.1.20"
+            password = "secret"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            local_credentials(&config, "shellyplug-s-1234").unwrap(),
+            Some(Credentials {
+                username: "admin".to_string(),
+                password: Secret::new("secret"),
+            })
+        );
+        assert_eq!(local_credentials(&config, "67890").unwrap(), None);
+        assert_eq!(
+            password_variable("shellyplug-s-1234"),
+            "SHELLY_WAYBAR_PASSWORD_SHELLYPLUG_S_1234"
+        );
+    }
+
+    // Test: Devices Credentials
+    #[tokio::test]
+    async fn test_devices_credentials() {
+        let config: Config = toml::from_str(
+            r#"
+            [devices.shellyplug-s-1234]
+            host = "192.168.1.20"
+            password = "secret"
+
+            [devices.67890]
+            password = "cloud"
+
+            [devices.13579]
+            host = "192.168.1.21"
+            password = "/"
+            "#,
+        )
+        .unwrap();
+
+        let args = Args::parse_from([
+            "shelly-waybar",
+            "--devices",
+            "plug:shellyplug-s-1234:Desk",
+            "plug:67890",
+        ]);
+        let credentials = devices_credentials(&args, &config).await.unwrap();
+        assert_eq!(credentials["shellyplug-s-1234"].username, "admin");
+        // Only devices polled on the LAN get credentials
+        assert!(!credentials.contains_key("67890"));
+
+        // A directory cannot be read as a password file
+        let args = Args::parse_from(["shelly-waybar", "--devices", "plug:13579"]);
+        assert!(devices_credentials(&args, &config).await.is_err());
+    }
+
+    // Test: Ethernet
+    #[test]
+    fn test_ethernet() {
+        let device_status = json!({
+            "switch:0": { "apower": 50.0, "voltage": 230.0, "current": 0.217, "output": true },
+            "eth": { "ip": "192.168.1.20" },
+            "wifi": { "sta_ip": null, "status": "disconnected", "ssid": null }
+        });
+        let ip = ethernet_ip(&device_status).unwrap().to_string();
+        assert_eq!(ip, "192.168.1.20");
+        let style = Style::from(OutputFormat::Short);
+        let mut fields = parse_plug_data(device_status, &style, &[]);
+        use_ethernet(&mut fields, ip, &style);
+        assert_eq!(
+            render(fields)["tooltip"],
+            "I: 0.217A Net: LAN IP: 192.168.1.20 O: ON"
+        );
+
+        let wifi = json!({ "eth": { "ip": null }, "wifi": { "rssi": -60 } });
+        assert_eq!(ethernet_ip(&wifi), None);
+    }
+
+    // Test: Script Fields
+    #[test]
+    fn test_script_fields() {
+        let device_status = json!({
+            "script:1": { "id": 1, "running": true, "mem_used": 1024 },
+            "script:2": { "id": 2, "running": false },
+            "script:3": { "id": 3, "running": false, "errors": ["crashed"] },
+            "switch:0": {}
+        });
+
+        let fields = script_fields(&device_status, &Style::from(OutputFormat::Long));
+        assert_eq!(fields[0].key, "script_1");
+        assert_eq!(fields[2].sample().value, Value::Bool(false));
+        let output = render(fields);
+        assert_eq!(
+            output["tooltip"],
+            "Script 1: running\nScript 2: stopped\nScript 3: error (crashed)"
+        );
+        assert!(script_fields(&json!({ "switch:0": {} }), &Style::default()).is_empty());
+    }
+
+    // Test: Lookup Path
+    #[test]
+    fn test_lookup_path() {
+        let status = json!({
+            "switch:0": { "aenergy": { "total": 1234.5 } },
+            "emeters": [{ "power": 1.0 }, { "power": 2.0 }]
+        });
+        assert_eq!(
+            lookup_path(&status, "switch:0.aenergy.total"),
+            Some(&json!(1234.5))
+        );
+        assert_eq!(lookup_path(&status, "emeters.1.power"), Some(&json!(2.0)));
+        assert_eq!(
+            lookup_path(&status, "$.emeters[0].power"),
+            Some(&json!(1.0))
+        );
+        assert_eq!(lookup_path(&status, "switch:1.output"), None);
+    }
+
+    // Test: Render Template
+    #[test]
+    fn test_render_template() {
+        let status = json!({
+            "temperature:0": { "tC": 21.46, "tF": 70.6 },
+            "humidity:0": { "rh": 48.2 },
+            "devicepower:0": { "battery": { "percent": 87 } },
+        });
+        let fields = parse_temperature_data(status, &Style::from(OutputFormat::Icons), "C");
+
+        assert_eq!(
+            render_template(
+                "{name}: {temperature} {humidity} {battery}",
+                &fields,
+                "12345",
+                Some("Bedroom")
+            ),
+            "Bedroom: 21.5°C 48% 87%"
+        );
+        assert_eq!(
+            render_template("{id} {unknown}", &fields, "12345", None),
+            "12345 {unknown}"
+        );
+    }
+
+    // Test: Parse Custom Data
+    #[test]
+    fn test_parse_custom_data() {
+        let status = json!({
+            "switch:0": { "output": true, "apower": 12.345, "aenergy": { "total": 1500 } }
+        });
+        let custom: CustomConfig = toml::from_str(
+            r#"
+            fields = [
+              { key = "power", path = "switch:0.apower", unit = "W", digits = 1 },
+              { key = "energy", path = "switch:0.aenergy.total", unit = "Wh" },
+              { key = "on", path = "switch:0.output" },
+              { key = "missing", path = "switch:1.output" },
+            ]
+            "#,
+        )
+        .unwrap();
+
+        let output = render_fields(
+            &parse_custom_data(status.clone(), &custom),
+            &Default::default(),
+        );
+        assert_eq!(
+            output["text"],
+            "power: 12.3W, energy: 1500Wh, on: ON, missing: -"
+        );
+
+        let custom = CustomConfig {
+            format: Some("{power} ({energy})".to_string()),
+            ..custom
+        };
+        let fields = parse_custom_data(status, &custom);
+        let output = render_fields(&fields, &Default::default());
+        assert_eq!(output["text"], "12.3W (1500Wh)");
+        assert_eq!(
+            output["tooltip"],
+            "power: 12.3W\nenergy: 1500Wh\non: ON\nmissing: -"
+        );
+        assert_eq!(fields[1].sample().value, json!(12.345));
+    }
+
+    // Test: Config Type
+    #[test]
+    fn test_config_type() {
+        let config: Config = toml::from_str(
+            r#"
+            [types.pool]
+            detect = ["temperature:0", "switch:0.output"]
+            format = "Water: {water}"
+            tooltip = "Pump: {pump}"
+            fields = [
+              { key = "water", path = "temperature:0.tC", unit = "°C", digits = 1 },
+              { key = "pump", path = "switch:0.output" },
+            ]
+            "#,
+        )
+        .unwrap();
+        let status = json!({
+            "temperature:0": { "tC": 26.54 },
+            "switch:0": { "output": true }
+        });
+
+        let pool = detect_config_type(&status, &config).unwrap();
+        assert!(detect_config_type(&json!({ "temperature:0": {} }), &config).is_none());
+
+        let fields = parse_custom_data(status, &pool.custom);
+        let output = render_fields(&fields, &Default::default());
+        assert_eq!(output["text"], "Water: 26.5°C");
+        assert_eq!(output["tooltip"], "Pump: ON");
+        assert!(fields
+            .iter()
+            .any(|field| field.key == "pump" && field.slot == Slot::Hidden));
+    }
+
+    // Test: Split Channel Selector
+    #[test]
+    fn test_split_channel_selector() {
+        assert_eq!(
+            split_channel_selector("plug:67890:Office#1"),
+            ("plug:67890:Office", Some(1))
+        );
+        assert_eq!(split_channel_selector("plug:67890"), ("plug:67890", None));
+        assert_eq!(
+            split_channel_selector("plug:67890:Room #A"),
+            ("plug:67890:Room #A", None)
+        );
+    }
+
+    // Test: Select Channel
+    #[test]
+    fn test_select_channel() {
+        let status = json!({
+            "switch:0": { "output": false, "apower": 0.0 },
+            "switch:1": { "output": true, "apower": 42.0 },
+            "wifi": { "rssi": -60 }
+        });
+        let status = select_channel(status, 1);
+        assert_eq!(status["switch:0"]["apower"], 42.0);
+        assert!(status.get("switch:1").is_none());
+        assert_eq!(status["wifi"]["rssi"], -60);
+
+        let fields = parse_plug_data(status, &Style::default(), &[]);
+        let output = render_fields(&fields, &Default::default());
+        assert!(output["text"].as_str().unwrap().starts_with("Power: 42.0W"));
+
+        let gen1 = select_channel(
+            json!({ "emeters": [{ "power": 1.0 }, { "power": 2.0 }] }),
+            1,
+        );
+        assert_eq!(gen1["emeters"], json!([{ "power": 2.0 }]));
+    }
+
+    // Test: Parse Channel Names
+    #[test]
+    fn test_parse_channel_names() {
+        assert_eq!(
+            parse_channel_names("em:13579:House:grid, solar"),
+            vec!["grid", "solar"]
+        );
+        assert!(parse_channel_names("em:13579:House").is_empty());
+        assert_eq!(
+            parse_channel_names("plug:67890:Desk:Lamp,Fan"),
+            vec!["Lamp", "Fan"]
+        );
+        assert!(parse_channel_names("door:67890:Front:Back").is_empty());
+    }
+
+    // Test: Parse Temperature Data
+    #[test]
+    fn test_parse_temperature_data() {
+        let device_status = json!({
+            "temperature:0": { "tC": 22.5, "tF": 72.5 },
+            "humidity:0": { "rh": 50 },
+            "devicepower:0": { "battery": { "percent": 80 } },
+            "reporter": { "rssi": -60 }
+        });
+
+        let output = render(parse_temperature_data(
+            device_status.clone(),
+            &Style::from(OutputFormat::Short),
+            "C",
+        ));
+        assert_eq!(output["text"], "T: 22.5°C H: 50%");
+        assert_eq!(output["tooltip"], "B: 80% RSSI: -60dBm");
+
+        let output = render(parse_temperature_data(
+            device_status.clone(),
+            &Style::from(OutputFormat::Long),
+            "F",
+        ));
+        assert_eq!(output["text"], "Temp: 72.5°F Humidity: 50%");
+        assert_eq!(output["tooltip"], "Battery: 80% RSSI: -60dBm");
+
+        let output = render(parse_temperature_data(
+            device_status,
+            &Style::from(OutputFormat::Icons),
+            "C",
+        ));
+        assert_eq!(output["text"], "22.5°C 💧50%");
+        assert_eq!(output["tooltip"], "🔋80% 📶-60dBm");
+    }
+
+    // Test: Parse Temperature Data Add-on Probes
+    #[test]
+    fn test_parse_temperature_data_addon_probes() {
+        let device_status = json!({
+            "temperature:0": { "tC": 22.5, "tF": 72.5 },
+            "humidity:0": { "rh": 50.4 },
+            "temperature:101": { "tC": 18.0, "tF": 64.4 },
+            "temperature:100": { "tC": 4.5, "tF": 40.1 },
+            "humidity:100": { "rh": 81 }
+        });
+
+        let fields = parse_temperature_data(
+            device_status.clone(),
+            &Style::from(OutputFormat::Short),
+            "C",
+        );
+        assert_eq!(fields[2].key, "temperature_100");
+        let output = render(fields);
+        assert_eq!(
+            output["text"],
+            "T: 22.5°C H: 50% T100: 4.5°C H100: 81% T101: 18.0°C"
+        );
+
+        let output = render(parse_temperature_data(
+            device_status,
+            &Style::from(OutputFormat::Long),
+            "F",
+        ));
+        assert_eq!(
+            output["text"],
+            "Temp: 72.5°F Humidity: 50% Temp 100: 40.1°F Humidity 100: 81% Temp 101: 64.4°F"
+        );
+
+        // A relay with a single probe on its Add-on
+        let addon_only = json!({ "temperature:100": { "tC": 19.25 } });
+        let output = render(parse_temperature_data(
+            addon_only,
+            &Style::from(OutputFormat::Short),
+            "C",
+        ));
+        assert_eq!(output["text"], "T: 19.2°C H: 0%");
+    }
+
+    // Test: Parse Temperature Data Humidity Only
+    #[test]
+    fn test_parse_temperature_data_humidity_only() {
+        let device_status = json!({
+            "humidity:0": { "rh": 63.2 },
+            "devicepower:0": { "battery": { "percent": 77 } },
+            "reporter": { "rssi": -71 }
+        });
+
+        let fields = parse_temperature_data(
+            device_status.clone(),
+            &Style::from(OutputFormat::Short),
+            "C",
+        );
+        assert!(fields.iter().all(|field| field.key != "temperature"));
+        assert_eq!(render(fields)["text"], "H: 63%");
+        assert_eq!(
+            autodetect_device_type(&device_status),
+            Some(DeviceType::Temperature)
+        );
+    }
+
+    // Test: Parse Temperature Data Power Source
+    #[test]
+    fn test_parse_temperature_data_power_source() {
+        let usb = json!({
+            "temperature:0": { "tC": 21.0 },
+            "humidity:0": { "rh": 45 },
+            "devicepower:0": { "battery": { "V": 0.0, "percent": 0 }, "external": { "present": true } },
+            "reporter": { "rssi": -60 }
+        });
+        let output = render(parse_temperature_data(
+            usb.clone(),
+            &Style::from(OutputFormat::Short),
+            "C",
+        ));
+        assert_eq!(output["tooltip"], "Src: ext RSSI: -60dBm");
+
+        let output = render(parse_temperature_data(
+            usb,
+            &Style::from(OutputFormat::Icons),
+            "C",
+        ));
+        assert_eq!(output["tooltip"], "⚡🔌 📶-60dBm");
+
+        let battery = json!({
+            "temperature:0": { "tC": 21.0 },
+            "devicepower:0": { "battery": { "V": 5.8, "percent": 74 }, "external": { "present": false } },
+            "reporter": { "rssi": -60 }
+        });
+        let output = render(parse_temperature_data(
+            battery,
+            &Style::from(OutputFormat::Long),
+            "C",
+        ));
+        assert_eq!(
+            output["tooltip"],
+            "Power: Battery Battery: 74% RSSI: -60dBm"
+        );
+    }
+
+    // Test: Parse Plug Data
+    #[test]
+    fn test_parse_plug_data() {
+        let device_status = json!({
+            "switch:0": { "apower": 50.0, "voltage": 230.0, "current": 0.217, "output": true },
+            "wifi": { "rssi": -70 }
+        });
+
+        let output = render(parse_plug_data(
+            device_status.clone(),
+            &Style::from(OutputFormat::Short),
+            &[],
+        ));
+        assert_eq!(output["text"], "P: 50.0W V: 230.0V");
+        assert_eq!(output["tooltip"], "I: 0.217A RSSI: -70dBm O: ON");
+
+        let output = render(parse_plug_data(
+            device_status.clone(),
+            &Style::from(OutputFormat::Long),
+            &[],
+        ));
+        assert_eq!(output["text"], "Power: 50.0W Voltage: 230.0V");
+        assert_eq!(
+            output["tooltip"],
+            "Current: 0.217A WiFi RSSI: -70dBm Output: ON"
+        );
+
+        let output = render(parse_plug_data(
+            device_status,
+            &Style::from(OutputFormat::Icons),
+            &[],
+        ));
+        assert_eq!(output["text"], "⚡50.0W 🔌230.0V");
+        assert_eq!(output["tooltip"], "🔋0.217A 📶-70dBm 🔆ON");
+
+        let with_energy = json!({
+            "switch:0": {
+                "apower": 50.0, "voltage": 230.0, "current": 0.217, "output": true,
+                "aenergy": { "total": 12345.6 }
+            },
+            "wifi": { "rssi": -70 }
+        });
+        let output = render(parse_plug_data(
+            with_energy,
+            &Style::from(OutputFormat::Short),
+            &[],
+        ));
+        assert_eq!(
+            output["tooltip"],
+            "I: 0.217A E: 12.35kWh RSSI: -70dBm O: ON"
+        );
+
+        let detached = json!({
+            "switch:0": { "apower": 0.0, "voltage": 230.0, "current": 0.0, "output": false },
+            "input:0": { "id": 0, "state": true },
+            "wifi": { "rssi": -70 }
+        });
+        let output = render(parse_plug_data(
+            detached.clone(),
+            &Style::from(OutputFormat::Long),
+            &[],
+        ));
+        assert_eq!(
+            output["tooltip"],
+            "Current: 0.000A WiFi RSSI: -70dBm Output: OFF Input: ON"
+        );
+        let output = render(parse_plug_data(
+            detached,
+            &Style::from(OutputFormat::Icons),
+            &[],
+        ));
+        assert_eq!(output["tooltip"], "🔋0.000A 📶-70dBm 🔆OFF 🔘 ON");
+    }
+
+    // Test: Tripped Plug
+    #[test]
+    fn test_tripped_plug() {
+        let device_status = json!({
+            "switch:0": {
+                "apower": 0.0, "voltage": 230.0, "current": 0.0, "output": false,
+                "errors": ["overpower"]
+            },
+            "wifi": { "rssi": -70 }
+        });
+
+        let output = render(parse_plug_data(
+            device_status.clone(),
+            &Style::from(OutputFormat::Short),
+            &[],
+        ));
+        assert!(output["tooltip"].as_str().unwrap().ends_with("O: TRIPPED"));
+
+        let fields = tripped_fields(&device_status, &Style::from(OutputFormat::Short));
+        assert_eq!(output_class(&fields), Some("alarm"));
+        assert_eq!(render(fields)["text"], "TRIPPED: overpower");
+
+        let healthy = json!({ "switch:0": { "output": true, "errors": [] } });
+        assert!(tripped_fields(&healthy, &Style::default()).is_empty());
+        assert_eq!(device_trip_errors(&healthy), None);
+    }
+
+    // Test: Overheat Fields
+    #[test]
+    fn test_overheat_fields() {
+        let device_status = json!({
+            "switch:0": { "output": true, "temperature": { "tC": 85.2, "tF": 185.4 } }
+        });
+
+        let fields = overheat_fields(&device_status, &Style::from(OutputFormat::Short), "C", 80.0);
+        assert_eq!(output_class(&fields), Some("warning"));
+        let output = render(fields);
+        assert_eq!(output["text"], "HOT");
+        assert_eq!(output["tooltip"], "Int: 85.2°C");
+
+        let fields = overheat_fields(&device_status, &Style::from(OutputFormat::Long), "F", 90.0);
+        assert_eq!(output_class(&fields), None);
+        let output = render(fields);
+        assert_eq!(output["text"], "");
+        assert_eq!(output["tooltip"], "Internal: 185.4°F");
+
+        assert!(
+            overheat_fields(&json!({ "switch:0": {} }), &Style::default(), "C", 80.0).is_empty()
+        );
+    }
+
+    // Test: Handle Overheat Status
+    #[test]
+    fn test_handle_overheat_status() {
+        struct Counter(std::cell::Cell<usize>);
+        impl Notifier for Counter {
+            fn notify(
+                &self,
+                _summary: &str,
+                _body: &str,
+                _urgency: Urgency,
+            ) -> Result<(), Box<dyn std::error::Error>> {
+                self.0.set(self.0.get() + 1);
+                Ok(())
+            }
+        }
+
+        let notifier = Counter(Default::default());
+        let timestamps = TimestampFormat {
+            clock: ClockFormat::H24,
+            timezone: Timezone::Named(Tz::UTC),
+        };
+        let hot = json!({ "switch:0": { "temperature": { "tC": 85.0 } } });
+        let overheating = Reading {
+            output: json!({}),
+            updated_at: Utc::now(),
+            short_text: None,
+            device_type: String::new(),
+            samples: vec![Sample {
+                key: "overheat".to_string(),
+                value: Value::Bool(true),
+                unit: String::new(),
+            }],
+        };
+
+        handle_overheat_status("12345", None, &hot, 90.0, None, &timestamps, &notifier);
+        assert_eq!(notifier.0.get(), 0);
+        handle_overheat_status(
+            "12345",
+            None,
+            &hot,
+            80.0,
+            Some(&overheating),
+            &timestamps,
+            &notifier,
+        );
+        assert_eq!(notifier.0.get(), 0);
+        handle_overheat_status("12345", None, &hot, 80.0, None, &timestamps, &notifier);
+        assert_eq!(notifier.0.get(), 1);
+    }
+
+    // Test: Parse Power Meter Data
+    #[test]
+    fn test_parse_power_meter_data() {
+        let device_status = json!({
+            "pm1:0": {
+                "id": 0, "voltage": 229.8, "current": 0.512, "apower": 95.3, "freq": 50.0,
+                "aenergy": { "total": 12345.6 }
+            },
+            "wifi": { "rssi": -62 }
+        });
+
+        let output = render(parse_plug_data(
+            device_status.clone(),
+            &Style::from(OutputFormat::Short),
+            &[],
+        ));
+        assert_eq!(output["text"], "P: 95.3W V: 229.8V");
+        assert_eq!(output["tooltip"], "I: 0.512A E: 12.35kWh RSSI: -62dBm");
+
+        let output = render(parse_plug_data(
+            device_status,
+            &Style::from(OutputFormat::Long),
+            &[],
+        ));
+        assert_eq!(
+            output["tooltip"],
+            "Current: 0.512A Energy: 12.35kWh WiFi RSSI: -62dBm"
+        );
+    }
+
+    // Test: Parse Plug Data Channels
+    #[test]
+    fn test_parse_plug_data_channels() {
+        let device_status = json!({
+            "switch:0": { "apower": 50.0, "voltage": 230.0, "current": 0.217, "output": true },
+            "switch:1": { "apower": 0.0, "voltage": 230.1, "current": 0.0, "output": false },
+            "wifi": { "rssi": -70 }
+        });
+
+        let fields = parse_plug_data(
+            device_status.clone(),
+            &Style::from(OutputFormat::Short),
+            &[],
+        );
+        assert_eq!(fields[0].key, "power");
+        assert_eq!(fields[1].key, "power_1");
+        let output = render(fields);
+        assert_eq!(output["text"], "Ch1: 50.0W Ch2: 0.0W");
+        assert_eq!(
+            output["tooltip"],
+            "Ch1: ON 230.0V 0.217A\nCh2: OFF 230.1V 0.000A\nRSSI: -70dBm"
+        );
+
+        let names = vec!["Heater".to_string(), "Fan".to_string()];
+        let output = render(parse_plug_data(
+            device_status,
+            &Style::from(OutputFormat::Icons),
+            &names,
+        ));
+        assert_eq!(output["text"], "⚡Heater 50.0W ⚡Fan 0.0W");
+        assert_eq!(
+            output["tooltip"],
+            "🔆Heater ON 230.0V 0.217A\n🔆Fan OFF 230.1V 0.000A\n📶-70dBm"
+        );
+    }
+
+    // Test: Parse Plug Data Aggregated Channels
+    #[test]
+    fn test_parse_plug_data_aggregated_channels() {
+        let device_status = json!({
+            "switch:0": { "apower": 50.0, "voltage": 230.0, "current": 0.217, "output": true },
+            "switch:1": { "apower": 0.0, "voltage": 230.1, "current": 0.0, "output": false },
+            "switch:2": { "apower": 25.5, "voltage": 230.2, "current": 0.111, "output": true },
+            "switch:3": { "apower": 0.0, "voltage": 230.0, "current": 0.0, "output": false },
+            "wifi": { "rssi": -70 }
+        });
+
+        let names = vec!["Heater".to_string(), "Fan".to_string()];
+        let fields = parse_plug_data(device_status, &Style::from(OutputFormat::Short), &names);
+        assert!(fields.iter().any(|field| field.key == "power_2"));
+        let output = render(fields);
+        assert_eq!(output["text"], "P: 75.5W");
+        assert_eq!(
+            output["tooltip"],
+            "Heater: ON 50.0W 230.0V 0.217A\nFan: OFF 0.0W 230.1V 0.000A\n\
+             Ch3: ON 25.5W 230.2V 0.111A\nCh4: OFF 0.0W 230.0V 0.000A\nRSSI: -70dBm"
+        );
+    }
+
+    // Test: ASCII Icon Set
+    #[test]
+    fn test_parse_dimmer_data() {
+        let device_status = json!({
+            "light:0": { "id": 0, "output": true, "brightness": 65.4, "apower": 23.25 },
+            "wifi": { "rssi": -55 }
+        });
+
+        let output = render(parse_dimmer_data(
+            device_status.clone(),
+            &Style::from(OutputFormat::Short),
+        ));
+        assert_eq!(output["text"], "O: ON Br: 65%");
+        assert_eq!(output["tooltip"], "P: 23.2W RSSI: -55dBm");
+
+        let output = render(parse_dimmer_data(
+            device_status.clone(),
+            &Style::from(OutputFormat::Long),
+        ));
+        assert_eq!(output["text"], "Output: ON Brightness: 65%");
+        assert_eq!(output["tooltip"], "Power: 23.2W WiFi RSSI: -55dBm");
+
+        let output = render(parse_dimmer_data(
+            device_status,
+            &Style::from(OutputFormat::Icons),
+        ));
+        assert_eq!(output["text"], "🔆ON 💡65%");
+    }
+
+    // Test: Parse Light Data
+    #[test]
+    fn test_parse_light_data() {
+        let device_status = json!({
+            "light:0": { "output": true, "brightness": 80, "ct": 2700, "white": 49.6, "apower": 6.5 },
+            "wifi": { "rssi": -60 }
+        });
+
+        let output = render(parse_light_data(
+            device_status.clone(),
+            &Style::from(OutputFormat::Short),
+        ));
+        assert_eq!(output["text"], "O: ON Br: 80% CT: 2700K");
+        assert_eq!(output["tooltip"], "W: 50% P: 6.5W RSSI: -60dBm");
+
+        let output = render(parse_light_data(
+            device_status,
+            &Style::from(OutputFormat::Icons),
+        ));
+        assert_eq!(output["text"], "🔆ON 💡80% 🌅2700K");
+    }
+
+    // Test: Parse RGBW Data
+    #[test]
+    fn test_parse_rgbw_data() {
+        let device_status = json!({
+            "rgbw:0": {
+                "output": true,
+                "brightness": 80,
+                "rgb": [255, 128, 0],
+                "white": 51,
+                "apower": 6.5
+            }
+        });
+
+        let output = render(parse_rgbw_data(
+            device_status.clone(),
+            &Style::from(OutputFormat::Short),
+        ));
+        assert_eq!(output["text"], "O: ON Br: 80%");
+        assert_eq!(output["tooltip"], "RGB: #ff8000 W: 20% P: 6.5W");
+
+        let output = render(parse_rgbw_data(
+            device_status.clone(),
+            &Style::from(OutputFormat::Icons),
+        ));
+        assert_eq!(output["text"], "🔆ON 💡80%");
+        assert_eq!(
+            output["tooltip"],
+            "<span color=\"#ff8000\">●</span> #ff8000 💡20% ⚡6.5W"
+        );
+
+        let ascii = Style {
+            format: OutputFormat::Icons,
+            icon_set: IconSet::Ascii,
+            ..Style::default()
+        };
+        let output = render(parse_rgbw_data(device_status, &ascii));
+        assert_eq!(output["tooltip"], "RGB:#ff8000 BR:20% P:6.5W");
+
+        let rgb = json!({ "rgb:0": { "output": false, "brightness": 0, "rgb": [0, 0, 255] } });
+        let output = render(parse_rgbw_data(rgb, &Style::from(OutputFormat::Long)));
+        assert_eq!(output["text"], "Output: OFF Brightness: 0%");
+        assert_eq!(output["tooltip"], "Color: #0000ff Power: 0.0W");
+    }
+
+    // Test: Parse EM3 Data
+    #[test]
+    fn test_parse_em3_data() {
+        let device_status = json!({
+            "em:0": {
+                "a_act_power": 400.12, "a_voltage": 230.1, "a_current": 1.74,
+                "b_act_power": 20.0, "b_voltage": 231.0, "b_current": 0.1,
+                "c_act_power": -150.5, "c_voltage": 229.4, "c_current": 0.66,
+                "total_act_power": 269.62, "total_current": 2.5
+            }
+        });
+
+        let output = render(parse_em3_data(
+            device_status.clone(),
+            &Style::from(OutputFormat::Short),
+        ));
+        assert_eq!(output["text"], "P: 269.6W");
+        assert_eq!(
+            output["tooltip"],
+            "I: 2.500A\nA: 400.1W 230.1V 1.740A\nB: 20.0W 231.0V 0.100A\nC: -150.5W 229.4V 0.660A"
+        );
+
+        let output = render(parse_em3_data(
+            device_status,
+            &Style::from(OutputFormat::Icons),
+        ));
+        assert_eq!(output["text"], "⚡269.6W");
+        assert!(output["tooltip"]
+            .as_str()
+            .unwrap()
+            .contains("\n⚡A 400.1W 230.1V 1.740A"));
+    }
+
+    // Test: Parse EM Data
+    #[test]
+    fn test_parse_em_data() {
+        let gen1 = json!({
+            "emeters": [
+                { "power": 1200.44, "voltage": 230.2, "total": 123456.0 },
+                { "power": -350.0, "voltage": 230.2, "total": 5000.0 }
+            ]
+        });
+        let names = vec!["grid".to_string(), "solar".to_string()];
+
+        let output = render(parse_em_data(
+            gen1.clone(),
+            &Style::from(OutputFormat::Short),
+            &names,
+        ));
+        assert_eq!(output["text"], "grid: 1200.4W solar: -350.0W");
+        assert_eq!(output["tooltip"], "grid: 123.46kWh solar: 5.00kWh");
+
+        let output = render(parse_em_data(gen1, &Style::from(OutputFormat::Long), &[]));
+        assert_eq!(output["text"], "Channel 1: 1200.4W Channel 2: -350.0W");
+
+        let pro = json!({
+            "em1:0": { "act_power": 80.0 },
+            "em1data:0": { "total_act_energy": 2500.0 }
+        });
+        let output = render(parse_em_data(
+            pro,
+            &Style::from(OutputFormat::Icons),
+            &names[..1],
+        ));
+        assert_eq!(output["text"], "⚡grid 80.0W");
+        assert_eq!(output["tooltip"], "📊grid 2.50kWh");
+    }
+
+    // Test: Parse Input Data
+    #[test]
+    fn test_parse_input_data() {
+        let device_status = json!({
+            "input:0": { "id": 0, "state": true },
+            "input:1": { "id": 1, "state": false },
+            "input:2": { "id": 2, "state": null },
+            "input:3": { "id": 3, "state": false }
+        });
+
+        let output = render(parse_input_data(
+            device_status.clone(),
+            &Style::from(OutputFormat::Short),
+        ));
+        assert_eq!(output["text"], "I1: ON I2: OFF I3: BTN I4: OFF");
+
+        let output = render(parse_input_data(
+            device_status.clone(),
+            &Style::from(OutputFormat::Long),
+        ));
+        assert_eq!(
+            output["text"],
+            "Input 1: ON Input 2: OFF Input 3: BTN Input 4: OFF"
+        );
+
+        let fields = parse_input_data(device_status, &Style::from(OutputFormat::Icons));
+        assert_eq!(fields[0].sample().value, true);
+        assert_eq!(render(fields)["text"], "🔘1 ON 🔘2 OFF 🔘3 BTN 🔘4 OFF");
+    }
+
+    // Test: Parse Uni Data
+    #[test]
+    fn test_parse_uni_data() {
+        let device_status = json!({
+            "adcs": [{ "voltage": 7.5 }],
+            "inputs": [{ "input": 1, "event": "" }, { "input": 0, "event": "" }],
+            "relays": [{ "ison": false }, { "ison": true }]
+        });
+
+        let output = render(parse_uni_data(
+            device_status.clone(),
+            &Style::from(OutputFormat::Short),
+            &AdcConfig::default(),
+        ));
+        assert_eq!(output["text"], "ADC: 7.50V");
+        assert_eq!(output["tooltip"], "I1: ON I2: OFF O1: OFF O2: ON");
+
+        let output = render(parse_uni_data(
+            device_status.clone(),
+            &Style::from(OutputFormat::Icons),
+            &AdcConfig::default(),
+        ));
+        assert_eq!(output["text"], "🔌ADC 7.50V");
+
+        let tank = AdcConfig {
+            label: Some("Tank".to_string()),
+            unit: "%".to_string(),
+            scale: 10.0,
+            offset: -5.0,
+            digits: 0,
+        };
+        let fields = parse_uni_data(device_status, &Style::from(OutputFormat::Long), &tank);
+        assert_eq!(fields[0].sample().unit, "%");
+        let output = render(fields);
+        assert_eq!(output["text"], "Tank: 70%");
+        assert_eq!(
+            output["tooltip"],
+            "Input 1: ON Input 2: OFF Output 1: OFF Output 2: ON"
+        );
+    }
+
+    // Test: Parse BLU Data
+    #[test]
+    fn test_parse_blu_data() {
+        let device_status = json!({
+            "switch:0": { "output": true },
+            "bthomedevice:200": { "id": 200, "rssi": -70, "battery": 95 },
+            "bthomedevice:201": { "id": 201, "rssi": -80, "battery": 60 },
+            "bthomesensor:200": { "id": 200, "value": 21.46 },
+            "bthomesensor:201": { "id": 201, "value": 48 },
+            "bthomesensor:202": { "id": 202, "value": true },
+            "bthomesensor:203": { "id": 203, "value": 3.3 }
+        });
+        let kinds = HashMap::from([
+            ("200".to_string(), BthomeKind::Temperature),
+            ("201".to_string(), BthomeKind::Humidity),
+            ("202".to_string(), BthomeKind::Window),
+        ]);
+
+        let fields = parse_blu_data(
+            device_status.clone(),
+            &Style::from(OutputFormat::Short),
+            &kinds,
+        );
+        assert_eq!(fields[2].sample().value, "open");
+        let output = render(fields);
+        assert_eq!(output["text"], "T: 21.5°C H: 48% Open S203: 3.3");
+        assert_eq!(
+            output["tooltip"],
+            "B200: 95% RSSI200: -70dBm B201: 60% RSSI201: -80dBm"
+        );
+
+        let output = render(parse_blu_data(
+            device_status,
+            &Style::from(OutputFormat::Long),
+            &HashMap::new(),
+        ));
+        assert_eq!(
+            output["text"],
+            "Sensor 200: 21.5 Sensor 201: 48.0 Sensor 202: ON Sensor 203: 3.3"
+        );
+    }
+
+    // Test: Parse Button Data
+    #[test]
+    fn test_parse_button_data() {
+        let timestamps = TimestampFormat {
+            clock: ClockFormat::H24,
+            timezone: Timezone::Named(Tz::UTC),
+        };
+        let updated_at = DateTime::parse_from_rfc3339("2024-01-15T10:20:30Z")
+            .unwrap()
+            .with_timezone(&Utc);
+        let button1 = json!({
+            "inputs": [{ "input": 0, "event": "SS", "event_cnt": 12 }],
+            "bat": { "value": 88 }
+        });
+
+        let fields = parse_button_data(
+            button1.clone(),
+            &Style::from(OutputFormat::Long),
+            &timestamps,
+            updated_at,
+        );
+        assert_eq!(fields[0].sample().value, "SS");
+        let output = render(fields);
+        assert_eq!(output["text"], "Double push");
+        assert_eq!(
+            output["tooltip"],
+            format!("Received: {} Battery: 88%", timestamps.format(updated_at))
+        );
+
+        let i3 = json!({
+            "inputs": [
+                { "input": 0, "event": "L" },
+                { "input": 0, "event": "" },
+                { "input": 1, "event": "S" }
+            ]
+        });
+        let output = render(parse_button_data(
+            i3,
+            &Style::from(OutputFormat::Short),
+            &timestamps,
+            updated_at,
+        ));
+        assert_eq!(output["text"], "I1: L I2: - I3: S");
+    }
+
+    // Test: Parse Display Data
+    #[test]
+    fn test_parse_display_data() {
+        let device_status = json!({
+            "temperature:0": { "tC": 21.34, "tF": 70.4 },
+            "humidity:0": { "rh": 41.6 },
+            "illuminance:0": { "lux": 230.2, "illumination": "bright" },
+            "switch:0": { "output": true },
+            "wifi": { "rssi": -58 }
+        });
+
+        let output = render(parse_display_data(
+            device_status.clone(),
+            &Style::from(OutputFormat::Short),
+            "C",
+        ));
+        assert_eq!(output["text"], "T: 21.3°C H: 42% L: 230");
+        assert_eq!(output["tooltip"], "O: ON RSSI: -58dBm");
+
+        let output = render(parse_display_data(
+            device_status,
+            &Style::from(OutputFormat::Long),
+            "F",
+        ));
+        assert_eq!(output["text"], "Temp: 70.4°F Humidity: 42% Lux: 230");
+        assert_eq!(output["tooltip"], "Output: ON WiFi RSSI: -58dBm");
+    }
+
+    // Test: Parse With ASCII Icon Set
+    #[test]
+    fn test_parse_with_ascii_icon_set() {
+        let style = Style {
+            format: OutputFormat::Icons,
+            icon_set: IconSet::Ascii,
+            ..Style::default()
+        };
+        let plug_status = json!({
+            "switch:0": { "apower": 50.0, "voltage": 230.0, "current": 0.217, "output": true },
+            "wifi": { "rssi": -70 }
+        });
+        let door_status = json!({
+            "window:0": { "open": false },
+            "illuminance:0": { "lux": 100 },
+            "devicepower:0": { "battery": { "percent": 90 } },
+            "reporter": { "rssi": -65 }
+        });
+
+        let output = render(parse_plug_data(plug_status, &style, &[]));
+        assert_eq!(output["text"], "P:50.0W V:230.0V");
+        assert_eq!(output["tooltip"], "I:0.217A S:-70dBm O:ON");
+
+        let output = render(parse_window_or_door_data(door_status, None, &style));
+        assert_eq!(output["text"], "CLOSED L:100");
+        assert_eq!(output["tooltip"], "B:90% S:-65dBm");
+    }
+
+    // Test: Parse Window/Door Data
+    #[test]
+    fn test_parse_window_or_door_data() {
+        let device_status = json!({
+            "window:0": { "open": true },
+            "illuminance:0": { "lux": 100 },
+            "devicepower:0": { "battery": { "percent": 90 } },
+            "reporter": { "rssi": -65 },
+            "tilt:0": { "angle": 30 }
+        });
+
+        let thresholds = TiltThresholds {
+            closed_max: 5,
+            wide_open_min: 30,
+        };
+
+        let output = render(parse_window_or_door_data(
+            device_status.clone(),
+            Some(thresholds),
+            &Style::from(OutputFormat::Short),
+        ));
+        assert_eq!(output["text"], "Open: L: 100, wide open");
+        assert_eq!(output["tooltip"], "B: 90% RSSI: -65dBm Tilt: 30°");
+
+        let output = render(parse_window_or_door_data(
+            device_status.clone(),
+            None,
+            &Style::from(OutputFormat::Long),
+        ));
+        assert_eq!(output["text"], "Open, Lux: 100");
+        assert_eq!(output["tooltip"], "Battery: 90% RSSI: -65dBm");
+
+        let output = render(parse_window_or_door_data(
+            device_status,
+            Some(thresholds),
+            &Style::from(OutputFormat::Icons),
+        ));
+        assert_eq!(output["text"], "🟢 🔆100, wide open");
+        assert_eq!(output["tooltip"], "🔋90% 📶-65dBm 📐30°");
+    }
+
+    // Test: Parse Cover Data
+    #[test]
+    fn test_parse_cover_data() {
+        let device_status = json!({
+            "cover:0": { "state": "closing", "current_pos": 40, "apower": 120.5 },
+            "wifi": { "rssi": -60 }
+        });
+
+        let output = render(parse_cover_data(
+            device_status.clone(),
+            &Style::from(OutputFormat::Short),
+        ));
+        assert_eq!(output["text"], "Moving, Pos: 40%");
+        assert_eq!(output["tooltip"], "P: 120.5W RSSI: -60dBm");
+
+        let output = render(parse_cover_data(
+            device_status.clone(),
+            &Style::from(OutputFormat::Long),
+        ));
+        assert_eq!(output["text"], "Moving, Position: 40%");
+        assert_eq!(output["tooltip"], "Power: 120.5W WiFi RSSI: -60dBm");
+
+        let output = render(parse_cover_data(
+            device_status,
+            &Style::from(OutputFormat::Icons),
+        ));
+        assert_eq!(output["text"], "↕️ 🪟40%");
+        assert_eq!(output["tooltip"], "⚡120.5W 📶-60dBm");
+
+        let stopped = json!({ "cover:0": { "state": "stopped", "current_pos": 0 } });
+        let fields = parse_cover_data(stopped, &Style::from(OutputFormat::Long));
+        assert_eq!(render(fields.clone())["text"], "Closed, Position: 0%");
+        assert_eq!(fields[0].sample().value, "stopped");
+
+        let uncalibrated = json!({ "cover:0": { "state": "open", "current_pos": null } });
+        let output = render(parse_cover_data(
+            uncalibrated,
+            &Style::from(OutputFormat::Long),
+        ));
+        assert_eq!(output["text"], "Open");
+    }
+
+    // Test: Parse Lux Data
+    #[test]
+    fn test_parse_lux_data() {
+        let device_status = json!({ "illuminance:0": { "id": 0, "lux": 320.4 } });
+
+        let fields = parse_lux_data(device_status.clone(), &Style::from(OutputFormat::Short));
+        assert_eq!(fields[0].sample().value, json!(320.4));
+        let output = render(fields);
+        assert_eq!(output["text"], "Dusk: 320lx");
+        assert_eq!(output["tooltip"], "Level: twilight");
+
+        let output = render(parse_lux_data(
+            json!({ "illuminance:0": { "lux": 12, "illumination": "dark" } }),
+            &Style::from(OutputFormat::Icons),
+        ));
+        assert_eq!(output["text"], "🌑12lx");
+
+        let output = render(parse_lux_data(
+            json!({ "illuminance:0": { "lux": 20000 } }),
+            &Style::from(OutputFormat::Long),
+        ));
+        assert_eq!(output["text"], "Bright: 20000lx");
+    }
+
+    // Test: Parse Cover Data Fault
+    #[test]
+    fn test_parse_cover_data_fault() {
+        let obstructed = json!({
+            "cover:0": {
+                "state": "stopped", "current_pos": 40, "last_direction": "close",
+                "errors": ["obstruction"]
+            }
+        });
+        let fields = parse_cover_data(obstructed.clone(), &Style::from(OutputFormat::Long));
+        assert_eq!(output_class(&fields), Some("warning"));
+        let output = render(fields);
+        assert_eq!(output["text"], "Open, Position: 40%, Obstruction detected");
+        assert!(output["tooltip"]
+            .as_str()
+            .unwrap()
+            .starts_with("Last direction: close"));
+
+        let output = render(parse_cover_data(
+            obstructed,
+            &Style::from(OutputFormat::Short),
+        ));
+        assert_eq!(output["text"], "Open, Pos: 40%, STOP");
+
+        let gen1 = json!({ "stop_reason": "safety_switch" });
+        assert_eq!(cover_fault(&gen1), Some("Safety switch engaged"));
+        assert_eq!(cover_fault(&json!({ "errors": [] })), None);
+    }
+
+    // Test: Parse TRV Data
+    #[test]
+    fn test_parse_trv_data() {
+        let gen1 = json!({
+            "thermostats": [{
+                "pos": 45.4,
+                "target_t": { "enabled": true, "value": 21.0, "units": "C" },
+                "tmp": { "value": 19.5, "units": "C", "is_valid": true }
+            }],
+            "bat": { "value": 80 }
+        });
+
+        let output = render(parse_trv_data(
+            gen1.clone(),
+            &Style::from(OutputFormat::Short),
+            "C",
+        ));
+        assert_eq!(output["text"], "T: 19.5°C Set: 21.0°C Valve: 45%");
+        assert_eq!(output["tooltip"], "B: 80%");
+
+        let output = render(parse_trv_data(
+            gen1.clone(),
+            &Style::from(OutputFormat::Long),
+            "F",
+        ));
+        assert_eq!(output["text"], "Temp: 67.1°F Target: 69.8°F Valve: 45%");
+        assert_eq!(output["tooltip"], "Battery: 80%");
+
+        let output = render(parse_trv_data(gen1, &Style::from(OutputFormat::Icons), "C"));
+        assert_eq!(output["text"], "\u{f2c9}19.5°C 🎯21.0°C 🔧45%");
+        assert_eq!(output["tooltip"], "🔋80%");
+
+        let blutrv = json!({
+            "blutrv:200": { "current_C": 20.5, "target_C": 22, "pos": 10, "battery": 95 }
+        });
+        let output = render(parse_trv_data(
+            blutrv,
+            &Style::from(OutputFormat::Long),
+            "C",
+        ));
+        assert_eq!(output["text"], "Temp: 20.5°C Target: 22.0°C Valve: 10%");
+        assert_eq!(output["tooltip"], "Battery: 95%");
+    }
+
+    // Test: Parse Smoke Data
+    #[test]
+    fn test_parse_smoke_data() {
+        let alarm = json!({
+            "smoke:0": { "id": 0, "alarm": true, "mute": false },
+            "devicepower:0": { "battery": { "percent": 90 } }
+        });
+
+        let fields = parse_smoke_data(alarm.clone(), &Style::from(OutputFormat::Short));
+        assert_eq!(output_class(&fields), Some("alarm"));
+        let output = render(fields);
+        assert_eq!(output["text"], "ALARM");
+        assert_eq!(output["tooltip"], "B: 90%");
+
+        let output = render(parse_smoke_data(alarm, &Style::from(OutputFormat::Icons)));
+        assert_eq!(output["text"], "🚨");
+
+        let mains = json!({
+            "smoke:0": { "alarm": false },
+            "devicepower:0": { "battery": { "percent": 0 }, "external": { "present": true } }
+        });
+        let output = render(parse_smoke_data(mains, &Style::from(OutputFormat::Long)));
+        assert_eq!(output["tooltip"], "Power: External");
+
+        let charging = json!({
+            "smoke:0": { "alarm": false },
+            "devicepower:0": { "battery": { "percent": 40, "charging": true }, "external": { "present": false } }
+        });
+        let output = render(parse_smoke_data(
+            charging,
+            &Style::from(OutputFormat::Short),
+        ));
+        assert_eq!(output["tooltip"], "Src: Battery B: 40% charging");
+
+        let clear = json!({ "smoke:0": { "alarm": false } });
+        let fields = parse_smoke_data(clear, &Style::from(OutputFormat::Long));
+        assert_eq!(output_class(&fields), None);
+        assert_eq!(render(fields)["text"], "No smoke");
+    }
+
+    // Test: Parse Gas Data
+    #[test]
+    fn test_parse_gas_data() {
+        let device_status = json!({
+            "gas_sensor": {
+                "sensor_state": "normal",
+                "self_test_state": "completed",
+                "alarm_state": "mild"
+            },
+            "concentration": { "ppm": 320, "is_valid": true }
+        });
+
+        let fields = parse_gas_data(device_status.clone(), &Style::from(OutputFormat::Short));
+        assert_eq!(output_class(&fields), Some("warning"));
+        let output = render(fields);
+        assert_eq!(output["text"], "WARN Gas: 320ppm");
+        assert_eq!(output["tooltip"], "Sensor: normal Test: completed");
+
+        let output = render(parse_gas_data(
+            device_status,
+            &Style::from(OutputFormat::Long),
+        ));
+        assert_eq!(output["text"], "Gas warning Concentration: 320ppm");
+        assert_eq!(output["tooltip"], "Sensor: normal Self-test: completed");
+
+        let heavy = json!({
+            "gas_sensor": { "sensor_state": "normal", "self_test_state": "not_completed", "alarm_state": "heavy" },
+            "concentration": { "ppm": 5000 }
+        });
+        let fields = parse_gas_data(heavy, &Style::from(OutputFormat::Icons));
+        assert_eq!(output_class(&fields), Some("alarm"));
+        let output = render(fields);
+        assert_eq!(output["text"], "🚨 💨5000ppm");
+        assert_eq!(output["tooltip"], "📟normal 🧪not completed");
+
+        let with_valve = json!({
+            "gas_sensor": { "sensor_state": "normal", "self_test_state": "completed", "alarm_state": "none" },
+            "concentration": { "ppm": 0 },
+            "valves": [{ "state": "opened" }]
+        });
+        let output = render(parse_gas_data(
+            with_valve,
+            &Style::from(OutputFormat::Short),
+        ));
+        assert_eq!(output["text"], "OK Gas: 0ppm Valve: open");
+
+        let not_connected = json!({ "valves": [{ "state": "not_connected" }] });
+        assert_eq!(valve_state(&not_connected), None);
+    }
+
+    // Test: Handle Valve Status
+    #[test]
+    fn test_handle_valve_status() {
+        struct Counter(std::cell::Cell<usize>);
+        impl Notifier for Counter {
+            fn notify(
+                &self,
+                _summary: &str,
+                _body: &str,
+                _urgency: Urgency,
+            ) -> Result<(), Box<dyn std::error::Error>> {
+                self.0.set(self.0.get() + 1);
+                Ok(())
+            }
+        }
+
+        let notifier = Counter(Default::default());
+        let timestamps = TimestampFormat {
+            clock: ClockFormat::H24,
+            timezone: Timezone::Named(Tz::UTC),
+        };
+        let closed = json!({ "valves": [{ "state": "closed" }] });
+        let reading = |valve: &str| Reading {
+            output: json!({}),
+            updated_at: Utc::now(),
+            short_text: None,
+            device_type: String::new(),
+            samples: vec![Sample {
+                key: "valve".to_string(),
+                value: Value::String(valve.to_string()),
+                unit: String::new(),
+            }],
+        };
+
+        // Nothing to compare with at startup
+        handle_valve_status("12345", None, &closed, None, &timestamps, &notifier);
+        assert_eq!(notifier.0.get(), 0);
+        let previous = reading("closed");
+        handle_valve_status(
+            "12345",
+            None,
+            &closed,
+            Some(&previous),
+            &timestamps,
+            &notifier,
+        );
+        assert_eq!(notifier.0.get(), 0);
+        let previous = reading("opened");
+        handle_valve_status(
+            "12345",
+            None,
+            &closed,
+            Some(&previous),
+            &timestamps,
+            &notifier,
+        );
+        assert_eq!(notifier.0.get(), 1);
+    }
+
+    // Test: Parse Motion Data
+    #[test]
+    fn test_parse_motion_data() {
+        let now = DateTime::from_timestamp(1_700_000_600, 0).unwrap();
+        let device_status = json!({
+            "sensor": { "motion": true, "vibration": false, "timestamp": 1_700_000_300, "is_valid": true },
+            "lux": { "value": 42, "illumination": "dark" },
+            "bat": { "value": 87 }
+        });
+
+        let output = render(parse_motion_data(
+            device_status.clone(),
+            &Style::from(OutputFormat::Short),
+            now,
+        ));
+        assert_eq!(output["text"], "Motion L: 42");
+        assert_eq!(output["tooltip"], "B: 87% Last: 5 min ago");
+
+        let output = render(parse_motion_data(
+            device_status.clone(),
+            &Style::from(OutputFormat::Long),
+            now,
+        ));
+        assert_eq!(output["text"], "Motion Lux: 42");
+        assert_eq!(output["tooltip"], "Battery: 87% Last motion: 5 min ago");
+
+        let idle = json!({ "sensor": { "motion": false, "timestamp": 0 }, "lux": { "value": 3 } });
+        let output = render(parse_motion_data(
+            idle,
+            &Style::from(OutputFormat::Icons),
+            now,
+        ));
+        assert_eq!(output["text"], "💤 🔆3");
+        assert_eq!(output["tooltip"], "🔋0%");
+    }
+
+    // Test: Handle Motion Status
+    #[test]
+    fn test_handle_motion_status() {
+        struct Counter(std::cell::Cell<usize>);
+        impl Notifier for Counter {
+            fn notify(
+                &self,
+                _summary: &str,
+                _body: &str,
+                _urgency: Urgency,
+            ) -> Result<(), Box<dyn std::error::Error>> {
+                self.0.set(self.0.get() + 1);
+                Ok(())
+            }
+        }
+
+        let notifier = Counter(Default::default());
+        let timestamps = TimestampFormat {
+            clock: ClockFormat::H24,
+            timezone: Timezone::Named(Tz::UTC),
+        };
+        let moving = json!({ "sensor": { "motion": true } });
+        let reading = |motion: bool| Reading {
+            output: json!({}),
+            updated_at: Utc::now(),
+            short_text: None,
+            device_type: String::new(),
+            samples: vec![Sample {
+                key: "motion".to_string(),
+                value: Value::Bool(motion),
+                unit: String::new(),
+            }],
+        };
+
+        handle_motion_status("12345", None, &moving, None, &timestamps, &notifier);
+        assert_eq!(notifier.0.get(), 1);
+        handle_motion_status(
+            "12345",
+            None,
+            &moving,
+            Some(&reading(true)),
+            &timestamps,
+            &notifier,
+        );
+        assert_eq!(notifier.0.get(), 1);
+        handle_motion_status(
+            "12345",
+            None,
+            &moving,
+            Some(&reading(false)),
+            &timestamps,
+            &notifier,
+        );
+        assert_eq!(notifier.0.get(), 2);
+    }
+
+    // Test: Parse Door Vibration
+    #[test]
+    fn test_parse_door_vibration() {
+        let device_status = gen1::normalize(json!({
+            "sensor": { "state": "close", "is_valid": true },
+            "lux": { "value": 12 },
+            "accel": { "tilt": 0, "vibration": 1 },
+            "bat": { "value": 80 }
+        }));
+
+        let fields = parse_window_or_door_data(
+            device_status.clone(),
+            None,
+            &Style::from(OutputFormat::Short),
+        );
+        assert!(was_active(
+            Some(&Reading {
+                output: json!({}),
+                updated_at: Utc::now(),
+                short_text: None,
+                device_type: String::new(),
+                samples: fields.iter().map(Field::sample).collect(),
+            }),
+            "vibration"
+        ));
+        assert_eq!(render(fields)["text"], "Closed: L: 12, VIB");
+
+        let mut still = device_status;
+        still["accel"]["vibration"] = json!(0);
+        let output = render(parse_window_or_door_data(
+            still,
+            None,
+            &Style::from(OutputFormat::Long),
+        ));
+        assert_eq!(output["text"], "Closed, Lux: 12");
+        assert!(output["tooltip"]
+            .as_str()
+            .unwrap()
+            .starts_with("Vibration: no"));
+    }
+
+    // Test: Handle Alarm Status
+    #[test]
+    fn test_handle_alarm_status() {
+        struct Recorder(std::cell::RefCell<Vec<(String, Urgency)>>);
+        impl Notifier for Recorder {
+            fn notify(
+                &self,
+                summary: &str,
+                _body: &str,
+                urgency: Urgency,
+            ) -> Result<(), Box<dyn std::error::Error>> {
+                self.0.borrow_mut().push((summary.to_string(), urgency));
+                Ok(())
+            }
+        }
+
+        let notifier = Recorder(Default::default());
+        let mut alarm_status_map = HashMap::new();
+        let timestamps = TimestampFormat {
+            clock: ClockFormat::H24,
+            timezone: Timezone::Named(Tz::UTC),
+        };
+        let name = Some("Kitchen".to_string());
+        for alarm in [None, Some("Smoke detected"), Some("Smoke detected"), None] {
+            handle_alarm_status(
+                "12345",
+                name.clone(),
+                alarm,
+                &mut alarm_status_map,
+                &timestamps,
+                &notifier,
+            );
+        }
+
+        assert_eq!(
+            *notifier.0.borrow(),
+            vec![
+                ("Alarm: Kitchen".to_string(), Urgency::Critical),
+                ("Alarm cleared: Kitchen".to_string(), Urgency::Critical),
+            ]
+        );
+    }
+
+    // Test: Tilt Thresholds State
+    #[test]
+    fn test_tilt_thresholds_state() {
+        let thresholds = TiltThresholds {
+            closed_max: 5,
+            wide_open_min: 30,
+        };
+
+        assert_eq!(thresholds.state(0), "closed");
+        assert_eq!(thresholds.state(5), "closed");
+        assert_eq!(thresholds.state(12), "tilted");
+        assert_eq!(thresholds.state(30), "wide open");
+        assert_eq!(thresholds.state(90), "wide open");
+    }
+
+    // Test: Door Status Change Notification
+    #[test]
+    fn test_handle_door_status() {
+        let mut door_status_map = HashMap::new();
+        let device_status_open = json!({
+            "window:0": { "open": true }
+        });
+        let device_status_closed = json!({
+            "window:0": { "open": false }
+        });
+
+        let device_id = "door-12345";
+        let device_name = Some("Front Door".to_string());
+        let timestamps = TimestampFormat {
+            clock: ClockFormat::H24,
+            timezone: Timezone::Named(Tz::UTC),
+        };
+
+        // Test status change from None to Open
+        let notification = handle_door_status(
+            device_id,
+            device_name.clone(),
+            &device_status_open,
+            &mut door_status_map,
+            &timestamps,
+            &notify::Noop,
+        );
+        assert!(notification.is_some());
+        assert!(door_status_map[&format!("{}:{}", device_id, device_name.clone().unwrap())]);
+
+        // Test status change from Open to Closed
+        let notification = handle_door_status(
+            device_id,
+            device_name.clone(),
+            &device_status_closed,
+            &mut door_status_map,
+            &timestamps,
+            &notify::Noop,
+        );
+        assert!(notification.is_some());
+        assert!(!door_status_map[&format!("{}:{}", device_id, device_name.clone().unwrap())]);
+    }
+
+    // Test: Fetch Device Status Mock
+    #[tokio::test]
+    async fn test_fetch_device_status() {
+        use httpmock::MockServer;
+
+        let server = MockServer::start_async().await;
+        let mock_response = json!({
+            "isok": true,
+            "data": {
+                "device_status": {
+                    "temperature:0": { "tC": 22.5, "tF": 72.5 },
+                    "humidity:0": { "rh": 50 }
+                }
+            }
+        });
+
+        let mock = server.mock(|when, then| {
+            when.method("POST").path("/device/status");
+            then.status(200).json_body(mock_response.clone());
+        });
+
+        let client = Client::new();
+        let response = fetch_device_status(
+            &client,
+            &server.base_url(),
+            "12345",
+            &Secret::new("mock-auth-key"),
+            &RateLimiter::unlimited(),
+            &RetryPolicy::none(),
+        )
+        .await;
 
         mock.assert();
-        assert!(response.is_some());
+        assert!(response.is_ok());
         assert_eq!(
             response.unwrap()["temperature:0"]["tC"],
             mock_response["data"]["device_status"]["temperature:0"]["tC"]
         );
     }
 
+    // Test: Fetch Device Status Rate Limited
+    #[tokio::test]
+    async fn test_fetch_device_status_rate_limited() {
+        use httpmock::MockServer;
+
+        let server = MockServer::start_async().await;
+        server.mock(|when, then| {
+            when.method("POST").path("/device/status");
+            then.status(429).header("Retry-After", "30");
+        });
+
+        let limiter = RateLimiter::unlimited();
+        let response = fetch_device_status(
+            &Client::new(),
+            &server.base_url(),
+            "12345",
+            &Secret::new("mock-auth-key"),
+            &limiter,
+            &RetryPolicy::none(),
+        )
+        .await;
+        assert!(matches!(response, Err(FetchError::Failed)));
+
+        // Held back by the Retry-After delay
+        let acquired = tokio::time::timeout(Duration::from_millis(100), limiter.acquire()).await;
+        assert!(acquired.is_err());
+    }
+
+    // Test: Retry-After Is Capped
+    #[tokio::test]
+    async fn test_fetch_device_status_retry_after_capped() {
+        use httpmock::MockServer;
+
+        let server = MockServer::start_async().await;
+        server.mock(|when, then| {
+            when.method("POST").path("/device/status");
+            then.status(429).header("Retry-After", "7200");
+        });
+
+        let limiter = RateLimiter::unlimited();
+        let response = fetch_device_status(
+            &Client::new(),
+            &server.base_url(),
+            "12345",
+            &Secret::new("mock-auth-key"),
+            &limiter,
+            &RetryPolicy::none(),
+        )
+        .await;
+        assert!(matches!(response, Err(FetchError::Failed)));
+
+        // Two hours are cut down to MAX_RETRY_AFTER
+        tokio::time::pause();
+        let wait = Duration::from_secs(MAX_RETRY_AFTER + 1);
+        let acquired = tokio::time::timeout(wait, limiter.acquire()).await;
+        assert!(acquired.is_ok());
+    }
+
+    // Test: Fetch Device Status Retry
+    #[tokio::test]
+    async fn test_fetch_device_status_retry() {
+        use httpmock::MockServer;
+
+        let server = MockServer::start_async().await;
+        let mock = server.mock(|when, then| {
+            when.method("POST").path("/device/status");
+            then.status(503);
+        });
+
+        let retry = RetryPolicy {
+            attempts: 3,
+            backoff: Duration::from_millis(10),
+            jitter: 0.5,
+        };
+        let response = fetch_device_status(
+            &Client::new(),
+            &server.base_url(),
+            "12345",
+            &Secret::new("mock-auth-key"),
+            &RateLimiter::unlimited(),
+            &retry,
+        )
+        .await;
+        mock.assert_hits(3);
+        assert_eq!(response, Err(FetchError::Unavailable));
+    }
+
+    // Test: Extract Timestamp
+    #[test]
+    fn test_extract_timestamp() {
+        let cloud = json!({ "_updated": "2024-01-15 10:20:30", "sys": { "unixtime": 1 } });
+        let device = json!({ "sys": { "unixtime": 1705314030 } });
+        let gen1 = json!({ "unixtime": 1705314030 });
+
+        let expected = DateTime::from_timestamp(1705314030, 0);
+        assert_eq!(extract_timestamp(&cloud), expected);
+        assert_eq!(extract_timestamp(&device), expected);
+        assert_eq!(extract_timestamp(&gen1), expected);
+        assert_eq!(extract_timestamp(&json!({})), None);
+    }
+
+    // Test: Humanize Age
+    #[test]
+    fn test_humanize_age() {
+        assert_eq!(humanize_age(-5), "0 s");
+        assert_eq!(humanize_age(35), "35 s");
+        assert_eq!(humanize_age(125), "2 min");
+        assert_eq!(humanize_age(7300), "2 h");
+        assert_eq!(humanize_age(200000), "2 d");
+    }
+
+    // Test: Render Age
+    #[test]
+    fn test_render_age() {
+        let now = DateTime::from_timestamp(1705314030, 0).unwrap();
+        let timestamps = TimestampFormat {
+            clock: ClockFormat::H24,
+            timezone: Timezone::Named(Tz::UTC),
+        };
+        let reading = Reading {
+            output: json!({ "text": "T: 22.5°C", "tooltip": "B: 80%" }),
+            updated_at: now - chrono::Duration::seconds(35),
+            short_text: None,
+            device_type: String::new(),
+            samples: Vec::new(),
+        };
+
+        let output = render_age(&reading, now, 3600, &timestamps);
+        assert_eq!(output["text"], "T: 22.5°C");
+        assert_eq!(output["tooltip"], "B: 80%\nupdated 35 s ago (10:19:55)");
+        assert!(output.get("class").is_none());
+
+        let output = render_age(
+            &reading,
+            now + chrono::Duration::hours(2),
+            3600,
+            &timestamps,
+        );
+        assert_eq!(output["text"], "T: 22.5°C (stale: 2 h)");
+        assert_eq!(output["tooltip"], "B: 80%\nstale: 2 h (10:19:55)");
+        assert_eq!(output["class"], "error");
+
+        let reading = Reading {
+            output: json!({ "text": "", "tooltip": "", "class": "low-battery" }),
+            ..reading
+        };
+        let output = render_age(
+            &reading,
+            now + chrono::Duration::hours(2),
+            3600,
+            &timestamps,
+        );
+        assert_eq!(output["class"], json!(["low-battery", "error"]));
+        assert_eq!(schema::validate_device_output(&output), Ok(()));
+    }
+
+    // Test: Can Toggle
+    #[test]
+    fn test_can_toggle() {
+        let reading = Reading {
+            output: json!({ "text": "", "tooltip": "" }),
+            updated_at: Utc::now(),
+            short_text: None,
+            device_type: "plug".to_string(),
+            samples: vec![Sample {
+                key: "output".to_string(),
+                value: Value::Bool(true),
+                unit: String::new(),
+            }],
+        };
+        let config = Config::default();
+
+        let args = Args::parse_from(["shelly-waybar", "--allow-control", "--devices", "plug:1"]);
+        assert!(can_toggle(&args, &config, &reading, "1"));
+        let args = Args::parse_from(["shelly-waybar", "--devices", "plug:1"]);
+        assert!(!can_toggle(&args, &config, &reading, "1"));
+
+        // Devices read from a broker are not switched by the tray or the dashboard
+        let args = Args::parse_from([
+            "shelly-waybar",
+            "--allow-control",
+            "--mqtt-source",
+            "mqtt://broker.lan",
+            "--devices",
+            "plug:1",
+        ]);
+        assert!(!can_toggle(&args, &config, &reading, "1"));
+    }
+
+    // Test: State Classes
+    #[test]
+    fn test_state_classes() {
+        let style = Style::from(OutputFormat::Long);
+        let plug = parse_plug_data(
+            json!({ "switch:0": { "output": false, "apower": 0.0 } }),
+            &style,
+            &[],
+        );
+        assert_eq!(state_classes(&plug, 20), vec!["off"]);
+
+        let door = parse_window_or_door_data(
+            json!({
+                "window:0": { "open": true },
+                "devicepower:0": { "battery": { "percent": 15 } }
+            }),
+            None,
+            &style,
+        );
+        assert_eq!(state_classes(&door, 20), vec!["open", "low-battery"]);
+        assert_eq!(state_classes(&door, 10), vec!["open"]);
+        assert_eq!(percentage(&door), Some(15));
+        assert_eq!(
+            alt(&DeviceType::Door, &state_classes(&door, 20)),
+            "door-open"
+        );
+        assert_eq!(
+            alt(&DeviceType::Temperature, &["low-battery"]),
+            "temperature"
+        );
+        assert_eq!(percentage(&plug), None);
+        let ht = parse_temperature_data(
+            json!({
+                "temperature:0": { "tC": 21.5 },
+                "humidity:0": { "rh": 48.2 },
+                "devicepower:0": { "battery": { "percent": 87 } }
+            }),
+            &style,
+            "C",
+        );
+        assert_eq!(percentage(&ht), Some(48));
+
+        let mut output = json!({ "text": "" });
+        set_classes(&mut output, &["on"]);
+        assert_eq!(output["class"], "on");
+        set_classes(&mut output, &["alarm", "on"]);
+        assert_eq!(classes(&output), vec!["alarm", "on"]);
+    }
+
+    // Test: Timestamp Format
+    #[test]
+    fn test_timestamp_format() {
+        let timestamp = DateTime::from_timestamp(1705314030, 0).unwrap();
+        let utc_24h = TimestampFormat {
+            clock: ClockFormat::H24,
+            timezone: parse_timezone("UTC").unwrap(),
+        };
+        let paris_12h = TimestampFormat {
+            clock: ClockFormat::H12,
+            timezone: parse_timezone("Europe/Paris").unwrap(),
+        };
+
+        assert_eq!(utc_24h.format(timestamp), "10:20:30");
+        assert_eq!(paris_12h.format(timestamp), "11:20:30 AM");
+        assert!(parse_timezone("local").is_ok());
+        assert!(parse_timezone("Mars/Olympus").is_err());
+    }
+
+    // Test: Parse Rate
+    #[test]
+    fn test_parse_rate() {
+        assert_eq!(parse_rate("0"), Ok(0.0));
+        assert_eq!(parse_rate("2.5"), Ok(2.5));
+        assert!(parse_rate("nan").is_err());
+        assert!(parse_rate("inf").is_err());
+        assert!(parse_rate("-1").is_err());
+        assert!(parse_rate("fast").is_err());
+    }
+
+    // Test: Fetch Device Status Invalid Token
+    #[tokio::test]
+    async fn test_fetch_device_status_invalid_token() {
+        use httpmock::MockServer;
+
+        let server = MockServer::start_async().await;
+        server.mock(|when, then| {
+            when.method("POST").path("/device/status");
+            then.status(200).json_body(json!({
+                "isok": false,
+                "errors": { "invalid_token": "Invalid token!" }
+            }));
+        });
+
+        let client = Client::new();
+        let response = fetch_device_status(
+            &client,
+            &server.base_url(),
+            "12345",
+            &Secret::new("mock-auth-key"),
+            &RateLimiter::unlimited(),
+            &RetryPolicy::none(),
+        )
+        .await;
+
+        assert_eq!(response, Err(FetchError::InvalidToken));
+        assert_eq!(auth_required_output()["class"], "auth-required");
+    }
+
+    // Test: Once Fails Without Data
+    #[tokio::test]
+    async fn test_once_fails_without_data() {
+        use httpmock::MockServer;
+
+        let server = MockServer::start_async().await;
+        let mut mock = server.mock(|when, then| {
+            when.method("POST").path("/device/status");
+            then.status(200).json_body(json!({
+                "isok": false,
+                "errors": { "invalid_token": "Invalid token!" }
+            }));
+        });
+        let args = Args::parse_from([
+            "shelly-waybar",
+            "--once",
+            "--notifier",
+            "none",
+            "--output",
+            "plain",
+            "--retry-attempts",
+            "1",
+            "--base-url",
+            &server.base_url(),
+            "--auth-key",
+            "mock-auth-key",
+            "--devices",
+            "plug:12345",
+        ]);
+        let config = Config::default();
+        let result = process_devices_loop(&args, &config).await;
+        assert_eq!(
+            result.unwrap_err().to_string(),
+            "The auth key was rejected by the Shelly cloud"
+        );
+
+        mock.delete();
+        server.mock(|when, then| {
+            when.method("POST").path("/device/status");
+            then.status(404);
+        });
+        let result = process_devices_loop(&args, &config).await;
+        assert_eq!(
+            result.unwrap_err().to_string(),
+            "No device data could be fetched"
+        );
+    }
+
+    // Test: Fetch Statuses Bulk
+    #[tokio::test]
+    async fn test_fetch_statuses_bulk() {
+        use httpmock::MockServer;
+
+        let server = MockServer::start_async().await;
+        let mock = server.mock(|when, then| {
+            when.method("POST").path("/device/all_status");
+            then.status(200).json_body(json!({
+                "isok": true,
+                "data": {
+                    "devices_status": {
+                        "12345": { "switch:0": { "apower": 50.0 } },
+                        "67890": { "switch:0": { "apower": 10.0 } }
+                    }
+                }
+            }));
+        });
+
+        let statuses = fetch_statuses(
+            &Client::new(),
+            &server.base_url(),
+            &Secret::new("mock-auth-key"),
+            &["12345", "13579"],
+            1,
+            true,
+            &RateLimiter::unlimited(),
+            &RetryPolicy::none(),
+        )
+        .await;
+
+        mock.assert();
+        assert_eq!(statuses.len(), 2);
+        assert_eq!(
+            statuses["12345"].as_ref().unwrap()["switch:0"]["apower"],
+            50.0
+        );
+        assert_eq!(statuses["13579"], Err(FetchError::Failed));
+    }
+
+    // Test: Fetch Statuses Timeout
+    #[tokio::test]
+    async fn test_fetch_statuses_timeout() {
+        use httpmock::MockServer;
+
+        let server = MockServer::start_async().await;
+        server.mock(|when, then| {
+            when.method("POST")
+                .path("/device/status")
+                .x_www_form_urlencoded_tuple("id", "12345");
+            then.status(200)
+                .json_body(json!({ "isok": true, "data": { "device_status": {} } }));
+        });
+        server.mock(|when, then| {
+            when.method("POST")
+                .path("/device/status")
+                .x_www_form_urlencoded_tuple("id", "13579");
+            then.status(200).delay(Duration::from_secs(5));
+        });
+
+        let client = Client::builder()
+            .timeout(Duration::from_millis(200))
+            .build()
+            .unwrap();
+        let statuses = fetch_statuses(
+            &client,
+            &server.base_url(),
+            &Secret::new("mock-auth-key"),
+            &["12345", "13579"],
+            2,
+            false,
+            &RateLimiter::unlimited(),
+            &RetryPolicy::none(),
+        )
+        .await;
+
+        // The hung request only fails its own device
+        assert!(statuses["12345"].is_ok());
+        assert_eq!(statuses["13579"], Err(FetchError::Unavailable));
+    }
+
+    // Test: Login Command
+    #[test]
+    fn test_login_command() {
+        // No --devices needed to log in
+        let args = Args::parse_from(["shelly-waybar", "login", "--email", "me@example.com"]);
+        let Some(Command::Login {
+            email,
+            code,
+            login_url,
+        }) = args.command
+        else {
+            panic!("expected the login command");
+        };
+        assert_eq!(email.as_deref(), Some("me@example.com"));
+        assert_eq!(code, None);
+        assert_eq!(login_url, auth::LOGIN_URL);
+        assert!(args.devices.is_empty());
+    }
+
+    // Test: Login OAuth Code
+    #[test]
+    fn test_login_oauth_code() {
+        let args = Args::parse_from(["shelly-waybar", "login", "--code", "oauth-code"]);
+        assert!(matches!(
+            args.command,
+            Some(Command::Login {
+                email: None,
+                code: Some(ref code),
+                ..
+            }) if code.expose() == "oauth-code"
+        ));
+
+        // Either the account credentials or the code of the OAuth flow
+        assert!(Args::try_parse_from([
+            "shelly-waybar",
+            "login",
+            "--email",
+            "me@example.com",
+            "--code",
+            "oauth-code",
+        ])
+        .is_err());
+    }
+
+    // Test: Auth Required
+    #[test]
+    fn test_auth_required() {
+        struct Recorder(std::cell::RefCell<Vec<Urgency>>);
+        impl Notifier for Recorder {
+            fn notify(
+                &self,
+                _summary: &str,
+                _body: &str,
+                urgency: Urgency,
+            ) -> Result<(), Box<dyn std::error::Error>> {
+                self.0.borrow_mut().push(urgency);
+                Ok(())
+            }
+        }
+
+        let output = auth_required_output();
+        assert_eq!(output["text"], "Shelly: auth required");
+        assert!(output["tooltip"]
+            .as_str()
+            .unwrap()
+            .contains("shelly-waybar login"));
+        assert_eq!(schema::validate_output(&output), Ok(()));
+
+        let notifier = Recorder(Default::default());
+        notify_auth_required(&notifier);
+        assert_eq!(*notifier.0.borrow(), vec![Urgency::Critical]);
+    }
+
+    // Test: Emit To Socket Clients
+    #[tokio::test]
+    async fn test_emit_to_socket_clients() {
+        use tokio::io::{AsyncBufReadExt, BufReader};
+        use tokio::net::UnixStream;
+
+        let path = env::temp_dir().join(format!(
+            "shelly-waybar-test-emit-{}.sock",
+            std::process::id()
+        ));
+        let ipc = IpcServer::start(&path).unwrap();
+        let args = Args::parse_from(["shelly-waybar", "--devices", "plug:1", "--output", "plain"]);
+        let output = json!({ "text": "P: 50.0W", "tooltip": "Desk" });
+        emit(&args, &output, None, Some(&ipc)).unwrap();
+
+        // Clients get the payload whatever the output mode of the daemon
+        let stream = UnixStream::connect(&path).await.unwrap();
+        let mut lines = BufReader::new(stream).lines();
+        let line = lines.next_line().await.unwrap().unwrap();
+        assert_eq!(serde_json::from_str::<Value>(&line).unwrap(), output);
+        fs::remove_file(&path).unwrap();
+    }
+
+    // Test: Power Fields
+    #[test]
+    fn test_power_fields() {
+        let style = Style::from(OutputFormat::Long);
+        let usb = json!({
+            "devicepower:0": { "battery": { "percent": 0 }, "external": { "present": true } }
+        });
+        assert_eq!(
+            render(power_fields(&usb, &style))["tooltip"],
+            "Power: External"
+        );
+
+        let charging = json!({
+            "devicepower:0": {
+                "battery": { "percent": 64, "charging": true },
+                "external": { "present": false }
+            }
+        });
+        assert_eq!(
+            render(power_fields(&charging, &style))["tooltip"],
+            "Power: Battery Battery: 64% charging"
+        );
+
+        // Battery-only devices report no power source
+        let battery = json!({ "devicepower:0": { "battery": { "percent": 87 } } });
+        assert_eq!(
+            render(power_fields(&battery, &Style::from(OutputFormat::Short)))["tooltip"],
+            "B: 87%"
+        );
+    }
+
+    // Test: Energy In Text
+    #[test]
+    fn test_energy_in_text() {
+        let status = json!({
+            "switch:0": {
+                "apower": 50.0, "voltage": 230.0, "current": 0.217, "output": true,
+                "aenergy": { "total": 12345.6 }
+            }
+        });
+        let device = "plug:12345".to_string();
+        let reading = |args: &Args| {
+            process_device(
+                &device,
+                status.clone(),
+                args,
+                &Config::default(),
+                &mut PollState::default(),
+                &notify::Noop,
+            )
+            .unwrap()
+        };
+
+        let args = Args::parse_from(["shelly-waybar", "--devices", &device, "--format", "short"]);
+        let output = reading(&args).output;
+        assert!(!output["text"].as_str().unwrap().contains("E: 12.35kWh"));
+        assert!(output["tooltip"].as_str().unwrap().contains("E: 12.35kWh"));
+
+        let args = Args::parse_from([
+            "shelly-waybar",
+            "--devices",
+            &device,
+            "--format",
+            "short",
+            "--energy-in-text",
+        ]);
+        let output = reading(&args).output;
+        assert!(output["text"].as_str().unwrap().contains("E: 12.35kWh"));
+        assert!(!output["tooltip"].as_str().unwrap().contains("E: 12.35kWh"));
+    }
+
+    // Test: Plug Input State
+    #[test]
+    fn test_plug_input_state() {
+        let style = Style::from(OutputFormat::Short);
+        let plug = |input: Value| {
+            render(parse_plug_data(
+                json!({ "switch:0": { "apower": 0.0, "output": true }, "input:0": input }),
+                &style,
+                &[],
+            ))
+        };
+        assert!(plug(json!({ "state": false }))["tooltip"]
+            .as_str()
+            .unwrap()
+            .ends_with("In: OFF"));
+        assert!(plug(json!({ "state": true }))["tooltip"]
+            .as_str()
+            .unwrap()
+            .ends_with("In: ON"));
+        // Inputs set as buttons have no state
+        assert!(!plug(json!({ "state": null }))["tooltip"]
+            .as_str()
+            .unwrap()
+            .contains("In:"));
+    }
+
+    // Test: Discover Command
+    #[test]
+    fn test_discover_command() {
+        let args = Args::parse_from(["shelly-waybar", "discover"]);
+        assert!(matches!(
+            args.command,
+            Some(Command::Discover { local: false, .. })
+        ));
+
+        let args = Args::parse_from(["shelly-waybar", "discover", "--local", "--wait", "2"]);
+        assert!(matches!(
+            args.command,
+            Some(Command::Discover {
+                local: true,
+                wait: 2
+            })
+        ));
+    }
+
+    // Test: UDP RPC
+    #[test]
+    fn test_udp_rpc() {
+        let args = Args::parse_from(["shelly-waybar", "--devices", "plug:1"]);
+        assert!(args.udp_rpc().is_none());
+
+        let args = Args::parse_from([
+            "shelly-waybar",
+            "--devices",
+            "plug:1",
+            "--udp-port",
+            "1010",
+            "--udp-timeout",
+            "250",
+        ]);
+        let udp = args.udp_rpc().unwrap();
+        assert_eq!(udp.port, 1010);
+        assert_eq!(udp.timeout, Duration::from_millis(250));
+    }
+
+    // Test: TLS Backend
+    #[tokio::test]
+    async fn test_tls_backend() {
+        let args = Args::parse_from(["shelly-waybar", "--devices", "plug:1"]);
+        let client = args.http_client().unwrap();
+
+        // Without a TLS backend, https URLs would be rejected before connecting
+        let err = client
+            .get("https://127.0.0.1:1/device/status")
+            .send()
+            .await
+            .unwrap_err();
+        assert!(err.is_connect());
+        assert!(!err.is_builder());
+    }
+
+    // Test: HTTP Client Timeout
+    #[tokio::test]
+    async fn test_http_client_timeout() {
+        use httpmock::MockServer;
+
+        let server = MockServer::start_async().await;
+        server.mock(|when, then| {
+            when.method("POST").path("/device/status");
+            then.status(200).delay(Duration::from_secs(5));
+        });
+
+        let args = Args::parse_from(["shelly-waybar", "--devices", "plug:1", "--timeout", "1"]);
+        let start = std::time::Instant::now();
+        let response = fetch_device_status(
+            &args.http_client().unwrap(),
+            &server.base_url(),
+            "12345",
+            &Secret::new("mock-auth-key"),
+            &RateLimiter::unlimited(),
+            &RetryPolicy::none(),
+        )
+        .await;
+        assert_eq!(response, Err(FetchError::Unavailable));
+        assert!(start.elapsed() < Duration::from_secs(3));
+    }
+
+    // Test: Cloud Fallback
+    #[tokio::test]
+    async fn test_cloud_fallback() {
+        use httpmock::MockServer;
+
+        let server = MockServer::start_async().await;
+        let cloud = server.mock(|when, then| {
+            when.method("POST").path("/device/status");
+            then.status(200).json_body(json!({
+                "isok": true,
+                "data": { "device_status": { "switch:0": { "apower": 50.0, "output": true } } }
+            }));
+        });
+        let config: Config = toml::from_str(
+            r#"
+            [devices.12345]
+            host = "127.0.0.1:1"
+            cloud_fallback = true
+            "#,
+        )
+        .unwrap();
+        let args = Args::parse_from([
+            "shelly-waybar",
+            "--once",
+            "--notifier",
+            "none",
+            "--output",
+            "plain",
+            "--retry-attempts",
+            "1",
+            "--base-url",
+            &server.base_url(),
+            "--auth-key",
+            "mock-auth-key",
+            "--devices",
+            "plug:12345",
+        ]);
+
+        // The device does not answer on the LAN, the cloud does
+        assert!(process_devices_loop(&args, &config).await.is_ok());
+        cloud.assert();
+    }
+
+    // Test: Toggle Device Of Another Account
+    #[tokio::test]
+    async fn test_toggle_device_other_account() {
+        use httpmock::MockServer;
+
+        let main = MockServer::start_async().await;
+        let unused = main.mock(|when, then| {
+            when.method("POST");
+            then.status(500);
+        });
+        let other = MockServer::start_async().await;
+        other.mock(|when, then| {
+            when.method("POST")
+                .path("/device/status")
+                .body_contains("auth_key=other-key");
+            then.status(200).json_body(json!({
+                "isok": true,
+                "data": { "device_status": {
+                    "switch:0": { "output": false },
+                    "switch:1": { "output": true }
+                } }
+            }));
+        });
+        let control = other.mock(|when, then| {
+            when.method("POST")
+                .path("/device/relay/control")
+                .body_contains("auth_key=other-key")
+                .body_contains("channel=1")
+                .body_contains("turn=off");
+            then.status(200).json_body(json!({ "isok": true }));
+        });
+        let config: Config = toml::from_str(&format!(
+            r#"
+            [devices.67890]
+            base_url = "{}"
+            auth_key = "other-key"
+            "#,
+            other.base_url()
+        ))
+        .unwrap();
+        let args = Args::parse_from(["shelly-waybar", "--allow-control", "--devices", "plug:1"]);
+        let (client, key) = (Client::new(), Secret::new("main-key"));
+        let mut connections = Connections::default();
+
+        // Channel 1 of the plug is switched on its own account, not on the main one
+        toggle_device(
+            &args,
+            &config,
+            &client,
+            &main.base_url(),
+            &key,
+            &mut connections,
+            "plug:67890:Office#1",
+        )
+        .await
+        .unwrap();
+        control.assert();
+        unused.assert_hits(0);
+        // The toggle counts against the rate limit of the account polling the device
+        let account = (other.base_url(), Some("other-key".to_string()));
+        assert!(connections.limiters.contains_key(&account));
+    }
+
+    // Test: HTTP Client Pool
+    #[tokio::test]
+    async fn test_http_client_pool() {
+        use httpmock::MockServer;
+
+        let server = MockServer::start_async().await;
+        let mock = server.mock(|when, then| {
+            when.path("/");
+            then.status(200);
+        });
+
+        let args = Args::parse_from([
+            "shelly-waybar",
+            "--devices",
+            "plug:1",
+            "--pool-idle-timeout",
+            "1",
+            "--pool-max-idle",
+            "0",
+            "--tcp-keepalive",
+            "30",
+        ]);
+        assert_eq!(args.pool_max_idle, Some(0));
+        let client = args.http_client().unwrap();
+        for _ in 0..2 {
+            let response = client.get(server.base_url()).send().await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+        }
+        mock.assert_hits(2);
+    }
+
+    // Test: Process Device Percentage And Alt
+    #[test]
+    fn test_process_device_percentage_and_alt() {
+        let device = "temperature:12345".to_string();
+        let status = json!({
+            "temperature:0": { "tC": 21.5 },
+            "humidity:0": { "rh": 48.2 },
+            "devicepower:0": { "battery": { "percent": 87 } }
+        });
+        let reading = |args: &Args, device: &str, status: Value| {
+            process_device(
+                device,
+                status,
+                args,
+                &Config::default(),
+                &mut PollState::default(),
+                &notify::Noop,
+            )
+            .unwrap()
+        };
+
+        let args = Args::parse_from(["shelly-waybar", "--devices", &device]);
+        let output = reading(&args, &device, status.clone()).output;
+        // The humidity comes before the battery
+        assert_eq!(output["percentage"], 48);
+        assert_eq!(output["alt"], "temperature");
+        assert_eq!(schema::validate_output(&output), Ok(()));
+
+        let door = "door:67890";
+        let door_status = json!({
+            "window:0": { "open": true },
+            "devicepower:0": { "battery": { "percent": 60 } }
+        });
+        let output = reading(&args, door, door_status).output;
+        assert_eq!(output["alt"], "door-open");
+        // Nothing else to show as a bar
+        assert_eq!(output["percentage"], 60);
+
+        // Bare values leave the icons to format-icons keyed by alt
+        let args = Args::parse_from(["shelly-waybar", "--devices", &device, "--bare"]);
+        let output = reading(&args, &device, status).output;
+        assert_eq!(output["text"], "21.5°C 48%");
+        assert_eq!(output["alt"], "temperature");
+    }
+
     #[test]
     fn test_resolve_input_with_path() {
         let temp_file = "/tmp/test_file.txt";
@@ -0,0 +1,463 @@
+use crate::config::MqttConfig;
+use crate::fields::Sample;
+use crate::keyring;
+use crate::Reading;
+use reqwest::Url;
+use rumqttc::{AsyncClient, ClientError, MqttOptions, QoS};
+use serde_json::{json, Map, Value};
+use std::collections::{HashMap, HashSet};
+use std::time::Duration;
+
+const DEFAULT_PORT: u16 = 1883;
+
+/// States of a Gen2 `cover` component.
+const COVER_STATES: [&str; 6] = [
+    "open",
+    "closed",
+    "opening",
+    "closing",
+    "stopped",
+    "calibrating",
+];
+
+/// Publishes device readings to an MQTT broker, optionally announcing them with
+/// Home Assistant discovery so they show up as sensors.
+pub struct MqttPublisher {
+    client: AsyncClient,
+    config: MqttConfig,
+    discovery_prefix: String,
+    announced: HashSet<String>,
+    /// Last payload queued on each topic, to only send changes
+    published: HashMap<String, String>,
+}
+
+impl MqttPublisher {
+    /// Connects to a `mqtt://[user[:password]@]host[:port]` broker. Without a
+    /// password in the URL, the `mqtt_password` Secret Service entry is used.
+    pub async fn connect(
+        url: &str,
+        config: &MqttConfig,
+        discovery_prefix: &str,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let options = options(url, "shelly-waybar").await?;
+        let (client, mut eventloop) = AsyncClient::new(options, 64);
+        // The event loop drives the connection and reconnects on the next poll after an error
+        tokio::spawn(async move {
+            loop {
+                if let Err(err) = eventloop.poll().await {
+                    eprintln!("Error: MQTT connection failed - {}", err);
+                    tokio::time::sleep(Duration::from_secs(5)).await;
+                }
+            }
+        });
+
+        Ok(MqttPublisher {
+            client,
+            config: config.clone(),
+            discovery_prefix: discovery_prefix.to_string(),
+            announced: HashSet::new(),
+            published: HashMap::new(),
+        })
+    }
+
+    /// Publishes each changed sample of a reading and, if configured, the whole reading;
+    /// the discovery config of a sample is sent the first time it is seen. Messages are
+    /// queued without waiting: while the broker is away and the queue is full, the rest is
+    /// dropped and sent on a later call.
+    pub fn publish(
+        &mut self,
+        device_id: &str,
+        device_name: Option<&str>,
+        reading: &Reading,
+    ) -> Result<(), ClientError> {
+        for sample in &reading.samples {
+            let topic = render_topic(&self.config.topic, device_id, device_name, &sample.key);
+            if self.config.discovery && !self.announced.contains(&topic) {
+                let (config_topic, config) = discovery_config(
+                    &self.discovery_prefix,
+                    device_id,
+                    device_name,
+                    &reading.device_type,
+                    sample,
+                    &topic,
+                );
+                self.client.try_publish(
+                    config_topic,
+                    QoS::AtLeastOnce,
+                    true,
+                    config.to_string(),
+                )?;
+                self.announced.insert(topic.clone());
+            }
+            let payload = match &sample.value {
+                Value::String(value) => value.clone(),
+                value => value.to_string(),
+            };
+            self.send(topic, payload)?;
+        }
+
+        if let Some(template) = &self.config.reading_topic {
+            let topic = render_topic(template, device_id, device_name, "");
+            let payload = reading_payload(device_id, device_name, reading);
+            self.send(topic, payload.to_string())?;
+        }
+        Ok(())
+    }
+
+    // Queue a payload unless it is the last one of its topic
+    fn send(&mut self, topic: String, payload: String) -> Result<(), ClientError> {
+        if self.published.get(&topic) == Some(&payload) {
+            return Ok(());
+        }
+        self.client.try_publish(
+            topic.clone(),
+            QoS::AtLeastOnce,
+            self.config.retain,
+            payload.clone(),
+        )?;
+        self.published.insert(topic, payload);
+        Ok(())
+    }
+}
+
+/// Connection options of a `mqtt://[user[:password]@]host[:port]` broker, with a client ID
+/// made unique by the process ID.
+pub async fn options(
+    url: &str,
+    client_name: &str,
+) -> Result<MqttOptions, Box<dyn std::error::Error>> {
+    let url = Url::parse(url).map_err(|err| format!("Invalid MQTT URL: {}", err))?;
+    if !matches!(url.scheme(), "mqtt" | "tcp") {
+        return Err(format!("Unsupported MQTT scheme '{}'", url.scheme()).into());
+    }
+    let host = url.host_str().ok_or("MQTT URL has no host")?;
+    let client_id = format!("{}-{}", client_name, std::process::id());
+
+    let mut options = MqttOptions::new(client_id, host, url.port().unwrap_or(DEFAULT_PORT));
+    options.set_keep_alive(Duration::from_secs(30));
+    if !url.username().is_empty() {
+        let password = match url.password() {
+            Some(password) => password.to_string(),
+            None => keyring::lookup("mqtt_password")
+                .await
+                .map(|secret| secret.expose().to_string())
+                .unwrap_or_default(),
+        };
+        options.set_credentials(url.username(), password);
+    }
+    Ok(options)
+}
+
+/// Fills the `{device_id}`, `{name}` and `{field}` placeholders of a topic,
+/// keeping MQTT wildcards and level separators out of the substituted values.
+pub fn render_topic(
+    template: &str,
+    device_id: &str,
+    device_name: Option<&str>,
+    field: &str,
+) -> String {
+    let escape = |value: &str| value.replace(['/', '+', '#'], "_");
+    template
+        .replace("{device_id}", &escape(device_id))
+        .replace("{name}", &escape(device_name.unwrap_or(device_id)))
+        .replace("{field}", &escape(field))
+}
+
+/// JSON object describing a whole reading, for the reading topic.
+pub fn reading_payload(device_id: &str, device_name: Option<&str>, reading: &Reading) -> Value {
+    let values: Map<String, Value> = reading
+        .samples
+        .iter()
+        .map(|sample| (sample.key.clone(), sample.value.clone()))
+        .collect();
+    let units: Map<String, Value> = reading
+        .samples
+        .iter()
+        .filter(|sample| !sample.unit.is_empty())
+        .map(|sample| (sample.key.clone(), Value::String(sample.unit.clone())))
+        .collect();
+    json!({
+        "device_id": device_id,
+        "name": device_name,
+        "updated_at": reading.updated_at,
+        "values": values,
+        "units": units,
+    })
+}
+
+/// Builds the Home Assistant discovery topic and payload announcing a sample.
+pub fn discovery_config(
+    prefix: &str,
+    device_id: &str,
+    device_name: Option<&str>,
+    device_type: &str,
+    sample: &Sample,
+    state_topic: &str,
+) -> (String, Value) {
+    let object_id = format!("shelly_waybar_{}_{}", device_id, sample.key);
+    let mut config = json!({
+        "name": sensor_name(&sample.key),
+        "unique_id": object_id,
+        "state_topic": state_topic,
+        "device": {
+            "identifiers": [format!("shelly_waybar_{}", device_id)],
+            "name": device_name.unwrap_or(device_id),
+            "manufacturer": "Shelly",
+        },
+    });
+
+    let component = match (device_type, sample.key.as_str()) {
+        // A cover also reports its motion, which no binary sensor can show
+        ("cover", "state") => {
+            config["device_class"] = json!("enum");
+            config["options"] = json!(COVER_STATES);
+            "sensor"
+        }
+        (_, "state") => {
+            config["device_class"] = json!("opening");
+            config["payload_on"] = json!("open");
+            config["payload_off"] = json!("closed");
+            "binary_sensor"
+        }
+        (_, "output") => {
+            config["device_class"] = json!("power");
+            config["payload_on"] = json!("ON");
+            config["payload_off"] = json!("OFF");
+            "binary_sensor"
+        }
+        (_, key) => {
+            if let Some(device_class) = device_class(key) {
+                config["device_class"] = json!(device_class);
+            }
+            if sample.value.is_number() {
+                config["state_class"] = json!("measurement");
+            }
+            if !sample.unit.is_empty() {
+                config["unit_of_measurement"] = json!(sample.unit);
+            }
+            "sensor"
+        }
+    };
+
+    (
+        format!("{}/{}/{}/config", prefix, component, object_id),
+        config,
+    )
+}
+
+fn device_class(key: &str) -> Option<&'static str> {
+    match key {
+        "temperature" => Some("temperature"),
+        "humidity" => Some("humidity"),
+        "battery" => Some("battery"),
+        "rssi" => Some("signal_strength"),
+        "power" => Some("power"),
+        "voltage" => Some("voltage"),
+        "current" => Some("current"),
+        "lux" => Some("illuminance"),
+        _ => None,
+    }
+}
+
+// Display name of a sensor, e.g. `rssi` -> `RSSI`, `power` -> `Power`
+fn sensor_name(key: &str) -> String {
+    if key == "rssi" {
+        return "RSSI".to_string();
+    }
+    let mut chars = key.chars();
+    chars
+        .next()
+        .map(|first| first.to_uppercase().chain(chars).collect())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(key: &str, value: Value, unit: &str) -> Sample {
+        Sample {
+            key: key.to_string(),
+            value,
+            unit: unit.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_discovery_config_sensor() {
+        let (topic, config) = discovery_config(
+            "homeassistant",
+            "67890",
+            Some("Desk"),
+            "plug",
+            &sample("power", json!(50.2), "W"),
+            "shelly-waybar/67890/power",
+        );
+
+        assert_eq!(
+            topic,
+            "homeassistant/sensor/shelly_waybar_67890_power/config"
+        );
+        assert_eq!(
+            config,
+            json!({
+                "name": "Power",
+                "unique_id": "shelly_waybar_67890_power",
+                "state_topic": "shelly-waybar/67890/power",
+                "device_class": "power",
+                "state_class": "measurement",
+                "unit_of_measurement": "W",
+                "device": {
+                    "identifiers": ["shelly_waybar_67890"],
+                    "name": "Desk",
+                    "manufacturer": "Shelly",
+                },
+            })
+        );
+    }
+
+    #[test]
+    fn test_discovery_config_binary_sensor() {
+        let (topic, config) = discovery_config(
+            "ha",
+            "12345",
+            None,
+            "door",
+            &sample("state", json!("open"), ""),
+            "shelly-waybar/12345/state",
+        );
+
+        assert_eq!(topic, "ha/binary_sensor/shelly_waybar_12345_state/config");
+        assert_eq!(config["device_class"], "opening");
+        assert_eq!(config["payload_on"], "open");
+        assert_eq!(config["device"]["name"], "12345");
+        assert!(config.get("unit_of_measurement").is_none());
+    }
+
+    #[test]
+    fn test_discovery_config_cover() {
+        let (topic, config) = discovery_config(
+            "homeassistant",
+            "67890",
+            Some("Shutter"),
+            "cover",
+            &sample("state", json!("opening"), ""),
+            "shelly-waybar/67890/state",
+        );
+
+        assert_eq!(
+            topic,
+            "homeassistant/sensor/shelly_waybar_67890_state/config"
+        );
+        assert_eq!(config["device_class"], "enum");
+        assert!(config["options"]
+            .as_array()
+            .unwrap()
+            .contains(&json!("opening")));
+        assert!(config.get("payload_on").is_none());
+        assert!(config.get("state_class").is_none());
+    }
+
+    #[test]
+    fn test_render_topic() {
+        assert_eq!(
+            render_topic("shelly-waybar/{device_id}/{field}", "12345", None, "power"),
+            "shelly-waybar/12345/power"
+        );
+        assert_eq!(
+            render_topic(
+                "home/{name}/{field}",
+                "12345",
+                Some("Desk/Lamp #2"),
+                "power"
+            ),
+            "home/Desk_Lamp _2/power"
+        );
+    }
+
+    #[test]
+    fn test_reading_payload() {
+        let reading = Reading {
+            output: json!({ "text": "", "tooltip": "" }),
+            updated_at: chrono::DateTime::from_timestamp(1705314030, 0).unwrap(),
+            short_text: None,
+            device_type: String::new(),
+            samples: vec![
+                sample("power", json!(50.2), "W"),
+                sample("output", json!("ON"), ""),
+            ],
+        };
+
+        assert_eq!(
+            reading_payload("67890", Some("Desk"), &reading),
+            json!({
+                "device_id": "67890",
+                "name": "Desk",
+                "updated_at": "2024-01-15T10:20:30Z",
+                "values": { "power": 50.2, "output": "ON" },
+                "units": { "power": "W" },
+            })
+        );
+    }
+
+    fn publisher(capacity: usize, discovery: bool) -> (MqttPublisher, rumqttc::EventLoop) {
+        // The event loop is never polled, so the queue fills up as with a broker away
+        let options = MqttOptions::new("shelly-waybar-test", "localhost", DEFAULT_PORT);
+        let (client, eventloop) = AsyncClient::new(options, capacity);
+        let config = MqttConfig {
+            discovery,
+            ..MqttConfig::default()
+        };
+        let publisher = MqttPublisher {
+            client,
+            config,
+            discovery_prefix: "homeassistant".to_string(),
+            announced: HashSet::new(),
+            published: HashMap::new(),
+        };
+        (publisher, eventloop)
+    }
+
+    fn reading(power: f64) -> Reading {
+        Reading {
+            output: json!({ "text": "", "tooltip": "" }),
+            updated_at: chrono::DateTime::from_timestamp(1705314030, 0).unwrap(),
+            short_text: None,
+            device_type: String::new(),
+            samples: vec![
+                sample("power", json!(power), "W"),
+                sample("output", json!("ON"), ""),
+            ],
+        }
+    }
+
+    #[test]
+    fn test_publish_changes_only() {
+        let (mut publisher, _eventloop) = publisher(2, false);
+
+        publisher.publish("67890", None, &reading(50.2)).unwrap();
+        // Nothing changed, nothing queued on the full queue
+        publisher.publish("67890", None, &reading(50.2)).unwrap();
+        assert!(publisher.publish("67890", None, &reading(48.0)).is_err());
+        assert_eq!(publisher.published["shelly-waybar/67890/power"], "50.2");
+    }
+
+    #[test]
+    fn test_publish_full_queue() {
+        let (mut publisher, _eventloop) = publisher(2, true);
+
+        // The discovery config and value of power fit, the discovery config of output does not
+        assert!(publisher.publish("67890", None, &reading(50.2)).is_err());
+        assert!(publisher.announced.contains("shelly-waybar/67890/power"));
+        assert!(!publisher.announced.contains("shelly-waybar/67890/output"));
+        assert!(!publisher
+            .published
+            .contains_key("shelly-waybar/67890/output"));
+    }
+
+    #[test]
+    fn test_sensor_name() {
+        assert_eq!(sensor_name("temperature"), "Temperature");
+        assert_eq!(sensor_name("rssi"), "RSSI");
+    }
+}
@@ -0,0 +1,197 @@
+use clap::ValueEnum;
+use std::error::Error;
+use std::time::Duration;
+use tokio::process::{Child, Command};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum Urgency {
+    #[default]
+    Normal,
+    Critical,
+}
+
+/// Delivers desktop notifications.
+pub trait Notifier {
+    fn notify(&self, summary: &str, body: &str, urgency: Urgency) -> Result<(), Box<dyn Error>>;
+}
+
+/// Notification backend selected with `--notifier`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, ValueEnum)]
+pub enum Backend {
+    /// Freedesktop notifications over D-Bus
+    #[default]
+    Freedesktop,
+    /// Run --notify-command, e.g. notify-send or terminal-notifier
+    Command,
+    /// Drop notifications, for headless setups
+    None,
+}
+
+pub fn notifier(backend: Backend, command: Option<&str>) -> Result<Box<dyn Notifier>, String> {
+    Ok(match backend {
+        Backend::Freedesktop => Box::new(Freedesktop),
+        Backend::Command => {
+            let command = command.ok_or("--notifier command requires --notify-command")?;
+            Box::new(CommandNotifier::new(command)?)
+        }
+        Backend::None => Box::new(Noop),
+    })
+}
+
+pub struct Freedesktop;
+
+impl Notifier for Freedesktop {
+    fn notify(&self, summary: &str, body: &str, urgency: Urgency) -> Result<(), Box<dyn Error>> {
+        notify_rust::Notification::new()
+            .summary(summary)
+            .body(body)
+            .urgency(match urgency {
+                Urgency::Normal => notify_rust::Urgency::Normal,
+                Urgency::Critical => notify_rust::Urgency::Critical,
+            })
+            .show()?;
+        Ok(())
+    }
+}
+
+/// Time a notification command gets to exit before it is killed.
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Runs a command for each notification, without waiting for it. Its `{summary}`,
+/// `{body}` and `{urgency}` arguments are substituted; without placeholders, the
+/// summary and body are appended as two arguments, as `notify-send` expects.
+pub struct CommandNotifier {
+    program: String,
+    args: Vec<String>,
+}
+
+impl CommandNotifier {
+    pub fn new(command: &str) -> Result<Self, String> {
+        let mut words = command.split_whitespace().map(str::to_string);
+        let program = words.next().ok_or("empty notification command")?;
+        let mut args: Vec<String> = words.collect();
+        if !args
+            .iter()
+            .any(|arg| arg.contains("{summary}") || arg.contains("{body}"))
+        {
+            args.push("{summary}".to_string());
+            args.push("{body}".to_string());
+        }
+        Ok(CommandNotifier { program, args })
+    }
+
+    fn args(&self, summary: &str, body: &str, urgency: Urgency) -> Vec<String> {
+        let urgency = match urgency {
+            Urgency::Normal => "normal",
+            Urgency::Critical => "critical",
+        };
+        self.args
+            .iter()
+            .map(|arg| {
+                arg.replace("{summary}", summary)
+                    .replace("{body}", body)
+                    .replace("{urgency}", urgency)
+            })
+            .collect()
+    }
+}
+
+impl Notifier for CommandNotifier {
+    fn notify(&self, summary: &str, body: &str, urgency: Urgency) -> Result<(), Box<dyn Error>> {
+        let child = Command::new(&self.program)
+            .args(self.args(summary, body, urgency))
+            .kill_on_drop(true)
+            .spawn()?;
+        let program = self.program.clone();
+        tokio::spawn(async move {
+            if let Err(err) = wait(child, COMMAND_TIMEOUT).await {
+                eprintln!("Error: Unable to send notification - {} {}", program, err);
+            }
+        });
+        Ok(())
+    }
+}
+
+// Wait for a notification command, killing it once it takes longer than `timeout`
+async fn wait(mut child: Child, timeout: Duration) -> Result<(), String> {
+    let status = match tokio::time::timeout(timeout, child.wait()).await {
+        Ok(status) => status.map_err(|err| format!("failed - {}", err))?,
+        Err(_) => {
+            let _ = child.kill().await;
+            return Err(format!("timed out after {} s", timeout.as_secs()));
+        }
+    };
+    if !status.success() {
+        return Err(format!("exited with {}", status));
+    }
+    Ok(())
+}
+
+pub struct Noop;
+
+impl Notifier for Noop {
+    fn notify(&self, _summary: &str, _body: &str, _urgency: Urgency) -> Result<(), Box<dyn Error>> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_command_args() {
+        let notify_send = CommandNotifier::new("notify-send -u {urgency}").unwrap();
+        assert_eq!(notify_send.program, "notify-send");
+        assert_eq!(
+            notify_send.args("Door", "The door is now Open", Urgency::Critical),
+            vec!["-u", "critical", "Door", "The door is now Open"]
+        );
+
+        let terminal_notifier =
+            CommandNotifier::new("terminal-notifier -title {summary} -message {body}").unwrap();
+        assert_eq!(
+            terminal_notifier.args("Door", "Open", Urgency::Normal),
+            vec!["-title", "Door", "-message", "Open"]
+        );
+
+        assert!(CommandNotifier::new(" ").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_command_notify() {
+        assert!(CommandNotifier::new("true")
+            .unwrap()
+            .notify("summary", "body", Urgency::Normal)
+            .is_ok());
+        assert!(CommandNotifier::new("shelly-waybar-missing-notifier")
+            .unwrap()
+            .notify("summary", "body", Urgency::Normal)
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_wait() {
+        let spawn =
+            |program: &str, args: &[&str]| Command::new(program).args(args).spawn().unwrap();
+        assert_eq!(wait(spawn("true", &[]), COMMAND_TIMEOUT).await, Ok(()));
+        assert_eq!(
+            wait(spawn("false", &[]), COMMAND_TIMEOUT).await,
+            Err("exited with exit status: 1".to_string())
+        );
+        // A hanging command does not hold the notifier
+        assert_eq!(
+            wait(spawn("sleep", &["5"]), Duration::from_secs(1)).await,
+            Err("timed out after 1 s".to_string())
+        );
+    }
+
+    #[test]
+    fn test_notifier() {
+        assert!(notifier(Backend::Command, None).is_err());
+        assert!(notifier(Backend::None, None)
+            .unwrap()
+            .notify("summary", "body", Urgency::Normal)
+            .is_ok());
+    }
+}
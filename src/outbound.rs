@@ -0,0 +1,154 @@
+use crate::broker::merge_components;
+use crate::http::Webhook;
+use futures::StreamExt;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use tokio_tungstenite::tungstenite::Message;
+
+/// Statuses streamed by Gen2 devices configured with an outbound WebSocket pointing at
+/// shelly-waybar, keyed by their ID (`src`), e.g. `shellyplus1pm-a8032ab12345`.
+#[derive(Clone, Default)]
+pub struct OutboundServer {
+    statuses: Arc<Mutex<HashMap<String, Value>>>,
+}
+
+impl OutboundServer {
+    /// Accepts the outbound WebSocket connections of devices on `addr`, returning the
+    /// server and the devices whose status changed.
+    pub async fn start(addr: SocketAddr) -> io::Result<(Self, UnboundedReceiver<Webhook>)> {
+        let listener = TcpListener::bind(addr).await?;
+        let server = OutboundServer::default();
+        let (sender, receiver) = mpsc::unbounded_channel();
+
+        let statuses = server.clone();
+        tokio::spawn(async move {
+            loop {
+                match listener.accept().await {
+                    Ok((stream, peer)) => {
+                        tokio::spawn(statuses.clone().serve(stream, peer, sender.clone()));
+                    }
+                    Err(err) => eprintln!("Error: Outbound WebSocket accept failed - {}", err),
+                }
+            }
+        });
+        Ok((server, receiver))
+    }
+
+    /// Last known status of a device.
+    pub fn status(&self, device_id: &str) -> Option<Value> {
+        let statuses = self.statuses.lock().unwrap_or_else(|err| err.into_inner());
+        statuses.get(device_id).cloned()
+    }
+
+    async fn serve(self, stream: TcpStream, peer: SocketAddr, sender: UnboundedSender<Webhook>) {
+        let mut socket = match tokio_tungstenite::accept_async(stream).await {
+            Ok(socket) => socket,
+            Err(err) => {
+                eprintln!("Error: Outbound WebSocket from {} failed - {}", peer, err);
+                return;
+            }
+        };
+        while let Some(Ok(message)) = socket.next().await {
+            let Message::Text(text) = message else {
+                continue;
+            };
+            let Some(device_id) = self.apply(&text) else {
+                continue;
+            };
+            if sender
+                .send(Webhook {
+                    device_id,
+                    event: None,
+                })
+                .is_err()
+            {
+                return;
+            }
+        }
+    }
+
+    /// Merges a status notification into the status of its device, returning the device ID.
+    fn apply(&self, text: &str) -> Option<String> {
+        let frame: Value = serde_json::from_str(text).ok()?;
+        if !matches!(
+            frame["method"].as_str()?,
+            "NotifyStatus" | "NotifyFullStatus"
+        ) {
+            return None;
+        }
+        let device_id = frame["src"].as_str()?;
+        let params = frame["params"].as_object()?;
+        let mut statuses = self.statuses.lock().unwrap_or_else(|err| err.into_inner());
+        let status = statuses
+            .entry(device_id.to_string())
+            .or_insert_with(|| json!({}));
+        merge_components(status, params);
+        Some(device_id.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::SinkExt;
+    use std::time::Duration;
+
+    #[test]
+    fn test_apply() {
+        let server = OutboundServer::default();
+        assert_eq!(
+            server.apply(
+                r#"{"src":"shellyplus1pm-a8032ab12345","dst":"ws","method":"NotifyFullStatus","params":{"ts":1.0,"switch:0":{"output":true,"apower":12.0}}}"#
+            ),
+            Some("shellyplus1pm-a8032ab12345".to_string())
+        );
+        server.apply(
+            r#"{"src":"shellyplus1pm-a8032ab12345","method":"NotifyStatus","params":{"switch:0":{"apower":3.0}}}"#,
+        );
+        assert_eq!(
+            server.apply(
+                r#"{"src":"shellyplus1pm-a8032ab12345","method":"NotifyEvent","params":{}}"#
+            ),
+            None
+        );
+
+        let status = server.status("shellyplus1pm-a8032ab12345").unwrap();
+        assert_eq!(status["switch:0"]["output"], true);
+        assert_eq!(status["switch:0"]["apower"], 3.0);
+        assert_eq!(server.status("unknown"), None);
+    }
+
+    #[tokio::test]
+    async fn test_start() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        drop(listener);
+        let (server, mut receiver) = OutboundServer::start(addr).await.unwrap();
+
+        let (mut device, _) = tokio_tungstenite::connect_async(format!("ws://{}/", addr))
+            .await
+            .unwrap();
+        device
+            .send(Message::Text(
+                r#"{"src":"shellyplusht-c049ef8b1234","method":"NotifyFullStatus","params":{"temperature:0":{"tC":21.5}}}"#
+                    .to_string(),
+            ))
+            .await
+            .unwrap();
+
+        let webhook = tokio::time::timeout(Duration::from_secs(5), receiver.recv()).await;
+        assert_eq!(
+            webhook.unwrap().unwrap().device_id,
+            "shellyplusht-c049ef8b1234"
+        );
+        assert_eq!(
+            server.status("shellyplusht-c049ef8b1234").unwrap()["temperature:0"]["tC"],
+            21.5
+        );
+    }
+}
@@ -0,0 +1,127 @@
+use serde_json::Value;
+use std::collections::HashMap;
+use std::io::Write;
+use std::os::unix::fs::FileTypeExt;
+use std::path::{Path, PathBuf};
+use std::{fs, io};
+use tokio::io::AsyncWriteExt;
+use tokio::net::unix::pipe;
+
+/// Writes the output of each device to its own file of a directory, for one Waybar module
+/// per device; a FIFO created there in place of the file gets every output as a new line.
+pub struct OutputDir {
+    path: PathBuf,
+    /// Open FIFOs, kept until their reader goes away
+    fifos: HashMap<PathBuf, pipe::Sender>,
+}
+
+impl OutputDir {
+    pub fn create(path: &Path) -> io::Result<Self> {
+        fs::create_dir_all(path)?;
+        Ok(OutputDir {
+            path: path.to_path_buf(),
+            fifos: HashMap::new(),
+        })
+    }
+
+    pub async fn write(
+        &mut self,
+        device_id: &str,
+        device_name: Option<&str>,
+        output: &Value,
+    ) -> io::Result<()> {
+        let path = self.path.join(file_name(device_id, device_name));
+        let line = format!("{}\n", output);
+        let is_fifo = fs::metadata(&path).is_ok_and(|metadata| metadata.file_type().is_fifo());
+        if !is_fifo {
+            return write_atomically(&path, line.as_bytes());
+        }
+
+        if !self.fifos.contains_key(&path) {
+            // Nobody reads the FIFO yet: the output is dropped, the next one will be written
+            match pipe::OpenOptions::new().open_sender(&path) {
+                Ok(sender) => self.fifos.insert(path.clone(), sender),
+                Err(err) if err.raw_os_error() == Some(ENXIO) => return Ok(()),
+                Err(err) => return Err(err),
+            };
+        }
+        if let Some(sender) = self.fifos.get_mut(&path) {
+            if sender.write_all(line.as_bytes()).await.is_err() {
+                // The reader went away, the FIFO is opened again for the next one
+                self.fifos.remove(&path);
+            }
+        }
+        Ok(())
+    }
+}
+
+// Error of opening the write end of a FIFO without reader
+const ENXIO: i32 = 6;
+
+/// File of a device, `<name>.json` from its name made safe for a path, or its ID.
+pub fn file_name(device_id: &str, device_name: Option<&str>) -> String {
+    let slug = device_name
+        .unwrap_or(device_id)
+        .to_lowercase()
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .collect::<Vec<_>>()
+        .join("-");
+    match slug.is_empty() {
+        true => format!("{}.json", device_id.replace('/', "-")),
+        false => format!("{}.json", slug),
+    }
+}
+
+// Replace the file at once, so that readers never get a partial output
+fn write_atomically(path: &Path, content: &[u8]) -> io::Result<()> {
+    let tmp_path = path.with_extension("json.tmp");
+    let mut file = fs::File::create(&tmp_path)?;
+    file.write_all(content)?;
+    fs::rename(tmp_path, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_file_name() {
+        assert_eq!(file_name("12345", Some("Front Door")), "front-door.json");
+        assert_eq!(
+            file_name("12345", Some("Salle de bain")),
+            "salle-de-bain.json"
+        );
+        assert_eq!(file_name("12345", None), "12345.json");
+        assert_eq!(file_name("12345", Some("&!")), "12345.json");
+    }
+
+    #[tokio::test]
+    async fn test_writes_file_per_device() {
+        let path =
+            std::env::temp_dir().join(format!("shelly-waybar-test-outputs-{}", std::process::id()));
+        let mut outputs = OutputDir::create(&path).unwrap();
+        outputs
+            .write(
+                "12345",
+                Some("Balcony"),
+                &serde_json::json!({"text": "21°C"}),
+            )
+            .await
+            .unwrap();
+        outputs
+            .write(
+                "12345",
+                Some("Balcony"),
+                &serde_json::json!({"text": "22°C"}),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(
+            fs::read_to_string(path.join("balcony.json")).unwrap(),
+            "{\"text\":\"22°C\"}\n"
+        );
+        fs::remove_dir_all(&path).unwrap();
+    }
+}
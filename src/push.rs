@@ -0,0 +1,248 @@
+use crate::auth::Secret;
+use crate::http::Webhook;
+use futures::StreamExt;
+use reqwest::header::ACCEPT;
+use reqwest::Client;
+use serde_json::Value;
+use std::error::Error;
+use std::time::Duration;
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use tokio_tungstenite::connect_async;
+use tokio_tungstenite::tungstenite::Message;
+
+/// Port of the real-time events WebSocket of the Shelly cloud servers.
+const EVENTS_PORT: u16 = 6113;
+/// Longest wait between two connection attempts.
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(300);
+
+/// WebSocket URL of the real-time events of a cloud server, e.g.
+/// `wss://shelly-001-eu.shelly.cloud:6113/shelly/wss/hk_sock`.
+pub fn events_url(base_url: &str) -> String {
+    let host = base_url
+        .trim_start_matches("https://")
+        .trim_start_matches("http://")
+        .trim_end_matches('/');
+    format!("wss://{}:{}/shelly/wss/hk_sock", host, EVENTS_PORT)
+}
+
+/// Connection carrying the cloud events.
+pub enum Transport {
+    /// The WebSocket of the cloud server, see [`events_url`]
+    WebSocket(String),
+    /// Server-Sent Events endpoint relaying the same events, e.g. through a proxy that does
+    /// not pass WebSockets, each `data` field holding one event
+    Sse(Client, String),
+}
+
+/// Keeps a connection to the cloud events, reconnecting when it drops, and returns the
+/// devices whose status changed. Polling carries on meanwhile as a fallback.
+pub fn start(transport: Transport, token: Secret) -> UnboundedReceiver<Webhook> {
+    let (sender, receiver) = mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        let mut delay = Duration::from_secs(5);
+        loop {
+            let result = match &transport {
+                Transport::WebSocket(url) => listen(url, &token, &sender).await,
+                Transport::Sse(client, url) => listen_sse(client, url, &token, &sender).await,
+            };
+            match result {
+                // Only once the bar is gone
+                Ok(()) if sender.is_closed() => return,
+                Ok(()) => {
+                    eprintln!("Error: Cloud events connection closed");
+                    delay = Duration::from_secs(5);
+                }
+                Err(err) => eprintln!(
+                    "Error: Cloud events connection failed - {}",
+                    token.redact(&err.to_string())
+                ),
+            }
+            tokio::time::sleep(delay).await;
+            delay = (delay * 2).min(MAX_RETRY_DELAY);
+        }
+    });
+    receiver
+}
+
+async fn listen(
+    url: &str,
+    token: &Secret,
+    sender: &UnboundedSender<Webhook>,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let (mut socket, _) = connect_async(format!("{}?t={}", url, token.expose())).await?;
+    while let Some(message) = socket.next().await {
+        let Message::Text(text) = message? else {
+            continue;
+        };
+        if !forward(&text, sender) {
+            return Ok(());
+        }
+    }
+    Ok(())
+}
+
+async fn listen_sse(
+    client: &Client,
+    url: &str,
+    token: &Secret,
+    sender: &UnboundedSender<Webhook>,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let mut response = client
+        .get(url)
+        .header(ACCEPT, "text/event-stream")
+        .bearer_auth(token.expose())
+        .send()
+        .await?
+        .error_for_status()?;
+    let mut buffer = Vec::new();
+    let mut data = Vec::new();
+    while let Some(chunk) = response.chunk().await? {
+        buffer.extend_from_slice(&chunk);
+        while let Some(end) = buffer.iter().position(|byte| *byte == b'\n') {
+            let line: Vec<u8> = buffer.drain(..=end).collect();
+            let line = String::from_utf8_lossy(&line);
+            let line = line.trim_end_matches(['\r', '\n']);
+            // A blank line dispatches the event, its data spanning several lines or not
+            if line.is_empty() {
+                if !data.is_empty() && !forward(&data.join("\n"), sender) {
+                    return Ok(());
+                }
+                data.clear();
+            } else if let Some(value) = line.strip_prefix("data:") {
+                data.push(value.strip_prefix(' ').unwrap_or(value).to_string());
+            }
+        }
+    }
+    Ok(())
+}
+
+// Sends the device of an event, telling whether the bar is still there
+fn forward(text: &str, sender: &UnboundedSender<Webhook>) -> bool {
+    match parse_event(text) {
+        Some(device_id) => sender
+            .send(Webhook {
+                device_id,
+                event: None,
+            })
+            .is_ok(),
+        None => true,
+    }
+}
+
+// Device of a status change event, e.g.
+// `{"event":"Shelly:StatusOnChange","device":{"id":"e8db84d6b7a4"},"status":{...}}`
+fn parse_event(text: &str) -> Option<String> {
+    let event: Value = serde_json::from_str(text).ok()?;
+    if !matches!(
+        event["event"].as_str()?,
+        "Shelly:StatusOnChange" | "Shelly:Online"
+    ) {
+        return None;
+    }
+    match &event["device"]["id"] {
+        Value::String(id) => Some(id.clone()),
+        Value::Number(id) => Some(id.to_string()),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::SinkExt;
+    use httpmock::MockServer;
+    use tokio::net::TcpListener;
+
+    #[test]
+    fn test_events_url() {
+        assert_eq!(
+            events_url("https://shelly-001-eu.shelly.cloud/"),
+            "wss://shelly-001-eu.shelly.cloud:6113/shelly/wss/hk_sock"
+        );
+    }
+
+    #[test]
+    fn test_parse_event() {
+        assert_eq!(
+            parse_event(
+                r#"{"event":"Shelly:StatusOnChange","device":{"id":"e8db84d6b7a4"},"status":{}}"#
+            ),
+            Some("e8db84d6b7a4".to_string())
+        );
+        assert_eq!(
+            parse_event(r#"{"event":"Shelly:Online","device":{"id":12345},"online":1}"#),
+            Some("12345".to_string())
+        );
+        assert_eq!(
+            parse_event(r#"{"event":"Shelly:Settings","device":{"id":"1"}}"#),
+            None
+        );
+        assert_eq!(parse_event("not json"), None);
+    }
+
+    #[tokio::test]
+    async fn test_listen() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut socket = tokio_tungstenite::accept_async(stream).await.unwrap();
+            socket
+                .send(Message::Text(
+                    r#"{"event":"Shelly:StatusOnChange","device":{"id":"12345"},"status":{}}"#
+                        .to_string(),
+                ))
+                .await
+                .unwrap();
+        });
+
+        let (sender, mut receiver) = mpsc::unbounded_channel();
+        let url = format!("ws://{}/shelly/wss/hk_sock", addr);
+        tokio::spawn(async move { listen(&url, &Secret::new("token"), &sender).await });
+        let webhook = tokio::time::timeout(Duration::from_secs(5), receiver.recv()).await;
+        assert_eq!(
+            webhook.unwrap(),
+            Some(Webhook {
+                device_id: "12345".to_string(),
+                event: None
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_listen_sse() {
+        let server = MockServer::start_async().await;
+        server.mock(|when, then| {
+            when.method("GET")
+                .path("/events")
+                .header("Authorization", "Bearer token");
+            then.status(200)
+                .header("Content-Type", "text/event-stream")
+                .body(concat!(
+                    ": keep-alive\n\n",
+                    "event: message\n",
+                    "data: {\"event\":\"Shelly:Settings\",\"device\":{\"id\":\"1\"}}\n\n",
+                    "data: {\"event\":\"Shelly:StatusOnChange\",\r\n",
+                    "data: \"device\":{\"id\":\"12345\"},\"status\":{}}\r\n\r\n",
+                ));
+        });
+
+        let (sender, mut receiver) = mpsc::unbounded_channel();
+        listen_sse(
+            &Client::new(),
+            &format!("{}/events", server.base_url()),
+            &Secret::new("token"),
+            &sender,
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            receiver.try_recv().unwrap(),
+            Webhook {
+                device_id: "12345".to_string(),
+                event: None
+            }
+        );
+        assert!(receiver.try_recv().is_err());
+    }
+}
@@ -0,0 +1,129 @@
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Token bucket spacing out the requests to a cloud account, shared by all of them.
+#[derive(Clone, Debug)]
+pub struct RateLimiter {
+    /// Requests per second, unlimited when 0
+    rate: f64,
+    burst: f64,
+    bucket: Arc<Mutex<Bucket>>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    refilled_at: Instant,
+    /// Set when the cloud answered 429 Too Many Requests
+    paused_until: Option<Instant>,
+}
+
+impl RateLimiter {
+    /// Allows `rate` requests per second on average, and up to `burst` at once.
+    pub fn new(rate: f64, burst: u32) -> Self {
+        let burst = f64::from(burst.max(1));
+        RateLimiter {
+            rate,
+            burst,
+            bucket: Arc::new(Mutex::new(Bucket {
+                tokens: burst,
+                refilled_at: Instant::now(),
+                paused_until: None,
+            })),
+        }
+    }
+
+    pub fn unlimited() -> Self {
+        RateLimiter::new(0.0, 1)
+    }
+
+    /// Waits until a request may be sent.
+    pub async fn acquire(&self) {
+        loop {
+            let wait = self.try_acquire(Instant::now());
+            match wait {
+                Some(wait) => tokio::time::sleep(wait).await,
+                None => return,
+            }
+        }
+    }
+
+    /// Holds every request back for `delay`, e.g. the `Retry-After` of a 429 response.
+    pub fn pause(&self, delay: Duration) {
+        let mut bucket = self.bucket.lock().unwrap_or_else(|err| err.into_inner());
+        let until = Instant::now() + delay;
+        bucket.paused_until = Some(
+            bucket
+                .paused_until
+                .map_or(until, |paused| paused.max(until)),
+        );
+    }
+
+    // Takes a token, or tells how long to wait for one
+    fn try_acquire(&self, now: Instant) -> Option<Duration> {
+        let mut bucket = self.bucket.lock().unwrap_or_else(|err| err.into_inner());
+        if let Some(paused_until) = bucket.paused_until {
+            if now < paused_until {
+                return Some(paused_until - now);
+            }
+            bucket.paused_until = None;
+        }
+        if self.rate <= 0.0 {
+            return None;
+        }
+        let elapsed = now.duration_since(bucket.refilled_at).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.rate).min(self.burst);
+        bucket.refilled_at = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            None
+        } else {
+            // A tiny rate can overflow the duration, which sleep clamps anyway
+            Some(
+                Duration::try_from_secs_f64((1.0 - bucket.tokens) / self.rate)
+                    .unwrap_or(Duration::MAX),
+            )
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_acquire_spaces_requests() {
+        let limiter = RateLimiter::new(2.0, 2);
+        let start = Instant::now();
+        for _ in 0..4 {
+            limiter.acquire().await;
+        }
+        // Two at once, then one every 500 ms
+        assert_eq!(start.elapsed(), Duration::from_secs(1));
+
+        let unlimited = RateLimiter::unlimited();
+        let start = Instant::now();
+        for _ in 0..10 {
+            unlimited.acquire().await;
+        }
+        assert_eq!(start.elapsed(), Duration::ZERO);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_pause() {
+        let limiter = RateLimiter::unlimited();
+        limiter.pause(Duration::from_secs(30));
+        let start = Instant::now();
+        limiter.acquire().await;
+        assert_eq!(start.elapsed(), Duration::from_secs(30));
+    }
+
+    #[test]
+    fn test_tiny_rate() {
+        let limiter = RateLimiter::new(1e-300, 1);
+        let now = Instant::now();
+        assert_eq!(limiter.try_acquire(now), None);
+        assert_eq!(limiter.try_acquire(now), Some(Duration::MAX));
+    }
+}
@@ -0,0 +1,122 @@
+use std::future::Future;
+use std::time::Duration;
+
+/// Retries of the requests failing for a transient reason, with an exponential backoff
+/// randomized so that devices failing together do not retry together.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RetryPolicy {
+    /// Attempts in all, including the first one
+    pub attempts: u32,
+    /// Delay before the first retry, doubled for each next one
+    pub backoff: Duration,
+    /// Fraction of the delay added at random, from 0 to 1
+    pub jitter: f64,
+}
+
+impl RetryPolicy {
+    pub fn none() -> Self {
+        RetryPolicy {
+            attempts: 1,
+            backoff: Duration::ZERO,
+            jitter: 0.0,
+        }
+    }
+
+    /// Runs `request` until it succeeds, fails for good, or the attempts are exhausted.
+    pub async fn run<T, E, F, Fut>(
+        &self,
+        mut request: F,
+        transient: impl Fn(&E) -> bool,
+    ) -> Result<T, E>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, E>>,
+    {
+        let mut attempt = 1;
+        loop {
+            match request().await {
+                Err(err) if attempt < self.attempts && transient(&err) => {
+                    tokio::time::sleep(self.delay(attempt, fastrand::f64())).await;
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    // Delay before retry number `retry`, `random` being drawn from [0, 1)
+    fn delay(&self, retry: u32, random: f64) -> Duration {
+        let backoff = self.backoff.saturating_mul(1 << (retry - 1).min(16));
+        backoff.mul_f64(1.0 + self.jitter.clamp(0.0, 1.0) * random)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    fn policy() -> RetryPolicy {
+        RetryPolicy {
+            attempts: 3,
+            backoff: Duration::from_millis(500),
+            jitter: 0.5,
+        }
+    }
+
+    #[test]
+    fn test_delay() {
+        let policy = policy();
+        assert_eq!(policy.delay(1, 0.0), Duration::from_millis(500));
+        assert_eq!(policy.delay(2, 0.0), Duration::from_secs(1));
+        assert_eq!(policy.delay(3, 0.5), Duration::from_millis(2500));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_run() {
+        let calls = Cell::new(0);
+        let result: Result<(), &str> = policy()
+            .run(
+                || {
+                    calls.set(calls.get() + 1);
+                    async { Err("unavailable") }
+                },
+                |err| *err == "unavailable",
+            )
+            .await;
+        assert_eq!(result, Err("unavailable"));
+        assert_eq!(calls.get(), 3);
+
+        calls.set(0);
+        let result: Result<(), &str> = policy()
+            .run(
+                || {
+                    calls.set(calls.get() + 1);
+                    async { Err("invalid token") }
+                },
+                |err| *err == "unavailable",
+            )
+            .await;
+        assert_eq!(result, Err("invalid token"));
+        assert_eq!(calls.get(), 1);
+
+        calls.set(0);
+        let result = policy()
+            .run(
+                || {
+                    calls.set(calls.get() + 1);
+                    let call = calls.get();
+                    async move {
+                        if call < 2 {
+                            Err("unavailable")
+                        } else {
+                            Ok(call)
+                        }
+                    }
+                },
+                |err| *err == "unavailable",
+            )
+            .await;
+        assert_eq!(result, Ok(2));
+    }
+}
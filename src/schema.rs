@@ -0,0 +1,181 @@
+use serde_json::Value;
+use std::sync::OnceLock;
+
+/// JSON Schema of the payload printed for Waybar.
+pub const OUTPUT_SCHEMA: &str = include_str!("../schema/waybar-output.schema.json");
+
+/// JSON Schema of the output of each device written to `--output-dir`.
+pub const DEVICE_OUTPUT_SCHEMA: &str = include_str!("../schema/device-output.schema.json");
+
+/// JSON Schema of the records printed with `--output jsonl`.
+pub const DEVICE_RECORD_SCHEMA: &str = include_str!("../schema/device-record.schema.json");
+
+fn parse(cell: &'static OnceLock<Value>, schema: &str) -> &'static Value {
+    cell.get_or_init(|| serde_json::from_str(schema).expect("valid schema"))
+}
+
+/// Checks a rendered payload against the output schema.
+///
+/// Only the keywords used by the schemas are supported: `type`, `enum`,
+/// `required`, `properties`, `additionalProperties` and `items`.
+pub fn validate_output(output: &Value) -> Result<(), String> {
+    static SCHEMA: OnceLock<Value> = OnceLock::new();
+    check(parse(&SCHEMA, OUTPUT_SCHEMA), output, "$")
+}
+
+/// Checks the output of a device against the device output schema.
+pub fn validate_device_output(output: &Value) -> Result<(), String> {
+    static SCHEMA: OnceLock<Value> = OnceLock::new();
+    check(parse(&SCHEMA, DEVICE_OUTPUT_SCHEMA), output, "$")
+}
+
+/// Checks a jsonl record against the device record schema.
+pub fn validate_device_record(record: &Value) -> Result<(), String> {
+    static SCHEMA: OnceLock<Value> = OnceLock::new();
+    check(parse(&SCHEMA, DEVICE_RECORD_SCHEMA), record, "$")
+}
+
+fn check(schema: &Value, value: &Value, path: &str) -> Result<(), String> {
+    if let Some(types) = schema.get("type") {
+        let matches = match types {
+            Value::Array(types) => types.iter().any(|t| has_type(value, t)),
+            t => has_type(value, t),
+        };
+        if !matches {
+            return Err(format!("{}: expected type {}, got {}", path, types, value));
+        }
+    }
+
+    if let Some(Value::Array(allowed)) = schema.get("enum") {
+        if !allowed.contains(value) {
+            return Err(format!("{}: {} is not one of {:?}", path, value, allowed));
+        }
+    }
+
+    if let Value::Object(object) = value {
+        if let Some(Value::Array(required)) = schema.get("required") {
+            for key in required.iter().filter_map(Value::as_str) {
+                if !object.contains_key(key) {
+                    return Err(format!("{}: missing property '{}'", path, key));
+                }
+            }
+        }
+        let properties = schema.get("properties").and_then(Value::as_object);
+        for (key, item) in object {
+            let item_path = format!("{}.{}", path, key);
+            match (
+                properties.and_then(|properties| properties.get(key)),
+                schema.get("additionalProperties"),
+            ) {
+                (Some(property), _) => check(property, item, &item_path)?,
+                (None, Some(Value::Bool(false))) => {
+                    return Err(format!("{}: unexpected property '{}'", path, key));
+                }
+                (None, Some(additional @ Value::Object(_))) => check(additional, item, &item_path)?,
+                (None, _) => {}
+            }
+        }
+    }
+
+    if let (Value::Array(items), Some(item_schema)) = (value, schema.get("items")) {
+        for (index, item) in items.iter().enumerate() {
+            check(item_schema, item, &format!("{}[{}]", path, index))?;
+        }
+    }
+
+    Ok(())
+}
+
+fn has_type(value: &Value, expected: &Value) -> bool {
+    match expected.as_str() {
+        Some("object") => value.is_object(),
+        Some("array") => value.is_array(),
+        Some("string") => value.is_string(),
+        Some("number") => value.is_number(),
+        Some("integer") => value.is_i64() || value.is_u64(),
+        Some("boolean") => value.is_boolean(),
+        Some("null") => value.is_null(),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_validate_output() {
+        assert!(validate_output(&json!({ "text": "P: 50.0W", "tooltip": "" })).is_ok());
+        assert!(validate_output(&json!({
+            "text": "Shelly: auth required",
+            "tooltip": "",
+            "class": "auth-required",
+        }))
+        .is_ok());
+        assert!(
+            validate_output(&json!({ "text": "", "tooltip": "", "class": ["a", "b"] })).is_ok()
+        );
+
+        assert_eq!(
+            validate_output(&json!({ "text": "P: 50.0W" })),
+            Err("$: missing property 'tooltip'".to_string())
+        );
+        assert_eq!(
+            validate_output(&json!({ "text": 50, "tooltip": "" })),
+            Err("$.text: expected type \"string\", got 50".to_string())
+        );
+        assert_eq!(
+            validate_output(&json!({ "text": "", "tooltip": "", "class": [1] })),
+            Err("$.class[0]: expected type \"string\", got 1".to_string())
+        );
+        assert_eq!(
+            validate_output(&json!({ "text": "", "tooltip": "", "extra": true })),
+            Err("$: unexpected property 'extra'".to_string())
+        );
+    }
+
+    #[test]
+    fn test_validate_device_output() {
+        assert!(validate_device_output(&json!({
+            "text": "🌡️ 21.5°C (stale: 2 h)",
+            "tooltip": "Balcony\nstale: 2 h (10:20:30)",
+            "class": ["alarm", "error"],
+            "alt": "temperature",
+            "percentage": 48,
+        }))
+        .is_ok());
+        assert_eq!(
+            validate_device_output(&json!({ "text": "", "tooltip": "", "percentage": 4.8 })),
+            Err("$.percentage: expected type \"integer\", got 4.8".to_string())
+        );
+    }
+
+    #[test]
+    fn test_validate_device_record() {
+        let record = json!({
+            "device_id": "67890",
+            "name": null,
+            "type": "plug",
+            "updated_at": "2024-01-15T10:20:30Z",
+            "timestamp": "2024-01-15T10:20:41Z",
+            "stale": false,
+            "values": { "power": 50.2, "output": "ON" },
+            "units": { "power": "W" },
+        });
+        assert!(validate_device_record(&record).is_ok());
+
+        let mut invalid = record.clone();
+        invalid["units"]["power"] = json!(1);
+        assert_eq!(
+            validate_device_record(&invalid),
+            Err("$.units.power: expected type \"string\", got 1".to_string())
+        );
+        let mut invalid = record;
+        invalid.as_object_mut().unwrap().remove("stale");
+        assert_eq!(
+            validate_device_record(&invalid),
+            Err("$: missing property 'stale'".to_string())
+        );
+    }
+}
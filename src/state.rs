@@ -0,0 +1,153 @@
+use crate::auth::Secret;
+use crate::PollState;
+use argon2::Argon2;
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use std::fs;
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::PathBuf;
+
+// Header of encrypted state files, followed by the salt, the nonce and the ciphertext
+const MAGIC: &[u8] = b"SHWBENC1";
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 12;
+
+/// On-disk copy of the polling state, optionally encrypted with a passphrase.
+#[derive(Debug)]
+pub struct StateStore {
+    pub path: PathBuf,
+    pub passphrase: Option<Secret>,
+}
+
+impl StateStore {
+    /// Loads the saved state, starting empty when the file does not exist yet.
+    pub fn load(&self) -> Result<PollState, Box<dyn std::error::Error>> {
+        let content = match fs::read(&self.path) {
+            Ok(content) => content,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                return Ok(PollState::default())
+            }
+            Err(err) => return Err(err.into()),
+        };
+
+        let plaintext = match (&self.passphrase, content.starts_with(MAGIC)) {
+            (Some(passphrase), true) => decrypt(passphrase, &content)?,
+            (None, true) => {
+                return Err(format!(
+                    "State file {} is encrypted: a passphrase is required",
+                    self.path.display()
+                )
+                .into())
+            }
+            (_, false) => content,
+        };
+        Ok(serde_json::from_slice(&plaintext)?)
+    }
+
+    /// Writes the state atomically, readable by the current user only.
+    pub fn save(&self, state: &PollState) -> Result<(), Box<dyn std::error::Error>> {
+        let plaintext = serde_json::to_vec(state)?;
+        let content = match &self.passphrase {
+            Some(passphrase) => encrypt(passphrase, &plaintext)?,
+            None => plaintext,
+        };
+
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let tmp_path = self.path.with_extension("tmp");
+        let mut file = fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(&tmp_path)?;
+        file.write_all(&content)?;
+        fs::rename(tmp_path, &self.path)?;
+        Ok(())
+    }
+}
+
+fn derive_key(passphrase: &Secret, salt: &[u8]) -> Result<Key, Box<dyn std::error::Error>> {
+    let mut key = Key::default();
+    Argon2::default()
+        .hash_password_into(passphrase.expose().as_bytes(), salt, &mut key)
+        .map_err(|err| format!("Unable to derive the state key - {}", err))?;
+    Ok(key)
+}
+
+fn encrypt(passphrase: &Secret, plaintext: &[u8]) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let mut salt = [0u8; SALT_LEN];
+    OsRng.fill_bytes(&mut salt);
+    let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+    let cipher = ChaCha20Poly1305::new(&derive_key(passphrase, &salt)?);
+    let ciphertext = cipher
+        .encrypt(&nonce, plaintext)
+        .map_err(|_| "Unable to encrypt the state")?;
+
+    Ok([MAGIC, &salt, &nonce, &ciphertext].concat())
+}
+
+fn decrypt(passphrase: &Secret, content: &[u8]) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let header_len = MAGIC.len() + SALT_LEN + NONCE_LEN;
+    if content.len() < header_len {
+        return Err("State file is truncated".into());
+    }
+    let salt = &content[MAGIC.len()..MAGIC.len() + SALT_LEN];
+    let nonce = Nonce::from_slice(&content[MAGIC.len() + SALT_LEN..header_len]);
+    let cipher = ChaCha20Poly1305::new(&derive_key(passphrase, salt)?);
+    cipher
+        .decrypt(nonce, &content[header_len..])
+        .map_err(|_| "Unable to decrypt the state file: wrong passphrase?".into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encrypt_decrypt() {
+        let passphrase = Secret::new("correct horse");
+        let encrypted = encrypt(&passphrase, b"front door open").unwrap();
+
+        assert!(encrypted.starts_with(MAGIC));
+        assert!(!encrypted
+            .windows(b"front door".len())
+            .any(|window| window == b"front door"));
+        assert_eq!(
+            decrypt(&passphrase, &encrypted).unwrap(),
+            b"front door open"
+        );
+        assert!(decrypt(&Secret::new("wrong"), &encrypted).is_err());
+    }
+
+    #[test]
+    fn test_save_and_load() {
+        let path = std::env::temp_dir().join(format!(
+            "shelly-waybar-test-state-{}.bin",
+            std::process::id()
+        ));
+        let encrypted = StateStore {
+            path: path.clone(),
+            passphrase: Some(Secret::new("correct horse")),
+        };
+        let mut state = PollState::default();
+        state
+            .door_status_map
+            .insert("door-12345:Front Door".to_string(), true);
+
+        encrypted.save(&state).unwrap();
+        let loaded = encrypted.load().unwrap();
+        assert_eq!(loaded.door_status_map, state.door_status_map);
+
+        let plain = StateStore {
+            path: path.clone(),
+            passphrase: None,
+        };
+        assert!(plain.load().is_err());
+        fs::remove_file(path).unwrap();
+        assert!(plain.load().unwrap().door_status_map.is_empty());
+    }
+}
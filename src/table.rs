@@ -0,0 +1,78 @@
+/// Line of the `status` table.
+#[derive(Debug, Clone, Default)]
+pub struct Row {
+    pub name: String,
+    pub device_type: String,
+    pub metrics: String,
+    pub battery: String,
+    pub rssi: String,
+    pub updated: String,
+}
+
+const HEADER: [&str; 6] = ["NAME", "TYPE", "READING", "BATTERY", "RSSI", "UPDATED"];
+
+/// Renders the rows under a header, each column padded to its widest cell.
+pub fn render(rows: &[Row]) -> String {
+    let lines: Vec<[&str; 6]> = std::iter::once(HEADER)
+        .chain(rows.iter().map(|row| {
+            [
+                row.name.as_str(),
+                &row.device_type,
+                &row.metrics,
+                &row.battery,
+                &row.rssi,
+                &row.updated,
+            ]
+        }))
+        .collect();
+    let mut widths = [0; 6];
+    for line in &lines {
+        for (width, cell) in widths.iter_mut().zip(line) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+
+    let mut table = String::new();
+    for line in &lines {
+        let cells: Vec<String> = line
+            .iter()
+            .zip(widths)
+            .map(|(cell, width)| format!("{:<width$}", cell, width = width))
+            .collect();
+        table.push_str(cells.join("  ").trim_end());
+        table.push('\n');
+    }
+    table
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let rows = [
+            Row {
+                name: "Balcony".to_string(),
+                device_type: "temperature".to_string(),
+                metrics: "T: 22.5°C H: 50%".to_string(),
+                battery: "80%".to_string(),
+                rssi: "-60dBm".to_string(),
+                updated: "35 s ago (10:19:55)".to_string(),
+            },
+            Row {
+                name: "Desk".to_string(),
+                device_type: "plug".to_string(),
+                metrics: "P: 50.2W".to_string(),
+                ..Row::default()
+            },
+        ];
+
+        assert_eq!(
+            render(&rows),
+            "NAME     TYPE         READING           BATTERY  RSSI    UPDATED\n\
+             Balcony  temperature  T: 22.5°C H: 50%  80%      -60dBm  35 s ago (10:19:55)\n\
+             Desk     plug         P: 50.2W\n"
+        );
+    }
+}
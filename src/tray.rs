@@ -0,0 +1,150 @@
+use tokio::sync::mpsc::UnboundedSender;
+
+/// Device entry of the tray menu.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(not(feature = "tray"), allow(dead_code))]
+pub struct TrayDevice {
+    /// Entry of the device in the devices list, with its channel selector
+    pub device: String,
+    /// Rendered text of the device
+    pub label: String,
+    /// Offers a toggle action, when the device has a relay and control is allowed
+    pub can_toggle: bool,
+}
+
+/// Content of the tray icon, shared with the Waybar output.
+#[derive(Debug, Clone, Default, PartialEq)]
+#[cfg_attr(not(feature = "tray"), allow(dead_code))]
+pub struct TrayState {
+    pub devices: Vec<TrayDevice>,
+    /// Merged tooltip of all devices
+    pub summary: String,
+    pub auth_required: bool,
+}
+
+impl TrayState {
+    #[cfg_attr(not(feature = "tray"), allow(dead_code))]
+    fn icon_name(&self) -> &'static str {
+        if self.auth_required {
+            "dialog-warning"
+        } else {
+            "network-wireless"
+        }
+    }
+}
+
+/// StatusNotifierItem showing the devices, with toggles sent back to the polling loop.
+#[cfg(feature = "tray")]
+pub struct TrayIcon {
+    handle: ksni::Handle<ShellyTray>,
+}
+
+#[cfg(feature = "tray")]
+struct ShellyTray {
+    state: TrayState,
+    toggles: UnboundedSender<String>,
+}
+
+#[cfg(feature = "tray")]
+impl ksni::Tray for ShellyTray {
+    fn id(&self) -> String {
+        env!("CARGO_PKG_NAME").into()
+    }
+
+    fn title(&self) -> String {
+        "Shelly".into()
+    }
+
+    fn icon_name(&self) -> String {
+        self.state.icon_name().into()
+    }
+
+    fn tool_tip(&self) -> ksni::ToolTip {
+        ksni::ToolTip {
+            title: "Shelly".into(),
+            description: self.state.summary.clone(),
+            ..Default::default()
+        }
+    }
+
+    fn menu(&self) -> Vec<ksni::MenuItem<Self>> {
+        use ksni::menu::{StandardItem, SubMenu};
+
+        self.state
+            .devices
+            .iter()
+            .map(|device| {
+                if !device.can_toggle {
+                    return StandardItem {
+                        label: device.label.clone(),
+                        enabled: false,
+                        ..Default::default()
+                    }
+                    .into();
+                }
+                let entry = device.device.clone();
+                SubMenu {
+                    label: device.label.clone(),
+                    submenu: vec![StandardItem {
+                        label: "Toggle".into(),
+                        activate: Box::new(move |tray: &mut Self| {
+                            let _ = tray.toggles.send(entry.clone());
+                        }),
+                        ..Default::default()
+                    }
+                    .into()],
+                    ..Default::default()
+                }
+                .into()
+            })
+            .collect()
+    }
+}
+
+#[cfg(feature = "tray")]
+impl TrayIcon {
+    pub async fn start(
+        toggles: UnboundedSender<String>,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        use ksni::TrayMethods;
+
+        let tray = ShellyTray {
+            state: TrayState::default(),
+            toggles,
+        };
+        Ok(TrayIcon {
+            handle: tray.spawn().await?,
+        })
+    }
+
+    pub async fn update(&self, state: TrayState) {
+        self.handle.update(|tray| tray.state = state).await;
+    }
+}
+
+#[cfg(not(feature = "tray"))]
+pub struct TrayIcon;
+
+#[cfg(not(feature = "tray"))]
+impl TrayIcon {
+    pub async fn start(
+        _toggles: UnboundedSender<String>,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        Err("shelly-waybar was built without tray support".into())
+    }
+
+    pub async fn update(&self, _state: TrayState) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_icon_name() {
+        let mut state = TrayState::default();
+        assert_eq!(state.icon_name(), "network-wireless");
+        state.auth_required = true;
+        assert_eq!(state.icon_name(), "dialog-warning");
+    }
+}
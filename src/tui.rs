@@ -0,0 +1,398 @@
+use crate::table::Row;
+use std::collections::VecDeque;
+use tokio::sync::mpsc::UnboundedSender;
+
+/// Values kept for the sparklines of each device.
+const HISTORY_LEN: usize = 120;
+
+/// Device line of the dashboard.
+#[derive(Debug, Clone, Default)]
+#[cfg_attr(not(feature = "tui"), allow(dead_code))]
+pub struct DashboardDevice {
+    /// Entry of the device in the devices list, with its channel selector
+    pub device: String,
+    pub row: Row,
+    /// Offers a toggle action, when the device has a relay and control is allowed
+    pub can_toggle: bool,
+    pub temperature: Option<f64>,
+    pub power: Option<f64>,
+}
+
+/// Content of the dashboard, shared with the Waybar output.
+#[derive(Debug, Clone, Default)]
+#[cfg_attr(not(feature = "tui"), allow(dead_code))]
+pub struct DashboardState {
+    pub devices: Vec<DashboardDevice>,
+    pub auth_required: bool,
+}
+
+/// Last values of a device, drawn as a sparkline.
+#[derive(Debug, Default)]
+#[cfg_attr(not(feature = "tui"), allow(dead_code))]
+struct History {
+    values: VecDeque<f64>,
+}
+
+#[cfg_attr(not(feature = "tui"), allow(dead_code))]
+impl History {
+    fn push(&mut self, value: f64) {
+        if self.values.len() == HISTORY_LEN {
+            self.values.pop_front();
+        }
+        self.values.push_back(value);
+    }
+
+    /// Heights of the bars from 1 to 8, the lowest value being the shortest one, since
+    /// temperatures hardly move away from their average.
+    fn bars(&self) -> Vec<u64> {
+        let min = self.values.iter().copied().fold(f64::INFINITY, f64::min);
+        let max = self
+            .values
+            .iter()
+            .copied()
+            .fold(f64::NEG_INFINITY, f64::max);
+        self.values
+            .iter()
+            .map(|value| match max > min {
+                true => 1 + ((value - min) / (max - min) * 7.0).round() as u64,
+                false => 1,
+            })
+            .collect()
+    }
+}
+
+/// Live table of the devices in the terminal, with toggles sent back to the polling loop.
+/// Dropping it hands the terminal and stderr back, e.g. to print the error ending the loop.
+#[cfg(feature = "tui")]
+pub struct Dashboard {
+    events: Option<std::sync::mpsc::Sender<Event>>,
+    ui: Option<std::thread::JoinHandle<()>>,
+    /// Descriptor of the original stderr
+    stderr: std::os::fd::RawFd,
+}
+
+#[cfg(feature = "tui")]
+enum Event {
+    Update(DashboardState),
+    Log(String),
+}
+
+#[cfg(feature = "tui")]
+impl Dashboard {
+    pub fn start(toggles: UnboundedSender<String>) -> Result<Self, Box<dyn std::error::Error>> {
+        let (sender, events) = std::sync::mpsc::channel();
+        let stderr = capture_stderr(sender.clone())?;
+        let terminal = match ratatui::try_init() {
+            Ok(terminal) => terminal,
+            Err(err) => {
+                restore_stderr(stderr);
+                return Err(err.into());
+            }
+        };
+        let ui = std::thread::spawn(move || run(terminal, events, toggles));
+        Ok(Dashboard {
+            events: Some(sender),
+            ui: Some(ui),
+            stderr,
+        })
+    }
+
+    pub fn update(&self, state: DashboardState) {
+        if let Some(events) = &self.events {
+            let _ = events.send(Event::Update(state));
+        }
+    }
+}
+
+#[cfg(feature = "tui")]
+impl Drop for Dashboard {
+    fn drop(&mut self) {
+        // The UI thread stops drawing once its events are gone, those of stderr included
+        self.events.take();
+        restore_stderr(self.stderr);
+        if let Some(ui) = self.ui.take() {
+            let _ = ui.join();
+        }
+        ratatui::restore();
+    }
+}
+
+#[cfg(feature = "tui")]
+fn restore_stderr(original: std::os::fd::RawFd) {
+    // SAFETY: `original` stays open for the whole process
+    unsafe { libc::dup2(original, libc::STDERR_FILENO) };
+}
+
+// Lines written to stderr, such as fetch errors, go to the log pane instead of over the
+// dashboard; a panic gets the terminal back to print its message. Returns the original stderr.
+#[cfg(feature = "tui")]
+fn capture_stderr(events: std::sync::mpsc::Sender<Event>) -> std::io::Result<std::os::fd::RawFd> {
+    use std::io::BufRead;
+    use std::os::fd::AsRawFd;
+
+    let (reader, writer) = std::io::pipe()?;
+    // SAFETY: dup and dup2 are called on open descriptors and report failures by their result
+    let original = unsafe { libc::dup(libc::STDERR_FILENO) };
+    if original < 0 || unsafe { libc::dup2(writer.as_raw_fd(), libc::STDERR_FILENO) } < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    let hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        restore_stderr(original);
+        hook(info);
+    }));
+
+    std::thread::spawn(move || {
+        for line in std::io::BufReader::new(reader).lines() {
+            let Ok(line) = line else {
+                return;
+            };
+            if events.send(Event::Log(line)).is_err() {
+                return;
+            }
+        }
+    });
+    Ok(original)
+}
+
+// Draw the dashboard and handle the keys until the user quits, exiting the process, or the
+// dashboard is dropped
+#[cfg(feature = "tui")]
+fn run(
+    mut terminal: ratatui::DefaultTerminal,
+    events: std::sync::mpsc::Receiver<Event>,
+    toggles: UnboundedSender<String>,
+) {
+    use ratatui::crossterm::event::{self, KeyCode, KeyEventKind, KeyModifiers};
+    use std::time::Duration;
+
+    use std::sync::mpsc::TryRecvError;
+
+    let mut app = App::default();
+    loop {
+        loop {
+            match events.try_recv() {
+                Ok(event) => app.apply(event),
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => return,
+            }
+        }
+        if terminal.draw(|frame| app.draw(frame)).is_err() {
+            break;
+        }
+        if !event::poll(Duration::from_millis(250)).unwrap_or(false) {
+            continue;
+        }
+        let Ok(event::Event::Key(key)) = event::read() else {
+            continue;
+        };
+        if key.kind != KeyEventKind::Press {
+            continue;
+        }
+        match key.code {
+            KeyCode::Char('q') | KeyCode::Esc => break,
+            KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => break,
+            KeyCode::Down | KeyCode::Char('j') => app.select_next(),
+            KeyCode::Up | KeyCode::Char('k') => app.select_previous(),
+            KeyCode::Enter | KeyCode::Char(' ') | KeyCode::Char('t') => app.toggle(&toggles),
+            _ => {}
+        }
+    }
+    ratatui::restore();
+    std::process::exit(0);
+}
+
+#[cfg(feature = "tui")]
+#[derive(Default)]
+struct App {
+    state: DashboardState,
+    selected: usize,
+    temperatures: std::collections::HashMap<String, History>,
+    powers: std::collections::HashMap<String, History>,
+    logs: VecDeque<String>,
+}
+
+#[cfg(feature = "tui")]
+impl App {
+    fn apply(&mut self, event: Event) {
+        match event {
+            Event::Update(state) => {
+                for device in &state.devices {
+                    if let Some(temperature) = device.temperature {
+                        let history = self.temperatures.entry(device.device.clone());
+                        history.or_default().push(temperature);
+                    }
+                    if let Some(power) = device.power {
+                        let history = self.powers.entry(device.device.clone());
+                        history.or_default().push(power);
+                    }
+                }
+                // The last readings stay on screen until the key is renewed
+                if state.auth_required {
+                    if !self.state.auth_required {
+                        self.log(
+                            "The Shelly cloud rejected the auth key, run `shelly-waybar login`",
+                        );
+                    }
+                    self.state.auth_required = true;
+                } else {
+                    self.selected = self.selected.min(state.devices.len().saturating_sub(1));
+                    self.state = state;
+                }
+            }
+            Event::Log(line) => self.log(&line),
+        }
+    }
+
+    fn log(&mut self, line: &str) {
+        if self.logs.len() == HISTORY_LEN {
+            self.logs.pop_front();
+        }
+        let time = chrono::Local::now().format("%H:%M:%S");
+        self.logs.push_back(format!("{} {}", time, line));
+    }
+
+    fn select_next(&mut self) {
+        if self.selected + 1 < self.state.devices.len() {
+            self.selected += 1;
+        }
+    }
+
+    fn select_previous(&mut self) {
+        self.selected = self.selected.saturating_sub(1);
+    }
+
+    fn toggle(&mut self, toggles: &UnboundedSender<String>) {
+        let Some(device) = self.state.devices.get(self.selected) else {
+            return;
+        };
+        let message = if !device.can_toggle {
+            format!(
+                "{} cannot be toggled: no relay, or control not allowed",
+                device.row.name
+            )
+        } else if toggles.send(device.device.clone()).is_ok() {
+            format!("Toggling {}", device.row.name)
+        } else {
+            return;
+        };
+        self.log(&message);
+    }
+
+    fn draw(&self, frame: &mut ratatui::Frame) {
+        use ratatui::layout::{Constraint, Layout};
+        use ratatui::style::{Modifier, Style};
+        use ratatui::widgets::{Block, Cell, List, Row, Sparkline, Table, TableState};
+
+        let [devices_area, sparklines_area, logs_area] = Layout::vertical([
+            Constraint::Min(4),
+            Constraint::Length(6),
+            Constraint::Length(8),
+        ])
+        .areas(frame.area());
+
+        let header = Row::new(["NAME", "TYPE", "READING", "BATTERY", "RSSI", "UPDATED"])
+            .style(Style::new().add_modifier(Modifier::BOLD));
+        let rows = self.state.devices.iter().map(|device| {
+            let row = &device.row;
+            Row::new([
+                Cell::from(row.name.as_str()),
+                Cell::from(row.device_type.as_str()),
+                Cell::from(row.metrics.as_str()),
+                Cell::from(row.battery.as_str()),
+                Cell::from(row.rssi.as_str()),
+                Cell::from(row.updated.as_str()),
+            ])
+        });
+        let title = match self.state.auth_required {
+            true => " Shelly (auth required) ",
+            false => " Shelly ",
+        };
+        let table = Table::new(
+            rows,
+            [
+                Constraint::Fill(2),
+                Constraint::Length(12),
+                Constraint::Fill(3),
+                Constraint::Length(8),
+                Constraint::Length(8),
+                Constraint::Fill(2),
+            ],
+        )
+        .header(header)
+        .block(
+            Block::bordered()
+                .title(title)
+                .title_bottom(" ↑↓ select  t toggle  q quit "),
+        )
+        .row_highlight_style(Style::new().add_modifier(Modifier::REVERSED));
+        let mut table_state = TableState::new().with_selected(Some(self.selected));
+        frame.render_stateful_widget(table, devices_area, &mut table_state);
+
+        let selected = self.state.devices.get(self.selected);
+        let [temperature_area, power_area] =
+            Layout::horizontal([Constraint::Fill(1), Constraint::Fill(1)]).areas(sparklines_area);
+        for (area, name, histories, unit) in [
+            (temperature_area, "Temperature", &self.temperatures, "°"),
+            (power_area, "Power", &self.powers, "W"),
+        ] {
+            let history = selected.and_then(|device| histories.get(&device.device));
+            let title = match history.and_then(|history| history.values.back()) {
+                Some(value) => format!(" {} {:.1}{} ", name, value, unit),
+                None => format!(" {} ", name),
+            };
+            let bars = history.map(History::bars).unwrap_or_default();
+            // The latest values, as many as fit
+            let width = area.width.saturating_sub(2) as usize;
+            let sparkline = Sparkline::default()
+                .block(Block::bordered().title(title))
+                .data(&bars[bars.len().saturating_sub(width)..])
+                .max(8);
+            frame.render_widget(sparkline, area);
+        }
+
+        let height = logs_area.height.saturating_sub(2) as usize;
+        let logs = List::new(
+            self.logs
+                .iter()
+                .skip(self.logs.len().saturating_sub(height))
+                .map(String::as_str),
+        )
+        .block(Block::bordered().title(" Log "));
+        frame.render_widget(logs, logs_area);
+    }
+}
+
+#[cfg(not(feature = "tui"))]
+pub struct Dashboard;
+
+#[cfg(not(feature = "tui"))]
+impl Dashboard {
+    pub fn start(_toggles: UnboundedSender<String>) -> Result<Self, Box<dyn std::error::Error>> {
+        Err("shelly-waybar was built without tui support".into())
+    }
+
+    pub fn update(&self, _state: DashboardState) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_history_bars() {
+        let mut history = History::default();
+        assert!(history.bars().is_empty());
+
+        history.push(21.0);
+        assert_eq!(history.bars(), [1]);
+        history.push(21.5);
+        history.push(22.4);
+        assert_eq!(history.bars(), [1, 4, 8]);
+
+        for _ in 0..HISTORY_LEN {
+            history.push(20.0);
+        }
+        assert_eq!(history.values.len(), HISTORY_LEN);
+    }
+}